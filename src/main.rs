@@ -1,84 +1,144 @@
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Barrier, RwLock};
+use std::sync::Arc;
 use std::thread;
+use std::env;
+use std::time::{Duration, SystemTime};
+#[cfg(windows)]
 use windows::initialize_mta;
 use std::error;
 use wasapi::wasapi::*;
+use wasapi::audiofile::open_audio_file;
+use wasapi::backend::*;
+use wasapi::bwf::{BextInfo, IxmlInfo};
+use wasapi::conversion::{bytes_to_channels, channels_to_bytes};
+use wasapi::distortion::DistortionMeasurement;
+use wasapi::engine::{capture_loop, get_stream_format, playback_loop, playback_loop_from, StreamSettings};
+use wasapi::filedevice::FileBackend;
+use wasapi::flac::{FlacSettings, ThreadedFlacWriter};
+use wasapi::generator::stepped_sine_frequencies;
+use wasapi::glitch::{GlitchDetector, GlitchSettings};
+use wasapi::history::{HistoryBuffer, HistorySaver, HistorySettings};
+use wasapi::jitter::{jitter_channel, Concealment, JitterSettings};
+use wasapi::latency::{LatencyMeasurement, Stimulus};
+use wasapi::loopback::WasapiLoopback;
+use wasapi::network::{NetProtocol, NetworkBackend};
+use wasapi::nulldevice::NullBackend;
+use wasapi::pipe::{PipeSink, PipeSource, UnderrunPolicy};
+use wasapi::player::{Player, PlayerEvent, PlayerSettings};
+use wasapi::rawpcm::{RawPcmReader, RawPcmWriter};
+use wasapi::rtp::{RtpEncoding, RtpStreamConfig};
+use wasapi::segment::{SegmentEvent, SegmentSettings, SegmentedRecorder};
+use wasapi::sharedmem::SharedMemReader;
+use wasapi::simulated::{Pacing, SimDeviceConfig};
+#[cfg(not(windows))]
+use wasapi::simulated::SimulatedBackend;
+use wasapi::sweep::SweepMeasurement;
+use wasapi::vad::{AutoRecorder, RecorderEvent, VadSettings};
+use wasapi::wav::{WavReader, WavWriter};
 
 type Res<T> = Result<T, Box<dyn error::Error>>;
 
-// Playback loop, play samples received from channel
-fn playback_loop(rx_play: std::sync::mpsc::Receiver<Vec<u8>>) -> Res<()> {
-    let collection = DeviceCollection::new(&Direction::Render)?;
-    let device = collection.get_device_with_name("SPDIF Interface (FX-AUDIO-DAC-X6)")?;
-    let mut audio_client = device.get_iaudioclient()?;
-    // int16
-    //let desired_format_ex = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
-    //let sharemode = ShareMode::Exclusive;
-    // float32
-    let desired_format_ex = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
-    let sharemode = ShareMode::Shared;
-    
-
-    let blockalign = desired_format_ex.get_blockalign();
-    desired_format_ex.print_waveformat();
-
-    
-
-    let supported_format = match audio_client.is_supported(&desired_format_ex, &sharemode)? {
-        FormatSupported::Yes => desired_format_ex,
-        FormatSupported::ClosestMatch(modified_format) => modified_format,
-    };
-    supported_format.print_waveformat();
-
-    let (def_time, min_time) = audio_client.get_periods()?;
-    println!("default period {}, min period {}", def_time, min_time);
+// Measure the round trip latency from a playback device to a capture device
+fn measure_latency(backend: &Arc<dyn Backend>, render_device: &str, capture_device: &str) -> Res<()> {
+    let format = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    let mut loopback = WasapiLoopback::with_backend(backend.clone(), render_device, capture_device, &format, &ShareMode::Shared);
+    let measurement = LatencyMeasurement::new(Stimulus::Mls { order: 15 });
+    let report = measurement.measure(&mut loopback)?;
+    println!("{}", report);
+    Ok(())
+}
 
+// Measure distortion and noise from a playback device to a capture device, and print a json report
+fn measure_distortion(backend: &Arc<dyn Backend>, render_device: &str, capture_device: &str) -> Res<()> {
+    let format = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    let mut loopback = WasapiLoopback::with_backend(backend.clone(), render_device, capture_device, &format, &ShareMode::Shared);
+    let measurement = DistortionMeasurement::new(&stepped_sine_frequencies(20.0, 10000.0, 3));
+    let report = measurement.measure(&mut loopback)?;
+    println!("{}", report.to_json()?);
+    Ok(())
+}
 
-    audio_client.initialize_client(&supported_format, min_time as i64, &Direction::Render, &sharemode)?;
-    println!("initialized playback");
+// Measure the frequency response from a playback device to a capture device,
+// and save the impulse response of each channel to a wav file
+fn measure_sweep(backend: &Arc<dyn Backend>, render_device: &str, capture_device: &str) -> Res<()> {
+    let format = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    let mut loopback = WasapiLoopback::with_backend(backend.clone(), render_device, capture_device, &format, &ShareMode::Shared);
+    let measurement = SweepMeasurement::new(20.0, 20000.0, 262144);
+    let responses = measurement.measure(&mut loopback)?;
+    for response in responses.iter() {
+        println!("Channel {}, latency {} frames", response.channel, response.latency);
+        for (n, freq) in response.response.frequencies.iter().enumerate() {
+            println!("{:8.1} Hz {:7.2} dB {:7.1} deg", freq, response.response.magnitude_db[n], response.response.phase_deg[n]);
+        }
+        for harmonic in response.harmonics.iter() {
+            // Only the frequencies where the harmonic stays within the sweep are valid
+            let max_db = harmonic.distortion_db.iter()
+                .zip(response.response.frequencies.iter())
+                .filter(|(_, freq)| *freq * harmonic.order as f64 <= measurement.f_end)
+                .map(|(level, _)| *level)
+                .fold(f64::NEG_INFINITY, f64::max);
+            println!("Harmonic {}, max {:.1} dB", harmonic.order, max_db);
+        }
+        let filename = format!("ir_ch{}.wav", response.channel);
+        response.save_impulse_response(&filename, 44100)?;
+        println!("Saved impulse response to {}", filename);
+    }
+    Ok(())
+}
 
+// Record from a capture device whenever there is voice activity, saving each recording to a wav file
+fn record_on_voice(backend: &Arc<dyn Backend>, capture_device: &str) -> Res<()> {
+    let collection = backend.get_device_collection(&Direction::Capture)?;
+    let device = collection.get_device_with_name(capture_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let format = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let blockalign = format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&format, min_time, &Direction::Capture, &ShareMode::Shared)?;
     let h_event = audio_client.set_get_eventhandle()?;
-
-    let mut buffer_frame_count = audio_client.get_bufferframecount()?;
-
-    let render_client = audio_client.get_audiorenderclient()?;
-    let mut sample_queue: VecDeque<u8> = VecDeque::with_capacity(100*blockalign as usize * (1024 + 2*buffer_frame_count as usize));
+    let capture_client = audio_client.get_audiocaptureclient()?;
+    let mut recorder = AutoRecorder::new(&format, VadSettings::new(44100), 0.5);
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    let mut writer = None;
+    let mut nbr_recordings = 0;
+    let mut ixml = IxmlInfo::new(&device.get_friendlyname()?, &device.get_id()?, &format);
     audio_client.start_stream()?;
+    let stream_start = SystemTime::now();
     loop {
-        buffer_frame_count = audio_client.get_available_frames()?;
-        println!("New buffer frame count {}", buffer_frame_count);
-        while sample_queue.len() < (blockalign as usize * buffer_frame_count as usize) {
-            println!("need more samples");
-            match rx_play.try_recv() {
-                Ok(chunk) => {
-                    println!("got chunk");
-                    for element in chunk.iter() {
-                        sample_queue.push_back(*element);
-                    }
+        let flags = capture_client.read_from_device_to_deque(blockalign, &mut sample_queue)?;
+        if flags.is_any_set() {
+            println!("capture flags: {}", flags);
+        }
+        let chunk: Vec<u8> = sample_queue.drain(..).collect();
+        for event in recorder.process(&chunk)? {
+            match event {
+                RecorderEvent::Started { position, time } => {
+                    let filename = format!("recording_{}.wav", nbr_recordings);
+                    println!("recording started at {:.2} s, saving to {}", time, filename);
+                    let bext = BextInfo::new(&filename, "wasapiplay", stream_start, 44100, position);
+                    ixml.time_reference = Some(bext.time_reference);
+                    let mut new_writer = WavWriter::create(&filename, &format)?;
+                    new_writer.add_bext(&bext)?;
+                    new_writer.add_ixml(&ixml)?;
+                    writer = Some(new_writer);
+                    nbr_recordings += 1;
                 }
-                Err(mpsc::TryRecvError::Empty) => {
-                    println!("no data, filling with zeros");
-                    for _ in 0..((blockalign as usize * buffer_frame_count as usize) - sample_queue.len())  {
-                        sample_queue.push_back(0);
+                RecorderEvent::Data(data) => {
+                    if let Some(writer) = writer.as_mut() {
+                        writer.write_bytes(&data)?;
                     }
                 }
-                Err(_) => {
-                    println!("oops");
-                    break;
+                RecorderEvent::Stopped { time, .. } => {
+                    if let Some(mut writer) = writer.take() {
+                        writer.close()?;
+                    }
+                    println!("recording stopped at {:.2} s", time);
                 }
             }
-            println!("deque len2 {}", sample_queue.len());
         }
-        //println!("wait for buf");
-
-        println!("write");
-        render_client.write_to_device_from_deque(buffer_frame_count as usize, blockalign as usize, &mut sample_queue )?;
-        println!("write ok");
-        if h_event.wait_for_event(100000).is_err() {
-            println!("error, stopping playback");
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping capture");
             audio_client.stop_stream()?;
             break;
         }
@@ -86,103 +146,700 @@ fn playback_loop(rx_play: std::sync::mpsc::Receiver<Vec<u8>>) -> Res<()> {
     Ok(())
 }
 
+// Record from a capture device to a flac file for a number of seconds.
+// The encoding runs in a separate thread, so the capture loop never waits for it.
+fn record_flac(backend: &Arc<dyn Backend>, capture_device: &str, filename: &str, seconds: f64) -> Res<()> {
+    let collection = backend.get_device_collection(&Direction::Capture)?;
+    let device = collection.get_device_with_name(capture_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let format = WaveFormat::new(24, 24, &SampleType::Int, 48000, 2);
+    let blockalign = format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&format, min_time, &Direction::Capture, &ShareMode::Shared)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let capture_client = audio_client.get_audiocaptureclient()?;
+    let writer = ThreadedFlacWriter::create(filename, &format, FlacSettings::new())?;
+    let total_frames = (seconds * format.get_samplespersec() as f64) as usize;
+    let mut nbr_frames = 0;
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    audio_client.start_stream()?;
+    while nbr_frames < total_frames {
+        let flags = capture_client.read_from_device_to_deque(blockalign, &mut sample_queue)?;
+        if flags.is_any_set() {
+            println!("capture flags: {}", flags);
+        }
+        let frames = (sample_queue.len() / blockalign).min(total_frames - nbr_frames);
+        let chunk: Vec<u8> = sample_queue.drain(..frames * blockalign).collect();
+        writer.write_bytes(&chunk)?;
+        nbr_frames += frames;
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping capture");
+            break;
+        }
+    }
+    audio_client.stop_stream()?;
+    println!("waiting for encoder, {} bytes pending", writer.get_pending_bytes());
+    let written = writer.close()?;
+    println!("saved {} frames to {}", written, filename);
+    Ok(())
+}
 
-// Capture loop, capture samples and send in chunks of "chunksize" frames to channel
-fn capture_loop(tx_capt: std::sync::mpsc::SyncSender<Vec<u8>>, chunksize: usize) -> Res<()> {
-    let collection = DeviceCollection::new(&Direction::Capture)?;
-    let device = collection.get_device_with_name("CABLE Output (VB-Audio Virtual Cable)")?;
+// Record continuously to a directory, in segments of a number of minutes that also split on full hours.
+// Only the last day of segments is kept.
+fn monitor(backend: &Arc<dyn Backend>, capture_device: &str, directory: &str, minutes: f64) -> Res<()> {
+    let collection = backend.get_device_collection(&Direction::Capture)?;
+    let device = collection.get_device_with_name(capture_device)?;
     let mut audio_client = device.get_iaudioclient()?;
+    let format = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let blockalign = format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&format, min_time, &Direction::Capture, &ShareMode::Shared)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let capture_client = audio_client.get_audiocaptureclient()?;
+    let mut settings = SegmentSettings::new(44100);
+    settings.max_duration = Some(60.0 * minutes);
+    settings.max_segments = Some((24.0 * 60.0 / minutes).ceil() as usize);
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    audio_client.start_stream()?;
+    let mut recorder = SegmentedRecorder::new(directory, &device.get_friendlyname()?, &format, SystemTime::now(), settings)?;
+    loop {
+        let flags = capture_client.read_from_device_to_deque(blockalign, &mut sample_queue)?;
+        if flags.is_any_set() {
+            println!("capture flags: {}", flags);
+        }
+        let chunk: Vec<u8> = sample_queue.drain(..).collect();
+        for event in recorder.write_bytes(&chunk)? {
+            match event {
+                SegmentEvent::Opened { path, .. } => println!("started {}", path.display()),
+                SegmentEvent::Closed { path, nbr_frames } => println!("saved {} frames to {}", nbr_frames, path.display()),
+                SegmentEvent::Deleted(path) => println!("deleted {}", path.display()),
+                SegmentEvent::DeleteFailed { path, error } => println!("could not delete {}: {}", path.display(), error),
+            }
+        }
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping capture");
+            audio_client.stop_stream()?;
+            break;
+        }
+    }
+    recorder.close()?;
+    Ok(())
+}
 
-    // int16
-    //let desired_format_ex = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
-    //let sharemode = ShareMode::Exclusive;
-    // float32
-    let desired_format_ex = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
-    let sharemode = ShareMode::Shared;
+// Keep the last seconds of a capture stream, and save them together with what follows
+// whenever a glitch is detected or enter is pressed.
+fn capture_history(backend: &Arc<dyn Backend>, capture_device: &str, directory: &str) -> Res<()> {
+    let collection = backend.get_device_collection(&Direction::Capture)?;
+    let device = collection.get_device_with_name(capture_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let format = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let blockalign = format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&format, min_time, &Direction::Capture, &ShareMode::Shared)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let capture_client = audio_client.get_audiocaptureclient()?;
+    let mut history = HistoryBuffer::new(&format, &HistorySettings::new(44100));
+    let trigger = history.get_trigger_handle();
+    thread::Builder::new()
+        .name("Trigger".to_string())
+        .spawn(move || {
+            let mut line = String::new();
+            while std::io::stdin().read_line(&mut line).is_ok_and(|len| len > 0) {
+                trigger.trigger();
+                line.clear();
+            }
+        })?;
+    let mut detector = GlitchDetector::new(2, GlitchSettings::new(44100));
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    audio_client.start_stream()?;
+    let saver = HistorySaver::new(directory, "{device}_{date}_{time}_{seq}.wav", &device.get_friendlyname()?, &format, SystemTime::now())?;
+    println!("press enter to save the last 10 seconds");
+    loop {
+        let flags = capture_client.read_from_device_to_deque(blockalign, &mut sample_queue)?;
+        let mut events = detector.report_flags(&flags, history.get_position());
+        let chunk: Vec<u8> = sample_queue.drain(..).collect();
+        events.extend(detector.process(&bytes_to_channels(&chunk, &format)?));
+        for event in events.iter() {
+            println!("glitch: {}", event);
+            // Long runs are reported when they end, the capture then starts as far back as possible
+            let position = history.trigger_at_or_later(event.position);
+            if position != event.position {
+                println!("trigger moved from position {} to {}", event.position, position);
+            }
+        }
+        for capture in history.process(&chunk)? {
+            println!("saving {} frames around position {}", capture.data.len() / blockalign, capture.trigger);
+            saver.save(capture)?;
+        }
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping capture");
+            audio_client.stop_stream()?;
+            break;
+        }
+    }
+    for path in saver.close()? {
+        println!("saved {}", path.display());
+    }
+    Ok(())
+}
 
-    let blockalign = desired_format_ex.get_blockalign();
-    println!("\nCapture requested");
-    desired_format_ex.print_waveformat();
+// Dump exactly what a capture device returns to a raw pcm file with a json sidecar.
+fn dump_capture(backend: &Arc<dyn Backend>, capture_device: &str, filename: &str, seconds: f64) -> Res<()> {
+    let collection = backend.get_device_collection(&Direction::Capture)?;
+    let device = collection.get_device_with_name(capture_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let format = WaveFormat::new(32, 32, &SampleType::Float, 48000, 2);
+    let blockalign = format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&format, min_time, &Direction::Capture, &ShareMode::Shared)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let capture_client = audio_client.get_audiocaptureclient()?;
+    audio_client.start_stream()?;
+    let mut writer = RawPcmWriter::create(filename, &format, &device.get_id()?, SystemTime::now())?;
+    let total_frames = (seconds * format.get_samplespersec() as f64) as u64;
+    while writer.get_nbr_frames() < total_frames {
+        let mut nbr_frames = capture_client.get_next_nbr_frames()? as usize;
+        while nbr_frames > 0 {
+            let mut data = vec![0u8; nbr_frames * blockalign];
+            let flags = capture_client.read_from_device(blockalign, &mut data)?;
+            writer.write_buffer(&data, &flags)?;
+            nbr_frames = capture_client.get_next_nbr_frames()? as usize;
+        }
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping capture");
+            break;
+        }
+    }
+    audio_client.stop_stream()?;
+    writer.close()?;
+    println!("saved {} frames with {} flagged buffers to {}", writer.get_nbr_frames(), writer.get_sidecar().events.len(), filename);
+    Ok(())
+}
 
-    let supported_format = match audio_client.is_supported(&desired_format_ex, &sharemode)? {
-        FormatSupported::Yes => desired_format_ex,
-        FormatSupported::ClosestMatch(modified_format) => modified_format,
+// Replay a raw pcm dump, using exactly the format it was captured with.
+// Shared mode is tried first, then exclusive mode.
+fn replay_dump(backend: &Arc<dyn Backend>, playback_device: &str, filename: &str) -> Res<()> {
+    let mut dump = RawPcmReader::open(filename)?;
+    let format = dump.get_format();
+    let collection = backend.get_device_collection(&Direction::Render)?;
+    let device = collection.get_device_with_name(playback_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let sharemode = match audio_client.is_supported(&format, &ShareMode::Shared)? {
+        FormatSupported::Yes => ShareMode::Shared,
+        FormatSupported::ClosestMatch(_) => match audio_client.is_supported(&format, &ShareMode::Exclusive) {
+            Ok(FormatSupported::Yes) => ShareMode::Exclusive,
+            _ => return Err(WasapiError::new("The device does not support the format of the dump").into()),
+        },
     };
-    println!("\nCapture got");
-    supported_format.print_waveformat();
+    format.print_waveformat();
+    let blockalign = format.get_blockalign() as usize;
     let (def_time, min_time) = audio_client.get_periods()?;
-    println!("default period {}, min period {}", def_time, min_time);
-
+    let period = match sharemode {
+        ShareMode::Shared => min_time,
+        ShareMode::Exclusive => def_time,
+    };
+    audio_client.initialize_client(&format, period, &Direction::Render, &sharemode)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let render_client = audio_client.get_audiorenderclient()?;
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    println!("replaying {} frames from {}", dump.get_nbr_frames(), dump.get_sidecar().device_id);
+    audio_client.start_stream()?;
+    loop {
+        let available = audio_client.get_available_frames()? as usize;
+        if dump.get_position() < dump.get_nbr_frames() {
+            if sample_queue.len() < available * blockalign {
+                sample_queue.extend(dump.read_bytes(available)?);
+            }
+        } else if sample_queue.is_empty() {
+            let padding = audio_client.get_current_padding()? as u64;
+            thread::sleep(Duration::from_millis(1000 * padding / format.get_samplespersec() as u64));
+            break;
+        }
+        let nbr_frames = available.min(sample_queue.len() / blockalign);
+        render_client.write_to_device_from_deque(nbr_frames, blockalign, &mut sample_queue)?;
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping playback");
+            break;
+        }
+    }
+    audio_client.stop_stream()?;
+    Ok(())
+}
 
-    audio_client.initialize_client(&supported_format, min_time as i64, &Direction::Capture, &sharemode)?;
-    println!("initialized capture");
+// Play a wav, aiff or flac file, converting the samples to the format used by the device
+fn play_file(backend: &Arc<dyn Backend>, playback_device: &str, filename: &str) -> Res<()> {
+    let mut file = open_audio_file(filename)?;
+    let file_format = file.get_format();
+    let collection = backend.get_device_collection(&Direction::Render)?;
+    let device = collection.get_device_with_name(playback_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let sharemode = ShareMode::Shared;
+    let device_format = match audio_client.is_supported(&file_format, &sharemode)? {
+        FormatSupported::Yes => file_format.clone(),
+        FormatSupported::ClosestMatch(modified_format) => modified_format,
+    };
+    if device_format.get_samplespersec() != file_format.get_samplespersec()
+        || device_format.get_nchannels() != file_format.get_nchannels()
+    {
+        return Err(WasapiError::new("The device does not support the sample rate and channels of the file").into());
+    }
+    device_format.print_waveformat();
+    let blockalign = device_format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&device_format, min_time, &Direction::Render, &sharemode)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let buffer_frame_count = audio_client.get_bufferframecount()? as usize;
+    let render_client = audio_client.get_audiorenderclient()?;
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    println!("playing {} frames", file.get_nbr_frames());
+    audio_client.start_stream()?;
+    loop {
+        let available = audio_client.get_available_frames()? as usize;
+        if file.get_position() < file.get_nbr_frames() {
+            if sample_queue.len() < available * blockalign {
+                let frames = file.read_frames(buffer_frame_count)?;
+                sample_queue.extend(channels_to_bytes(&frames, &device_format)?);
+            }
+        } else if sample_queue.is_empty() {
+            // Wait for the device to play what is left in its buffer
+            let padding = audio_client.get_current_padding()? as u64;
+            thread::sleep(Duration::from_millis(1000 * padding / device_format.get_samplespersec() as u64));
+            break;
+        }
+        let nbr_frames = available.min(sample_queue.len() / blockalign);
+        render_client.write_to_device_from_deque(nbr_frames, blockalign, &mut sample_queue)?;
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping playback");
+            break;
+        }
+    }
+    audio_client.stop_stream()?;
+    Ok(())
+}
 
+// Play a list of files back to back without gaps, resampling them to the rate used by the device.
+// Commands are read from stdin: "p" to pause or resume, "n" for the next track, "s <seconds>" to seek.
+fn play_playlist(backend: &Arc<dyn Backend>, playback_device: &str, filenames: &[String]) -> Res<()> {
+    let mut player = Player::new(&WaveFormat::new(32, 32, &SampleType::Float, 44100, 2), PlayerSettings::new());
+    for filename in filenames.iter() {
+        player.enqueue_file(filename)?;
+    }
+    let collection = backend.get_device_collection(&Direction::Render)?;
+    let device = collection.get_device_with_name(playback_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let sharemode = ShareMode::Shared;
+    let desired_format = open_audio_file(&filenames[0])?.get_format();
+    let device_format = match audio_client.is_supported(&desired_format, &sharemode)? {
+        FormatSupported::Yes => desired_format,
+        FormatSupported::ClosestMatch(modified_format) => modified_format,
+    };
+    device_format.print_waveformat();
+    player.set_output_format(&device_format);
+    let samplerate = device_format.get_samplespersec() as u64;
+    let mut last_second = u64::MAX;
+    player.set_position_callback(move |position| {
+        if position.output_frames / samplerate != last_second {
+            last_second = position.output_frames / samplerate;
+            if let Some(track) = position.track {
+                println!("track {} at {:.1} s", track, position.time);
+            }
+        }
+    });
+    let blockalign = device_format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&device_format, min_time, &Direction::Render, &sharemode)?;
     let h_event = audio_client.set_get_eventhandle()?;
+    let render_client = audio_client.get_audiorenderclient()?;
+    let (tx_cmd, rx_cmd) = mpsc::channel::<String>();
+    thread::Builder::new()
+        .name("Commands".to_string())
+        .spawn(move || {
+            let mut line = String::new();
+            while std::io::stdin().read_line(&mut line).is_ok_and(|len| len > 0) {
+                if tx_cmd.send(line.trim().to_string()).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        })?;
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    audio_client.start_stream()?;
+    loop {
+        while let Ok(command) = rx_cmd.try_recv() {
+            let parts: Vec<&str> = command.split_whitespace().collect();
+            let result = match parts.as_slice() {
+                ["p"] if player.is_paused() => {
+                    player.resume();
+                    Ok(())
+                }
+                ["p"] => {
+                    player.pause();
+                    Ok(())
+                }
+                ["n"] => player.skip_to(player.get_position().track.map(|track| track + 1).unwrap_or(0)),
+                ["s", seconds] => match seconds.parse::<f64>() {
+                    Ok(seconds) => player.seek_time(seconds),
+                    Err(_) => Err(WasapiError::new("Invalid time").into()),
+                },
+                _ => Err(WasapiError::new("Unknown command").into()),
+            };
+            if let Err(err) = result {
+                println!("{}", err);
+            }
+        }
+        for event in player.take_events() {
+            match event {
+                PlayerEvent::TrackStarted { index, .. } => println!("playing {}", player.get_track_name(index).unwrap_or("")),
+                PlayerEvent::QueueFinished => println!("end of playlist"),
+                _ => {}
+            }
+        }
+        if player.is_finished() && sample_queue.is_empty() {
+            let padding = audio_client.get_current_padding()? as u64;
+            thread::sleep(Duration::from_millis(1000 * padding / samplerate));
+            break;
+        }
+        let available = audio_client.get_available_frames()? as usize;
+        if sample_queue.len() < available * blockalign && !player.is_finished() {
+            sample_queue.extend(player.fill(available - sample_queue.len() / blockalign)?);
+        }
+        let nbr_frames = available.min(sample_queue.len() / blockalign);
+        render_client.write_to_device_from_deque(nbr_frames, blockalign, &mut sample_queue)?;
+        if h_event.wait_for_event(1000).is_err() {
+            println!("error, stopping playback");
+            break;
+        }
+    }
+    audio_client.stop_stream()?;
+    Ok(())
+}
 
-    let buffer_frame_count = audio_client.get_bufferframecount()?;
+// Parse a format given on the command line as samplerate, channels, bits and sample type ("int" or "float")
+fn parse_format(args: &[String]) -> Res<WaveFormat> {
+    let samplerate: usize = args[0].parse()?;
+    let channels: usize = args[1].parse()?;
+    let bits: usize = args[2].parse()?;
+    let sample_type = match args[3].as_str() {
+        "int" => SampleType::Int,
+        "float" => SampleType::Float,
+        _ => return Err(WasapiError::new("The sample type must be int or float").into()),
+    };
+    Ok(WaveFormat::new(8 * bits.div_ceil(8), bits, &sample_type, samplerate, channels))
+}
 
-    let render_client = audio_client.get_audiocaptureclient()?;
-    let mut sample_queue: VecDeque<u8> = VecDeque::with_capacity(100*blockalign as usize * (1024 + 2*buffer_frame_count as usize));
+// Render raw pcm read from stdin, for example piped from sox or ffmpeg.
+// Messages are printed to stderr, to keep them apart from any piped output.
+fn play_stdin(backend: &Arc<dyn Backend>, playback_device: &str, format: &WaveFormat, policy: UnderrunPolicy) -> Res<()> {
+    let collection = backend.get_device_collection(&Direction::Render)?;
+    let device = collection.get_device_with_name(playback_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let sharemode = match audio_client.is_supported(format, &ShareMode::Shared)? {
+        FormatSupported::Yes => ShareMode::Shared,
+        FormatSupported::ClosestMatch(_) => match audio_client.is_supported(format, &ShareMode::Exclusive) {
+            Ok(FormatSupported::Yes) => ShareMode::Exclusive,
+            _ => return Err(WasapiError::new("The device does not support the format").into()),
+        },
+    };
+    let blockalign = format.get_blockalign() as usize;
+    let (def_time, min_time) = audio_client.get_periods()?;
+    let period = match sharemode {
+        ShareMode::Shared => min_time,
+        ShareMode::Exclusive => def_time,
+    };
+    audio_client.initialize_client(format, period, &Direction::Render, &sharemode)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let buffer_frame_count = audio_client.get_bufferframecount()? as usize;
+    let render_client = audio_client.get_audiorenderclient()?;
+    let mut source = PipeSource::stdin(format, policy, 4 * buffer_frame_count)?;
+    // Start with a full buffer, to avoid underruns at the start
+    source.wait_for_frames(2 * buffer_frame_count, Duration::from_secs(10));
     audio_client.start_stream()?;
     loop {
-        //println!("deque len {}", sample_queue.len());
-        while sample_queue.len() > (blockalign as usize * chunksize as usize) {
-            println!("pushing samples");
-            let mut chunk = vec![0u8; blockalign as usize * chunksize as usize];
-            for element in chunk.iter_mut() {
-                *element = sample_queue.pop_front().unwrap();
-            }
-            tx_capt.send(chunk)?;
+        let available = audio_client.get_available_frames()? as usize;
+        let data = source.fill(available)?;
+        if !data.is_empty() {
+            render_client.write_to_device(data.len() / blockalign, blockalign, &data)?;
         }
-        println!("capturing");
-        render_client.read_from_device_to_deque(blockalign as usize, &mut sample_queue)?;
-        println!("captured");
-        if h_event.wait_for_event(1000000).is_err() {
-            println!("error, stopping capture");
-            audio_client.stop_stream()?;
+        if source.is_finished() {
+            // Wait for the device to play what is left in its buffer
+            let padding = audio_client.get_current_padding()? as u64;
+            thread::sleep(Duration::from_millis(1000 * padding / format.get_samplespersec() as u64));
+            break;
+        }
+        if h_event.wait_for_event(1000).is_err() {
+            eprintln!("error, stopping playback");
             break;
         }
     }
+    audio_client.stop_stream()?;
+    eprintln!("played {} frames, {} underruns", source.get_nbr_frames(), source.get_nbr_underruns());
     Ok(())
 }
 
-// Main loop
-fn main() -> Res<()> {
-    initialize_mta()?;
-    let (tx_play, rx_play): (std::sync::mpsc::SyncSender<Vec<u8>>, std::sync::mpsc::Receiver<Vec<u8>>) = mpsc::sync_channel(2);
+// Write captured raw pcm to stdout, until the reading program exits.
+fn capture_stdout(backend: &Arc<dyn Backend>, capture_device: &str, format: &WaveFormat) -> Res<()> {
+    let collection = backend.get_device_collection(&Direction::Capture)?;
+    let device = collection.get_device_with_name(capture_device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let blockalign = format.get_blockalign() as usize;
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(format, min_time, &Direction::Capture, &ShareMode::Shared)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    let capture_client = audio_client.get_audiocaptureclient()?;
+    let sink = PipeSink::stdout(format)?;
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    audio_client.start_stream()?;
+    loop {
+        // Read all packets that are waiting, one event can stand for several
+        while capture_client.get_next_nbr_frames()? > 0 {
+            capture_client.read_from_device_to_deque(blockalign, &mut sample_queue)?;
+        }
+        let chunk: Vec<u8> = sample_queue.drain(..).collect();
+        if sink.write_bytes(&chunk).is_err() {
+            break;
+        }
+        if h_event.wait_for_event(1000).is_err() {
+            eprintln!("error, stopping capture");
+            break;
+        }
+    }
+    audio_client.stop_stream()?;
+    // A closed pipe is the normal way to stop
+    let _ = sink.close();
+    Ok(())
+}
+
+// Pass the data from a capture device to a playback device, the devices may belong to different backends
+fn passthrough(playback_backend: &Arc<dyn Backend>, playback_device: &str, capture_backend: &Arc<dyn Backend>, capture_device: &str, format: &WaveFormat) -> Res<()> {
     let (tx_capt, rx_capt): (std::sync::mpsc::SyncSender<Vec<u8>>, std::sync::mpsc::Receiver<Vec<u8>>) = mpsc::sync_channel(2);
-    let buffer_fill = Arc::new(AtomicUsize::new(0));
-    let buffer_fill_clone = buffer_fill.clone();
     let chunksize = 4096;
-    
+    // The chunks go to the playback through a jitter buffer, that follows how regularly they arrive
+    let mut jitter = JitterSettings::new(2 * chunksize);
+    jitter.adaptive = true;
+    jitter.min_latency = chunksize;
+    jitter.max_latency = 8 * chunksize;
+    jitter.concealment = Concealment::Fade;
+    // The chunks are passed on without conversion, so both devices must use exactly the given format
+    let mut settings_play = StreamSettings::new(playback_device, format);
+    settings_play.exact_format = true;
+    let mut settings_capt = StreamSettings::new(capture_device, format);
+    settings_capt.exact_format = true;
+    settings_capt.chunksize = chunksize;
+    get_stream_format(playback_backend.as_ref(), &settings_play, &Direction::Render)?;
+    get_stream_format(capture_backend.as_ref(), &settings_capt, &Direction::Capture)?;
+    let (mut tx_play, mut rx_play) = jitter_channel(format, jitter);
+
     // Playback
+    let backend_play = playback_backend.clone();
     let _handle = thread::Builder::new()
         .name("Player".to_string())
         .spawn(move || {
-            let result = playback_loop(rx_play);
+            let result = playback_loop_from(backend_play.as_ref(), &settings_play, &mut rx_play);
+            println!("Playback stopped, {:?}", rx_play.get_stats());
             if let Err(err) = result {
                 println!("Playback failed with error {}", err);
             }
-        });
+        })?;
 
     // Capture
+    let backend_capt = capture_backend.clone();
     let _handle = thread::Builder::new()
         .name("Capture".to_string())
         .spawn(move || {
-            let result = capture_loop(tx_capt, chunksize);
+            let result = capture_loop(backend_capt.as_ref(), &settings_capt, tx_capt, &mut |event| println!("glitch: {}", event));
             if let Err(err) = result {
                 println!("Capture failed with error {}", err);
             }
-        });
+        })?;
 
     loop {
         match rx_capt.recv() {
             Ok(chunk) => {
                 println!("sending");
-                tx_play.send(chunk).unwrap();
+                tx_play.send(chunk)?;
+            },
+            Err(err) => {
+                println!("Some error {}", err);
+                return Ok(());
             },
-            Err(err) => println!("Some error {}", err),
         }
-        //return Ok(());
     }
 }
+
+// Parse a network protocol given on the command line
+fn parse_protocol(arg: &str) -> Res<NetProtocol> {
+    match arg {
+        "udp" => Ok(NetProtocol::Udp),
+        "tcp" => Ok(NetProtocol::Tcp),
+        _ => Err(WasapiError::new("The protocol must be udp or tcp").into()),
+    }
+}
+
+// Send the data from a capture device to another machine
+fn send_to_network(backend: &Arc<dyn Backend>, capture_device: &str, remote: &str, protocol: NetProtocol, format: &WaveFormat) -> Res<()> {
+    let network = NetworkBackend::new();
+    network.add_render_device(SimDeviceConfig::new("Network", &Direction::Render, format), remote, protocol, None)?;
+    let network: Arc<dyn Backend> = Arc::new(network);
+    passthrough(&network, "Network", backend, capture_device, format)
+}
+
+// Play the data received from another machine on a playback device, with an adaptive jitter buffer starting at 50 ms
+fn receive_from_network(backend: &Arc<dyn Backend>, playback_device: &str, port: u16, protocol: NetProtocol, format: &WaveFormat) -> Res<()> {
+    let network = NetworkBackend::new();
+    let mut jitter = JitterSettings::new(format.get_samplespersec() as usize / 20);
+    jitter.adaptive = true;
+    let addr = network.add_capture_device(SimDeviceConfig::new("Network", &Direction::Capture, format), ("0.0.0.0", port), protocol, jitter)?;
+    println!("listening on {}", addr);
+    let network: Arc<dyn Backend> = Arc::new(network);
+    passthrough(backend, playback_device, &network, "Network", format)
+}
+
+// Send the data from a capture device as an RTP stream, and print the session description for the receivers
+fn send_rtp(backend: &Arc<dyn Backend>, capture_device: &str, address: &str, encoding: &str, samplerate: usize, channels: usize) -> Res<()> {
+    let encoding = match encoding {
+        "L16" => RtpEncoding::L16,
+        "L24" => RtpEncoding::L24,
+        _ => return Err(WasapiError::new("The encoding must be L16 or L24").into()),
+    };
+    let stream = RtpStreamConfig::new("RTP", address.parse()?, encoding, samplerate, channels);
+    let network = NetworkBackend::new();
+    let origin = network.add_rtp_sender(&stream)?;
+    print!("{}", stream.get_sdp(origin.ip()));
+    let network: Arc<dyn Backend> = Arc::new(network);
+    passthrough(&network, "RTP", backend, capture_device, &stream.get_format())
+}
+
+// Play an RTP stream described by an SDP file on a playback device, with an adaptive jitter buffer starting at 50 ms
+fn receive_rtp(backend: &Arc<dyn Backend>, playback_device: &str, sdpfile: &str) -> Res<()> {
+    let sdp = std::fs::read_to_string(sdpfile)?;
+    let stream = RtpStreamConfig::from_sdp("RTP", &sdp)?;
+    let network = NetworkBackend::new();
+    let mut jitter = JitterSettings::new(stream.samplerate / 20);
+    jitter.adaptive = true;
+    let addr = network.add_rtp_receiver(&stream, jitter)?;
+    println!("listening on {}", addr);
+    let network: Arc<dyn Backend> = Arc::new(network);
+    passthrough(backend, playback_device, &network, "RTP", &stream.get_format())
+}
+
+// Play the frames written to a shared memory ring by another process, until that process closes it.
+// The ring is created here, holding 100 ms, and the other process attaches to it.
+fn play_shared_memory(backend: &Arc<dyn Backend>, playback_device: &str, path: &str, format: &WaveFormat) -> Res<()> {
+    let mut reader = SharedMemReader::create(path, format, format.get_samplespersec() as usize / 10)?;
+    // The other process writes in the format of the ring, which the device must use as it is
+    let mut settings = StreamSettings::new(playback_device, format);
+    settings.exact_format = true;
+    println!("waiting for frames in {}", path);
+    playback_loop_from(backend.as_ref(), &settings, &mut reader)?;
+    println!("done, {} frames were dropped", reader.get_overruns());
+    Ok(())
+}
+
+// Run the passthrough offline, from one wav file to another
+fn passthrough_files(input: &str, output: &str, pacing: Pacing) -> Res<()> {
+    let reader = WavReader::open(input)?;
+    let format = reader.get_format();
+    let nbr_frames = reader.get_nbr_frames();
+    let file_backend = FileBackend::new(pacing);
+    file_backend.add_capture_file("File input", input)?;
+    file_backend.add_render_file(SimDeviceConfig::new("File output", &Direction::Render, &format), output)?;
+
+    // The capture stream stops at the end of the file, and sends straight to the playback stream
+    // so that the virtual clock can not run ahead of the data
+    let (tx, rx) = mpsc::sync_channel(2);
+    let mut settings_capt = StreamSettings::new("File input", &format);
+    settings_capt.max_frames = Some(nbr_frames);
+    let backend_capt = file_backend.clone();
+    let handle = thread::Builder::new()
+        .name("Capture".to_string())
+        .spawn(move || capture_loop(&backend_capt, &settings_capt, tx, &mut |event| println!("glitch: {}", event)).map_err(|err| err.to_string()))?;
+    playback_loop(&file_backend, &StreamSettings::new("File output", &format), rx)?;
+    if let Ok(Err(err)) = handle.join() {
+        println!("Capture failed with error {}", err);
+    }
+    let time = file_backend.get_simulated().get_time().as_secs_f64();
+    println!("Processed {} frames, {:.1} seconds", nbr_frames, time);
+    Ok(())
+}
+
+// The devices of the system, using WASAPI
+#[cfg(windows)]
+fn system_backend() -> Res<Arc<dyn Backend>> {
+    initialize_mta()?;
+    Ok(Arc::new(NullBackend::new(WasapiBackend::new())))
+}
+
+// Without WASAPI there are no system devices, only the null devices and the file and network commands are available
+#[cfg(not(windows))]
+fn system_backend() -> Res<Arc<dyn Backend>> {
+    let simulated = SimulatedBackend::new();
+    simulated.set_pacing(Pacing::RealTime);
+    Ok(Arc::new(NullBackend::new(simulated)))
+}
+
+// Main loop
+fn main() -> Res<()> {
+    // The null devices can be used by all commands, under their reserved names
+    let backend = system_backend()?;
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 4 && args[1] == "latency" {
+        return measure_latency(&backend, &args[2], &args[3]);
+    }
+    if args.len() == 4 && args[1] == "distortion" {
+        return measure_distortion(&backend, &args[2], &args[3]);
+    }
+    if args.len() == 4 && args[1] == "sweep" {
+        return measure_sweep(&backend, &args[2], &args[3]);
+    }
+    if args.len() == 3 && args[1] == "record" {
+        return record_on_voice(&backend, &args[2]);
+    }
+    if args.len() == 5 && args[1] == "flac" {
+        return record_flac(&backend, &args[2], &args[3], args[4].parse()?);
+    }
+    if args.len() == 5 && args[1] == "monitor" {
+        return monitor(&backend, &args[2], &args[3], args[4].parse()?);
+    }
+    if args.len() == 4 && args[1] == "history" {
+        return capture_history(&backend, &args[2], &args[3]);
+    }
+    if args.len() == 5 && args[1] == "dump" {
+        return dump_capture(&backend, &args[2], &args[3], args[4].parse()?);
+    }
+    if args.len() == 4 && args[1] == "replay" {
+        return replay_dump(&backend, &args[2], &args[3]);
+    }
+    if args.len() == 4 && args[1] == "play" {
+        return play_file(&backend, &args[2], &args[3]);
+    }
+    if (args.len() == 7 || args.len() == 8) && args[1] == "pipeplay" {
+        let policy = if args.len() == 8 && args[7] == "block" { UnderrunPolicy::Block } else { UnderrunPolicy::Silence };
+        return play_stdin(&backend, &args[2], &parse_format(&args[3..7])?, policy);
+    }
+    if args.len() == 7 && args[1] == "pipecapture" {
+        return capture_stdout(&backend, &args[2], &parse_format(&args[3..7])?);
+    }
+    if args.len() >= 4 && args[1] == "playlist" {
+        return play_playlist(&backend, &args[2], &args[3..]);
+    }
+    if (args.len() == 4 || args.len() == 5) && args[1] == "offline" {
+        let pacing = if args.len() == 5 && args[4] == "realtime" { Pacing::RealTime } else { Pacing::AsFastAsPossible };
+        return passthrough_files(&args[2], &args[3], pacing);
+    }
+    if args.len() == 9 && args[1] == "netsend" {
+        return send_to_network(&backend, &args[2], &args[3], parse_protocol(&args[4])?, &parse_format(&args[5..9])?);
+    }
+    if args.len() == 9 && args[1] == "netreceive" {
+        return receive_from_network(&backend, &args[2], args[3].parse()?, parse_protocol(&args[4])?, &parse_format(&args[5..9])?);
+    }
+    if args.len() == 7 && args[1] == "rtpsend" {
+        return send_rtp(&backend, &args[2], &args[3], &args[4], args[5].parse()?, args[6].parse()?);
+    }
+    if args.len() == 4 && args[1] == "rtpreceive" {
+        return receive_rtp(&backend, &args[2], &args[3]);
+    }
+    if args.len() == 8 && args[1] == "shmplay" {
+        return play_shared_memory(&backend, &args[2], &args[3], &parse_format(&args[4..8])?);
+    }
+    passthrough(&backend, "SPDIF Interface (FX-AUDIO-DAC-X6)", &backend, "CABLE Output (VB-Audio Virtual Cable)", &WaveFormat::new(32, 32, &SampleType::Float, 44100, 2))
+}
@@ -1,13 +1,21 @@
+// The bindings are only generated on Windows, the rest of the crate builds on all platforms.
+#[cfg(not(windows))]
+fn main() {}
+
+#[cfg(windows)]
 fn main() {
     windows::build!(
         Windows::Win32::Media::Audio::CoreAudio::{
             eConsole, eRender, eCapture, IAudioClient, IAudioRenderClient, IAudioCaptureClient, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator, IMMDeviceCollection,
             AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, AUDCLNT_STREAMFLAGS_LOOPBACK, DEVICE_STATE_ACTIVE, WAVE_FORMAT_EXTENSIBLE,
+            AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR,
         },
         Windows::Win32::Devices::FunctionDiscovery::IFunctionInstance,
         Windows::Win32::Media::Multimedia::{
             WAVEFORMATEX,
             WAVEFORMATEXTENSIBLE,
+            WAVE_FORMAT_PCM,
+            WAVE_FORMAT_IEEE_FLOAT,
             KSDATAFORMAT_SUBTYPE_PCM,
             KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
         },
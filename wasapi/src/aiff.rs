@@ -0,0 +1,487 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::conversion::{bytes_to_channels, channels_to_bytes};
+use crate::wasapi::{SampleType, WasapiError, WasapiRes, WaveFormat};
+
+// Version of the AIFC specification, stored in the FVER chunk.
+const AIFC_VERSION: u32 = 0xA280_5140;
+
+// Sample encoding of an AIFF or AIFC file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AiffEncoding {
+    // Big-endian signed integers, plain AIFF or AIFC with compression type NONE or twos
+    BigEndian,
+    // Little-endian signed integers, AIFC with compression type sowt
+    LittleEndian,
+    // Big-endian 32-bit float, AIFC with compression type fl32
+    Float32,
+    // Big-endian 64-bit float, AIFC with compression type fl64
+    Float64,
+}
+
+impl AiffEncoding {
+    // Get the default encoding for a format, big-endian integers or float of the same size
+    pub fn from_format(wave_fmt: &WaveFormat) -> WasapiRes<Self> {
+        match (wave_fmt.get_subformat()?, wave_fmt.get_bitspersample()) {
+            (SampleType::Int, _) => Ok(AiffEncoding::BigEndian),
+            (SampleType::Float, 32) => Ok(AiffEncoding::Float32),
+            (SampleType::Float, 64) => Ok(AiffEncoding::Float64),
+            (SampleType::Float, bits) => {
+                Err(WasapiError::new(format!("Unsupported float size {}", bits).as_str()).into())
+            }
+        }
+    }
+
+    // Get the AIFC compression type and name
+    fn compression(&self) -> (&'static [u8; 4], &'static str) {
+        match self {
+            AiffEncoding::BigEndian => (b"NONE", "not compressed"),
+            AiffEncoding::LittleEndian => (b"sowt", ""),
+            AiffEncoding::Float32 => (b"fl32", "32-bit floating point"),
+            AiffEncoding::Float64 => (b"fl64", "64-bit floating point"),
+        }
+    }
+
+    fn from_compression(id: &[u8; 4]) -> WasapiRes<Self> {
+        match id {
+            b"NONE" | b"twos" => Ok(AiffEncoding::BigEndian),
+            b"sowt" => Ok(AiffEncoding::LittleEndian),
+            b"fl32" | b"FL32" => Ok(AiffEncoding::Float32),
+            b"fl64" | b"FL64" => Ok(AiffEncoding::Float64),
+            _ => Err(WasapiError::new(
+                format!(
+                    "Unsupported AIFC compression type {}",
+                    String::from_utf8_lossy(id)
+                )
+                .as_str(),
+            )
+            .into()),
+        }
+    }
+}
+
+// Convert a value to an 80-bit IEEE 754 extended precision number, as used for the sample rate.
+pub fn f64_to_extended(value: f64) -> [u8; 10] {
+    let mut bytes = [0u8; 10];
+    if value == 0.0 || !value.is_finite() {
+        return bytes;
+    }
+    let sign = if value < 0.0 { 0x8000 } else { 0 };
+    let bits = value.abs().to_bits();
+    let exponent = ((bits >> 52) & 0x7FF) as i32;
+    let fraction = bits & ((1 << 52) - 1);
+    let (exponent, mantissa) = if exponent == 0 {
+        // Subnormal, normalize so that the integer bit is set
+        let shift = fraction.leading_zeros();
+        (-1011 - shift as i32, fraction << shift)
+    } else {
+        (exponent - 1023, (1 << 63) | (fraction << 11))
+    };
+    let sign_exponent = sign | (exponent + 16383) as u16;
+    bytes[0..2].copy_from_slice(&sign_exponent.to_be_bytes());
+    bytes[2..10].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
+
+// Convert an 80-bit IEEE 754 extended precision number to f64.
+pub fn extended_to_f64(bytes: &[u8; 10]) -> f64 {
+    let sign_exponent = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&bytes[2..10]);
+    let mantissa = u64::from_be_bytes(raw);
+    if mantissa == 0 {
+        return 0.0;
+    }
+    let exponent = (sign_exponent & 0x7FFF) as i32 - 16383;
+    // Scale in two steps, to avoid overflowing the power of two for values near the limits of f64
+    let value = mantissa as f64
+        * 2.0f64.powi(-63)
+        * 2.0f64.powi(exponent / 2)
+        * 2.0f64.powi(exponent - exponent / 2);
+    if sign_exponent & 0x8000 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+// Convert between the sample layout of a WaveFormat and the one of an AIFF file.
+// The conversion is the same in both directions.
+fn convert_samples(data: &mut [u8], bytes_per_sample: usize, encoding: AiffEncoding) {
+    if encoding != AiffEncoding::LittleEndian {
+        for sample in data.chunks_exact_mut(bytes_per_sample) {
+            sample.reverse();
+        }
+    }
+    // 8-bit samples are signed in AIFF and unsigned in WAV
+    if bytes_per_sample == 1 {
+        for value in data.iter_mut() {
+            *value ^= 0x80;
+        }
+    }
+}
+
+fn read_u32_be<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+// Streaming reader for AIFF and AIFC files.
+// The data is converted to the little-endian layout described by the WaveFormat.
+pub struct AiffReader<R: Read + Seek> {
+    reader: R,
+    wave_fmt: WaveFormat,
+    encoding: AiffEncoding,
+    is_aifc: bool,
+    data_offset: u64,
+    nbr_frames: u64,
+    position: u64,
+}
+
+impl AiffReader<BufReader<File>> {
+    // Open an aiff file
+    pub fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        AiffReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> AiffReader<R> {
+    // Read the headers from a reader. A file that was not finalized is read up to the end.
+    pub fn new(mut reader: R) -> WasapiRes<Self> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"FORM" {
+            return Err(WasapiError::new("Not an aiff file").into());
+        }
+        let is_aifc = match &header[8..12] {
+            b"AIFF" => false,
+            b"AIFC" => true,
+            _ => return Err(WasapiError::new("Not an aiff file").into()),
+        };
+        let mut comm = None;
+        let mut ssnd = None;
+        let mut position = 12;
+        while position + 8 <= file_len {
+            reader.seek(SeekFrom::Start(position))?;
+            let mut id = [0u8; 4];
+            reader.read_exact(&mut id)?;
+            let size = read_u32_be(&mut reader)? as u64;
+            let offset = position + 8;
+            // The size of the SSND chunk is zero until the writer is closed
+            let size = if &id == b"SSND" && size == 0 {
+                file_len - offset
+            } else {
+                size.min(file_len - offset)
+            };
+            match &id {
+                b"COMM" => {
+                    let mut data = vec![0u8; size as usize];
+                    reader.read_exact(&mut data)?;
+                    comm = Some(data);
+                }
+                b"SSND" => ssnd = Some((offset, size)),
+                _ => {}
+            }
+            position = offset + size + size % 2;
+        }
+        let comm = comm.ok_or_else(|| WasapiError::new("The file has no COMM chunk"))?;
+        let (ssnd_offset, ssnd_size) =
+            ssnd.ok_or_else(|| WasapiError::new("The file has no SSND chunk"))?;
+        if comm.len() < 18 || (is_aifc && comm.len() < 22) {
+            return Err(WasapiError::new("The COMM chunk is too short").into());
+        }
+        let channels = u16::from_be_bytes([comm[0], comm[1]]) as usize;
+        let declared_frames = u32::from_be_bytes([comm[2], comm[3], comm[4], comm[5]]) as u64;
+        let bits = u16::from_be_bytes([comm[6], comm[7]]) as usize;
+        let mut rate = [0u8; 10];
+        rate.copy_from_slice(&comm[8..18]);
+        let samplerate = extended_to_f64(&rate).round() as usize;
+        let encoding = if is_aifc {
+            AiffEncoding::from_compression(&[comm[18], comm[19], comm[20], comm[21]])?
+        } else {
+            AiffEncoding::BigEndian
+        };
+        let wave_fmt = match encoding {
+            AiffEncoding::BigEndian | AiffEncoding::LittleEndian => {
+                if bits == 0 || bits > 32 {
+                    return Err(WasapiError::new(
+                        format!("Unsupported sample size {}", bits).as_str(),
+                    )
+                    .into());
+                }
+                let storebits = 8 * bits.div_ceil(8);
+                WaveFormat::new(storebits, bits, &SampleType::Int, samplerate, channels)
+            }
+            AiffEncoding::Float32 => {
+                WaveFormat::new(32, 32, &SampleType::Float, samplerate, channels)
+            }
+            AiffEncoding::Float64 => {
+                WaveFormat::new(64, 64, &SampleType::Float, samplerate, channels)
+            }
+        };
+        let blockalign = wave_fmt.get_blockalign() as u64;
+        if blockalign == 0 {
+            return Err(WasapiError::new("Invalid block alignment").into());
+        }
+        if ssnd_size < 8 {
+            return Err(WasapiError::new("The SSND chunk is too short").into());
+        }
+        reader.seek(SeekFrom::Start(ssnd_offset))?;
+        let data_start = read_u32_be(&mut reader)? as u64;
+        let _block_size = read_u32_be(&mut reader)?;
+        let data_offset = ssnd_offset + 8 + data_start;
+        let available_frames = (ssnd_size - 8).saturating_sub(data_start) / blockalign;
+        // A file that was not finalized has zero frames in the COMM chunk
+        let nbr_frames = if declared_frames == 0 {
+            available_frames
+        } else {
+            declared_frames.min(available_frames)
+        };
+        reader.seek(SeekFrom::Start(data_offset))?;
+        Ok(AiffReader {
+            reader,
+            wave_fmt,
+            encoding,
+            is_aifc,
+            data_offset,
+            nbr_frames,
+            position: 0,
+        })
+    }
+
+    // Get the format of the file
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the sample encoding of the file
+    pub fn get_encoding(&self) -> AiffEncoding {
+        self.encoding
+    }
+
+    // Check if the file is AIFC rather than plain AIFF
+    pub fn is_aifc(&self) -> bool {
+        self.is_aifc
+    }
+
+    // Get the length of the file in frames
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.nbr_frames
+    }
+
+    // Get the current position in frames
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    // Move to a frame
+    pub fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        let frame = frame.min(self.nbr_frames);
+        let blockalign = self.wave_fmt.get_blockalign() as u64;
+        self.reader
+            .seek(SeekFrom::Start(self.data_offset + frame * blockalign))?;
+        self.position = frame;
+        Ok(())
+    }
+
+    // Read raw data for up to nbr_frames frames in the layout of the WaveFormat, less at the end of the file
+    pub fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        let nbr_frames = (nbr_frames as u64).min(self.nbr_frames - self.position);
+        let mut data = vec![0u8; (nbr_frames * self.wave_fmt.get_blockalign() as u64) as usize];
+        self.reader.read_exact(&mut data)?;
+        self.position += nbr_frames;
+        let bytes_per_sample = self.wave_fmt.get_bitspersample() as usize / 8;
+        convert_samples(&mut data, bytes_per_sample, self.encoding);
+        Ok(data)
+    }
+
+    // Read up to nbr_frames frames, as one vector per channel
+    pub fn read_frames(&mut self, nbr_frames: usize) -> WasapiRes<Vec<Vec<f64>>> {
+        let data = self.read_bytes(nbr_frames)?;
+        bytes_to_channels(&data, &self.wave_fmt)
+    }
+}
+
+// Streaming writer for AIFF and AIFC files.
+// Big-endian integers are written as plain AIFF, the other encodings as AIFC.
+// The sizes are filled in when the writer is closed or dropped.
+pub struct AiffWriter<W: Write + Seek> {
+    writer: W,
+    wave_fmt: WaveFormat,
+    encoding: AiffEncoding,
+    // Offsets of the frame count in the COMM chunk and of the size of the SSND chunk
+    frames_offset: u64,
+    ssnd_size_offset: u64,
+    data_bytes: u64,
+    closed: bool,
+}
+
+impl AiffWriter<BufWriter<File>> {
+    // Create an aiff file
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        wave_fmt: &WaveFormat,
+        encoding: AiffEncoding,
+    ) -> WasapiRes<Self> {
+        AiffWriter::new(BufWriter::new(File::create(path)?), wave_fmt, encoding)
+    }
+}
+
+impl<W: Write + Seek> AiffWriter<W> {
+    // Create a writer for the given format and encoding, and write the header
+    pub fn new(mut writer: W, wave_fmt: &WaveFormat, encoding: AiffEncoding) -> WasapiRes<Self> {
+        let sample_type = wave_fmt.get_subformat()?;
+        let storebits = wave_fmt.get_bitspersample() as usize;
+        let validbits = wave_fmt.get_validbitspersample() as usize;
+        let supported = match (encoding, sample_type) {
+            (AiffEncoding::BigEndian, SampleType::Int)
+            | (AiffEncoding::LittleEndian, SampleType::Int) => {
+                (1..=32).contains(&validbits) && storebits == 8 * validbits.div_ceil(8)
+            }
+            (AiffEncoding::Float32, SampleType::Float) => storebits == 32,
+            (AiffEncoding::Float64, SampleType::Float) => storebits == 64,
+            _ => false,
+        };
+        if !supported || wave_fmt.get_blockalign() == 0 {
+            return Err(WasapiError::new(
+                format!(
+                    "The format can not be stored as {:?}, {} bits in {} bit samples",
+                    encoding, validbits, storebits
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        let is_aifc = encoding != AiffEncoding::BigEndian;
+        writer.write_all(b"FORM")?;
+        writer.write_all(&0u32.to_be_bytes())?;
+        writer.write_all(if is_aifc { b"AIFC" } else { b"AIFF" })?;
+        if is_aifc {
+            writer.write_all(b"FVER")?;
+            writer.write_all(&4u32.to_be_bytes())?;
+            writer.write_all(&AIFC_VERSION.to_be_bytes())?;
+        }
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&wave_fmt.get_nchannels().to_be_bytes());
+        comm.extend_from_slice(&0u32.to_be_bytes());
+        comm.extend_from_slice(&(validbits as u16).to_be_bytes());
+        comm.extend_from_slice(&f64_to_extended(wave_fmt.get_samplespersec() as f64));
+        if is_aifc {
+            let (id, name) = encoding.compression();
+            comm.extend_from_slice(id);
+            // Pascal string, padded to an even length
+            comm.push(name.len() as u8);
+            comm.extend_from_slice(name.as_bytes());
+            if name.len() % 2 == 0 {
+                comm.push(0);
+            }
+        }
+        writer.write_all(b"COMM")?;
+        writer.write_all(&(comm.len() as u32).to_be_bytes())?;
+        let frames_offset = writer.stream_position()? + 2;
+        writer.write_all(&comm)?;
+        writer.write_all(b"SSND")?;
+        let ssnd_size_offset = writer.stream_position()?;
+        writer.write_all(&0u32.to_be_bytes())?;
+        // Offset and block size
+        writer.write_all(&[0; 8])?;
+        Ok(AiffWriter {
+            writer,
+            wave_fmt: wave_fmt.clone(),
+            encoding,
+            frames_offset,
+            ssnd_size_offset,
+            data_bytes: 0,
+            closed: false,
+        })
+    }
+
+    // Get the format of the file
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the sample encoding of the file
+    pub fn get_encoding(&self) -> AiffEncoding {
+        self.encoding
+    }
+
+    // Get the number of frames written so far
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.data_bytes / self.wave_fmt.get_blockalign() as u64
+    }
+
+    // Get a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    // Write raw data in the layout of the WaveFormat, whole frames only
+    pub fn write_bytes(&mut self, data: &[u8]) -> WasapiRes<()> {
+        if self.closed {
+            return Err(WasapiError::new("The writer is closed").into());
+        }
+        if !data
+            .len()
+            .is_multiple_of(self.wave_fmt.get_blockalign() as usize)
+        {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        let mut converted = data.to_vec();
+        let bytes_per_sample = self.wave_fmt.get_bitspersample() as usize / 8;
+        convert_samples(&mut converted, bytes_per_sample, self.encoding);
+        self.writer.write_all(&converted)?;
+        self.data_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    // Write frames given as one vector per channel
+    pub fn write_frames(&mut self, channels: &[Vec<f64>]) -> WasapiRes<()> {
+        let data = channels_to_bytes(channels, &self.wave_fmt)?;
+        self.write_bytes(&data)
+    }
+
+    // Flush the written data to the file, without updating the header
+    pub fn flush(&mut self) -> WasapiRes<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // Pad the data and fill in the sizes in the header
+    pub fn close(&mut self) -> WasapiRes<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        if self.data_bytes % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        let file_len = self.writer.stream_position()?;
+        if file_len - 8 > u32::MAX as u64 {
+            return Err(WasapiError::new("The file is too large for AIFF").into());
+        }
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&((file_len - 8) as u32).to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(self.frames_offset))?;
+        self.writer
+            .write_all(&(self.get_nbr_frames() as u32).to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(self.ssnd_size_offset))?;
+        self.writer
+            .write_all(&((self.data_bytes + 8) as u32).to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(file_len))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for AiffWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::aiff::AiffReader;
+use crate::conversion::bytes_to_channels;
+use crate::flac::FlacReader;
+use crate::rawpcm::{sidecar_path, RawPcmReader};
+use crate::wasapi::{WasapiError, WasapiRes, WaveFormat};
+use crate::wav::WavReader;
+
+// Common interface of the readers for the supported file types.
+// The data is always returned in the layout described by the WaveFormat,
+// so that any file can be sent to a device that uses that format.
+pub trait AudioFileReader {
+    // Get the format of the file
+    fn get_format(&self) -> WaveFormat;
+
+    // Get the length of the file in frames
+    fn get_nbr_frames(&self) -> u64;
+
+    // Get the current position in frames
+    fn get_position(&self) -> u64;
+
+    // Move to a frame
+    fn seek(&mut self, frame: u64) -> WasapiRes<()>;
+
+    // Read raw data for up to nbr_frames frames, less at the end of the file
+    fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>>;
+
+    // Read up to nbr_frames frames, as one vector per channel
+    fn read_frames(&mut self, nbr_frames: usize) -> WasapiRes<Vec<Vec<f64>>> {
+        let data = self.read_bytes(nbr_frames)?;
+        bytes_to_channels(&data, &self.get_format())
+    }
+}
+
+impl<R: Read + Seek> AudioFileReader for WavReader<R> {
+    fn get_format(&self) -> WaveFormat {
+        WavReader::get_format(self)
+    }
+
+    fn get_nbr_frames(&self) -> u64 {
+        WavReader::get_nbr_frames(self)
+    }
+
+    fn get_position(&self) -> u64 {
+        WavReader::get_position(self)
+    }
+
+    fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        WavReader::seek(self, frame)
+    }
+
+    fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        WavReader::read_bytes(self, nbr_frames)
+    }
+}
+
+impl<R: Read + Seek> AudioFileReader for AiffReader<R> {
+    fn get_format(&self) -> WaveFormat {
+        AiffReader::get_format(self)
+    }
+
+    fn get_nbr_frames(&self) -> u64 {
+        AiffReader::get_nbr_frames(self)
+    }
+
+    fn get_position(&self) -> u64 {
+        AiffReader::get_position(self)
+    }
+
+    fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        AiffReader::seek(self, frame)
+    }
+
+    fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        AiffReader::read_bytes(self, nbr_frames)
+    }
+}
+
+impl<R: Read + Seek> AudioFileReader for FlacReader<R> {
+    fn get_format(&self) -> WaveFormat {
+        FlacReader::get_format(self)
+    }
+
+    fn get_nbr_frames(&self) -> u64 {
+        FlacReader::get_nbr_frames(self)
+    }
+
+    fn get_position(&self) -> u64 {
+        FlacReader::get_position(self)
+    }
+
+    fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        FlacReader::seek(self, frame)
+    }
+
+    fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        FlacReader::read_bytes(self, nbr_frames)
+    }
+}
+
+impl<R: Read + Seek> AudioFileReader for RawPcmReader<R> {
+    fn get_format(&self) -> WaveFormat {
+        RawPcmReader::get_format(self)
+    }
+
+    fn get_nbr_frames(&self) -> u64 {
+        RawPcmReader::get_nbr_frames(self)
+    }
+
+    fn get_position(&self) -> u64 {
+        RawPcmReader::get_position(self)
+    }
+
+    fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        RawPcmReader::seek(self, frame)
+    }
+
+    fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        RawPcmReader::read_bytes(self, nbr_frames)
+    }
+}
+
+// The supported file types.
+enum FileKind {
+    Wav,
+    Aiff,
+    Flac,
+}
+
+// Get the file type from the first bytes of a file.
+fn file_kind(magic: &[u8; 4]) -> Option<FileKind> {
+    match magic {
+        // Wave64 files start with "riff" followed by the rest of a GUID
+        b"RIFF" | b"RF64" | b"BW64" | b"riff" => Some(FileKind::Wav),
+        b"FORM" => Some(FileKind::Aiff),
+        b"fLaC" => Some(FileKind::Flac),
+        _ => None,
+    }
+}
+
+// Open a reader for an audio file, with the type given by the contents of the file.
+pub fn open_reader<R: Read + Seek + 'static>(mut reader: R) -> WasapiRes<Box<dyn AudioFileReader>> {
+    let mut magic = [0u8; 4];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+    match file_kind(&magic) {
+        Some(FileKind::Wav) => Ok(Box::new(WavReader::new(reader)?)),
+        Some(FileKind::Aiff) => Ok(Box::new(AiffReader::new(reader)?)),
+        Some(FileKind::Flac) => Ok(Box::new(FlacReader::new(reader)?)),
+        None => Err(WasapiError::new("Unknown file type").into()),
+    }
+}
+
+// Open an audio file of any supported type.
+// A file of unknown type with a JSON sidecar next to it is opened as a raw PCM dump,
+// files that start like a supported type are always opened as that type.
+pub fn open_audio_file<P: AsRef<Path>>(path: P) -> WasapiRes<Box<dyn AudioFileReader>> {
+    let mut file = BufReader::new(File::open(&path)?);
+    let mut magic = [0u8; 4];
+    let known = file.read_exact(&mut magic).is_ok() && file_kind(&magic).is_some();
+    if !known && sidecar_path(&path).is_file() {
+        return Ok(Box::new(RawPcmReader::open(path)?));
+    }
+    open_reader(file)
+}
@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+
+use crate::wasapi::{
+    BufferFlags, Direction, FormatSupported, ShareMode, WasapiError, WasapiRes, WaveFormat,
+};
+#[cfg(windows)]
+use crate::wasapi::{
+    AudioCaptureClient, AudioClient, AudioRenderClient, Device, DeviceCollection, Handle,
+};
+
+// The traits in this module describe what the rest of the code needs from an audio system:
+// device enumeration, format queries, stream initialization, buffer access, events and start/stop.
+// They follow the WASAPI objects, which are the first implementation,
+// and let the same code run against other implementations, for example simulated devices in tests.
+
+// Entry point of a backend, giving access to the devices.
+pub trait Backend: Send + Sync {
+    // Get a collection of all active playback or capture devices
+    fn get_device_collection(
+        &self,
+        direction: &Direction,
+    ) -> WasapiRes<Box<dyn DeviceCollectionTrait>>;
+
+    // Get the default playback or capture device
+    fn get_default_device(&self, direction: &Direction) -> WasapiRes<Box<dyn DeviceTrait>>;
+}
+
+// A collection of devices.
+pub trait DeviceCollectionTrait {
+    // Get the number of devices
+    fn get_nbr_devices(&self) -> WasapiRes<u32>;
+
+    // Get a device using index
+    fn get_device_at_index(&self, idx: u32) -> WasapiRes<Box<dyn DeviceTrait>>;
+
+    // Get a device using name
+    fn get_device_with_name(&self, name: &str) -> WasapiRes<Box<dyn DeviceTrait>> {
+        for n in 0..self.get_nbr_devices()? {
+            let device = self.get_device_at_index(n)?;
+            if device.get_friendlyname()? == name {
+                return Ok(device);
+            }
+        }
+        Err(WasapiError::new(format!("Unable to find device {}", name).as_str()).into())
+    }
+}
+
+// A playback or capture device.
+pub trait DeviceTrait {
+    // Get an audio client for the device
+    fn get_iaudioclient(&self) -> WasapiRes<Box<dyn AudioClientTrait>>;
+
+    // Get the state of the device
+    fn get_state(&self) -> WasapiRes<u32>;
+
+    // Get the name of the device
+    fn get_friendlyname(&self) -> WasapiRes<String>;
+
+    // Get the unique id of the device
+    fn get_id(&self) -> WasapiRes<String>;
+}
+
+// A client for one stream on a device.
+pub trait AudioClientTrait {
+    // Check if a format is supported, return the nearest match (identical to requested for exclusive mode)
+    fn is_supported(
+        &self,
+        wave_fmt: &WaveFormat,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<FormatSupported>;
+
+    // Get default and minimum periods in 100-nanosecond units
+    fn get_periods(&self) -> WasapiRes<(i64, i64)>;
+
+    // Initialize the stream
+    fn initialize_client(
+        &mut self,
+        wavefmt: &WaveFormat,
+        period: i64,
+        direction: &Direction,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<()>;
+
+    // Create and return an event handle, signaled when the device wants more data or has data ready
+    fn set_get_eventhandle(&self) -> WasapiRes<Box<dyn HandleTrait>>;
+
+    // Get buffer size in frames
+    fn get_bufferframecount(&self) -> WasapiRes<u32>;
+
+    // Get current padding in frames
+    fn get_current_padding(&self) -> WasapiRes<u32>;
+
+    // Get buffer size minus padding in frames
+    fn get_available_frames(&self) -> WasapiRes<u32>;
+
+    // Start the stream
+    fn start_stream(&self) -> WasapiRes<()>;
+
+    // Stop the stream
+    fn stop_stream(&self) -> WasapiRes<()>;
+
+    // Get a client for writing to a playback stream
+    fn get_audiorenderclient(&self) -> WasapiRes<Box<dyn RenderClientTrait>>;
+
+    // Get a client for reading from a capture stream
+    fn get_audiocaptureclient(&self) -> WasapiRes<Box<dyn CaptureClientTrait>>;
+}
+
+// Writing to the buffer of a playback stream.
+pub trait RenderClientTrait {
+    // Write raw bytes data to a device from a slice
+    fn write_to_device(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &[u8],
+    ) -> WasapiRes<()>;
+
+    // Write raw bytes data to a device from a deque
+    fn write_to_device_from_deque(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<()>;
+}
+
+// Reading from the buffer of a capture stream.
+pub trait CaptureClientTrait {
+    // Get number of frames in next packet
+    fn get_next_nbr_frames(&self) -> WasapiRes<u32>;
+
+    // Read raw bytes data from a device into a slice, returns the flags of the buffer
+    fn read_from_device(&self, bytes_per_frame: usize, data: &mut [u8]) -> WasapiRes<BufferFlags>;
+
+    // Read raw bytes data from a device into a deque, returns the flags of the buffer
+    fn read_from_device_to_deque(
+        &self,
+        bytes_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<BufferFlags>;
+}
+
+// An event handle of a stream.
+pub trait HandleTrait {
+    // Wait for an event, returns an error on timeout
+    fn wait_for_event(&self, timeout_ms: u32) -> WasapiRes<()>;
+}
+
+// The WASAPI backend, using the real devices of the system. Only available on Windows.
+#[cfg(windows)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WasapiBackend;
+
+#[cfg(windows)]
+impl WasapiBackend {
+    pub fn new() -> Self {
+        WasapiBackend
+    }
+}
+
+#[cfg(windows)]
+impl Backend for WasapiBackend {
+    fn get_device_collection(
+        &self,
+        direction: &Direction,
+    ) -> WasapiRes<Box<dyn DeviceCollectionTrait>> {
+        Ok(Box::new(DeviceCollection::new(direction)?))
+    }
+
+    fn get_default_device(&self, direction: &Direction) -> WasapiRes<Box<dyn DeviceTrait>> {
+        Ok(Box::new(crate::wasapi::get_default_device(direction)?))
+    }
+}
+
+#[cfg(windows)]
+impl DeviceCollectionTrait for DeviceCollection {
+    fn get_nbr_devices(&self) -> WasapiRes<u32> {
+        DeviceCollection::get_nbr_devices(self)
+    }
+
+    fn get_device_at_index(&self, idx: u32) -> WasapiRes<Box<dyn DeviceTrait>> {
+        Ok(Box::new(DeviceCollection::get_device_at_index(self, idx)?))
+    }
+
+    fn get_device_with_name(&self, name: &str) -> WasapiRes<Box<dyn DeviceTrait>> {
+        Ok(Box::new(DeviceCollection::get_device_with_name(
+            self, name,
+        )?))
+    }
+}
+
+#[cfg(windows)]
+impl DeviceTrait for Device {
+    fn get_iaudioclient(&self) -> WasapiRes<Box<dyn AudioClientTrait>> {
+        Ok(Box::new(Device::get_iaudioclient(self)?))
+    }
+
+    fn get_state(&self) -> WasapiRes<u32> {
+        Device::get_state(self)
+    }
+
+    fn get_friendlyname(&self) -> WasapiRes<String> {
+        Device::get_friendlyname(self)
+    }
+
+    fn get_id(&self) -> WasapiRes<String> {
+        Device::get_id(self)
+    }
+}
+
+#[cfg(windows)]
+impl AudioClientTrait for AudioClient {
+    fn is_supported(
+        &self,
+        wave_fmt: &WaveFormat,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<FormatSupported> {
+        AudioClient::is_supported(self, wave_fmt, sharemode)
+    }
+
+    fn get_periods(&self) -> WasapiRes<(i64, i64)> {
+        AudioClient::get_periods(self)
+    }
+
+    fn initialize_client(
+        &mut self,
+        wavefmt: &WaveFormat,
+        period: i64,
+        direction: &Direction,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<()> {
+        AudioClient::initialize_client(self, wavefmt, period, direction, sharemode)
+    }
+
+    fn set_get_eventhandle(&self) -> WasapiRes<Box<dyn HandleTrait>> {
+        Ok(Box::new(AudioClient::set_get_eventhandle(self)?))
+    }
+
+    fn get_bufferframecount(&self) -> WasapiRes<u32> {
+        AudioClient::get_bufferframecount(self)
+    }
+
+    fn get_current_padding(&self) -> WasapiRes<u32> {
+        AudioClient::get_current_padding(self)
+    }
+
+    fn get_available_frames(&self) -> WasapiRes<u32> {
+        AudioClient::get_available_frames(self)
+    }
+
+    fn start_stream(&self) -> WasapiRes<()> {
+        AudioClient::start_stream(self)
+    }
+
+    fn stop_stream(&self) -> WasapiRes<()> {
+        AudioClient::stop_stream(self)
+    }
+
+    fn get_audiorenderclient(&self) -> WasapiRes<Box<dyn RenderClientTrait>> {
+        Ok(Box::new(AudioClient::get_audiorenderclient(self)?))
+    }
+
+    fn get_audiocaptureclient(&self) -> WasapiRes<Box<dyn CaptureClientTrait>> {
+        Ok(Box::new(AudioClient::get_audiocaptureclient(self)?))
+    }
+}
+
+#[cfg(windows)]
+impl RenderClientTrait for AudioRenderClient {
+    fn write_to_device(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &[u8],
+    ) -> WasapiRes<()> {
+        AudioRenderClient::write_to_device(self, nbr_frames, byte_per_frame, data)
+    }
+
+    fn write_to_device_from_deque(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<()> {
+        AudioRenderClient::write_to_device_from_deque(self, nbr_frames, byte_per_frame, data)
+    }
+}
+
+#[cfg(windows)]
+impl CaptureClientTrait for AudioCaptureClient {
+    fn get_next_nbr_frames(&self) -> WasapiRes<u32> {
+        AudioCaptureClient::get_next_nbr_frames(self)
+    }
+
+    fn read_from_device(&self, bytes_per_frame: usize, data: &mut [u8]) -> WasapiRes<BufferFlags> {
+        AudioCaptureClient::read_from_device(self, bytes_per_frame, data)
+    }
+
+    fn read_from_device_to_deque(
+        &self,
+        bytes_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<BufferFlags> {
+        AudioCaptureClient::read_from_device_to_deque(self, bytes_per_frame, data)
+    }
+}
+
+#[cfg(windows)]
+impl HandleTrait for Handle {
+    fn wait_for_event(&self, timeout_ms: u32) -> WasapiRes<()> {
+        Handle::wait_for_event(self, timeout_ms)
+    }
+}
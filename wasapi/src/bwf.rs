@@ -0,0 +1,335 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::wasapi::{SampleType, WasapiError, WasapiRes, WaveFormat};
+
+// Sizes of the fixed length text fields of the bext chunk.
+const DESCRIPTION_LEN: usize = 256;
+const ORIGINATOR_LEN: usize = 32;
+const ORIGINATOR_REFERENCE_LEN: usize = 32;
+const DATE_LEN: usize = 10;
+const TIME_LEN: usize = 8;
+// Size of the bext chunk without the coding history, EBU Tech 3285 version 1.
+const BEXT_FIXED_LEN: usize = 602;
+const BEXT_VERSION: u16 = 1;
+
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+
+// Convert a number of days since 1970-01-01 to a (year, month, day) date in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Split a time into the UTC date as "yyyy-mm-dd", the UTC time of day as "hh:mm:ss",
+// and the time since midnight.
+pub fn utc_date_time(time: SystemTime) -> (String, String, Duration) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let second_of_day = seconds % SECONDS_PER_DAY;
+    let date = format!("{:04}-{:02}-{:02}", year, month, day);
+    let time_of_day = format!(
+        "{:02}:{:02}:{:02}",
+        second_of_day / 3600,
+        (second_of_day / 60) % 60,
+        second_of_day % 60
+    );
+    let since_midnight = Duration::new(second_of_day, since_epoch.subsec_nanos());
+    (date, time_of_day, since_midnight)
+}
+
+// Convert a time since midnight to a number of samples.
+fn samples_since_midnight(since_midnight: Duration, samplerate: usize) -> u64 {
+    let samplerate = samplerate as u64;
+    since_midnight.as_secs() * samplerate
+        + since_midnight.subsec_nanos() as u64 * samplerate / 1_000_000_000
+}
+
+// Write a string to a fixed length field, truncated or padded with zeros.
+fn write_text(data: &mut Vec<u8>, text: &str, len: usize) {
+    let bytes = text.as_bytes();
+    let used = bytes.len().min(len);
+    data.extend_from_slice(&bytes[..used]);
+    data.extend(std::iter::repeat_n(0, len - used));
+}
+
+// Read a zero terminated or zero padded string.
+fn read_text(data: &[u8]) -> String {
+    let end = data
+        .iter()
+        .position(|value| *value == 0)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).to_string()
+}
+
+// Contents of a Broadcast Wave Format bext chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BextInfo {
+    // Free description of the recording, at most 256 characters
+    pub description: String,
+    // Name of the originator, at most 32 characters
+    pub originator: String,
+    // Unique reference of the originator, at most 32 characters
+    pub originator_reference: String,
+    // Date of the start of the recording, as "yyyy-mm-dd"
+    pub origination_date: String,
+    // Time of the start of the recording, as "hh:mm:ss"
+    pub origination_time: String,
+    // Position of the first sample, in samples since midnight
+    pub time_reference: u64,
+    // History of the coding of the audio, lines of text ended by CR LF
+    pub coding_history: String,
+}
+
+impl BextInfo {
+    // Create the bext info for a recording that starts at a position in frames in a stream
+    // that was started at the given time. The date and time are in UTC.
+    pub fn new(
+        description: &str,
+        originator: &str,
+        stream_start: SystemTime,
+        samplerate: usize,
+        position: u64,
+    ) -> Self {
+        let (_, _, stream_since_midnight) = utc_date_time(stream_start);
+        let offset = Duration::from_secs_f64(position as f64 / samplerate as f64);
+        let (origination_date, origination_time, _) = utc_date_time(stream_start + offset);
+        BextInfo {
+            description: description.to_string(),
+            originator: originator.to_string(),
+            originator_reference: String::new(),
+            origination_date,
+            origination_time,
+            time_reference: samples_since_midnight(stream_since_midnight, samplerate) + position,
+            coding_history: String::new(),
+        }
+    }
+
+    // Serialize to the contents of a bext chunk
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(BEXT_FIXED_LEN + self.coding_history.len());
+        write_text(&mut data, &self.description, DESCRIPTION_LEN);
+        write_text(&mut data, &self.originator, ORIGINATOR_LEN);
+        write_text(
+            &mut data,
+            &self.originator_reference,
+            ORIGINATOR_REFERENCE_LEN,
+        );
+        write_text(&mut data, &self.origination_date, DATE_LEN);
+        write_text(&mut data, &self.origination_time, TIME_LEN);
+        data.extend_from_slice(&((self.time_reference & 0xFFFF_FFFF) as u32).to_le_bytes());
+        data.extend_from_slice(&((self.time_reference >> 32) as u32).to_le_bytes());
+        data.extend_from_slice(&BEXT_VERSION.to_le_bytes());
+        // UMID and reserved space, left empty
+        data.resize(BEXT_FIXED_LEN, 0);
+        data.extend_from_slice(self.coding_history.as_bytes());
+        data
+    }
+
+    // Parse the contents of a bext chunk
+    pub fn from_bytes(data: &[u8]) -> WasapiRes<Self> {
+        if data.len() < BEXT_FIXED_LEN {
+            return Err(WasapiError::new("The bext chunk is too short").into());
+        }
+        let mut offset = 0;
+        let mut next_field = |len: usize| {
+            let field = &data[offset..offset + len];
+            offset += len;
+            field
+        };
+        let description = read_text(next_field(DESCRIPTION_LEN));
+        let originator = read_text(next_field(ORIGINATOR_LEN));
+        let originator_reference = read_text(next_field(ORIGINATOR_REFERENCE_LEN));
+        let origination_date = read_text(next_field(DATE_LEN));
+        let origination_time = read_text(next_field(TIME_LEN));
+        let low = next_field(4);
+        let high = next_field(4);
+        let time_reference = u32::from_le_bytes([low[0], low[1], low[2], low[3]]) as u64
+            | (u32::from_le_bytes([high[0], high[1], high[2], high[3]]) as u64) << 32;
+        Ok(BextInfo {
+            description,
+            originator,
+            originator_reference,
+            origination_date,
+            origination_time,
+            time_reference,
+            coding_history: read_text(&data[BEXT_FIXED_LEN..]),
+        })
+    }
+}
+
+// Escape the characters that are not allowed in xml text.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Find the contents of the first element with the given name, without unescaping.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start_tag = format!("<{}>", name);
+    let end_tag = format!("</{}>", name);
+    let start = xml.find(&start_tag)? + start_tag.len();
+    let end = start + xml[start..].find(&end_tag)?;
+    Some(xml[start..end].trim())
+}
+
+pub(crate) fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|value| format!("{:02X}", value)).collect()
+}
+
+pub(crate) fn from_hex(text: &str) -> WasapiRes<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(WasapiError::new("Invalid hex string").into());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|n| {
+            u8::from_str_radix(&text[n..n + 2], 16)
+                .map_err(|_| WasapiError::new("Invalid hex string").into())
+        })
+        .collect()
+}
+
+// Contents of an iXML chunk, describing the capture device and the negotiated format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IxmlInfo {
+    // Friendly name of the device
+    pub device_name: String,
+    // Id of the device
+    pub device_id: String,
+    // The format used for the stream
+    pub wave_fmt: WaveFormat,
+    // Position of the first sample in samples since midnight, usually the same as in the bext chunk
+    pub time_reference: Option<u64>,
+}
+
+impl IxmlInfo {
+    // Create the iXML info for a device and format
+    pub fn new(device_name: &str, device_id: &str, wave_fmt: &WaveFormat) -> Self {
+        IxmlInfo {
+            device_name: device_name.to_string(),
+            device_id: device_id.to_string(),
+            wave_fmt: wave_fmt.clone(),
+            time_reference: None,
+        }
+    }
+
+    // Serialize to an iXML document.
+    // The device and format are stored in a WASAPI element, with the complete fmt chunk as hex.
+    pub fn to_xml(&self) -> String {
+        let wave_fmt = &self.wave_fmt;
+        let sample_type = match wave_fmt.get_subformat() {
+            Ok(SampleType::Int) => "Int",
+            Ok(SampleType::Float) => "Float",
+            Err(_) => "Unknown",
+        };
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<BWFXML>\n");
+        xml.push_str("  <IXML_VERSION>1.61</IXML_VERSION>\n");
+        xml.push_str("  <SPEED>\n");
+        xml.push_str(&format!(
+            "    <FILE_SAMPLE_RATE>{}</FILE_SAMPLE_RATE>\n",
+            wave_fmt.get_samplespersec()
+        ));
+        xml.push_str(&format!(
+            "    <AUDIO_BIT_DEPTH>{}</AUDIO_BIT_DEPTH>\n",
+            wave_fmt.get_validbitspersample()
+        ));
+        if let Some(time_reference) = self.time_reference {
+            xml.push_str(&format!(
+                "    <TIMESTAMP_SAMPLE_RATE>{}</TIMESTAMP_SAMPLE_RATE>\n",
+                wave_fmt.get_samplespersec()
+            ));
+            xml.push_str(&format!(
+                "    <TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_HI>{}</TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_HI>\n",
+                time_reference >> 32
+            ));
+            xml.push_str(&format!(
+                "    <TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_LO>{}</TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_LO>\n",
+                time_reference & 0xFFFF_FFFF
+            ));
+        }
+        xml.push_str("  </SPEED>\n");
+        xml.push_str("  <TRACK_LIST>\n");
+        xml.push_str(&format!(
+            "    <TRACK_COUNT>{}</TRACK_COUNT>\n",
+            wave_fmt.get_nchannels()
+        ));
+        for channel in 1..=wave_fmt.get_nchannels() {
+            xml.push_str(&format!(
+                "    <TRACK><CHANNEL_INDEX>{}</CHANNEL_INDEX><INTERLEAVE_INDEX>{}</INTERLEAVE_INDEX></TRACK>\n",
+                channel, channel
+            ));
+        }
+        xml.push_str("  </TRACK_LIST>\n");
+        xml.push_str("  <WASAPI>\n");
+        xml.push_str(&format!(
+            "    <DEVICE_NAME>{}</DEVICE_NAME>\n",
+            xml_escape(&self.device_name)
+        ));
+        xml.push_str(&format!(
+            "    <DEVICE_ID>{}</DEVICE_ID>\n",
+            xml_escape(&self.device_id)
+        ));
+        xml.push_str(&format!("    <SAMPLE_TYPE>{}</SAMPLE_TYPE>\n", sample_type));
+        xml.push_str(&format!(
+            "    <CHANNEL_MASK>{}</CHANNEL_MASK>\n",
+            wave_fmt.get_dwchannelmask()
+        ));
+        xml.push_str(&format!(
+            "    <WAVEFORMAT>{}</WAVEFORMAT>\n",
+            to_hex(&wave_fmt.to_bytes())
+        ));
+        xml.push_str("  </WASAPI>\n");
+        xml.push_str("</BWFXML>\n");
+        xml
+    }
+
+    // Parse an iXML document written by to_xml
+    pub fn from_xml(xml: &str) -> WasapiRes<Self> {
+        let wasapi = xml_element(xml, "WASAPI")
+            .ok_or_else(|| WasapiError::new("The iXML has no WASAPI element"))?;
+        let wave_fmt_hex = xml_element(wasapi, "WAVEFORMAT")
+            .ok_or_else(|| WasapiError::new("The iXML has no format"))?;
+        let wave_fmt = WaveFormat::from_bytes(&from_hex(wave_fmt_hex)?)?;
+        let timestamp =
+            |name: &str| xml_element(xml, name).and_then(|value| value.parse::<u64>().ok());
+        let time_reference = match (
+            timestamp("TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_HI"),
+            timestamp("TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_LO"),
+        ) {
+            (Some(high), Some(low)) => Some(high << 32 | low),
+            _ => None,
+        };
+        Ok(IxmlInfo {
+            device_name: xml_element(wasapi, "DEVICE_NAME")
+                .map(xml_unescape)
+                .unwrap_or_default(),
+            device_id: xml_element(wasapi, "DEVICE_ID")
+                .map(xml_unescape)
+                .unwrap_or_default(),
+            wave_fmt,
+            time_reference,
+        })
+    }
+}
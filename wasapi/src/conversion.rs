@@ -0,0 +1,143 @@
+use crate::wasapi::{SampleType, WasapiError, WasapiRes, WaveFormat};
+
+// Convert a buffer of raw interleaved samples to one vector of f64 per channel.
+// Integer samples are scaled to the range -1.0 .. 1.0.
+pub fn bytes_to_channels(data: &[u8], wave_fmt: &WaveFormat) -> WasapiRes<Vec<Vec<f64>>> {
+    let sample_type = wave_fmt.get_subformat()?;
+    let nchannels = wave_fmt.get_nchannels() as usize;
+    let blockalign = wave_fmt.get_blockalign() as usize;
+    let bytes_per_sample = wave_fmt.get_bitspersample() as usize / 8;
+    check_layout(&sample_type, bytes_per_sample, nchannels, blockalign)?;
+    if !data.len().is_multiple_of(blockalign) {
+        return Err(WasapiError::new(
+            format!(
+                "Data length {} is not a multiple of the block size {}",
+                data.len(),
+                blockalign
+            )
+            .as_str(),
+        )
+        .into());
+    }
+    let nbr_frames = data.len() / blockalign;
+    let mut channels = vec![Vec::with_capacity(nbr_frames); nchannels];
+    for frame in data.chunks_exact(blockalign) {
+        for (chan, sample) in channels
+            .iter_mut()
+            .zip(frame.chunks_exact(bytes_per_sample))
+        {
+            chan.push(decode_sample(sample, &sample_type));
+        }
+    }
+    Ok(channels)
+}
+
+// Convert one vector of f64 per channel to a buffer of raw interleaved samples.
+// Values outside -1.0 .. 1.0 are clipped for integer formats.
+pub fn channels_to_bytes(channels: &[Vec<f64>], wave_fmt: &WaveFormat) -> WasapiRes<Vec<u8>> {
+    let sample_type = wave_fmt.get_subformat()?;
+    let nchannels = wave_fmt.get_nchannels() as usize;
+    let blockalign = wave_fmt.get_blockalign() as usize;
+    let bytes_per_sample = wave_fmt.get_bitspersample() as usize / 8;
+    let validbits = wave_fmt.get_validbitspersample() as usize;
+    check_layout(&sample_type, bytes_per_sample, nchannels, blockalign)?;
+    if channels.len() != nchannels {
+        return Err(WasapiError::new(
+            format!("Got {} channels, expected {}", channels.len(), nchannels).as_str(),
+        )
+        .into());
+    }
+    let nbr_frames = channels.iter().map(|chan| chan.len()).max().unwrap_or(0);
+    if channels.iter().any(|chan| chan.len() != nbr_frames) {
+        return Err(WasapiError::new("All channels must have the same length").into());
+    }
+    let mut data = vec![0u8; nbr_frames * blockalign];
+    for (n, frame) in data.chunks_exact_mut(blockalign).enumerate() {
+        for (chan, sample) in channels
+            .iter()
+            .zip(frame.chunks_exact_mut(bytes_per_sample))
+        {
+            encode_sample(chan[n], sample, &sample_type, validbits);
+        }
+    }
+    Ok(data)
+}
+
+// Get a buffer of silence for a number of frames. Silence is all zero bytes,
+// except for 8-bit integer samples that are stored as unsigned values.
+pub fn silence(nbr_frames: usize, wave_fmt: &WaveFormat) -> Vec<u8> {
+    let fill = match wave_fmt.get_subformat() {
+        Ok(SampleType::Int) if wave_fmt.get_bitspersample() == 8 => 0x80,
+        _ => 0,
+    };
+    vec![fill; nbr_frames * wave_fmt.get_blockalign() as usize]
+}
+
+fn check_layout(
+    sample_type: &SampleType,
+    bytes_per_sample: usize,
+    nchannels: usize,
+    blockalign: usize,
+) -> WasapiRes<()> {
+    let valid_size = match sample_type {
+        SampleType::Int => (1..=4).contains(&bytes_per_sample),
+        SampleType::Float => bytes_per_sample == 4 || bytes_per_sample == 8,
+    };
+    if !valid_size || nchannels == 0 || blockalign != nchannels * bytes_per_sample {
+        return Err(WasapiError::new(
+            format!(
+                "Unsupported sample layout, {} bytes per sample, {} channels, block size {}",
+                bytes_per_sample, nchannels, blockalign
+            )
+            .as_str(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Decode a single little-endian sample.
+fn decode_sample(bytes: &[u8], sample_type: &SampleType) -> f64 {
+    match (sample_type, bytes.len()) {
+        (SampleType::Float, 4) => {
+            f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64
+        }
+        (SampleType::Float, _) => {
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(bytes);
+            f64::from_le_bytes(raw)
+        }
+        (SampleType::Int, 1) => (bytes[0] as f64 - 128.0) / 128.0,
+        (SampleType::Int, len) => {
+            // Place the bytes at the top of an i32 to get the sign right,
+            // then scale by the full range of the storage size.
+            let mut raw = [0u8; 4];
+            raw[4 - len..].copy_from_slice(bytes);
+            i32::from_le_bytes(raw) as f64 / 2147483648.0
+        }
+    }
+}
+
+// Encode a single sample as little-endian bytes.
+fn encode_sample(value: f64, bytes: &mut [u8], sample_type: &SampleType, validbits: usize) {
+    match (sample_type, bytes.len()) {
+        (SampleType::Float, 4) => bytes.copy_from_slice(&(value as f32).to_le_bytes()),
+        (SampleType::Float, _) => bytes.copy_from_slice(&value.to_le_bytes()),
+        (SampleType::Int, len) => {
+            let storebits = 8 * len;
+            let validbits = if validbits == 0 || validbits > storebits {
+                storebits
+            } else {
+                validbits
+            };
+            let max = (1i64 << (validbits - 1)) as f64;
+            let scaled = (value * max).round().max(-max).min(max - 1.0) as i64;
+            let stored = scaled << (storebits - validbits);
+            if len == 1 {
+                bytes[0] = (stored + 128) as u8;
+            } else {
+                bytes.copy_from_slice(&stored.to_le_bytes()[..len]);
+            }
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use std::f64::consts::PI;
+
+use serde::Serialize;
+
+use crate::dsp::{fft_real, to_db};
+use crate::generator::{apply_fade, sine};
+use crate::loopback::Loopback;
+use crate::wasapi::{WasapiError, WasapiRes};
+
+// Coefficients of the 7-term Blackman-Harris window, with side lobes below -180 dB.
+const BLACKMAN_HARRIS_7: [f64; 7] = [
+    0.271_051_400_693_42,
+    0.433_297_939_234_48,
+    0.218_122_999_543_11,
+    0.065_925_446_388_03,
+    0.010_811_742_098_37,
+    0.000_776_584_825_22,
+    0.000_013_887_217_35,
+];
+
+// Number of bins on each side of a peak that belong to the main lobe of the window.
+const LOBE_BINS: usize = 10;
+
+// Generate a 7-term Blackman-Harris window.
+pub fn blackman_harris(len: usize) -> Vec<f64> {
+    (0..len)
+        .map(|n| {
+            BLACKMAN_HARRIS_7
+                .iter()
+                .enumerate()
+                .map(|(k, a)| {
+                    let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                    sign * a * (2.0 * PI * (k * n) as f64 / len as f64).cos()
+                })
+                .sum()
+        })
+        .collect()
+}
+
+// Result of analyzing a single tone.
+#[derive(Clone, Debug, Serialize)]
+pub struct ToneAnalysis {
+    // Frequency of the fundamental found in the signal, in Hz
+    pub frequency: f64,
+    // Level of the fundamental in dBFS, where a full scale sine is 0 dBFS
+    pub level_dbfs: f64,
+    // Total harmonic distortion, relative to the fundamental
+    pub thd_db: f64,
+    pub thd_percent: f64,
+    // Total harmonic distortion plus noise, relative to the fundamental
+    pub thdn_db: f64,
+    pub thdn_percent: f64,
+    // Level of everything that is not the fundamental or a harmonic, in dBFS
+    pub noise_dbfs: f64,
+    // Level of each harmonic relative to the fundamental, starting at the second harmonic
+    pub harmonics_db: Vec<f64>,
+}
+
+// Settings for the analysis of a signal.
+#[derive(Clone, Debug)]
+pub struct AnalysisSettings {
+    pub samplerate: usize,
+    // Highest harmonic to include, the fundamental counts as the first
+    pub nbr_harmonics: usize,
+    // Frequency band for the noise and distortion, in Hz
+    pub f_low: f64,
+    pub f_high: f64,
+}
+
+impl AnalysisSettings {
+    // Default settings, with the 20 Hz - 20 kHz audio band and 9 harmonics
+    pub fn new(samplerate: usize) -> Self {
+        AnalysisSettings {
+            samplerate,
+            nbr_harmonics: 9,
+            f_low: 20.0,
+            f_high: 20000.0,
+        }
+    }
+}
+
+// Power spectrum of a windowed signal, scaled so that the sum of all bins is the mean square value.
+struct PowerSpectrum {
+    bins: Vec<f64>,
+    bin_width: f64,
+}
+
+impl PowerSpectrum {
+    fn new(samples: &[f64], samplerate: usize) -> Self {
+        let len = samples.len();
+        let window = blackman_harris(len);
+        let windowed: Vec<f64> = samples
+            .iter()
+            .zip(window.iter())
+            .map(|(s, w)| s * w)
+            .collect();
+        let window_power: f64 = window.iter().map(|w| w * w).sum();
+        let scale = 2.0 / (len as f64 * window_power);
+        let bins = fft_real(&windowed, len)
+            .iter()
+            .take(len / 2 + 1)
+            .map(|value| value.norm_sqr() * scale)
+            .collect();
+        PowerSpectrum {
+            bins,
+            bin_width: samplerate as f64 / len as f64,
+        }
+    }
+
+    fn bin_of(&self, freq: f64) -> usize {
+        ((freq / self.bin_width).round() as usize).min(self.bins.len() - 1)
+    }
+
+    // Find the largest bin near a frequency
+    fn find_peak_near(&self, freq: f64) -> usize {
+        let center = self.bin_of(freq);
+        let start = center.saturating_sub(LOBE_BINS / 2);
+        let end = (center + LOBE_BINS / 2).min(self.bins.len() - 1);
+        (start..=end)
+            .max_by(|a, b| self.bins[*a].partial_cmp(&self.bins[*b]).unwrap())
+            .unwrap_or(center)
+    }
+
+    // Sum the power of the main lobe around a bin
+    fn lobe_power(&self, bin: usize) -> f64 {
+        let start = bin.saturating_sub(LOBE_BINS);
+        let end = (bin + LOBE_BINS).min(self.bins.len() - 1);
+        self.bins[start..=end].iter().sum()
+    }
+
+    // Sum the power in a frequency band
+    fn band_power(&self, f_low: f64, f_high: f64) -> f64 {
+        let start = self.bin_of(f_low).max(LOBE_BINS);
+        let end = self.bin_of(f_high);
+        if end < start {
+            return 0.0;
+        }
+        self.bins[start..=end].iter().sum()
+    }
+}
+
+// Convert a mean square value to dBFS, where a full scale sine is 0 dBFS.
+fn ms_to_dbfs(mean_square: f64) -> f64 {
+    to_db((2.0 * mean_square).sqrt())
+}
+
+// Analyze a recorded tone, measuring its level, harmonic distortion and noise.
+pub fn analyze_tone(
+    samples: &[f64],
+    freq: f64,
+    settings: &AnalysisSettings,
+) -> WasapiRes<ToneAnalysis> {
+    let nyquist = settings.samplerate as f64 / 2.0;
+    if samples.len() < 4 * LOBE_BINS || freq <= 0.0 || freq >= nyquist {
+        return Err(WasapiError::new(
+            "Unable to analyze tone, too few samples or invalid frequency",
+        )
+        .into());
+    }
+    let spectrum = PowerSpectrum::new(samples, settings.samplerate);
+    let f_high = settings.f_high.min(nyquist);
+
+    let fund_bin = spectrum.find_peak_near(freq);
+    let fund_power = spectrum.lobe_power(fund_bin);
+    let fund_freq = fund_bin as f64 * spectrum.bin_width;
+
+    let mut harmonics_db = Vec::new();
+    let mut harmonic_power = 0.0;
+    for order in 2..=settings.nbr_harmonics {
+        let harm_freq = order as f64 * fund_freq;
+        if harm_freq > f_high {
+            break;
+        }
+        let power = spectrum.lobe_power(spectrum.find_peak_near(harm_freq));
+        harmonic_power += power;
+        harmonics_db.push(10.0 * (power / fund_power).max(1.0e-30).log10());
+    }
+    let total_power = spectrum.band_power(settings.f_low, f_high);
+    let distortion_noise_power = (total_power - fund_power).max(0.0);
+    let noise_power = (distortion_noise_power - harmonic_power).max(0.0);
+
+    let thd = (harmonic_power / fund_power).sqrt();
+    let thdn = (distortion_noise_power / fund_power).sqrt();
+    Ok(ToneAnalysis {
+        frequency: fund_freq,
+        level_dbfs: ms_to_dbfs(fund_power),
+        thd_db: to_db(thd),
+        thd_percent: 100.0 * thd,
+        thdn_db: to_db(thdn),
+        thdn_percent: 100.0 * thdn,
+        noise_dbfs: ms_to_dbfs(noise_power),
+        harmonics_db,
+    })
+}
+
+// Measure the level of a recorded noise floor in dBFS, within the analysis band.
+pub fn analyze_noise(samples: &[f64], settings: &AnalysisSettings) -> f64 {
+    let spectrum = PowerSpectrum::new(samples, settings.samplerate);
+    let f_high = settings.f_high.min(settings.samplerate as f64 / 2.0);
+    ms_to_dbfs(spectrum.band_power(settings.f_low, f_high))
+}
+
+// Measure the level of a single frequency in a recorded signal, in dBFS.
+pub fn analyze_level(samples: &[f64], freq: f64, settings: &AnalysisSettings) -> f64 {
+    let spectrum = PowerSpectrum::new(samples, settings.samplerate);
+    ms_to_dbfs(spectrum.lobe_power(spectrum.bin_of(freq)))
+}
+
+// Crosstalk from a driven channel into another channel.
+#[derive(Clone, Debug, Serialize)]
+pub struct Crosstalk {
+    pub channel: usize,
+    // Level in the other channel relative to the driven channel
+    pub level_db: f64,
+}
+
+// Results for one frequency of the stepped sine.
+#[derive(Clone, Debug, Serialize)]
+pub struct ToneResult {
+    pub analysis: ToneAnalysis,
+    pub crosstalk: Vec<Crosstalk>,
+}
+
+// Results for one channel.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelReport {
+    pub channel: usize,
+    // Noise floor in dBFS, measured while playing silence
+    pub noise_floor_dbfs: f64,
+    // Signal to noise ratio, relative to a full scale sine
+    pub snr_db: f64,
+    // Dynamic range, measured as THD+N of a -60 dBFS tone, plus 60 dB
+    pub dynamic_range_db: f64,
+    pub tones: Vec<ToneResult>,
+}
+
+// Complete result of a distortion measurement.
+#[derive(Clone, Debug, Serialize)]
+pub struct DistortionReport {
+    pub samplerate: usize,
+    pub amplitude_dbfs: f64,
+    pub channels: Vec<ChannelReport>,
+}
+
+impl DistortionReport {
+    // Get the report as pretty-printed JSON
+    pub fn to_json(&self) -> WasapiRes<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+// Settings for a stepped sine distortion and noise measurement.
+#[derive(Clone, Debug)]
+pub struct DistortionMeasurement {
+    // Frequencies of the stepped sine, in Hz
+    pub frequencies: Vec<f64>,
+    // Level of the test tones in dBFS
+    pub amplitude_dbfs: f64,
+    // Frequency of the tone used for the dynamic range measurement
+    pub reference_frequency: f64,
+    // Number of frames to analyze for each step, should be a power of two
+    pub analysis_frames: usize,
+    // Number of frames to skip at the start of each capture, must be longer than the latency
+    pub settle_frames: usize,
+    pub nbr_harmonics: usize,
+    pub f_low: f64,
+    pub f_high: f64,
+}
+
+impl DistortionMeasurement {
+    // Create a measurement with default settings for the given frequencies
+    pub fn new(frequencies: &[f64]) -> Self {
+        DistortionMeasurement {
+            frequencies: frequencies.to_vec(),
+            amplitude_dbfs: -1.0,
+            reference_frequency: 1000.0,
+            analysis_frames: 65536,
+            settle_frames: 8192,
+            nbr_harmonics: 9,
+            f_low: 20.0,
+            f_high: 20000.0,
+        }
+    }
+
+    // Play a tone on one channel and return the analyzed part of the capture for all channels
+    fn capture_tone(
+        &self,
+        loopback: &mut dyn Loopback,
+        nchannels: usize,
+        channel: usize,
+        freq: f64,
+        amplitude: f64,
+    ) -> WasapiRes<Vec<Vec<f64>>> {
+        let samplerate = loopback.get_format().get_samplespersec() as usize;
+        let len = 2 * self.settle_frames + self.analysis_frames;
+        let mut data = vec![vec![0.0; len]; nchannels];
+        if amplitude > 0.0 {
+            data[channel] = sine(freq, amplitude, len, samplerate);
+            apply_fade(&mut data[channel], self.settle_frames / 2);
+        }
+        let captured = loopback.play_and_capture(&data, 0)?;
+        Ok(captured
+            .iter()
+            .map(|chan| {
+                chan[self.settle_frames..self.settle_frames + self.analysis_frames].to_vec()
+            })
+            .collect())
+    }
+
+    // Run the measurement on a loopback
+    pub fn measure(&self, loopback: &mut dyn Loopback) -> WasapiRes<DistortionReport> {
+        let format = loopback.get_format();
+        let samplerate = format.get_samplespersec() as usize;
+        let nchannels = format.get_nchannels() as usize;
+        let settings = AnalysisSettings {
+            samplerate,
+            nbr_harmonics: self.nbr_harmonics,
+            f_low: self.f_low,
+            f_high: self.f_high,
+        };
+        let amplitude = 10.0f64.powf(self.amplitude_dbfs / 20.0);
+
+        let silence = self.capture_tone(loopback, nchannels, 0, 0.0, 0.0)?;
+        let mut channels = Vec::with_capacity(nchannels);
+        for channel in 0..nchannels {
+            let noise_floor_dbfs = analyze_noise(&silence[channel], &settings);
+            let low_level = self.capture_tone(
+                loopback,
+                nchannels,
+                channel,
+                self.reference_frequency,
+                0.001,
+            )?;
+            let low_level = analyze_tone(&low_level[channel], self.reference_frequency, &settings)?;
+            let mut tones = Vec::with_capacity(self.frequencies.len());
+            for freq in self.frequencies.iter() {
+                let captured = self.capture_tone(loopback, nchannels, channel, *freq, amplitude)?;
+                let analysis = analyze_tone(&captured[channel], *freq, &settings)?;
+                let crosstalk = (0..nchannels)
+                    .filter(|other| *other != channel)
+                    .map(|other| Crosstalk {
+                        channel: other,
+                        level_db: analyze_level(&captured[other], analysis.frequency, &settings)
+                            - analysis.level_dbfs,
+                    })
+                    .collect();
+                tones.push(ToneResult {
+                    analysis,
+                    crosstalk,
+                });
+            }
+            channels.push(ChannelReport {
+                channel,
+                noise_floor_dbfs,
+                snr_db: -noise_floor_dbfs,
+                dynamic_range_db: 60.0 - low_level.thdn_db,
+                tones,
+            });
+        }
+        Ok(DistortionReport {
+            samplerate,
+            amplitude_dbfs: self.amplitude_dbfs,
+            channels,
+        })
+    }
+}
@@ -0,0 +1,125 @@
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+// Smallest power of two that is larger than or equal to a value.
+pub fn next_pow2(value: usize) -> usize {
+    value.max(1).next_power_of_two()
+}
+
+// Forward FFT of a real signal, zero padded to the given length.
+pub fn fft_real(data: &[f64], len: usize) -> Vec<Complex<f64>> {
+    let mut buffer: Vec<Complex<f64>> = data
+        .iter()
+        .take(len)
+        .map(|value| Complex::new(*value, 0.0))
+        .collect();
+    buffer.resize(len, Complex::new(0.0, 0.0));
+    let mut planner = FftPlanner::new();
+    planner.plan_fft_forward(len).process(&mut buffer);
+    buffer
+}
+
+// Inverse FFT returning the real part, normalized so that ifft(fft(x)) == x.
+pub fn ifft_real(spectrum: &[Complex<f64>]) -> Vec<f64> {
+    let len = spectrum.len();
+    let mut buffer = spectrum.to_vec();
+    let mut planner = FftPlanner::new();
+    planner.plan_fft_inverse(len).process(&mut buffer);
+    buffer.iter().map(|value| value.re / len as f64).collect()
+}
+
+// Linear cross correlation of a signal against a reference.
+// Element k of the result is sum(reference[n] * signal[n + k]), for k = 0 .. signal.len().
+pub fn cross_correlation(reference: &[f64], signal: &[f64]) -> Vec<f64> {
+    let len = next_pow2(reference.len() + signal.len());
+    let ref_spec = fft_real(reference, len);
+    let sig_spec = fft_real(signal, len);
+    let product: Vec<Complex<f64>> = sig_spec
+        .iter()
+        .zip(ref_spec.iter())
+        .map(|(s, r)| s * r.conj())
+        .collect();
+    let mut corr = ifft_real(&product);
+    corr.truncate(signal.len());
+    corr
+}
+
+// Linear convolution of two signals.
+pub fn convolve(first: &[f64], second: &[f64]) -> Vec<f64> {
+    if first.is_empty() || second.is_empty() {
+        return Vec::new();
+    }
+    let out_len = first.len() + second.len() - 1;
+    let len = next_pow2(out_len);
+    let first_spec = fft_real(first, len);
+    let second_spec = fft_real(second, len);
+    let product: Vec<Complex<f64>> = first_spec
+        .iter()
+        .zip(second_spec.iter())
+        .map(|(a, b)| a * b)
+        .collect();
+    let mut result = ifft_real(&product);
+    result.truncate(out_len);
+    result
+}
+
+// Find the index and value of the element with the largest magnitude.
+pub fn find_peak(data: &[f64]) -> Option<(usize, f64)> {
+    data.iter().enumerate().fold(
+        None,
+        |best: Option<(usize, f64)>, (idx, value)| match best {
+            Some((_, best_value)) if best_value.abs() >= value.abs() => best,
+            _ => Some((idx, *value)),
+        },
+    )
+}
+
+// Root mean square value of a signal.
+pub fn rms(data: &[f64]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    (data.iter().map(|value| value * value).sum::<f64>() / data.len() as f64).sqrt()
+}
+
+// Convert an amplitude ratio to dB.
+pub fn to_db(value: f64) -> f64 {
+    20.0 * value.max(1.0e-30).log10()
+}
+
+// Mean value of a set of values.
+pub fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// Median of a set of values.
+pub fn median(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+// Standard deviation of a set of values.
+pub fn std_dev(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let avg = mean(values);
+    (values
+        .iter()
+        .map(|value| (value - avg).powi(2))
+        .sum::<f64>()
+        / (values.len() - 1) as f64)
+        .sqrt()
+}
@@ -0,0 +1,389 @@
+use std::collections::VecDeque;
+use std::error;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::backend::{AudioClientTrait, Backend, HandleTrait};
+use crate::conversion::{bytes_to_channels, silence};
+use crate::glitch::{GlitchDetector, GlitchEvent, GlitchSettings};
+use crate::wasapi::{
+    BufferFlags, Direction, FormatSupported, ShareMode, WasapiError, WasapiRes, WaveFormat,
+};
+
+// Settings for a playback or capture stream.
+#[derive(Clone, Debug)]
+pub struct StreamSettings {
+    // Name of the device
+    pub device: String,
+    // The desired format, in shared mode the closest match supported by the device is used instead
+    pub format: WaveFormat,
+    pub sharemode: ShareMode,
+    // Size in frames of the chunks sent by the capture loop
+    pub chunksize: usize,
+    // Number of frames after which the capture loop stops, None to capture until the channel is closed
+    pub max_frames: Option<u64>,
+    // Timeout for the buffer events, in milliseconds
+    pub timeout: u32,
+    // Fail instead of using the closest match when the device does not support the format,
+    // for streams whose data must stay in the given format, for example when sent over the network
+    pub exact_format: bool,
+    // Number of times in a row a failed stream is retried before the loop returns the error,
+    // by waiting again after a timeout, and by opening the device again after other errors
+    pub max_retries: usize,
+}
+
+impl StreamSettings {
+    // Default settings, shared mode and chunks of 4096 frames
+    pub fn new(device: &str, format: &WaveFormat) -> Self {
+        StreamSettings {
+            device: device.to_string(),
+            format: format.clone(),
+            sharemode: ShareMode::Shared,
+            chunksize: 4096,
+            max_frames: None,
+            timeout: 1000,
+            exact_format: false,
+            max_retries: 0,
+        }
+    }
+}
+
+// Get the format to use for a stream, the closest match of the desired one unless an exact match is required
+fn negotiate_format(
+    audio_client: &dyn AudioClientTrait,
+    settings: &StreamSettings,
+) -> WasapiRes<WaveFormat> {
+    match audio_client.is_supported(&settings.format, &settings.sharemode)? {
+        FormatSupported::Yes => Ok(settings.format.clone()),
+        FormatSupported::ClosestMatch(modified_format) if settings.exact_format => {
+            Err(WasapiError::new(
+                format!(
+                    "The device {} does not support the format, the closest match is {:?}",
+                    settings.device, modified_format
+                )
+                .as_str(),
+            )
+            .into())
+        }
+        FormatSupported::ClosestMatch(modified_format) => Ok(modified_format),
+    }
+}
+
+// Get the format that a playback or capture loop with these settings would use,
+// so that callers can check it before starting the stream
+pub fn get_stream_format(
+    backend: &dyn Backend,
+    settings: &StreamSettings,
+    direction: &Direction,
+) -> WasapiRes<WaveFormat> {
+    let collection = backend.get_device_collection(direction)?;
+    let device = collection.get_device_with_name(&settings.device)?;
+    let audio_client = device.get_iaudioclient()?;
+    negotiate_format(audio_client.as_ref(), settings)
+}
+
+// Source of the samples for a playback loop.
+pub trait PlaybackSource {
+    // Get the next frames in the format of the stream, usually nbr_frames of them.
+    // What is missing is filled with silence. Returns None when the source is finished.
+    fn fill(&mut self, nbr_frames: usize, format: &WaveFormat) -> Option<Vec<u8>>;
+}
+
+// Source reading the chunks from a channel, silence is played when the channel is empty
+struct ChannelSource {
+    rx_play: mpsc::Receiver<Vec<u8>>,
+}
+
+impl PlaybackSource for ChannelSource {
+    fn fill(&mut self, nbr_frames: usize, format: &WaveFormat) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        while data.len() < nbr_frames * format.get_blockalign() as usize {
+            match self.rx_play.try_recv() {
+                Ok(chunk) => data.extend(chunk),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) if data.is_empty() => return None,
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        }
+        Some(data)
+    }
+}
+
+// Playback loop, play samples received from a channel.
+// When the channel is empty, silence is played until more data arrives.
+// When the sending side is closed, the remaining data is played and the loop returns.
+pub fn playback_loop(
+    backend: &dyn Backend,
+    settings: &StreamSettings,
+    rx_play: mpsc::Receiver<Vec<u8>>,
+) -> WasapiRes<()> {
+    let mut source = ChannelSource { rx_play };
+    playback_loop_from(backend, settings, &mut source)
+}
+
+// A stream that has been initialized, but not yet started
+struct OpenStream {
+    audio_client: Box<dyn AudioClientTrait>,
+    h_event: Box<dyn HandleTrait>,
+    format: WaveFormat,
+}
+
+// Open the device of a stream and initialize a client.
+// A stream that is opened again after a failure must get the format it had before.
+fn open_stream(
+    backend: &dyn Backend,
+    settings: &StreamSettings,
+    direction: &Direction,
+    previous: Option<&WaveFormat>,
+) -> WasapiRes<OpenStream> {
+    let collection = backend.get_device_collection(direction)?;
+    let device = collection.get_device_with_name(&settings.device)?;
+    let mut audio_client = device.get_iaudioclient()?;
+    let format = negotiate_format(audio_client.as_ref(), settings)?;
+    if previous.is_some_and(|previous| *previous != format) {
+        return Err(WasapiError::new(
+            format!("The format of the device {} has changed", settings.device).as_str(),
+        )
+        .into());
+    }
+    let (_def_time, min_time) = audio_client.get_periods()?;
+    audio_client.initialize_client(&format, min_time, direction, &settings.sharemode)?;
+    let h_event = audio_client.set_get_eventhandle()?;
+    Ok(OpenStream {
+        audio_client,
+        h_event,
+        format,
+    })
+}
+
+// Count a retry of a failed stream, returns the error when all retries are used up
+fn retry(
+    settings: &StreamSettings,
+    retries: &mut usize,
+    err: Box<dyn error::Error>,
+) -> WasapiRes<()> {
+    if *retries >= settings.max_retries {
+        return Err(err);
+    }
+    *retries += 1;
+    Ok(())
+}
+
+// Wait before opening the device again, it may be gone for a while
+fn wait_for_device(settings: &StreamSettings) {
+    thread::sleep(Duration::from_millis(settings.timeout as u64));
+}
+
+// Playback loop, play samples from a source until it is finished.
+// When the stream fails, it is retried as set by max_retries, and playback continues from where it was.
+pub fn playback_loop_from(
+    backend: &dyn Backend,
+    settings: &StreamSettings,
+    source: &mut dyn PlaybackSource,
+) -> WasapiRes<()> {
+    let mut sample_queue: VecDeque<u8> = VecDeque::new();
+    let mut finished = false;
+    let mut format = None;
+    let mut retries = 0;
+    loop {
+        let stream = match open_stream(backend, settings, &Direction::Render, format.as_ref()) {
+            Ok(stream) => stream,
+            Err(err) => {
+                retry(settings, &mut retries, err)?;
+                wait_for_device(settings);
+                continue;
+            }
+        };
+        format = Some(stream.format.clone());
+        let result = play_stream(
+            &stream,
+            settings,
+            source,
+            &mut sample_queue,
+            &mut finished,
+            &mut retries,
+        );
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) => retry(settings, &mut retries, err)?,
+        }
+    }
+}
+
+// Play on an opened stream until the source is finished, or the stream fails
+fn play_stream(
+    stream: &OpenStream,
+    settings: &StreamSettings,
+    source: &mut dyn PlaybackSource,
+    sample_queue: &mut VecDeque<u8>,
+    finished: &mut bool,
+    retries: &mut usize,
+) -> WasapiRes<()> {
+    let audio_client = stream.audio_client.as_ref();
+    let format = &stream.format;
+    let blockalign = format.get_blockalign() as usize;
+    let render_client = audio_client.get_audiorenderclient()?;
+    audio_client.start_stream()?;
+    loop {
+        let available = audio_client.get_available_frames()? as usize;
+        if !*finished && sample_queue.len() < available * blockalign {
+            match source.fill(available - sample_queue.len() / blockalign, format) {
+                Some(data) => {
+                    sample_queue.extend(data);
+                    let missing = (available * blockalign).saturating_sub(sample_queue.len());
+                    sample_queue.extend(silence(missing / blockalign, format));
+                }
+                None => *finished = true,
+            }
+        }
+        if *finished {
+            if sample_queue.is_empty() && audio_client.get_current_padding()? == 0 {
+                break;
+            }
+            // Complete the last buffer with silence
+            let missing = (available * blockalign).saturating_sub(sample_queue.len());
+            if missing > 0 && !sample_queue.is_empty() {
+                sample_queue.extend(silence(missing / blockalign, format));
+            }
+        }
+        let nbr_frames = available.min(sample_queue.len() / blockalign);
+        if nbr_frames > 0 {
+            render_client.write_to_device_from_deque(nbr_frames, blockalign, sample_queue)?;
+        }
+        if stream.h_event.wait_for_event(settings.timeout).is_err() {
+            // The device may only have stalled, wait for the next event
+            if *retries < settings.max_retries {
+                *retries += 1;
+                continue;
+            }
+            audio_client.stop_stream()?;
+            return Err(WasapiError::new("Timed out waiting for playback event").into());
+        }
+        *retries = 0;
+    }
+    audio_client.stop_stream()?;
+    Ok(())
+}
+
+// What a capture loop keeps when the stream is opened again after a failure
+struct CaptureState {
+    format: WaveFormat,
+    sample_queue: VecDeque<u8>,
+    detector: GlitchDetector,
+    captured_frames: u64,
+    sent_frames: u64,
+}
+
+// Capture loop, capture samples and send them in chunks of "chunksize" frames to a channel.
+// Glitches found in the data or reported by the device are passed to a callback.
+// The loop returns when the receiving side of the channel is closed,
+// or when max_frames frames have been sent, with a shorter last chunk if needed.
+// When the stream fails, it is retried as set by max_retries,
+// and the gap in the data is reported as a discontinuity.
+pub fn capture_loop(
+    backend: &dyn Backend,
+    settings: &StreamSettings,
+    tx_capt: mpsc::SyncSender<Vec<u8>>,
+    on_glitch: &mut dyn FnMut(&GlitchEvent),
+) -> WasapiRes<()> {
+    let mut state: Option<CaptureState> = None;
+    let mut retries = 0;
+    loop {
+        let previous = state.as_ref().map(|state| &state.format);
+        let stream = match open_stream(backend, settings, &Direction::Capture, previous) {
+            Ok(stream) => stream,
+            Err(err) => {
+                retry(settings, &mut retries, err)?;
+                wait_for_device(settings);
+                continue;
+            }
+        };
+        let reopened = state.is_some();
+        let state = state.get_or_insert_with(|| CaptureState {
+            format: stream.format.clone(),
+            sample_queue: VecDeque::new(),
+            detector: GlitchDetector::new(
+                stream.format.get_nchannels() as usize,
+                GlitchSettings::new(stream.format.get_samplespersec() as usize),
+            ),
+            captured_frames: 0,
+            sent_frames: 0,
+        });
+        if reopened {
+            let flags = BufferFlags {
+                data_discontinuity: true,
+                ..Default::default()
+            };
+            for event in state.detector.report_flags(&flags, state.captured_frames) {
+                on_glitch(&event);
+            }
+        }
+        match capture_stream(&stream, settings, &tx_capt, on_glitch, state, &mut retries) {
+            Ok(()) => return Ok(()),
+            Err(err) => retry(settings, &mut retries, err)?,
+        }
+    }
+}
+
+// Capture on an opened stream until the capture is done, or the stream fails
+fn capture_stream(
+    stream: &OpenStream,
+    settings: &StreamSettings,
+    tx_capt: &mpsc::SyncSender<Vec<u8>>,
+    on_glitch: &mut dyn FnMut(&GlitchEvent),
+    state: &mut CaptureState,
+    retries: &mut usize,
+) -> WasapiRes<()> {
+    let audio_client = stream.audio_client.as_ref();
+    let format = &stream.format;
+    let blockalign = format.get_blockalign() as usize;
+    let capture_client = audio_client.get_audiocaptureclient()?;
+    let chunk_bytes = settings.chunksize * blockalign;
+    audio_client.start_stream()?;
+    loop {
+        while capture_client.get_next_nbr_frames()? > 0 {
+            let queue_len = state.sample_queue.len();
+            let flags =
+                capture_client.read_from_device_to_deque(blockalign, &mut state.sample_queue)?;
+            if flags.is_any_set() {
+                for event in state.detector.report_flags(&flags, state.captured_frames) {
+                    on_glitch(&event);
+                }
+            }
+            state.captured_frames += ((state.sample_queue.len() - queue_len) / blockalign) as u64;
+        }
+        loop {
+            let mut nbr_bytes = chunk_bytes;
+            if let Some(max_frames) = settings.max_frames {
+                if state.sent_frames >= max_frames {
+                    audio_client.stop_stream()?;
+                    return Ok(());
+                }
+                nbr_bytes = nbr_bytes.min((max_frames - state.sent_frames) as usize * blockalign);
+            }
+            if state.sample_queue.len() < nbr_bytes {
+                break;
+            }
+            let chunk: Vec<u8> = state.sample_queue.drain(..nbr_bytes).collect();
+            for event in state.detector.process(&bytes_to_channels(&chunk, format)?) {
+                on_glitch(&event);
+            }
+            if tx_capt.send(chunk).is_err() {
+                audio_client.stop_stream()?;
+                return Ok(());
+            }
+            state.sent_frames += (nbr_bytes / blockalign) as u64;
+        }
+        if stream.h_event.wait_for_event(settings.timeout).is_err() {
+            // The device may only have stalled, wait for the next event
+            if *retries < settings.max_retries {
+                *retries += 1;
+                continue;
+            }
+            audio_client.stop_stream()?;
+            return Err(WasapiError::new("Timed out waiting for capture event").into());
+        }
+        *retries = 0;
+    }
+}
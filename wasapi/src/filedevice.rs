@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek};
+use std::path::{Path, PathBuf};
+
+use crate::backend::{Backend, DeviceCollectionTrait, DeviceTrait};
+use crate::simulated::{Pacing, SimDeviceConfig, SimSink, SimSource, SimulatedBackend};
+use crate::wasapi::{Direction, WasapiError, WasapiRes, WaveFormat};
+use crate::wav::{WavReader, WavWriter};
+
+// Source for a simulated capture device, reading a wav file.
+pub struct WavSource<R: Read + Seek> {
+    reader: WavReader<R>,
+}
+
+impl WavSource<BufReader<File>> {
+    // Open a wav file
+    pub fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        Ok(WavSource::new(WavReader::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> WavSource<R> {
+    // Create a source from a reader
+    pub fn new(reader: WavReader<R>) -> Self {
+        WavSource { reader }
+    }
+
+    // Get the format of the file
+    pub fn get_format(&self) -> WaveFormat {
+        self.reader.get_format()
+    }
+
+    // Get the length of the file in frames
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.reader.get_nbr_frames()
+    }
+}
+
+impl<R: Read + Seek + Send> SimSource for WavSource<R> {
+    fn read(&mut self, nbr_frames: usize, format: &WaveFormat) -> WasapiRes<Vec<u8>> {
+        if *format != self.reader.get_format() {
+            return Err(WasapiError::new("The stream format does not match the file").into());
+        }
+        self.reader.read_bytes(nbr_frames)
+    }
+}
+
+// Sink for a simulated render device, writing a wav file in the format of the stream.
+// Each new stream on the device replaces the file.
+pub struct WavSink {
+    path: PathBuf,
+    writer: Option<WavWriter<BufWriter<File>>>,
+}
+
+impl WavSink {
+    // Create a sink, the file is created when a stream is initialized
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        WavSink {
+            path: path.as_ref().to_path_buf(),
+            writer: None,
+        }
+    }
+}
+
+impl SimSink for WavSink {
+    fn open(&mut self, format: &WaveFormat) -> WasapiRes<()> {
+        self.close()?;
+        self.writer = Some(WavWriter::create(&self.path, format)?);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> WasapiRes<()> {
+        match &mut self.writer {
+            Some(writer) => writer.write_bytes(data),
+            None => Err(WasapiError::new("The file is not open").into()),
+        }
+    }
+
+    fn close(&mut self) -> WasapiRes<()> {
+        match self.writer.take() {
+            Some(mut writer) => writer.close(),
+            None => Ok(()),
+        }
+    }
+}
+
+// A backend with capture devices that play wav files, and render devices that record to wav files.
+// It is a simulated backend, so the devices negotiate formats, buffer sizes and periods like real ones,
+// and code written for real devices can run unchanged on files,
+// either as fast as possible or at the pace of real devices.
+// A capture device returns silence, flagged as silent, after the end of its file.
+// The file of a render device is finalized when the client of the stream is dropped.
+#[derive(Clone)]
+pub struct FileBackend {
+    simulated: SimulatedBackend,
+}
+
+impl FileBackend {
+    // Create a backend without devices
+    pub fn new(pacing: Pacing) -> Self {
+        let simulated = SimulatedBackend::new();
+        simulated.set_pacing(pacing);
+        FileBackend { simulated }
+    }
+
+    // Add a capture device reading a wav file.
+    // The format of the file is the mix format of the device, and the only format supported in exclusive mode.
+    pub fn add_capture_file<P: AsRef<Path>>(&self, name: &str, path: P) -> WasapiRes<()> {
+        let source = WavSource::open(path)?;
+        let config = SimDeviceConfig::new(name, &Direction::Capture, &source.get_format());
+        self.simulated.add_device(config)?;
+        self.simulated.set_capture_source(name, Box::new(source))
+    }
+
+    // Add a render device writing to a wav file, with the formats and periods of a device configuration
+    pub fn add_render_file<P: AsRef<Path>>(
+        &self,
+        config: SimDeviceConfig,
+        path: P,
+    ) -> WasapiRes<()> {
+        if config.direction != Direction::Render {
+            return Err(
+                WasapiError::new("The device configuration is not for a render device").into(),
+            );
+        }
+        let name = config.name.clone();
+        self.simulated.add_device(config)?;
+        self.simulated
+            .set_render_sink(&name, Box::new(WavSink::new(path)))
+    }
+
+    // Get the simulated backend, to read the clock, count underruns or inject faults
+    pub fn get_simulated(&self) -> &SimulatedBackend {
+        &self.simulated
+    }
+}
+
+impl Backend for FileBackend {
+    fn get_device_collection(
+        &self,
+        direction: &Direction,
+    ) -> WasapiRes<Box<dyn DeviceCollectionTrait>> {
+        self.simulated.get_device_collection(direction)
+    }
+
+    fn get_default_device(&self, direction: &Direction) -> WasapiRes<Box<dyn DeviceTrait>> {
+        self.simulated.get_default_device(direction)
+    }
+}
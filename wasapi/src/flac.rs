@@ -0,0 +1,1558 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use crate::conversion::{bytes_to_channels, channels_to_bytes};
+use crate::wasapi::{SampleType, WasapiError, WasapiRes, WaveFormat};
+
+// Length of the STREAMINFO metadata block.
+const STREAMINFO_LEN: usize = 34;
+// Offset of the STREAMINFO contents, after the stream marker and the metadata block header.
+const STREAMINFO_OFFSET: u64 = 8;
+// Highest order of the linear predictor allowed by the format.
+const MAX_LPC_ORDER: usize = 32;
+// Highest Rice parameter, with the 5-bit parameters of residual coding method 1.
+const MAX_RICE_PARAM: u32 = 30;
+// Highest Rice parameter with the 4-bit parameters of residual coding method 0.
+const MAX_RICE_PARAM_4BIT: u32 = 14;
+
+// Settings for the FLAC encoder.
+#[derive(Clone, Debug)]
+pub struct FlacSettings {
+    // Number of frames per block
+    pub block_size: usize,
+    // Highest order of the linear predictor, 0 to only use the fixed predictors
+    pub max_lpc_order: usize,
+    // Precision of the quantized predictor coefficients in bits, at most 15
+    pub lpc_precision: u32,
+    // Highest partition order for the Rice coding of the residual
+    pub max_partition_order: usize,
+    // Try left/side, right/side and mid/side coding of stereo streams
+    pub stereo_decorrelation: bool,
+}
+
+impl FlacSettings {
+    // Default settings, similar to compression level 5 of the reference encoder
+    pub fn new() -> Self {
+        FlacSettings {
+            block_size: 4096,
+            max_lpc_order: 8,
+            lpc_precision: 14,
+            max_partition_order: 6,
+            stereo_decorrelation: true,
+        }
+    }
+}
+
+impl Default for FlacSettings {
+    fn default() -> Self {
+        FlacSettings::new()
+    }
+}
+
+// CRC-8 with polynomial x^8 + x^2 + x + 1, used for the frame headers.
+fn crc8_update(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+    for _ in 0..8 {
+        crc = if crc & 0x80 != 0 {
+            (crc << 1) ^ 0x07
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+// CRC-16 with polynomial x^16 + x^15 + x^2 + 1, used for the complete frames.
+fn crc16_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ ((byte as u16) << 8);
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 {
+            (crc << 1) ^ 0x8005
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+// Map signed values to unsigned, 0, -1, 1, -2, 2 ... become 0, 1, 2, 3, 4 ...
+fn zigzag(value: i64) -> u64 {
+    if value >= 0 {
+        (value as u64) << 1
+    } else {
+        ((-value as u64) << 1) - 1
+    }
+}
+
+fn unzigzag(value: u64) -> i64 {
+    if value & 1 == 0 {
+        (value >> 1) as i64
+    } else {
+        -((value >> 1) as i64) - 1
+    }
+}
+
+// Writer of big-endian bit fields.
+struct BitWriter {
+    data: Vec<u8>,
+    acc: u64,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            data: Vec::new(),
+            acc: 0,
+            nbits: 0,
+        }
+    }
+
+    // Write the lowest bits of a value, at most 32 bits
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.nbits += bits;
+        while self.nbits >= 8 {
+            self.nbits -= 8;
+            self.data.push((self.acc >> self.nbits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    // Write a number of zeros followed by a one
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn write_rice(&mut self, value: i64, param: u32) {
+        let unsigned = zigzag(value);
+        self.write_unary(unsigned >> param);
+        self.write(unsigned, param);
+    }
+
+    // Pad with zeros to a whole byte
+    fn align(&mut self) {
+        if self.nbits > 0 {
+            self.write(0, 8 - self.nbits);
+        }
+    }
+}
+
+// Reader of big-endian bit fields, that keeps the checksums of the bytes it reads.
+struct BitReader<'a, R: Read> {
+    reader: &'a mut R,
+    acc: u64,
+    nbits: u32,
+    crc8: u8,
+    crc16: u16,
+}
+
+impl<'a, R: Read> BitReader<'a, R> {
+    fn new(reader: &'a mut R) -> Self {
+        BitReader {
+            reader,
+            acc: 0,
+            nbits: 0,
+            crc8: 0,
+            crc16: 0,
+        }
+    }
+
+    fn fetch_byte(&mut self) -> std::io::Result<()> {
+        let mut byte = [0u8; 1];
+        self.reader.read_exact(&mut byte)?;
+        self.crc8 = crc8_update(self.crc8, byte[0]);
+        self.crc16 = crc16_update(self.crc16, byte[0]);
+        self.acc = (self.acc << 8) | byte[0] as u64;
+        self.nbits += 8;
+        Ok(())
+    }
+
+    // Read an unsigned value of at most 32 bits
+    fn read(&mut self, bits: u32) -> std::io::Result<u64> {
+        if bits == 0 {
+            return Ok(0);
+        }
+        while self.nbits < bits {
+            self.fetch_byte()?;
+        }
+        self.nbits -= bits;
+        Ok((self.acc >> self.nbits) & ((1 << bits) - 1))
+    }
+
+    // Read a two's complement value of at most 32 bits
+    fn read_signed(&mut self, bits: u32) -> std::io::Result<i64> {
+        if bits == 0 {
+            return Ok(0);
+        }
+        let value = self.read(bits)?;
+        Ok(((value << (64 - bits)) as i64) >> (64 - bits))
+    }
+
+    // Read a number of zeros followed by a one, and return the number of zeros
+    fn read_unary(&mut self) -> std::io::Result<u64> {
+        let mut zeros = 0;
+        loop {
+            if self.nbits == 0 {
+                self.fetch_byte()?;
+            }
+            let remaining = self.acc & ((1 << self.nbits) - 1);
+            if remaining == 0 {
+                zeros += self.nbits as u64;
+                self.nbits = 0;
+            } else {
+                let highest = 63 - remaining.leading_zeros();
+                zeros += (self.nbits - 1 - highest) as u64;
+                self.nbits = highest;
+                return Ok(zeros);
+            }
+        }
+    }
+
+    fn read_rice(&mut self, param: u32) -> std::io::Result<i64> {
+        let high = self.read_unary()?;
+        let low = self.read(param)?;
+        Ok(unzigzag((high << param) | low))
+    }
+
+    fn is_aligned(&self) -> bool {
+        self.nbits.is_multiple_of(8)
+    }
+
+    // Skip the padding to the next whole byte
+    fn align(&mut self) {
+        self.nbits -= self.nbits % 8;
+    }
+}
+
+// Rice coding of a residual, with the chosen partition order and one parameter per partition.
+struct RiceCoding {
+    partition_order: usize,
+    params: Vec<u32>,
+    bits: u64,
+}
+
+// Find the best Rice parameter for a partition, with the number of bits it needs.
+// The number of bits is estimated from the sum of the values.
+fn best_rice_param(nbr_values: u64, sum: u64) -> (u32, u64) {
+    let mut best = (0, u64::MAX);
+    for param in 0..=MAX_RICE_PARAM {
+        let bits = nbr_values * (param as u64 + 1) + (sum >> param);
+        if bits < best.1 {
+            best = (param, bits);
+        }
+    }
+    best
+}
+
+// Choose the partitioning and parameters of the Rice coding of a residual.
+fn choose_rice_coding(
+    residual: &[i64],
+    block_size: usize,
+    predictor_order: usize,
+    max_partition_order: usize,
+) -> RiceCoding {
+    let mut max_order = 0;
+    while max_order < max_partition_order.min(15)
+        && block_size.is_multiple_of(1 << (max_order + 1))
+        && (block_size >> (max_order + 1)) > predictor_order
+    {
+        max_order += 1;
+    }
+    // Sums of the partitions of the highest order, merged pairwise for the lower orders
+    let partition_len = block_size >> max_order;
+    let mut sums: Vec<(u64, u64)> = (0..1 << max_order)
+        .map(|partition| {
+            let start = (partition * partition_len).saturating_sub(predictor_order);
+            let end = (partition + 1) * partition_len - predictor_order;
+            let sum = residual[start..end]
+                .iter()
+                .map(|value| zigzag(*value))
+                .sum();
+            ((end - start) as u64, sum)
+        })
+        .collect();
+    let mut best: Option<RiceCoding> = None;
+    let mut order = max_order;
+    loop {
+        let choices: Vec<(u32, u64)> = sums
+            .iter()
+            .map(|(nbr_values, sum)| best_rice_param(*nbr_values, *sum))
+            .collect();
+        let max_param = choices.iter().map(|(param, _)| *param).max().unwrap_or(0);
+        let param_bits = if max_param > MAX_RICE_PARAM_4BIT {
+            5
+        } else {
+            4
+        };
+        let bits = 6 + choices
+            .iter()
+            .map(|(_, bits)| param_bits + bits)
+            .sum::<u64>();
+        if best.as_ref().is_none_or(|coding| bits < coding.bits) {
+            best = Some(RiceCoding {
+                partition_order: order,
+                params: choices.iter().map(|(param, _)| *param).collect(),
+                bits,
+            });
+        }
+        if order == 0 {
+            break;
+        }
+        sums = sums
+            .chunks(2)
+            .map(|pair| (pair[0].0 + pair[1].0, pair[0].1 + pair[1].1))
+            .collect();
+        order -= 1;
+    }
+    best.unwrap_or(RiceCoding {
+        partition_order: 0,
+        params: vec![0],
+        bits: 6,
+    })
+}
+
+// Predictor of an encoded subframe.
+enum Predictor {
+    Constant,
+    Verbatim,
+    Fixed(usize),
+    Lpc {
+        coefficients: Vec<i64>,
+        precision: u32,
+        shift: u32,
+    },
+}
+
+// A subframe ready to be written, with its size in bits.
+struct Subframe {
+    predictor: Predictor,
+    residual: Vec<i64>,
+    rice: Option<RiceCoding>,
+    bits: u64,
+}
+
+// Residual of one of the fixed polynomial predictors.
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|n| {
+            let predicted = match order {
+                0 => 0,
+                1 => samples[n - 1],
+                2 => 2 * samples[n - 1] - samples[n - 2],
+                3 => 3 * samples[n - 1] - 3 * samples[n - 2] + samples[n - 3],
+                _ => 4 * samples[n - 1] - 6 * samples[n - 2] + 4 * samples[n - 3] - samples[n - 4],
+            };
+            samples[n] - predicted
+        })
+        .collect()
+}
+
+// Prediction of a sample from the previous ones, with quantized coefficients.
+fn lpc_predict(history: &[i64], coefficients: &[i64], shift: u32) -> i64 {
+    let sum: i64 = coefficients
+        .iter()
+        .zip(history.iter().rev())
+        .map(|(coefficient, sample)| coefficient * sample)
+        .sum();
+    sum >> shift
+}
+
+// Residual of a linear predictor, or None if it does not fit in 32 bits.
+fn lpc_residual(samples: &[i64], coefficients: &[i64], shift: u32) -> Option<Vec<i64>> {
+    let order = coefficients.len();
+    let mut residual = Vec::with_capacity(samples.len() - order);
+    for n in order..samples.len() {
+        let value = samples[n] - lpc_predict(&samples[n - order..n], coefficients, shift);
+        if value > i32::MAX as i64 || value < i32::MIN as i64 {
+            return None;
+        }
+        residual.push(value);
+    }
+    Some(residual)
+}
+
+// Calculate the predictor coefficients for all orders up to max_order,
+// from the autocorrelation of the windowed signal with the Levinson-Durbin recursion.
+fn lpc_coefficients(samples: &[i64], max_order: usize) -> Vec<Vec<f64>> {
+    let len = samples.len();
+    // Tukey window with half of the length tapered
+    let taper = len / 4;
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(n, value)| {
+            let weight = if n < taper {
+                0.5 - 0.5 * (std::f64::consts::PI * n as f64 / taper as f64).cos()
+            } else if n >= len - taper {
+                0.5 - 0.5 * (std::f64::consts::PI * (len - 1 - n) as f64 / taper as f64).cos()
+            } else {
+                1.0
+            };
+            *value as f64 * weight
+        })
+        .collect();
+    let autoc: Vec<f64> = (0..=max_order)
+        .map(|lag| {
+            windowed[lag..]
+                .iter()
+                .zip(windowed.iter())
+                .map(|(a, b)| a * b)
+                .sum()
+        })
+        .collect();
+    let mut result = Vec::new();
+    if autoc[0] <= 0.0 {
+        return result;
+    }
+    let mut lpc = vec![0.0; max_order];
+    let mut error = autoc[0];
+    for i in 0..max_order {
+        let mut reflection = -autoc[i + 1];
+        for j in 0..i {
+            reflection -= lpc[j] * autoc[i - j];
+        }
+        reflection /= error;
+        lpc[i] = reflection;
+        for j in 0..i / 2 {
+            let tmp = lpc[j];
+            lpc[j] += reflection * lpc[i - 1 - j];
+            lpc[i - 1 - j] += reflection * tmp;
+        }
+        if i % 2 == 1 {
+            lpc[i / 2] += lpc[i / 2] * reflection;
+        }
+        error *= 1.0 - reflection * reflection;
+        result.push(lpc[..=i].iter().map(|value| -value).collect());
+        if error <= 0.0 {
+            break;
+        }
+    }
+    result
+}
+
+// Quantize predictor coefficients to a precision in bits, returning the coefficients and the shift.
+fn quantize_coefficients(coefficients: &[f64], precision: u32) -> Option<(Vec<i64>, u32)> {
+    let max_abs = coefficients
+        .iter()
+        .fold(0.0f64, |max, value| max.max(value.abs()));
+    if max_abs <= 0.0 || !max_abs.is_finite() {
+        return None;
+    }
+    let log2_max = max_abs.log2().floor() as i32 + 1;
+    let shift = (precision as i32 - 1 - log2_max).clamp(0, 15) as u32;
+    let max_value = (1i64 << (precision - 1)) - 1;
+    let min_value = -(1i64 << (precision - 1));
+    let mut error = 0.0;
+    let quantized = coefficients
+        .iter()
+        .map(|value| {
+            error += value * (1u64 << shift) as f64;
+            let rounded = (error.round() as i64).clamp(min_value, max_value);
+            error -= rounded as f64;
+            rounded
+        })
+        .collect();
+    Some((quantized, shift))
+}
+
+// Find the smallest encoding of the samples of one channel in a block.
+fn encode_subframe(samples: &[i64], bps: u32, settings: &FlacSettings) -> Subframe {
+    let len = samples.len();
+    if samples.iter().all(|value| *value == samples[0]) {
+        return Subframe {
+            predictor: Predictor::Constant,
+            residual: Vec::new(),
+            rice: None,
+            bits: 8 + bps as u64,
+        };
+    }
+    let mut best = Subframe {
+        predictor: Predictor::Verbatim,
+        residual: Vec::new(),
+        rice: None,
+        bits: 8 + len as u64 * bps as u64,
+    };
+    for order in 0..=4.min(len - 1) {
+        let residual = fixed_residual(samples, order);
+        let rice = choose_rice_coding(&residual, len, order, settings.max_partition_order);
+        let bits = 8 + order as u64 * bps as u64 + rice.bits;
+        if bits < best.bits {
+            best = Subframe {
+                predictor: Predictor::Fixed(order),
+                residual,
+                rice: Some(rice),
+                bits,
+            };
+        }
+    }
+    let max_order = settings.max_lpc_order.min(MAX_LPC_ORDER).min(len - 1);
+    let precision = settings.lpc_precision.clamp(5, 15);
+    if max_order > 0 {
+        for float_coefficients in lpc_coefficients(samples, max_order) {
+            let (coefficients, shift) = match quantize_coefficients(&float_coefficients, precision)
+            {
+                Some(quantized) => quantized,
+                None => continue,
+            };
+            let residual = match lpc_residual(samples, &coefficients, shift) {
+                Some(residual) => residual,
+                None => continue,
+            };
+            let order = coefficients.len();
+            let rice = choose_rice_coding(&residual, len, order, settings.max_partition_order);
+            let bits = 8 + order as u64 * (bps + precision) as u64 + 4 + 5 + rice.bits;
+            if bits < best.bits {
+                best = Subframe {
+                    predictor: Predictor::Lpc {
+                        coefficients,
+                        precision,
+                        shift,
+                    },
+                    residual,
+                    rice: Some(rice),
+                    bits,
+                };
+            }
+        }
+    }
+    best
+}
+
+fn write_residual(
+    writer: &mut BitWriter,
+    residual: &[i64],
+    rice: &RiceCoding,
+    block_size: usize,
+    order: usize,
+) {
+    let use_5bit = rice.params.iter().any(|param| *param > MAX_RICE_PARAM_4BIT);
+    writer.write(if use_5bit { 1 } else { 0 }, 2);
+    writer.write(rice.partition_order as u64, 4);
+    let partition_len = block_size >> rice.partition_order;
+    let mut start = 0;
+    for (partition, param) in rice.params.iter().enumerate() {
+        let nbr_values = if partition == 0 {
+            partition_len - order
+        } else {
+            partition_len
+        };
+        writer.write(*param as u64, if use_5bit { 5 } else { 4 });
+        for value in residual[start..start + nbr_values].iter() {
+            writer.write_rice(*value, *param);
+        }
+        start += nbr_values;
+    }
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i64], bps: u32, subframe: &Subframe) {
+    // Zero padding bit, the type, and no wasted bits
+    match &subframe.predictor {
+        Predictor::Constant => {
+            writer.write(0, 8);
+            writer.write_signed(samples[0], bps);
+        }
+        Predictor::Verbatim => {
+            writer.write(0x01 << 1, 8);
+            for value in samples.iter() {
+                writer.write_signed(*value, bps);
+            }
+        }
+        Predictor::Fixed(order) => {
+            writer.write(((0x08 + *order) << 1) as u64, 8);
+            for value in samples[..*order].iter() {
+                writer.write_signed(*value, bps);
+            }
+        }
+        Predictor::Lpc {
+            coefficients,
+            precision,
+            shift,
+        } => {
+            let order = coefficients.len();
+            writer.write(((0x20 + order - 1) << 1) as u64, 8);
+            for value in samples[..order].iter() {
+                writer.write_signed(*value, bps);
+            }
+            writer.write(*precision as u64 - 1, 4);
+            writer.write(*shift as u64, 5);
+            for coefficient in coefficients.iter() {
+                writer.write_signed(*coefficient, *precision);
+            }
+        }
+    }
+    if let Some(rice) = &subframe.rice {
+        let order = match subframe.predictor {
+            Predictor::Fixed(order) => order,
+            Predictor::Lpc {
+                ref coefficients, ..
+            } => coefficients.len(),
+            _ => 0,
+        };
+        write_residual(writer, &subframe.residual, rice, samples.len(), order);
+    }
+}
+
+// Write a number with the extended UTF-8 coding used for the frame numbers.
+fn write_utf8(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let nbr_bytes = match value {
+        0..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        0x1_0000..=0x1F_FFFF => 4,
+        0x20_0000..=0x3FF_FFFF => 5,
+        0x400_0000..=0x7FFF_FFFF => 6,
+        _ => 7,
+    };
+    let prefix = (0xFF00u64 >> nbr_bytes) & 0xFF;
+    writer.write(prefix | (value >> (6 * (nbr_bytes - 1))), 8);
+    for n in (0..nbr_bytes - 1).rev() {
+        writer.write(0x80 | ((value >> (6 * n)) & 0x3F), 8);
+    }
+}
+
+// Get the frame header code for a block size, and the number of extra bits needed after the header.
+fn block_size_code(block_size: usize) -> (u64, u32) {
+    match block_size {
+        192 => (1, 0),
+        576 | 1152 | 2304 | 4608 => (2 + (block_size / 576).trailing_zeros() as u64, 0),
+        256 | 512 | 1024 | 2048 | 4096 | 8192 | 16384 | 32768 => {
+            (8 + (block_size / 256).trailing_zeros() as u64, 0)
+        }
+        _ if block_size <= 256 => (6, 8),
+        _ => (7, 16),
+    }
+}
+
+fn sample_size_code(bps: u32) -> u64 {
+    match bps {
+        8 => 1,
+        12 => 2,
+        16 => 4,
+        20 => 5,
+        24 => 6,
+        32 => 7,
+        _ => 0,
+    }
+}
+
+// Encode one block, one vector of samples per channel.
+fn encode_frame(
+    channels: &[Vec<i64>],
+    bps: u32,
+    frame_number: u64,
+    settings: &FlacSettings,
+) -> Vec<u8> {
+    let block_size = channels[0].len();
+    let mut writer = BitWriter::new();
+    // Sync code, reserved bit and fixed block size
+    writer.write(0xFFF8, 16);
+    let (size_code, size_bits) = block_size_code(block_size);
+    writer.write(size_code, 4);
+    // The sample rate is given by STREAMINFO
+    writer.write(0, 4);
+
+    let mut subframes: Vec<(Vec<i64>, u32, Subframe)> = Vec::new();
+    let mut assignment = channels.len() as u64 - 1;
+    if channels.len() == 2 && settings.stereo_decorrelation {
+        let left = &channels[0];
+        let right = &channels[1];
+        let side: Vec<i64> = left.iter().zip(right.iter()).map(|(l, r)| l - r).collect();
+        let mid: Vec<i64> = left
+            .iter()
+            .zip(right.iter())
+            .map(|(l, r)| (l + r) >> 1)
+            .collect();
+        let left_sub = encode_subframe(left, bps, settings);
+        let right_sub = encode_subframe(right, bps, settings);
+        let side_sub = encode_subframe(&side, bps + 1, settings);
+        let mid_sub = encode_subframe(&mid, bps, settings);
+        let costs = [
+            left_sub.bits + right_sub.bits,
+            left_sub.bits + side_sub.bits,
+            side_sub.bits + right_sub.bits,
+            mid_sub.bits + side_sub.bits,
+        ];
+        let best = (0..4).min_by_key(|n| costs[*n]).unwrap_or(0);
+        subframes = match best {
+            0 => vec![
+                (left.clone(), bps, left_sub),
+                (right.clone(), bps, right_sub),
+            ],
+            1 => vec![(left.clone(), bps, left_sub), (side, bps + 1, side_sub)],
+            2 => vec![(side, bps + 1, side_sub), (right.clone(), bps, right_sub)],
+            _ => vec![(mid, bps, mid_sub), (side, bps + 1, side_sub)],
+        };
+        if best > 0 {
+            assignment = 7 + best as u64;
+        }
+    } else {
+        for samples in channels.iter() {
+            let subframe = encode_subframe(samples, bps, settings);
+            subframes.push((samples.clone(), bps, subframe));
+        }
+    }
+    writer.write(assignment, 4);
+    writer.write(sample_size_code(bps), 3);
+    writer.write(0, 1);
+    write_utf8(&mut writer, frame_number);
+    writer.write(block_size as u64 - 1, size_bits);
+    let crc8 = writer
+        .data
+        .iter()
+        .fold(0, |crc, byte| crc8_update(crc, *byte));
+    writer.write(crc8 as u64, 8);
+
+    for (samples, bps, subframe) in subframes.iter() {
+        write_subframe(&mut writer, samples, *bps, subframe);
+    }
+    writer.align();
+    let crc16 = writer
+        .data
+        .iter()
+        .fold(0, |crc, byte| crc16_update(crc, *byte));
+    writer.write(crc16 as u64, 16);
+    writer.data
+}
+
+// Contents of the STREAMINFO metadata block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamInfo {
+    pub min_block_size: usize,
+    pub max_block_size: usize,
+    pub min_frame_size: usize,
+    pub max_frame_size: usize,
+    pub samplerate: usize,
+    pub channels: usize,
+    pub bits_per_sample: usize,
+    // Total number of frames, 0 if unknown
+    pub nbr_frames: u64,
+    // MD5 of the decoded samples, all zeros if unknown
+    pub md5: [u8; 16],
+}
+
+impl StreamInfo {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write(self.min_block_size as u64, 16);
+        writer.write(self.max_block_size as u64, 16);
+        writer.write(self.min_frame_size as u64, 24);
+        writer.write(self.max_frame_size as u64, 24);
+        writer.write(self.samplerate as u64, 20);
+        writer.write(self.channels as u64 - 1, 3);
+        writer.write(self.bits_per_sample as u64 - 1, 5);
+        writer.write(self.nbr_frames >> 32, 4);
+        writer.write(self.nbr_frames & 0xFFFF_FFFF, 32);
+        writer.data.extend_from_slice(&self.md5);
+        writer.data
+    }
+
+    fn from_bytes(data: &[u8]) -> WasapiRes<Self> {
+        if data.len() < STREAMINFO_LEN {
+            return Err(WasapiError::new("The STREAMINFO block is too short").into());
+        }
+        let mut cursor = std::io::Cursor::new(data);
+        let mut reader = BitReader::new(&mut cursor);
+        let min_block_size = reader.read(16)? as usize;
+        let max_block_size = reader.read(16)? as usize;
+        let min_frame_size = reader.read(24)? as usize;
+        let max_frame_size = reader.read(24)? as usize;
+        let samplerate = reader.read(20)? as usize;
+        let channels = reader.read(3)? as usize + 1;
+        let bits_per_sample = reader.read(5)? as usize + 1;
+        let nbr_frames = (reader.read(4)? << 32) | reader.read(32)?;
+        let mut md5 = [0u8; 16];
+        md5.copy_from_slice(&data[18..34]);
+        Ok(StreamInfo {
+            min_block_size,
+            max_block_size,
+            min_frame_size,
+            max_frame_size,
+            samplerate,
+            channels,
+            bits_per_sample,
+            nbr_frames,
+            md5,
+        })
+    }
+}
+
+// Convert the samples of a block to signed little-endian bytes with the smallest whole number
+// of bytes per sample, the layout used for the MD5 checksum.
+fn md5_bytes(channels: &[Vec<i64>], bps: u32, data: &mut Vec<u8>) {
+    let bytes_per_sample = bps.div_ceil(8) as usize;
+    let nbr_frames = channels.first().map(|chan| chan.len()).unwrap_or(0);
+    for n in 0..nbr_frames {
+        for chan in channels.iter() {
+            data.extend_from_slice(&chan[n].to_le_bytes()[..bytes_per_sample]);
+        }
+    }
+}
+
+// Streaming FLAC encoder.
+// Data is encoded one block at a time, and STREAMINFO is completed when the writer is closed or dropped.
+pub struct FlacWriter<W: Write + Seek> {
+    writer: W,
+    wave_fmt: WaveFormat,
+    settings: FlacSettings,
+    bps: u32,
+    // Samples that do not yet fill a block
+    buffer: Vec<Vec<i64>>,
+    md5: md5::Context,
+    nbr_frames: u64,
+    frame_number: u64,
+    min_frame_size: usize,
+    max_frame_size: usize,
+    closed: bool,
+}
+
+impl FlacWriter<BufWriter<File>> {
+    // Create a flac file
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        wave_fmt: &WaveFormat,
+        settings: FlacSettings,
+    ) -> WasapiRes<Self> {
+        FlacWriter::new(BufWriter::new(File::create(path)?), wave_fmt, settings)
+    }
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    // Create an encoder for 16 or 24 bit integer data with up to 8 channels.
+    // 24 bit samples may be stored in 32 bits.
+    pub fn new(mut writer: W, wave_fmt: &WaveFormat, settings: FlacSettings) -> WasapiRes<Self> {
+        let storebits = wave_fmt.get_bitspersample() as u32;
+        let bps = wave_fmt.get_validbitspersample() as u32;
+        let channels = wave_fmt.get_nchannels() as usize;
+        let samplerate = wave_fmt.get_samplespersec() as usize;
+        let is_int = matches!(wave_fmt.get_subformat()?, SampleType::Int);
+        if !is_int || !(bps == 16 || bps == 24) || !(storebits == bps || storebits == 32) {
+            return Err(
+                WasapiError::new("Only 16 and 24 bit integer formats are supported").into(),
+            );
+        }
+        if channels == 0 || channels > 8 {
+            return Err(WasapiError::new("Only 1 to 8 channels are supported").into());
+        }
+        if samplerate == 0 || samplerate >= 1 << 20 {
+            return Err(WasapiError::new("Unsupported sample rate").into());
+        }
+        if settings.block_size < 16 || settings.block_size > 65535 {
+            return Err(WasapiError::new("The block size must be between 16 and 65535").into());
+        }
+        writer.write_all(b"fLaC")?;
+        // Last metadata block, of type STREAMINFO
+        writer.write_all(&[0x80, 0, 0, STREAMINFO_LEN as u8])?;
+        let streaminfo = StreamInfo {
+            min_block_size: settings.block_size,
+            max_block_size: settings.block_size,
+            min_frame_size: 0,
+            max_frame_size: 0,
+            samplerate,
+            channels,
+            bits_per_sample: bps as usize,
+            nbr_frames: 0,
+            md5: [0; 16],
+        };
+        writer.write_all(&streaminfo.to_bytes())?;
+        Ok(FlacWriter {
+            writer,
+            wave_fmt: wave_fmt.clone(),
+            settings,
+            bps,
+            buffer: vec![Vec::new(); channels],
+            md5: md5::Context::new(),
+            nbr_frames: 0,
+            frame_number: 0,
+            min_frame_size: usize::MAX,
+            max_frame_size: 0,
+            closed: false,
+        })
+    }
+
+    // Get the format of the data
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the number of frames written so far
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.nbr_frames
+    }
+
+    // Get a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    // Write raw data in the format given when creating the writer, whole frames only
+    pub fn write_bytes(&mut self, data: &[u8]) -> WasapiRes<()> {
+        if self.closed {
+            return Err(WasapiError::new("The writer is closed").into());
+        }
+        let blockalign = self.wave_fmt.get_blockalign() as usize;
+        if !data.len().is_multiple_of(blockalign) {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        let bytes_per_sample = self.wave_fmt.get_bitspersample() as usize / 8;
+        let padding = 8 * bytes_per_sample as u32 - self.bps;
+        let mut md5_data = Vec::with_capacity(data.len());
+        for frame in data.chunks_exact(blockalign) {
+            for (chan, sample) in self
+                .buffer
+                .iter_mut()
+                .zip(frame.chunks_exact(bytes_per_sample))
+            {
+                let mut raw = [0u8; 4];
+                raw[4 - bytes_per_sample..].copy_from_slice(sample);
+                let value =
+                    (i32::from_le_bytes(raw) >> (32 - 8 * bytes_per_sample as u32)) >> padding;
+                md5_data.extend_from_slice(&value.to_le_bytes()[..self.bps as usize / 8]);
+                chan.push(value as i64);
+            }
+        }
+        self.md5.consume(&md5_data);
+        self.nbr_frames += (data.len() / blockalign) as u64;
+        let block_size = self.settings.block_size;
+        while self.buffer[0].len() >= block_size {
+            let block: Vec<Vec<i64>> = self
+                .buffer
+                .iter_mut()
+                .map(|chan| chan.drain(..block_size).collect())
+                .collect();
+            self.write_frame(&block)?;
+        }
+        Ok(())
+    }
+
+    // Write frames given as one vector per channel
+    pub fn write_frames(&mut self, channels: &[Vec<f64>]) -> WasapiRes<()> {
+        let data = channels_to_bytes(channels, &self.wave_fmt)?;
+        self.write_bytes(&data)
+    }
+
+    fn write_frame(&mut self, block: &[Vec<i64>]) -> WasapiRes<()> {
+        let frame = encode_frame(block, self.bps, self.frame_number, &self.settings);
+        self.writer.write_all(&frame)?;
+        self.frame_number += 1;
+        self.min_frame_size = self.min_frame_size.min(frame.len());
+        self.max_frame_size = self.max_frame_size.max(frame.len());
+        Ok(())
+    }
+
+    // Flush the encoded data to the file, without updating STREAMINFO
+    pub fn flush(&mut self) -> WasapiRes<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // Encode the last partial block and complete STREAMINFO
+    pub fn close(&mut self) -> WasapiRes<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        if !self.buffer[0].is_empty() {
+            let block = std::mem::take(&mut self.buffer);
+            self.write_frame(&block)?;
+        }
+        let md5 = std::mem::replace(&mut self.md5, md5::Context::new()).compute();
+        let streaminfo = StreamInfo {
+            min_block_size: self.settings.block_size,
+            max_block_size: self.settings.block_size,
+            min_frame_size: if self.frame_number > 0 {
+                self.min_frame_size
+            } else {
+                0
+            },
+            max_frame_size: self.max_frame_size,
+            samplerate: self.wave_fmt.get_samplespersec() as usize,
+            channels: self.wave_fmt.get_nchannels() as usize,
+            bits_per_sample: self.bps as usize,
+            nbr_frames: self.nbr_frames,
+            md5: md5.0,
+        };
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.writer.write_all(&streaminfo.to_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for FlacWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+// FLAC encoder running in a separate thread.
+// Writing only queues the data, so it can be called from a capture loop without blocking it.
+pub struct ThreadedFlacWriter {
+    wave_fmt: WaveFormat,
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    handle: Option<thread::JoinHandle<Result<u64, String>>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl ThreadedFlacWriter {
+    // Create a flac file
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        wave_fmt: &WaveFormat,
+        settings: FlacSettings,
+    ) -> WasapiRes<Self> {
+        ThreadedFlacWriter::new(BufWriter::new(File::create(path)?), wave_fmt, settings)
+    }
+
+    // Create an encoder, and start the encoding thread
+    pub fn new<W: Write + Seek + Send + 'static>(
+        writer: W,
+        wave_fmt: &WaveFormat,
+        settings: FlacSettings,
+    ) -> WasapiRes<Self> {
+        let mut encoder = FlacWriter::new(writer, wave_fmt, settings)?;
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let pending_encoder = pending.clone();
+        let handle = thread::Builder::new()
+            .name("FlacEncoder".to_string())
+            .spawn(move || {
+                for data in receiver.iter() {
+                    encoder.write_bytes(&data).map_err(|err| err.to_string())?;
+                    pending_encoder.fetch_sub(data.len(), Ordering::AcqRel);
+                }
+                encoder.close().map_err(|err| err.to_string())?;
+                Ok(encoder.get_nbr_frames())
+            })?;
+        Ok(ThreadedFlacWriter {
+            wave_fmt: wave_fmt.clone(),
+            sender: Some(sender),
+            handle: Some(handle),
+            pending,
+        })
+    }
+
+    // Get the format of the data
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the number of bytes that are queued but not yet encoded
+    pub fn get_pending_bytes(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    // Queue raw data for encoding, whole frames only. This never waits for the encoder.
+    pub fn write_bytes(&self, data: &[u8]) -> WasapiRes<()> {
+        if !data
+            .len()
+            .is_multiple_of(self.wave_fmt.get_blockalign() as usize)
+        {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| WasapiError::new("The writer is closed"))?;
+        self.pending.fetch_add(data.len(), Ordering::AcqRel);
+        if sender.send(data.to_vec()).is_err() {
+            return Err(WasapiError::new("The encoder has stopped").into());
+        }
+        Ok(())
+    }
+
+    // Wait for the encoder to finish, and return the number of frames written
+    pub fn close(mut self) -> WasapiRes<u64> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> WasapiRes<u64> {
+        self.sender.take();
+        let handle = self
+            .handle
+            .take()
+            .ok_or_else(|| WasapiError::new("The writer is closed"))?;
+        match handle.join() {
+            Ok(Ok(nbr_frames)) => Ok(nbr_frames),
+            Ok(Err(err)) => Err(WasapiError::new(&err).into()),
+            Err(_) => Err(WasapiError::new("The encoder thread panicked").into()),
+        }
+    }
+}
+
+impl Drop for ThreadedFlacWriter {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            let _ = self.finish();
+        }
+    }
+}
+
+// Get the number of bits of a sample size code, or None if it is given by STREAMINFO.
+fn sample_size_from_code(code: u64) -> WasapiRes<Option<u32>> {
+    match code {
+        0 => Ok(None),
+        1 => Ok(Some(8)),
+        2 => Ok(Some(12)),
+        4 => Ok(Some(16)),
+        5 => Ok(Some(20)),
+        6 => Ok(Some(24)),
+        7 => Ok(Some(32)),
+        _ => Err(WasapiError::new("Reserved sample size").into()),
+    }
+}
+
+fn decode_residual<R: Read>(
+    reader: &mut BitReader<R>,
+    block_size: usize,
+    order: usize,
+    samples: &mut Vec<i64>,
+) -> WasapiRes<()> {
+    let (param_bits, escape) = match reader.read(2)? {
+        0 => (4, 0x0F),
+        1 => (5, 0x1F),
+        _ => return Err(WasapiError::new("Reserved residual coding method").into()),
+    };
+    let partition_order = reader.read(4)? as usize;
+    let partition_len = block_size >> partition_order;
+    if partition_len << partition_order != block_size || partition_len < order {
+        return Err(WasapiError::new("Invalid partition order").into());
+    }
+    for partition in 0..1 << partition_order {
+        let nbr_values = if partition == 0 {
+            partition_len - order
+        } else {
+            partition_len
+        };
+        let param = reader.read(param_bits)? as u32;
+        if param == escape {
+            let bits = reader.read(5)? as u32;
+            for _ in 0..nbr_values {
+                samples.push(reader.read_signed(bits)?);
+            }
+        } else {
+            for _ in 0..nbr_values {
+                samples.push(reader.read_rice(param)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_subframe<R: Read>(
+    reader: &mut BitReader<R>,
+    block_size: usize,
+    bps: u32,
+) -> WasapiRes<Vec<i64>> {
+    if reader.read(1)? != 0 {
+        return Err(WasapiError::new("Invalid subframe header").into());
+    }
+    let kind = reader.read(6)? as usize;
+    let wasted = if reader.read(1)? == 1 {
+        reader.read_unary()? as u32 + 1
+    } else {
+        0
+    };
+    if wasted >= bps {
+        return Err(WasapiError::new("Invalid number of wasted bits").into());
+    }
+    let bps = bps - wasted;
+    let mut samples = Vec::with_capacity(block_size);
+    match kind {
+        0x00 => {
+            let value = reader.read_signed(bps)?;
+            samples.resize(block_size, value);
+        }
+        0x01 => {
+            for _ in 0..block_size {
+                samples.push(reader.read_signed(bps)?);
+            }
+        }
+        0x08..=0x0C => {
+            let order = kind - 0x08;
+            if order > block_size {
+                return Err(WasapiError::new("Predictor order larger than the block").into());
+            }
+            for _ in 0..order {
+                samples.push(reader.read_signed(bps)?);
+            }
+            decode_residual(reader, block_size, order, &mut samples)?;
+            for n in order..block_size {
+                let predicted = match order {
+                    0 => 0,
+                    1 => samples[n - 1],
+                    2 => 2 * samples[n - 1] - samples[n - 2],
+                    3 => 3 * samples[n - 1] - 3 * samples[n - 2] + samples[n - 3],
+                    _ => {
+                        4 * samples[n - 1] - 6 * samples[n - 2] + 4 * samples[n - 3]
+                            - samples[n - 4]
+                    }
+                };
+                samples[n] += predicted;
+            }
+        }
+        0x20..=0x3F => {
+            let order = kind - 0x1F;
+            if order > block_size {
+                return Err(WasapiError::new("Predictor order larger than the block").into());
+            }
+            for _ in 0..order {
+                samples.push(reader.read_signed(bps)?);
+            }
+            let precision = reader.read(4)? as u32 + 1;
+            if precision == 16 {
+                return Err(WasapiError::new("Invalid coefficient precision").into());
+            }
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err(WasapiError::new("Negative coefficient shift").into());
+            }
+            let mut coefficients = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefficients.push(reader.read_signed(precision)?);
+            }
+            decode_residual(reader, block_size, order, &mut samples)?;
+            for n in order..block_size {
+                samples[n] += lpc_predict(&samples[n - order..n], &coefficients, shift as u32);
+            }
+        }
+        _ => return Err(WasapiError::new("Reserved subframe type").into()),
+    }
+    if wasted > 0 {
+        for value in samples.iter_mut() {
+            *value <<= wasted;
+        }
+    }
+    Ok(samples)
+}
+
+// Read a number with the extended UTF-8 coding used for the frame and sample numbers.
+fn read_utf8<R: Read>(reader: &mut BitReader<R>) -> WasapiRes<u64> {
+    let first = reader.read(8)?;
+    let nbr_bytes = (first as u8).leading_ones();
+    if nbr_bytes == 0 {
+        return Ok(first);
+    }
+    if nbr_bytes == 1 || nbr_bytes > 7 {
+        return Err(WasapiError::new("Invalid frame number").into());
+    }
+    let mut value = first & (0x7F >> nbr_bytes);
+    for _ in 1..nbr_bytes {
+        let byte = reader.read(8)?;
+        if byte & 0xC0 != 0x80 {
+            return Err(WasapiError::new("Invalid frame number").into());
+        }
+        value = (value << 6) | (byte & 0x3F);
+    }
+    Ok(value)
+}
+
+// Decode the next frame, or return None at the end of the stream.
+fn decode_frame<R: Read>(reader: &mut R, info: &StreamInfo) -> WasapiRes<Option<Vec<Vec<i64>>>> {
+    let mut reader = BitReader::new(reader);
+    let sync = match reader.read(8) {
+        Ok(sync) => sync,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if sync != 0xFF || reader.read(7)? != 0x7C {
+        return Err(WasapiError::new("Lost sync").into());
+    }
+    let _variable_block_size = reader.read(1)?;
+    let size_code = reader.read(4)?;
+    let rate_code = reader.read(4)?;
+    let assignment = reader.read(4)?;
+    let bps = sample_size_from_code(reader.read(3)?)?.unwrap_or(info.bits_per_sample as u32);
+    reader.read(1)?;
+    let _number = read_utf8(&mut reader)?;
+    let block_size = match size_code {
+        0 => return Err(WasapiError::new("Reserved block size").into()),
+        1 => 192,
+        2..=5 => 576 << (size_code - 2),
+        6 => reader.read(8)? as usize + 1,
+        7 => reader.read(16)? as usize + 1,
+        _ => 256 << (size_code - 8),
+    };
+    match rate_code {
+        12 => {
+            reader.read(8)?;
+        }
+        13 | 14 => {
+            reader.read(16)?;
+        }
+        15 => return Err(WasapiError::new("Invalid sample rate").into()),
+        _ => {}
+    }
+    let crc8 = reader.crc8;
+    if reader.read(8)? as u8 != crc8 {
+        return Err(WasapiError::new("Frame header CRC mismatch").into());
+    }
+    let nbr_channels = match assignment {
+        0..=7 => assignment as usize + 1,
+        8..=10 => 2,
+        _ => return Err(WasapiError::new("Reserved channel assignment").into()),
+    };
+    if nbr_channels != info.channels {
+        return Err(WasapiError::new("The number of channels changed").into());
+    }
+    let mut channels = Vec::with_capacity(nbr_channels);
+    for channel in 0..nbr_channels {
+        // The side channel has one extra bit
+        let is_side = matches!((assignment, channel), (8, 1) | (9, 0) | (10, 1));
+        let channel_bps = if is_side { bps + 1 } else { bps };
+        channels.push(decode_subframe(&mut reader, block_size, channel_bps)?);
+    }
+    if !reader.is_aligned() {
+        reader.align();
+    }
+    let crc16 = reader.crc16;
+    if reader.read(16)? as u16 != crc16 {
+        return Err(WasapiError::new("Frame CRC mismatch").into());
+    }
+    if assignment >= 8 {
+        let (first, second) = channels.split_at_mut(1);
+        for (a, b) in first[0].iter_mut().zip(second[0].iter_mut()) {
+            match assignment {
+                // Left and side
+                8 => *b = *a - *b,
+                // Side and right
+                9 => *a += *b,
+                // Mid and side
+                _ => {
+                    let side = *b;
+                    let mid = (*a << 1) | (side & 1);
+                    *a = (mid + side) >> 1;
+                    *b = (mid - side) >> 1;
+                }
+            }
+        }
+    }
+    Ok(Some(channels))
+}
+
+// Streaming FLAC decoder.
+// The data is returned in the layout of the WaveFormat, with the samples padded to whole bytes.
+pub struct FlacReader<R: Read + Seek> {
+    reader: R,
+    info: StreamInfo,
+    wave_fmt: WaveFormat,
+    nbr_frames: u64,
+    // Position in frames and file offset of the frames decoded so far
+    index: Vec<(u64, u64)>,
+    // The current block, and the position of its first frame
+    block: Vec<Vec<i64>>,
+    block_start: u64,
+    position: u64,
+}
+
+impl FlacReader<BufReader<File>> {
+    // Open a flac file
+    pub fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        FlacReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> FlacReader<R> {
+    // Read the metadata from a reader.
+    // If the total length is not given by STREAMINFO, the stream is scanned to find it.
+    pub fn new(mut reader: R) -> WasapiRes<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut marker = [0u8; 4];
+        reader.read_exact(&mut marker)?;
+        if &marker != b"fLaC" {
+            return Err(WasapiError::new("Not a flac file").into());
+        }
+        let mut info = None;
+        loop {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header)?;
+            let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            let mut data = vec![0u8; length];
+            reader.read_exact(&mut data)?;
+            if header[0] & 0x7F == 0 {
+                info = Some(StreamInfo::from_bytes(&data)?);
+            }
+            if header[0] & 0x80 != 0 {
+                break;
+            }
+        }
+        let info = info.ok_or_else(|| WasapiError::new("The file has no STREAMINFO block"))?;
+        let bps = info.bits_per_sample;
+        let wave_fmt = WaveFormat::new(
+            8 * bps.div_ceil(8),
+            bps,
+            &SampleType::Int,
+            info.samplerate,
+            info.channels,
+        );
+        let first_frame = reader.stream_position()?;
+        let mut flac = FlacReader {
+            reader,
+            nbr_frames: info.nbr_frames,
+            info,
+            wave_fmt,
+            index: vec![(0, first_frame)],
+            block: Vec::new(),
+            block_start: 0,
+            position: 0,
+        };
+        if flac.nbr_frames == 0 {
+            while flac.next_block()? {}
+            flac.nbr_frames = flac.block_start + flac.block_len() as u64;
+            flac.seek(0)?;
+        }
+        Ok(flac)
+    }
+
+    // Get the format of the decoded data
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the contents of STREAMINFO
+    pub fn get_streaminfo(&self) -> StreamInfo {
+        self.info.clone()
+    }
+
+    // Get the length of the file in frames
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.nbr_frames
+    }
+
+    // Get the current position in frames
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    fn block_len(&self) -> usize {
+        self.block.first().map(|chan| chan.len()).unwrap_or(0)
+    }
+
+    // Decode the block after the current one, returns false at the end of the stream
+    fn next_block(&mut self) -> WasapiRes<bool> {
+        let start = self.block_start + self.block_len() as u64;
+        let offset = self.reader.stream_position()?;
+        match decode_frame(&mut self.reader, &self.info)? {
+            Some(block) => {
+                if self.index.last().is_some_and(|(frame, _)| start > *frame) {
+                    self.index.push((start, offset));
+                }
+                self.block = block;
+                self.block_start = start;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Move to a frame
+    pub fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        let frame = frame.min(self.nbr_frames);
+        // Start from the last known frame before the target
+        let entry = self
+            .index
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= frame)
+            .copied()
+            .unwrap_or(self.index[0]);
+        self.reader.seek(SeekFrom::Start(entry.1))?;
+        self.block = Vec::new();
+        self.block_start = entry.0;
+        self.position = frame;
+        while self.block_start + (self.block_len() as u64) <= frame && self.next_block()? {}
+        Ok(())
+    }
+
+    // Read up to nbr_frames frames as one vector of integer samples per channel
+    fn read_samples(&mut self, nbr_frames: usize) -> WasapiRes<Vec<Vec<i64>>> {
+        let nbr_frames = (nbr_frames as u64).min(self.nbr_frames - self.position) as usize;
+        let mut samples = vec![Vec::with_capacity(nbr_frames); self.info.channels];
+        while samples[0].len() < nbr_frames {
+            let block_end = self.block_start + self.block_len() as u64;
+            if self.position >= block_end && !self.next_block()? {
+                break;
+            }
+            let offset = (self.position - self.block_start) as usize;
+            let available = self.block_len() - offset;
+            let count = available.min(nbr_frames - samples[0].len());
+            for (chan, block) in samples.iter_mut().zip(self.block.iter()) {
+                chan.extend_from_slice(&block[offset..offset + count]);
+            }
+            self.position += count as u64;
+        }
+        Ok(samples)
+    }
+
+    // Read raw data for up to nbr_frames frames, less at the end of the file
+    pub fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        let samples = self.read_samples(nbr_frames)?;
+        let bps = self.info.bits_per_sample as u32;
+        let bytes_per_sample = bps.div_ceil(8) as usize;
+        let padding = 8 * bytes_per_sample as u32 - bps;
+        let nbr_read = samples[0].len();
+        let mut data = Vec::with_capacity(nbr_read * self.wave_fmt.get_blockalign() as usize);
+        for n in 0..nbr_read {
+            for chan in samples.iter() {
+                let value = chan[n] << padding;
+                if bytes_per_sample == 1 {
+                    // 8-bit samples are unsigned
+                    data.push((value + 128) as u8);
+                } else {
+                    data.extend_from_slice(&value.to_le_bytes()[..bytes_per_sample]);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    // Read up to nbr_frames frames, as one vector per channel
+    pub fn read_frames(&mut self, nbr_frames: usize) -> WasapiRes<Vec<Vec<f64>>> {
+        let data = self.read_bytes(nbr_frames)?;
+        bytes_to_channels(&data, &self.wave_fmt)
+    }
+
+    // Decode the complete stream and compare with the MD5 checksum in STREAMINFO.
+    // Returns true if the checksums match, or if the file has no checksum.
+    // The position is kept.
+    pub fn verify(&mut self) -> WasapiRes<bool> {
+        let position = self.position;
+        self.seek(0)?;
+        let mut context = md5::Context::new();
+        let mut data = Vec::new();
+        loop {
+            let samples = self.read_samples(self.info.max_block_size.max(4096))?;
+            if samples[0].is_empty() {
+                break;
+            }
+            data.clear();
+            md5_bytes(&samples, self.info.bits_per_sample as u32, &mut data);
+            context.consume(&data);
+        }
+        self.seek(position)?;
+        if self.info.md5 == [0; 16] {
+            return Ok(true);
+        }
+        Ok(context.compute().0 == self.info.md5)
+    }
+}
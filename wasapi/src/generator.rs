@@ -0,0 +1,126 @@
+use std::f64::consts::PI;
+
+use crate::wasapi::{WasapiError, WasapiRes};
+
+// Feedback taps for maximum length sequences, indexed by order.
+const MLS_TAPS: [&[u32]; 21] = [
+    &[],
+    &[],
+    &[2, 1],
+    &[3, 2],
+    &[4, 3],
+    &[5, 3],
+    &[6, 5],
+    &[7, 6],
+    &[8, 6, 5, 4],
+    &[9, 5],
+    &[10, 7],
+    &[11, 9],
+    &[12, 6, 4, 1],
+    &[13, 4, 3, 1],
+    &[14, 5, 3, 1],
+    &[15, 14],
+    &[16, 15, 13, 4],
+    &[17, 14],
+    &[18, 11],
+    &[19, 6, 2, 1],
+    &[20, 17],
+];
+
+// Generate a maximum length sequence of the given order (2 - 20).
+// The sequence is 2^order - 1 samples long and the values are +1 and -1.
+pub fn mls(order: u32) -> WasapiRes<Vec<f64>> {
+    if order < 2 || order as usize >= MLS_TAPS.len() {
+        return Err(WasapiError::new(
+            format!("MLS order must be between 2 and 20, got {}", order).as_str(),
+        )
+        .into());
+    }
+    let mask = MLS_TAPS[order as usize]
+        .iter()
+        .fold(0u32, |acc, tap| acc | (1 << (order - tap)));
+    let len = (1usize << order) - 1;
+    let mut state = 1u32;
+    let mut sequence = Vec::with_capacity(len);
+    for _ in 0..len {
+        sequence.push(if state & 1 == 1 { 1.0 } else { -1.0 });
+        let feedback = (state & mask).count_ones() & 1;
+        state = (state >> 1) | (feedback << (order - 1));
+    }
+    Ok(sequence)
+}
+
+// Generate an exponential (logarithmic) sine sweep from f_start to f_end Hz.
+pub fn log_sweep(
+    f_start: f64,
+    f_end: f64,
+    nbr_frames: usize,
+    samplerate: usize,
+    amplitude: f64,
+) -> Vec<f64> {
+    let duration = nbr_frames as f64 / samplerate as f64;
+    let rate = (f_end / f_start).ln();
+    (0..nbr_frames)
+        .map(|n| {
+            let t = n as f64 / samplerate as f64;
+            let phase = 2.0 * PI * f_start * duration / rate * ((t * rate / duration).exp() - 1.0);
+            amplitude * phase.sin()
+        })
+        .collect()
+}
+
+// Generate a sine wave with the given frequency in Hz and peak amplitude.
+pub fn sine(freq: f64, amplitude: f64, nbr_frames: usize, samplerate: usize) -> Vec<f64> {
+    (0..nbr_frames)
+        .map(|n| amplitude * (2.0 * PI * freq * n as f64 / samplerate as f64).sin())
+        .collect()
+}
+
+// Get the frequencies of a stepped sine, logarithmically spaced from f_start to f_end Hz.
+pub fn stepped_sine_frequencies(f_start: f64, f_end: f64, steps_per_octave: usize) -> Vec<f64> {
+    if f_start <= 0.0 || f_end < f_start || steps_per_octave == 0 {
+        return Vec::new();
+    }
+    let nbr_steps = ((f_end / f_start).log2() * steps_per_octave as f64).round() as usize;
+    (0..=nbr_steps)
+        .map(|n| f_start * 2.0f64.powf(n as f64 / steps_per_octave as f64))
+        .collect()
+}
+
+// Apply a raised cosine fade in and fade out of the given length in frames.
+pub fn apply_fade(data: &mut [f64], fade_frames: usize) {
+    let fade_frames = fade_frames.min(data.len() / 2);
+    let len = data.len();
+    for n in 0..fade_frames {
+        let gain = 0.5 - 0.5 * (PI * n as f64 / fade_frames as f64).cos();
+        data[n] *= gain;
+        data[len - 1 - n] *= gain;
+    }
+}
+
+// Simple xorshift pseudo random generator, for reproducible noise.
+pub struct NoiseGenerator {
+    state: u64,
+}
+
+impl NoiseGenerator {
+    // Create a new generator, the same seed always gives the same sequence
+    pub fn new(seed: u64) -> Self {
+        NoiseGenerator { state: seed.max(1) }
+    }
+
+    // Get the next value, uniformly distributed in the range -1.0 .. 1.0
+    pub fn next_value(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+
+    // Generate a block of white noise with the given peak amplitude
+    pub fn generate(&mut self, nbr_frames: usize, amplitude: f64) -> Vec<f64> {
+        (0..nbr_frames)
+            .map(|_| amplitude * self.next_value())
+            .collect()
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::wasapi::BufferFlags;
+
+// The kind of a detected glitch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GlitchKind {
+    // A sample that deviates strongly from the continuation predicted from the previous samples
+    Jump,
+    // A run of samples that are exactly zero
+    ZeroRun,
+    // A run of identical non-zero samples
+    StuckSamples,
+    // A sudden change of the mean value
+    DcStep,
+    // The capture device reported a discontinuity in the data
+    Discontinuity,
+    // The capture device marked a buffer as silent
+    SilentBuffer,
+    // The capture device reported an uncertain timestamp
+    TimestampError,
+}
+
+// A detected glitch.
+#[derive(Clone, Debug)]
+pub struct GlitchEvent {
+    pub kind: GlitchKind,
+    // The channel, or None for events that apply to all channels
+    pub channel: Option<usize>,
+    // Position of the start of the event in frames, counted from the start of the stream
+    pub position: u64,
+    // Time of the start of the event in seconds, counted from the start of the stream
+    pub time: f64,
+    // Length of the event in frames
+    pub length: usize,
+    // Size of the jump or step, or the value of stuck samples
+    pub magnitude: f64,
+}
+
+impl fmt::Display for GlitchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let channel = match self.channel {
+            Some(channel) => format!("channel {}", channel),
+            None => "all channels".to_string(),
+        };
+        write!(
+            f,
+            "{:.6} s (frame {}), {}: {:?}, length {}, magnitude {:.4}",
+            self.time, self.position, channel, self.kind, self.length, self.magnitude
+        )
+    }
+}
+
+// Settings for the glitch detector.
+#[derive(Clone, Debug)]
+pub struct GlitchSettings {
+    pub samplerate: usize,
+    // A prediction error larger than this many times the average error is a jump
+    pub jump_factor: f64,
+    // Prediction errors smaller than this are never jumps
+    pub min_jump: f64,
+    // Shortest run of exact zeros to report
+    pub min_zero_run: usize,
+    // Shortest run of identical non-zero samples to report
+    pub min_stuck_run: usize,
+    // Length of the windows used to compare mean values, in frames
+    pub dc_window: usize,
+    // A change of the mean value larger than this is a DC step
+    pub dc_threshold: f64,
+}
+
+impl GlitchSettings {
+    // Default settings for a samplerate
+    pub fn new(samplerate: usize) -> Self {
+        GlitchSettings {
+            samplerate,
+            jump_factor: 10.0,
+            min_jump: 0.01,
+            min_zero_run: 32,
+            min_stuck_run: 16,
+            dc_window: samplerate / 10,
+            dc_threshold: 0.1,
+        }
+    }
+}
+
+// Number of samples before the average prediction error is trusted.
+const WARMUP_FRAMES: u64 = 256;
+// Smoothing coefficient for the average prediction error.
+const ERROR_SMOOTHING: f64 = 1.0 / 128.0;
+
+// State of a run of identical samples.
+struct Run {
+    value: f64,
+    start: u64,
+    length: usize,
+}
+
+// Detector state of a single channel.
+struct ChannelState {
+    prev: [f64; 2],
+    avg_error: f64,
+    holdoff: usize,
+    run: Option<Run>,
+    dc_samples: VecDeque<f64>,
+    dc_old_sum: f64,
+    dc_new_sum: f64,
+    // Largest mean difference seen and its position, while above the threshold
+    dc_candidate: Option<(f64, u64)>,
+}
+
+impl ChannelState {
+    fn new(dc_window: usize) -> Self {
+        ChannelState {
+            prev: [0.0; 2],
+            avg_error: 0.0,
+            holdoff: 0,
+            run: None,
+            dc_samples: VecDeque::with_capacity(2 * dc_window + 1),
+            dc_old_sum: 0.0,
+            dc_new_sum: 0.0,
+            dc_candidate: None,
+        }
+    }
+}
+
+// Streaming detector for glitches and discontinuities.
+// The stream is fed in chunks of any size, and the detector keeps track of the position.
+pub struct GlitchDetector {
+    settings: GlitchSettings,
+    channels: Vec<ChannelState>,
+    position: u64,
+}
+
+impl GlitchDetector {
+    // Create a detector for a number of channels
+    pub fn new(nbr_channels: usize, settings: GlitchSettings) -> Self {
+        let channels = (0..nbr_channels)
+            .map(|_| ChannelState::new(settings.dc_window))
+            .collect();
+        GlitchDetector {
+            settings,
+            channels,
+            position: 0,
+        }
+    }
+
+    // Get the current position in frames, counted from the start of the stream
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    fn event(
+        &self,
+        kind: GlitchKind,
+        channel: Option<usize>,
+        position: u64,
+        length: usize,
+        magnitude: f64,
+    ) -> GlitchEvent {
+        GlitchEvent {
+            kind,
+            channel,
+            position,
+            time: position as f64 / self.settings.samplerate as f64,
+            length,
+            magnitude,
+        }
+    }
+
+    // Analyze the next chunk of the stream, one vector per channel.
+    // Runs of samples are reported when they end, so an event may belong to an earlier chunk.
+    pub fn process(&mut self, data: &[Vec<f64>]) -> Vec<GlitchEvent> {
+        let mut events = Vec::new();
+        let nbr_frames = data.iter().map(|chan| chan.len()).min().unwrap_or(0);
+        for (channel, samples) in data.iter().enumerate().take(self.channels.len()) {
+            for (n, value) in samples.iter().take(nbr_frames).enumerate() {
+                let position = self.position + n as u64;
+                self.check_jump(channel, position, *value, &mut events);
+                self.check_run(channel, position, *value, &mut events);
+                self.check_dc(channel, position, *value, &mut events);
+            }
+        }
+        self.position += nbr_frames as u64;
+        events.sort_by_key(|event| event.position);
+        events
+    }
+
+    // Report the flags of a captured buffer that starts at the given position
+    pub fn report_flags(&self, flags: &BufferFlags, position: u64) -> Vec<GlitchEvent> {
+        let mut events = Vec::new();
+        if flags.data_discontinuity {
+            events.push(self.event(GlitchKind::Discontinuity, None, position, 0, 0.0));
+        }
+        if flags.silent {
+            events.push(self.event(GlitchKind::SilentBuffer, None, position, 0, 0.0));
+        }
+        if flags.timestamp_error {
+            events.push(self.event(GlitchKind::TimestampError, None, position, 0, 0.0));
+        }
+        events
+    }
+
+    // End the stream and report any events that are still in progress
+    pub fn finish(&mut self) -> Vec<GlitchEvent> {
+        let mut events = Vec::new();
+        for channel in 0..self.channels.len() {
+            if let Some(run) = self.channels[channel].run.take() {
+                if let Some(event) = self.run_event(channel, &run) {
+                    events.push(event);
+                }
+            }
+            if let Some((diff, position)) = self.channels[channel].dc_candidate.take() {
+                events.push(self.event(GlitchKind::DcStep, Some(channel), position, 0, diff));
+            }
+        }
+        events.sort_by_key(|event| event.position);
+        events
+    }
+
+    // Compare each sample with a linear extrapolation of the two previous ones.
+    fn check_jump(
+        &mut self,
+        channel: usize,
+        position: u64,
+        value: f64,
+        events: &mut Vec<GlitchEvent>,
+    ) {
+        let state = &self.channels[channel];
+        let predicted = 2.0 * state.prev[1] - state.prev[0];
+        let error = value - predicted;
+        let threshold = (self.settings.jump_factor * state.avg_error).max(self.settings.min_jump);
+        let is_jump = position >= WARMUP_FRAMES && state.holdoff == 0 && error.abs() > threshold;
+        if is_jump {
+            events.push(self.event(GlitchKind::Jump, Some(channel), position, 1, error));
+        }
+        let state = &mut self.channels[channel];
+        if is_jump {
+            // The next two predictions use the glitched sample, skip them
+            state.holdoff = 2;
+        } else if state.holdoff > 0 {
+            state.holdoff -= 1;
+        } else {
+            state.avg_error += ERROR_SMOOTHING * (error.abs() - state.avg_error);
+        }
+        state.prev = [state.prev[1], value];
+    }
+
+    // Track runs of identical samples.
+    fn check_run(
+        &mut self,
+        channel: usize,
+        position: u64,
+        value: f64,
+        events: &mut Vec<GlitchEvent>,
+    ) {
+        let state = &mut self.channels[channel];
+        if let Some(run) = state.run.as_mut() {
+            if run.value == value {
+                run.length += 1;
+                return;
+            }
+        }
+        let new_run = Run {
+            value,
+            start: position,
+            length: 1,
+        };
+        if let Some(run) = state.run.replace(new_run) {
+            if let Some(event) = self.run_event(channel, &run) {
+                events.push(event);
+            }
+        }
+    }
+
+    fn run_event(&self, channel: usize, run: &Run) -> Option<GlitchEvent> {
+        if run.value == 0.0 && run.length >= self.settings.min_zero_run {
+            Some(self.event(
+                GlitchKind::ZeroRun,
+                Some(channel),
+                run.start,
+                run.length,
+                0.0,
+            ))
+        } else if run.value != 0.0 && run.length >= self.settings.min_stuck_run {
+            Some(self.event(
+                GlitchKind::StuckSamples,
+                Some(channel),
+                run.start,
+                run.length,
+                run.value,
+            ))
+        } else {
+            None
+        }
+    }
+
+    // Compare the mean values of two adjacent windows.
+    // A step gives the largest difference when it is exactly between the windows.
+    fn check_dc(
+        &mut self,
+        channel: usize,
+        position: u64,
+        value: f64,
+        events: &mut Vec<GlitchEvent>,
+    ) {
+        let window = self.settings.dc_window;
+        if window == 0 {
+            return;
+        }
+        let state = &mut self.channels[channel];
+        state.dc_samples.push_back(value);
+        state.dc_new_sum += value;
+        if state.dc_samples.len() > window {
+            let moved = state.dc_samples[state.dc_samples.len() - window - 1];
+            state.dc_new_sum -= moved;
+            state.dc_old_sum += moved;
+        }
+        if state.dc_samples.len() > 2 * window {
+            let dropped = state.dc_samples.pop_front().unwrap_or_default();
+            state.dc_old_sum -= dropped;
+        }
+        if state.dc_samples.len() < 2 * window {
+            return;
+        }
+        let diff = (state.dc_new_sum - state.dc_old_sum) / window as f64;
+        let step_position = position + 1 - window as u64;
+        if diff.abs() > self.settings.dc_threshold {
+            match state.dc_candidate {
+                Some((max_diff, _)) if max_diff.abs() >= diff.abs() => {}
+                _ => state.dc_candidate = Some((diff, step_position)),
+            }
+        } else if let Some((max_diff, step_position)) = state.dc_candidate.take() {
+            events.push(self.event(
+                GlitchKind::DcStep,
+                Some(channel),
+                step_position,
+                0,
+                max_diff,
+            ));
+        }
+    }
+}
+
+// Scan a complete recording, one vector per channel.
+pub fn find_glitches(data: &[Vec<f64>], settings: GlitchSettings) -> Vec<GlitchEvent> {
+    let mut detector = GlitchDetector::new(data.len(), settings);
+    let mut events = detector.process(data);
+    events.extend(detector.finish());
+    events.sort_by_key(|event| event.position);
+    events
+}
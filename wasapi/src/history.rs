@@ -0,0 +1,310 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::bwf::BextInfo;
+use crate::segment::segment_name;
+use crate::wasapi::{WasapiError, WasapiRes, WaveFormat};
+use crate::wav::WavWriter;
+
+// Settings for the retroactive capture.
+#[derive(Clone, Debug)]
+pub struct HistorySettings {
+    pub samplerate: usize,
+    // Length of the history saved before a trigger, in seconds
+    pub history: f64,
+    // Length saved after a trigger, in seconds
+    pub post: f64,
+}
+
+impl HistorySettings {
+    // Default settings, 10 seconds before and 5 seconds after the trigger
+    pub fn new(samplerate: usize) -> Self {
+        HistorySettings {
+            samplerate,
+            history: 10.0,
+            post: 5.0,
+        }
+    }
+}
+
+// Handle for triggering a capture from another thread, for example one that waits for user input.
+// The trigger takes effect at the start of the next block given to the recorder.
+#[derive(Clone, Debug)]
+pub struct TriggerHandle {
+    requests: Arc<AtomicUsize>,
+}
+
+impl TriggerHandle {
+    // Request a capture
+    pub fn trigger(&self) {
+        self.requests.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+// A saved span of the stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryCapture {
+    // Stream position of the first frame
+    pub start: u64,
+    // Stream position of the trigger
+    pub trigger: u64,
+    pub data: Vec<u8>,
+}
+
+// Circular buffer that always holds the most recent part of a capture stream.
+// When triggered, the history before the trigger and the data after it are returned as one capture.
+// The buffer is allocated once, and holds both the history and the post-trigger part,
+// so that a trigger can also be placed up to the post-trigger length back in time,
+// for example at the position of an event found by an analyzer.
+pub struct HistoryBuffer {
+    wave_fmt: WaveFormat,
+    ring: Vec<u8>,
+    ring_frames: u64,
+    history_frames: u64,
+    post_frames: u64,
+    // Number of frames written so far
+    position: u64,
+    // Positions of the triggers that are waiting for their post-trigger data
+    pending: Vec<u64>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl HistoryBuffer {
+    // Create a buffer for a stream in the given format
+    pub fn new(wave_fmt: &WaveFormat, settings: &HistorySettings) -> Self {
+        let history_frames = (settings.history * settings.samplerate as f64).round() as u64;
+        let post_frames = (settings.post * settings.samplerate as f64).round() as u64;
+        let ring_frames = (history_frames + post_frames).max(1);
+        let blockalign = wave_fmt.get_blockalign() as usize;
+        HistoryBuffer {
+            wave_fmt: wave_fmt.clone(),
+            ring: vec![0; ring_frames as usize * blockalign],
+            ring_frames,
+            history_frames,
+            post_frames,
+            position: 0,
+            pending: Vec::new(),
+            requests: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // Get a handle that can trigger captures from other threads
+    pub fn get_trigger_handle(&self) -> TriggerHandle {
+        TriggerHandle {
+            requests: self.requests.clone(),
+        }
+    }
+
+    // Get the number of frames written so far
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    // Get the number of triggers waiting for data
+    pub fn get_nbr_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Trigger a capture at the current position
+    pub fn trigger(&mut self) {
+        self.pending.push(self.position);
+    }
+
+    // Trigger a capture at a stream position. This may be in the past, by at most the post-trigger length.
+    pub fn trigger_at(&mut self, position: u64) -> WasapiRes<()> {
+        if position + self.post_frames < self.position {
+            return Err(WasapiError::new("The trigger position is too far back").into());
+        }
+        self.pending.push(position);
+        Ok(())
+    }
+
+    // Trigger a capture at a stream position, or as far back as still possible when the position is too far back.
+    // This suits events that are only reported when they end, like long runs of silence.
+    // Returns the position of the trigger.
+    pub fn trigger_at_or_later(&mut self, position: u64) -> u64 {
+        let position = position.max(self.position.saturating_sub(self.post_frames));
+        self.pending.push(position);
+        position
+    }
+
+    // Copy a span of frames from the ring, the span must be in the ring
+    fn copy_span(&self, start: u64, end: u64) -> Vec<u8> {
+        let blockalign = self.wave_fmt.get_blockalign() as usize;
+        let mut data = Vec::with_capacity((end - start) as usize * blockalign);
+        let mut frame = start;
+        while frame < end {
+            let index = frame % self.ring_frames;
+            let frames = (self.ring_frames - index).min(end - frame);
+            let offset = index as usize * blockalign;
+            data.extend_from_slice(&self.ring[offset..offset + frames as usize * blockalign]);
+            frame += frames;
+        }
+        data
+    }
+
+    // Store frames in the ring, there must be room for them without overwriting data needed by a trigger
+    fn store(&mut self, data: &[u8]) {
+        let blockalign = self.wave_fmt.get_blockalign() as usize;
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let index = (self.position % self.ring_frames) as usize;
+            let frames = (self.ring_frames as usize - index).min(remaining.len() / blockalign);
+            let (chunk, rest) = remaining.split_at(frames * blockalign);
+            self.ring[index * blockalign..(index + frames) * blockalign].copy_from_slice(chunk);
+            self.position += frames as u64;
+            remaining = rest;
+        }
+    }
+
+    // Add captured data, whole frames only, and return the captures that were completed.
+    pub fn process(&mut self, data: &[u8]) -> WasapiRes<Vec<HistoryCapture>> {
+        let blockalign = self.wave_fmt.get_blockalign() as usize;
+        if !data.len().is_multiple_of(blockalign) {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        for _ in 0..self.requests.swap(0, Ordering::AcqRel) {
+            self.pending.push(self.position);
+        }
+        let mut captures = Vec::new();
+        let mut remaining = data;
+        loop {
+            // Complete the captures that have all their data
+            let mut index = 0;
+            while index < self.pending.len() {
+                let trigger = self.pending[index];
+                if trigger + self.post_frames > self.position {
+                    index += 1;
+                    continue;
+                }
+                self.pending.swap_remove(index);
+                let start = trigger.saturating_sub(self.history_frames);
+                let end = trigger + self.post_frames;
+                captures.push(HistoryCapture {
+                    start,
+                    trigger,
+                    data: self.copy_span(start, end),
+                });
+            }
+            if remaining.is_empty() {
+                break;
+            }
+            // Store up to the end of the next capture, to not overwrite its oldest frames
+            let limit = self
+                .pending
+                .iter()
+                .map(|trigger| trigger + self.post_frames - self.position)
+                .min()
+                .unwrap_or(u64::MAX);
+            let frames = (limit as usize).min(remaining.len() / blockalign);
+            let (chunk, rest) = remaining.split_at(frames * blockalign);
+            self.store(chunk);
+            remaining = rest;
+        }
+        captures.sort_by_key(|capture| capture.trigger);
+        Ok(captures)
+    }
+}
+
+// Writer of captures to wav files, running in a separate thread so that saving does not delay the capture loop.
+pub struct HistorySaver {
+    sender: Option<mpsc::Sender<HistoryCapture>>,
+    handle: Option<thread::JoinHandle<Result<Vec<PathBuf>, String>>>,
+}
+
+impl HistorySaver {
+    // Start the saving thread. The files are named from a template like the ones of the segmented recorder,
+    // using the time of the first saved frame.
+    pub fn new<P: AsRef<Path>>(
+        directory: P,
+        template: &str,
+        device: &str,
+        wave_fmt: &WaveFormat,
+        stream_start: SystemTime,
+    ) -> WasapiRes<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        let template = template.to_string();
+        let device = device.to_string();
+        let wave_fmt = wave_fmt.clone();
+        let samplerate = wave_fmt.get_samplespersec() as usize;
+        let (sender, receiver) = mpsc::channel::<HistoryCapture>();
+        let handle = thread::Builder::new()
+            .name("HistorySaver".to_string())
+            .spawn(move || {
+                let mut paths = Vec::new();
+                for (sequence, capture) in receiver.iter().enumerate() {
+                    let start = stream_start
+                        + Duration::from_secs_f64(capture.start as f64 / samplerate as f64);
+                    let name = segment_name(&template, &device, start, sequence as u64);
+                    let path = directory.join(name);
+                    let save = || -> WasapiRes<()> {
+                        let mut writer: WavWriter<BufWriter<File>> =
+                            WavWriter::create(&path, &wave_fmt)?;
+                        let description =
+                            format!("{} trigger at {}", device, capture.trigger - capture.start);
+                        let bext = BextInfo::new(
+                            &description,
+                            "wasapi",
+                            stream_start,
+                            samplerate,
+                            capture.start,
+                        );
+                        writer.add_bext(&bext)?;
+                        writer.write_bytes(&capture.data)?;
+                        writer.close()
+                    };
+                    save().map_err(|err| err.to_string())?;
+                    paths.push(path);
+                }
+                Ok(paths)
+            })?;
+        Ok(HistorySaver {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    // Queue a capture for saving, this does not wait for the file to be written
+    pub fn save(&self, capture: HistoryCapture) -> WasapiRes<()> {
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| WasapiError::new("The saver is closed"))?;
+        if sender.send(capture).is_err() {
+            return Err(WasapiError::new("The saving thread has stopped").into());
+        }
+        Ok(())
+    }
+
+    // Wait for all captures to be saved, and return the paths of the files
+    pub fn close(mut self) -> WasapiRes<Vec<PathBuf>> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> WasapiRes<Vec<PathBuf>> {
+        self.sender.take();
+        let handle = self
+            .handle
+            .take()
+            .ok_or_else(|| WasapiError::new("The saver is closed"))?;
+        match handle.join() {
+            Ok(Ok(paths)) => Ok(paths),
+            Ok(Err(err)) => Err(WasapiError::new(&err).into()),
+            Err(_) => Err(WasapiError::new("The saving thread panicked").into()),
+        }
+    }
+}
+
+impl Drop for HistorySaver {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            let _ = self.finish();
+        }
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::conversion::{bytes_to_channels, channels_to_bytes, silence};
+use crate::engine::PlaybackSource;
+use crate::network::NetPacket;
+use crate::wasapi::{WasapiError, WasapiRes, WaveFormat};
+
+// An adaptive latency is this many times the estimated jitter, on top of the largest packet
+const JITTER_FACTOR: f64 = 4.0;
+// Number of played packets remembered, to tell duplicates from late packets
+const PLAYED_HISTORY: usize = 64;
+
+// What is played in place of frames that did not arrive in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Concealment {
+    // Play silence
+    Silence,
+    // Repeat the last played packet for as long as needed
+    Repeat,
+    // Repeat the last played packet once while fading it out, then play silence
+    Fade,
+}
+
+// Settings for a jitter buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JitterSettings {
+    // Number of frames to buffer before starting, in frames. The starting point when adaptive.
+    pub latency: usize,
+    // Follow the jitter of the arrival times, between min_latency and max_latency
+    pub adaptive: bool,
+    pub min_latency: usize,
+    pub max_latency: usize,
+    pub concealment: Concealment,
+}
+
+impl JitterSettings {
+    // A fixed latency in frames, lost frames are replaced by silence
+    pub fn new(latency: usize) -> Self {
+        JitterSettings {
+            latency,
+            adaptive: false,
+            min_latency: latency / 4,
+            max_latency: 4 * latency,
+            concealment: Concealment::Silence,
+        }
+    }
+}
+
+// Statistics of a jitter buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterStats {
+    // Packets received
+    pub packets: u64,
+    // Packets that arrived after their frames were due, and were dropped
+    pub late_packets: u64,
+    // Packets that were received before, and were dropped
+    pub duplicate_packets: u64,
+    // Received data that could not be parsed
+    pub invalid_packets: u64,
+    // Frames replaced because their packet never arrived
+    pub lost_frames: u64,
+    // Frames made up by the concealment, for lost packets and when the buffer ran empty
+    pub concealed_frames: u64,
+    // Frames skipped to bring down the latency
+    pub dropped_frames: u64,
+    // Times the buffer ran empty and had to fill up again
+    pub underruns: u64,
+    // Estimated jitter of the arrival times, in frames
+    pub jitter: f64,
+    // Current latency, in frames
+    pub latency: usize,
+}
+
+// Buffer that puts received packets back in order using their timestamps,
+// and conceals the frames of lost packets.
+// Frames are only returned when the buffer has filled up to the latency,
+// which gives late packets time to arrive. When the buffer runs empty it fills up again before continuing.
+// An adaptive buffer estimates the jitter of the arrival times like RFC 3550 does,
+// raises the latency when the jitter grows, and skips frames when more is buffered than needed.
+pub struct JitterBuffer {
+    settings: JitterSettings,
+    latency: usize,
+    stream_id: Option<u32>,
+    format: Option<WaveFormat>,
+    // Data of the packets by timestamp
+    packets: BTreeMap<u64, Vec<u8>>,
+    // Position of the next frame to return, None while filling up
+    position: Option<u64>,
+    // End of the frames that were played or skipped, kept while filling up after an underrun,
+    // so that packets arriving after their frames were due are not played
+    played_until: Option<u64>,
+    // Timestamps of the last played packets
+    played: VecDeque<u64>,
+    // Arrival time in frames and timestamp of the previous packet
+    last_arrival: Option<(f64, u64)>,
+    // Largest packet of the stream, in frames
+    max_packet: usize,
+    // Lowest number of buffered frames seen after a packet arrived, and the frames received since the last check
+    window_min: Option<u64>,
+    window_frames: u64,
+    // The last played packet, used by the concealment
+    last_packet: Vec<u8>,
+    // Number of frames concealed in a row
+    concealed: usize,
+    // No more packets will arrive, what is left can be played without waiting
+    finished: bool,
+    clock: Instant,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    // Create a buffer
+    pub fn new(settings: JitterSettings) -> Self {
+        JitterBuffer {
+            latency: settings.latency,
+            settings,
+            stream_id: None,
+            format: None,
+            packets: BTreeMap::new(),
+            position: None,
+            played_until: None,
+            played: VecDeque::new(),
+            last_arrival: None,
+            max_packet: 0,
+            window_min: None,
+            window_frames: 0,
+            last_packet: Vec::new(),
+            concealed: 0,
+            finished: false,
+            clock: Instant::now(),
+            stats: JitterStats::default(),
+        }
+    }
+
+    // Get the format of the received stream, None before the first packet
+    pub fn get_format(&self) -> Option<WaveFormat> {
+        self.format.clone()
+    }
+
+    // Get the statistics
+    pub fn get_stats(&self) -> JitterStats {
+        JitterStats {
+            latency: self.latency,
+            ..self.stats
+        }
+    }
+
+    // Count received data that could not be parsed
+    pub(crate) fn count_invalid(&mut self) {
+        self.stats.invalid_packets += 1;
+    }
+
+    fn blockalign(&self) -> usize {
+        self.format
+            .as_ref()
+            .map(|format| format.get_blockalign() as usize)
+            .unwrap_or(1)
+    }
+
+    // Get the number of frames from the next one to return to the end of the newest packet
+    pub fn get_buffered_frames(&self) -> u64 {
+        match (self.packets.keys().next(), self.packets.iter().next_back()) {
+            (Some(first), Some((last, data))) => {
+                let end = last + (data.len() / self.blockalign()) as u64;
+                end.saturating_sub(self.position.unwrap_or(*first))
+            }
+            _ => 0,
+        }
+    }
+
+    // Tell the buffer that no more packets will arrive, so that the rest is played without waiting for the latency
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    // Add a packet that arrived now
+    pub fn push(&mut self, packet: NetPacket) {
+        let arrival = self.clock.elapsed();
+        self.push_at(packet, arrival);
+    }
+
+    // Add a packet with its arrival time. A packet from a new stream, or with a new format, restarts the buffer.
+    pub fn push_at(&mut self, packet: NetPacket, arrival: Duration) {
+        if self.stream_id != Some(packet.stream_id) || self.format.as_ref() != Some(&packet.format)
+        {
+            self.restart(&packet);
+        }
+        self.stats.packets += 1;
+        if self.packets.contains_key(&packet.timestamp) || self.played.contains(&packet.timestamp) {
+            self.stats.duplicate_packets += 1;
+            return;
+        }
+        let nbr_frames = packet.nbr_frames();
+        self.max_packet = self.max_packet.max(nbr_frames as usize);
+        self.update_jitter(packet.timestamp, arrival);
+        let end = packet.timestamp + nbr_frames;
+        if self
+            .played_until
+            .is_some_and(|played_until| end <= played_until)
+        {
+            self.stats.late_packets += 1;
+            return;
+        }
+        self.packets.insert(packet.timestamp, packet.data);
+        if self.position.is_none() {
+            // When nothing is read, for example before the stream is started,
+            // the oldest packets are dropped to keep at most twice the latency
+            while let Some((&first, data)) = self.packets.iter().next() {
+                let nbr_frames = (data.len() / self.blockalign()) as u64;
+                // The oldest packet can overlap the newer ones, and be longer than the buffered span
+                if self.get_buffered_frames().saturating_sub(nbr_frames) < 2 * self.latency as u64 {
+                    break;
+                }
+                self.packets.remove(&first);
+                self.stats.dropped_frames += nbr_frames;
+            }
+        } else {
+            self.check_depth(nbr_frames);
+        }
+    }
+
+    fn restart(&mut self, packet: &NetPacket) {
+        self.stream_id = Some(packet.stream_id);
+        self.format = Some(packet.format.clone());
+        self.packets.clear();
+        self.position = None;
+        self.played_until = None;
+        self.played.clear();
+        self.last_arrival = None;
+        self.max_packet = 0;
+        self.window_min = None;
+        self.window_frames = 0;
+        self.last_packet.clear();
+        self.concealed = 0;
+        self.finished = false;
+        self.latency = self.settings.latency;
+    }
+
+    // Update the jitter estimate with the difference between the arrival times and the timestamps
+    fn update_jitter(&mut self, timestamp: u64, arrival: Duration) {
+        let samplerate = self
+            .format
+            .as_ref()
+            .map(|format| format.get_samplespersec() as f64)
+            .unwrap_or(1.0);
+        let arrival = arrival.as_secs_f64() * samplerate;
+        match self.last_arrival {
+            Some((last_arrival, last_timestamp)) => {
+                let difference =
+                    (arrival - last_arrival) - (timestamp as f64 - last_timestamp as f64);
+                self.stats.jitter += (difference.abs() - self.stats.jitter) / 16.0;
+            }
+            // Start from the jitter that gives the configured latency, and follow the measured jitter from there
+            None if self.settings.adaptive => {
+                self.stats.jitter =
+                    self.latency.saturating_sub(self.max_packet) as f64 / JITTER_FACTOR;
+            }
+            None => {}
+        }
+        self.last_arrival = Some((arrival, timestamp));
+        if self.settings.adaptive {
+            let latency = self.max_packet as f64 + JITTER_FACTOR * self.stats.jitter;
+            self.latency = (latency.round() as usize)
+                .min(self.settings.max_latency)
+                .max(self.settings.min_latency);
+        }
+    }
+
+    // Once every second of received frames, skip the frames that were never needed since the last check
+    fn check_depth(&mut self, nbr_frames: u64) {
+        let depth = self.get_buffered_frames();
+        self.window_min = Some(self.window_min.map_or(depth, |min| min.min(depth)));
+        self.window_frames += nbr_frames;
+        let samplerate = self
+            .format
+            .as_ref()
+            .map(|format| format.get_samplespersec() as u64)
+            .unwrap_or(0);
+        if self.window_frames < samplerate {
+            return;
+        }
+        let excess = self.window_min.unwrap_or(0);
+        if self.settings.adaptive && excess > (self.latency + self.max_packet) as u64 {
+            self.skip(excess - self.latency as u64);
+        }
+        self.window_min = None;
+        self.window_frames = 0;
+    }
+
+    fn skip(&mut self, nbr_frames: u64) {
+        if let Some(position) = self.position {
+            let position = position + nbr_frames;
+            let blockalign = self.blockalign();
+            self.packets
+                .retain(|start, data| start + (data.len() / blockalign) as u64 > position);
+            self.position = Some(position);
+            self.played_until = Some(position);
+            self.stats.dropped_frames += nbr_frames;
+        }
+    }
+
+    // Make up frames, from the last played packet
+    fn conceal(&mut self, nbr_frames: usize, format: &WaveFormat) -> Vec<u8> {
+        let blockalign = self.blockalign();
+        let packet_frames = self.last_packet.len() / blockalign;
+        if packet_frames == 0 {
+            // Nothing was played yet
+            return silence(nbr_frames, format);
+        }
+        let start = self.concealed;
+        self.concealed += nbr_frames;
+        self.stats.concealed_frames += nbr_frames as u64;
+        match self.settings.concealment {
+            Concealment::Silence => silence(nbr_frames, format),
+            Concealment::Repeat => (start..start + nbr_frames)
+                .flat_map(|frame| {
+                    let frame = frame % packet_frames;
+                    self.last_packet[frame * blockalign..(frame + 1) * blockalign]
+                        .iter()
+                        .copied()
+                })
+                .collect(),
+            Concealment::Fade => {
+                let faded = bytes_to_channels(&self.last_packet, format).and_then(|channels| {
+                    let channels: Vec<Vec<f64>> = channels
+                        .iter()
+                        .map(|channel| {
+                            (start..start + nbr_frames)
+                                .map(|frame| {
+                                    let gain = 1.0 - (frame + 1) as f64 / packet_frames as f64;
+                                    channel.get(frame).map_or(0.0, |value| gain * value)
+                                })
+                                .collect()
+                        })
+                        .collect();
+                    channels_to_bytes(&channels, format)
+                });
+                faded.unwrap_or_else(|_| silence(nbr_frames, format))
+            }
+        }
+    }
+
+    // Get the next frames. Returns an empty vector while filling up,
+    // and fewer frames than asked for if the buffer runs empty.
+    pub fn read(&mut self, nbr_frames: usize) -> Vec<u8> {
+        let format = match &self.format {
+            Some(format) => format.clone(),
+            None => return Vec::new(),
+        };
+        let blockalign = self.blockalign();
+        let mut position = match (self.position, self.packets.keys().next()) {
+            (Some(position), _) => position,
+            // After an underrun, continue after the frames that were already played
+            (None, Some(first))
+                if self.finished || self.get_buffered_frames() >= self.latency as u64 =>
+            {
+                self.played_until
+                    .map_or(*first, |played_until| played_until.max(*first))
+            }
+            _ => return Vec::new(),
+        };
+        let mut data = Vec::with_capacity(nbr_frames * blockalign);
+        while data.len() < nbr_frames * blockalign {
+            let needed = nbr_frames - data.len() / blockalign;
+            let (start, packet_frames) = match self.packets.iter().next() {
+                Some((start, packet)) => (*start, packet.len() / blockalign),
+                None => {
+                    if !self.finished {
+                        self.stats.underruns += 1;
+                    }
+                    self.position = None;
+                    self.played_until = Some(position);
+                    return data;
+                }
+            };
+            if start + packet_frames as u64 <= position {
+                self.packets.remove(&start);
+            } else if start > position {
+                let gap = ((start - position) as usize).min(needed);
+                data.extend(self.conceal(gap, &format));
+                self.stats.lost_frames += gap as u64;
+                position += gap as u64;
+            } else {
+                let offset = (position - start) as usize;
+                let count = (packet_frames - offset).min(needed);
+                data.extend_from_slice(
+                    &self.packets[&start][offset * blockalign..(offset + count) * blockalign],
+                );
+                self.concealed = 0;
+                position += count as u64;
+                if offset + count == packet_frames {
+                    if let Some(packet) = self.packets.remove(&start) {
+                        self.last_packet = packet;
+                    }
+                    self.played.push_back(start);
+                    if self.played.len() > PLAYED_HISTORY {
+                        self.played.pop_front();
+                    }
+                }
+            }
+        }
+        self.position = Some(position);
+        self.played_until = Some(position);
+        data
+    }
+
+    // Get exactly the number of frames asked for, concealing what is missing.
+    // Before the first packet arrives nothing is known about the stream, and an empty vector is returned.
+    pub fn fill(&mut self, nbr_frames: usize) -> Vec<u8> {
+        let mut data = self.read(nbr_frames);
+        if let Some(format) = self.format.clone() {
+            let missing = nbr_frames - data.len() / self.blockalign();
+            if missing > 0 {
+                data.extend(self.conceal(missing, &format));
+            }
+        }
+        data
+    }
+}
+
+// State shared by the two sides of a jitter channel
+struct JitterChannel {
+    buffer: Mutex<JitterBuffer>,
+    sender_closed: AtomicBool,
+    receiver_closed: AtomicBool,
+}
+
+// Create a channel passing chunks of frames from one thread to another through a jitter buffer.
+// Unlike a channel of fixed depth, it knows when the frames were sent,
+// which lets it adapt the latency to the way the chunks arrive, and conceal the frames that arrive too late.
+pub fn jitter_channel(
+    format: &WaveFormat,
+    settings: JitterSettings,
+) -> (JitterSender, JitterReceiver) {
+    let channel = Arc::new(JitterChannel {
+        buffer: Mutex::new(JitterBuffer::new(settings)),
+        sender_closed: AtomicBool::new(false),
+        receiver_closed: AtomicBool::new(false),
+    });
+    let sender = JitterSender {
+        channel: channel.clone(),
+        format: format.clone(),
+        sequence: 0,
+        position: 0,
+    };
+    (sender, JitterReceiver { channel })
+}
+
+// Sending side of a jitter channel.
+pub struct JitterSender {
+    channel: Arc<JitterChannel>,
+    format: WaveFormat,
+    sequence: u32,
+    position: u64,
+}
+
+impl JitterSender {
+    // Send a chunk of frames, fails when the receiving side is closed
+    pub fn send(&mut self, data: Vec<u8>) -> WasapiRes<()> {
+        if self.channel.receiver_closed.load(Ordering::Relaxed) {
+            return Err(WasapiError::new("The receiving side of the channel is closed").into());
+        }
+        let nbr_frames = (data.len() / self.format.get_blockalign() as usize) as u64;
+        let packet = NetPacket {
+            stream_id: 0,
+            sequence: self.sequence,
+            timestamp: self.position,
+            format: self.format.clone(),
+            data,
+        };
+        self.channel.buffer.lock().unwrap().push(packet);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.position += nbr_frames;
+        Ok(())
+    }
+}
+
+impl Drop for JitterSender {
+    fn drop(&mut self) {
+        self.channel.sender_closed.store(true, Ordering::Relaxed);
+    }
+}
+
+// Receiving side of a jitter channel.
+pub struct JitterReceiver {
+    channel: Arc<JitterChannel>,
+}
+
+impl JitterReceiver {
+    // Get the statistics of the jitter buffer
+    pub fn get_stats(&self) -> JitterStats {
+        self.channel.buffer.lock().unwrap().get_stats()
+    }
+}
+
+impl PlaybackSource for JitterReceiver {
+    // Frames are concealed while the sending side is open, after it is closed the rest is played
+    fn fill(&mut self, nbr_frames: usize, _format: &WaveFormat) -> Option<Vec<u8>> {
+        let mut buffer = self.channel.buffer.lock().unwrap();
+        if !self.channel.sender_closed.load(Ordering::Relaxed) {
+            return Some(buffer.fill(nbr_frames));
+        }
+        buffer.finish();
+        if buffer.get_buffered_frames() == 0 {
+            return None;
+        }
+        Some(buffer.read(nbr_frames))
+    }
+}
+
+impl Drop for JitterReceiver {
+    fn drop(&mut self) {
+        self.channel.receiver_closed.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+
+use crate::dsp::{cross_correlation, find_peak, median, rms, std_dev};
+use crate::generator::{log_sweep, mls};
+use crate::loopback::Loopback;
+use crate::wasapi::{WasapiError, WasapiRes};
+
+// Stimulus signal used for measuring latency.
+#[derive(Clone, Debug)]
+pub enum Stimulus {
+    // Maximum length sequence of the given order
+    Mls {
+        order: u32,
+    },
+    // Logarithmic sine sweep, with start and end frequencies in Hz and length in frames
+    Chirp {
+        f_start: f64,
+        f_end: f64,
+        length: usize,
+    },
+}
+
+impl Stimulus {
+    // Generate the stimulus signal
+    pub fn generate(&self, samplerate: usize, amplitude: f64) -> WasapiRes<Vec<f64>> {
+        match self {
+            Stimulus::Mls { order } => {
+                Ok(mls(*order)?.iter().map(|value| amplitude * value).collect())
+            }
+            Stimulus::Chirp {
+                f_start,
+                f_end,
+                length,
+            } => {
+                if *f_start <= 0.0 || *f_end <= *f_start || *f_end > samplerate as f64 / 2.0 {
+                    return Err(WasapiError::new("Invalid chirp frequency range").into());
+                }
+                Ok(log_sweep(*f_start, *f_end, *length, samplerate, amplitude))
+            }
+        }
+    }
+}
+
+// A delay found by cross correlation.
+#[derive(Clone, Debug)]
+pub struct DelayEstimate {
+    // Delay in frames
+    pub delay: usize,
+    // Height of the correlation peak relative to the rms of the correlation,
+    // a low value means that the stimulus was not found in the captured signal.
+    pub peak_ratio: f64,
+}
+
+// Find the delay of a reference signal within a captured signal, searching delays up to max_delay frames.
+pub fn find_delay(reference: &[f64], captured: &[f64], max_delay: usize) -> Option<DelayEstimate> {
+    if reference.is_empty() || captured.is_empty() {
+        return None;
+    }
+    let corr = cross_correlation(reference, captured);
+    let search = &corr[..corr.len().min(max_delay + 1)];
+    let (delay, peak) = find_peak(search)?;
+    let corr_rms = rms(&corr);
+    if corr_rms == 0.0 {
+        return None;
+    }
+    Some(DelayEstimate {
+        delay,
+        peak_ratio: peak.abs() / corr_rms,
+    })
+}
+
+// Settings for a round trip latency measurement.
+#[derive(Clone, Debug)]
+pub struct LatencyMeasurement {
+    // Stimulus to play
+    pub stimulus: Stimulus,
+    // Peak amplitude of the stimulus
+    pub amplitude: f64,
+    // Number of times to repeat the measurement
+    pub runs: usize,
+    // Longest latency to look for, in frames
+    pub max_latency: usize,
+    // Channel used for the stimulus and the capture
+    pub channel: usize,
+    // Minimum peak ratio for a run to be accepted
+    pub min_peak_ratio: f64,
+}
+
+impl LatencyMeasurement {
+    // Create a measurement with default settings for the given stimulus
+    pub fn new(stimulus: Stimulus) -> Self {
+        LatencyMeasurement {
+            stimulus,
+            amplitude: 0.5,
+            runs: 5,
+            max_latency: 48000,
+            channel: 0,
+            min_peak_ratio: 10.0,
+        }
+    }
+
+    // Run the measurement on a loopback, and report the delays found
+    pub fn measure(&self, loopback: &mut dyn Loopback) -> WasapiRes<LatencyReport> {
+        let format = loopback.get_format();
+        let samplerate = format.get_samplespersec() as usize;
+        let nchannels = format.get_nchannels() as usize;
+        if self.channel >= nchannels {
+            return Err(WasapiError::new(
+                format!(
+                    "Channel {} does not exist, the format has {} channels",
+                    self.channel, nchannels
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        let stimulus = self.stimulus.generate(samplerate, self.amplitude)?;
+        let mut data = vec![vec![0.0; stimulus.len()]; nchannels];
+        data[self.channel] = stimulus.clone();
+
+        let mut delays = Vec::with_capacity(self.runs);
+        let mut failed_runs = 0;
+        for _ in 0..self.runs {
+            let captured = loopback.play_and_capture(&data, self.max_latency)?;
+            match find_delay(&stimulus, &captured[self.channel], self.max_latency) {
+                Some(estimate) if estimate.peak_ratio >= self.min_peak_ratio => {
+                    delays.push(estimate.delay)
+                }
+                _ => failed_runs += 1,
+            }
+        }
+        if delays.is_empty() {
+            return Err(WasapiError::new(
+                "The stimulus was not found in any of the captured signals",
+            )
+            .into());
+        }
+        Ok(LatencyReport::new(&delays, failed_runs, samplerate))
+    }
+}
+
+// Result of a latency measurement.
+#[derive(Clone, Debug)]
+pub struct LatencyReport {
+    // Delay of each successful run, in frames
+    pub delays: Vec<usize>,
+    // Number of runs where the stimulus could not be found
+    pub failed_runs: usize,
+    // Sample rate used for the measurement
+    pub samplerate: usize,
+    // Median delay in frames
+    pub median: f64,
+    // Difference between the longest and shortest delay, in frames
+    pub jitter_pp: usize,
+    // Standard deviation of the delays, in frames
+    pub jitter_std: f64,
+}
+
+impl LatencyReport {
+    // Calculate the statistics for a set of delays
+    pub fn new(delays: &[usize], failed_runs: usize, samplerate: usize) -> Self {
+        let values: Vec<f64> = delays.iter().map(|delay| *delay as f64).collect();
+        let min = delays.iter().min().copied().unwrap_or(0);
+        let max = delays.iter().max().copied().unwrap_or(0);
+        LatencyReport {
+            delays: delays.to_vec(),
+            failed_runs,
+            samplerate,
+            median: median(&values),
+            jitter_pp: max - min,
+            jitter_std: std_dev(&values),
+        }
+    }
+
+    // Get the median delay in milliseconds
+    pub fn get_median_ms(&self) -> f64 {
+        1000.0 * self.median / self.samplerate as f64
+    }
+
+    // Get the peak to peak jitter in milliseconds
+    pub fn get_jitter_ms(&self) -> f64 {
+        1000.0 * self.jitter_pp as f64 / self.samplerate as f64
+    }
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "runs: {} ok, {} failed",
+            self.delays.len(),
+            self.failed_runs
+        )?;
+        writeln!(f, "delays: {:?} frames", self.delays)?;
+        writeln!(
+            f,
+            "median: {:.1} frames, {:.3} ms",
+            self.median,
+            self.get_median_ms()
+        )?;
+        write!(
+            f,
+            "jitter: {} frames peak to peak ({:.3} ms), {:.2} frames std dev",
+            self.jitter_pp,
+            self.get_jitter_ms(),
+            self.jitter_std
+        )
+    }
+}
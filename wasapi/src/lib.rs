@@ -1,7 +1,43 @@
+// The WASAPI bindings and the device wrappers using them are only available on Windows.
+// Everything else, formats, conversions, files, DSP and the engine, builds on all platforms.
+#[cfg(windows)]
 ::windows::include_bindings!();
+#[cfg(windows)]
 use Windows::Win32::System::PropertiesSystem::PROPERTYKEY;
 pub mod wasapi;
+pub mod aiff;
+pub mod audiofile;
+pub mod backend;
+pub mod bwf;
+pub mod conversion;
+pub mod distortion;
+pub mod engine;
+pub mod filedevice;
+pub mod dsp;
+pub mod flac;
+pub mod generator;
+pub mod glitch;
+pub mod history;
+pub mod jitter;
+pub mod latency;
+pub mod loopback;
+pub mod network;
+pub mod nulldevice;
+pub mod pipe;
+pub mod player;
+pub mod rawpcm;
+pub mod resample;
+pub mod rtp;
+pub mod segment;
+pub mod sharedmem;
+pub mod simulated;
+pub mod sweep;
+pub mod vad;
+pub mod wav;
+#[cfg(not(windows))]
+mod winstructs;
 
+#[cfg(windows)]
 #[allow(non_upper_case_globals)]
 pub const PKEY_Device_FriendlyName: PROPERTYKEY = PROPERTYKEY {
     fmtid: windows::Guid::from_values(
@@ -13,6 +49,7 @@ pub const PKEY_Device_FriendlyName: PROPERTYKEY = PROPERTYKEY {
     pid: 14,
 };
 
+#[cfg(windows)]
 #[allow(non_upper_case_globals)]
 pub const PKEY_Device_DeviceDesc: PROPERTYKEY = PROPERTYKEY {
     fmtid: windows::Guid::from_values(
@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::backend::Backend;
+#[cfg(windows)]
+use crate::backend::WasapiBackend;
+use crate::conversion::{bytes_to_channels, channels_to_bytes, silence};
+use crate::generator::NoiseGenerator;
+use crate::wasapi::{Direction, FormatSupported, ShareMode, WasapiError, WasapiRes, WaveFormat};
+
+// A playback and a capture device connected in a loop, possibly through some processing chain.
+// Used by the measurements to play a stimulus and record the response.
+pub trait Loopback {
+    // Get the format used for both playback and capture
+    fn get_format(&self) -> WaveFormat;
+
+    // Play one vector of samples per channel, and return what was captured.
+    // The capture starts when playback starts, and continues for extra_frames after the end of the data.
+    fn play_and_capture(
+        &mut self,
+        data: &[Vec<f64>],
+        extra_frames: usize,
+    ) -> WasapiRes<Vec<Vec<f64>>>;
+}
+
+// Processing function for a simulated loopback, called with the played samples of all channels.
+pub type SignalProcessor = Box<dyn FnMut(&[Vec<f64>]) -> Vec<Vec<f64>> + Send>;
+
+// A simulated loopback, that delays the signal by a known number of frames.
+// The samples are passed through the sample format both ways, like with a real device.
+pub struct SimulatedLoopback {
+    format: WaveFormat,
+    delays: Vec<usize>,
+    run: usize,
+    gain: f64,
+    noise_level: f64,
+    noise: NoiseGenerator,
+    processor: Option<SignalProcessor>,
+}
+
+impl SimulatedLoopback {
+    // Create a new simulated loopback with a fixed delay in frames
+    pub fn new(format: &WaveFormat, delay: usize) -> Self {
+        SimulatedLoopback {
+            format: format.clone(),
+            delays: vec![delay],
+            run: 0,
+            gain: 1.0,
+            noise_level: 0.0,
+            noise: NoiseGenerator::new(12345),
+            processor: None,
+        }
+    }
+
+    // Use a different delay for each run, repeating the list when it runs out
+    pub fn set_delays(&mut self, delays: &[usize]) {
+        if !delays.is_empty() {
+            self.delays = delays.to_vec();
+        }
+    }
+
+    // Set the gain of the loop
+    pub fn set_gain(&mut self, gain: f64) {
+        self.gain = gain;
+    }
+
+    // Add white noise with the given peak amplitude to the captured signal
+    pub fn set_noise_level(&mut self, level: f64) {
+        self.noise_level = level;
+    }
+
+    // Process the played signal with a function, for example a filter, a nonlinearity or crosstalk
+    pub fn set_processor(&mut self, processor: SignalProcessor) {
+        self.processor = Some(processor);
+    }
+
+    // Get the number of runs made so far
+    pub fn get_nbr_runs(&self) -> usize {
+        self.run
+    }
+}
+
+impl Loopback for SimulatedLoopback {
+    fn get_format(&self) -> WaveFormat {
+        self.format.clone()
+    }
+
+    fn play_and_capture(
+        &mut self,
+        data: &[Vec<f64>],
+        extra_frames: usize,
+    ) -> WasapiRes<Vec<Vec<f64>>> {
+        let delay = self.delays[self.run % self.delays.len()];
+        self.run += 1;
+        let played = bytes_to_channels(&channels_to_bytes(data, &self.format)?, &self.format)?;
+        let nbr_frames = played.first().map(|chan| chan.len()).unwrap_or(0) + extra_frames;
+        let processed = match self.processor.as_mut() {
+            Some(processor) => processor(&played),
+            None => played,
+        };
+        let mut captured = Vec::with_capacity(processed.len());
+        for chan in processed.iter() {
+            let mut output = vec![0.0; delay];
+            output.extend(chan.iter().map(|value| self.gain * value));
+            output.resize(nbr_frames, 0.0);
+            for value in output.iter_mut() {
+                *value += self.noise_level * self.noise.next_value();
+            }
+            captured.push(output);
+        }
+        bytes_to_channels(&channels_to_bytes(&captured, &self.format)?, &self.format)
+    }
+}
+
+// A loopback using a playback device and a capture device of a backend, by default the WASAPI devices.
+pub struct WasapiLoopback {
+    backend: Arc<dyn Backend>,
+    render_device: String,
+    capture_device: String,
+    format: WaveFormat,
+    sharemode: ShareMode,
+}
+
+impl WasapiLoopback {
+    // Create a new loopback from the names of the playback and capture WASAPI devices
+    #[cfg(windows)]
+    pub fn new(
+        render_device: &str,
+        capture_device: &str,
+        format: &WaveFormat,
+        sharemode: &ShareMode,
+    ) -> Self {
+        WasapiLoopback::with_backend(
+            Arc::new(WasapiBackend::new()),
+            render_device,
+            capture_device,
+            format,
+            sharemode,
+        )
+    }
+
+    // Create a new loopback using the devices of another backend
+    pub fn with_backend(
+        backend: Arc<dyn Backend>,
+        render_device: &str,
+        capture_device: &str,
+        format: &WaveFormat,
+        sharemode: &ShareMode,
+    ) -> Self {
+        WasapiLoopback {
+            backend,
+            render_device: render_device.to_owned(),
+            capture_device: capture_device.to_owned(),
+            format: format.clone(),
+            sharemode: sharemode.clone(),
+        }
+    }
+}
+
+impl Loopback for WasapiLoopback {
+    fn get_format(&self) -> WaveFormat {
+        self.format.clone()
+    }
+
+    fn play_and_capture(
+        &mut self,
+        data: &[Vec<f64>],
+        extra_frames: usize,
+    ) -> WasapiRes<Vec<Vec<f64>>> {
+        let blockalign = self.format.get_blockalign() as usize;
+        let mut play_queue: VecDeque<u8> =
+            channels_to_bytes(data, &self.format)?.into_iter().collect();
+        let total_bytes = play_queue.len() + extra_frames * blockalign;
+
+        let render_collection = self.backend.get_device_collection(&Direction::Render)?;
+        let render_device = render_collection.get_device_with_name(&self.render_device)?;
+        let mut render_audio_client = render_device.get_iaudioclient()?;
+        let capture_collection = self.backend.get_device_collection(&Direction::Capture)?;
+        let capture_device = capture_collection.get_device_with_name(&self.capture_device)?;
+        let mut capture_audio_client = capture_device.get_iaudioclient()?;
+
+        for audio_client in [&render_audio_client, &capture_audio_client].iter() {
+            if let FormatSupported::ClosestMatch(_) =
+                audio_client.is_supported(&self.format, &self.sharemode)?
+            {
+                return Err(WasapiError::new(
+                    "The requested format is not supported by both devices",
+                )
+                .into());
+            }
+        }
+        let (_, min_time) = render_audio_client.get_periods()?;
+        render_audio_client.initialize_client(
+            &self.format,
+            min_time,
+            &Direction::Render,
+            &self.sharemode,
+        )?;
+        let (_, min_time) = capture_audio_client.get_periods()?;
+        capture_audio_client.initialize_client(
+            &self.format,
+            min_time,
+            &Direction::Capture,
+            &self.sharemode,
+        )?;
+
+        let h_render = render_audio_client.set_get_eventhandle()?;
+        // The capture side is polled after each playback event, but needs an event handle to start.
+        let _h_capture = capture_audio_client.set_get_eventhandle()?;
+        let render_client = render_audio_client.get_audiorenderclient()?;
+        let capture_client = capture_audio_client.get_audiocaptureclient()?;
+
+        let mut captured: VecDeque<u8> = VecDeque::with_capacity(total_bytes);
+        capture_audio_client.start_stream()?;
+        render_audio_client.start_stream()?;
+        while captured.len() < total_bytes {
+            let nbr_frames = render_audio_client.get_available_frames()? as usize;
+            while play_queue.len() < nbr_frames * blockalign {
+                play_queue.push_back(0);
+            }
+            render_client.write_to_device_from_deque(nbr_frames, blockalign, &mut play_queue)?;
+            if h_render.wait_for_event(1000).is_err() {
+                render_audio_client.stop_stream()?;
+                capture_audio_client.stop_stream()?;
+                return Err(WasapiError::new("Timed out waiting for playback event").into());
+            }
+            while capture_client.get_next_nbr_frames()? > 0 {
+                let start = captured.len();
+                let flags = capture_client.read_from_device_to_deque(blockalign, &mut captured)?;
+                if flags.silent {
+                    // The data of a silent buffer may contain anything, replace it with real silence
+                    let nbr_frames = (captured.len() - start) / blockalign;
+                    captured.truncate(start);
+                    captured.extend(silence(nbr_frames, &self.format));
+                }
+            }
+        }
+        render_audio_client.stop_stream()?;
+        capture_audio_client.stop_stream()?;
+        let captured: Vec<u8> = captured.into_iter().take(total_bytes).collect();
+        bytes_to_channels(&captured, &self.format)
+    }
+}
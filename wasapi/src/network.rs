@@ -0,0 +1,643 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{Backend, DeviceCollectionTrait, DeviceTrait};
+use crate::jitter::{JitterBuffer, JitterSettings, JitterStats};
+use crate::rtp::{RtpReceiver, RtpSender, RtpStreamConfig};
+use crate::simulated::{Pacing, SimDeviceConfig, SimSink, SimSource, SimulatedBackend};
+use crate::wasapi::{Direction, SampleType, WasapiError, WasapiRes, WaveFormat, MAX_CHANNELS};
+
+// Start of every packet
+const MAGIC: &[u8; 4] = b"WNET";
+const VERSION: u8 = 1;
+// Size of the packet header in bytes
+const HEADER_SIZE: usize = 36;
+// Largest packet that is accepted, UDP can not carry more
+const MAX_PACKET_SIZE: usize = 65507;
+// How often the receiving thread checks if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// How the packets are sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetProtocol {
+    // One datagram per packet, packets may be lost or arrive out of order
+    Udp,
+    // A connection per stream, each packet is preceded by its length as a 32-bit little endian value
+    Tcp,
+}
+
+// A packet of audio data, with the format of the stream, a sequence number,
+// and a timestamp that is the position in frames of the first frame since the start of the stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetPacket {
+    // Random number identifying the stream, a new stream starts from sequence and timestamp 0
+    pub stream_id: u32,
+    pub sequence: u32,
+    pub timestamp: u64,
+    pub format: WaveFormat,
+    pub data: Vec<u8>,
+}
+
+impl NetPacket {
+    // Serialize the packet, the header has all values in little endian order:
+    // magic "WNET", version (u8), sample type (u8, 0 for int and 1 for float), channels (u16),
+    // sample rate (u32), bits per sample (u16), valid bits per sample (u16),
+    // stream id (u32), sequence number (u32), timestamp (u64) and number of frames (u32).
+    pub fn to_bytes(&self) -> WasapiRes<Vec<u8>> {
+        let sample_type = match self.format.get_subformat()? {
+            SampleType::Int => 0u8,
+            SampleType::Float => 1u8,
+        };
+        let nbr_frames = self.data.len() / self.format.get_blockalign() as usize;
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(sample_type);
+        bytes.extend_from_slice(&self.format.get_nchannels().to_le_bytes());
+        bytes.extend_from_slice(&self.format.get_samplespersec().to_le_bytes());
+        bytes.extend_from_slice(&self.format.get_bitspersample().to_le_bytes());
+        bytes.extend_from_slice(&self.format.get_validbitspersample().to_le_bytes());
+        bytes.extend_from_slice(&self.stream_id.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&(nbr_frames as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        Ok(bytes)
+    }
+
+    // Parse a packet
+    pub fn from_bytes(bytes: &[u8]) -> WasapiRes<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return Err(WasapiError::new("Not an audio packet").into());
+        }
+        if bytes[4] != VERSION {
+            return Err(WasapiError::new(
+                format!("Unsupported packet version {}", bytes[4]).as_str(),
+            )
+            .into());
+        }
+        let sample_type = match bytes[5] {
+            0 => SampleType::Int,
+            1 => SampleType::Float,
+            _ => return Err(WasapiError::new("Unsupported sample type").into()),
+        };
+        let u16_at = |pos: usize| u16::from_le_bytes([bytes[pos], bytes[pos + 1]]);
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+        };
+        let channels = u16_at(6) as usize;
+        let samplerate = u32_at(8) as usize;
+        let bits = u16_at(12) as usize;
+        let validbits = u16_at(14) as usize;
+        if channels == 0 || bits == 0 || !bits.is_multiple_of(8) || validbits > bits {
+            return Err(WasapiError::new("Invalid format in packet").into());
+        }
+        // The size of a frame must fit in the block alignment of the format
+        if channels > MAX_CHANNELS || channels * bits / 8 > u16::MAX as usize {
+            return Err(WasapiError::new(
+                format!(
+                    "Unsupported format in packet, {} channels of {} bits",
+                    channels, bits
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        let format = WaveFormat::new(bits, validbits, &sample_type, samplerate, channels);
+        let stream_id = u32_at(16);
+        let sequence = u32_at(20);
+        let timestamp = u32_at(24) as u64 | (u32_at(28) as u64) << 32;
+        let nbr_frames = u32_at(32) as usize;
+        let data = &bytes[HEADER_SIZE..];
+        if data.len() != nbr_frames * format.get_blockalign() as usize {
+            return Err(WasapiError::new("Wrong length of packet data").into());
+        }
+        Ok(NetPacket {
+            stream_id,
+            sequence,
+            timestamp,
+            format,
+            data: data.to_vec(),
+        })
+    }
+
+    pub(crate) fn nbr_frames(&self) -> u64 {
+        (self.data.len() / self.format.get_blockalign() as usize) as u64
+    }
+}
+
+// Receiving side of a network capture device.
+// A separate thread receives the packets into a jitter buffer, which the device reads at its own pace.
+pub struct NetReceiver {
+    buffer: Arc<Mutex<JitterBuffer>>,
+    closed: Arc<AtomicBool>,
+    local_addr: SocketAddr,
+}
+
+impl NetReceiver {
+    // Start receiving on a local address, through a jitter buffer
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        protocol: NetProtocol,
+        jitter: JitterSettings,
+    ) -> WasapiRes<Self> {
+        let buffer = Arc::new(Mutex::new(JitterBuffer::new(jitter)));
+        let closed = Arc::new(AtomicBool::new(false));
+        let buffer_thread = buffer.clone();
+        let closed_thread = closed.clone();
+        let local_addr = match protocol {
+            NetProtocol::Udp => {
+                let socket = UdpSocket::bind(addr)?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                let local_addr = socket.local_addr()?;
+                thread::Builder::new()
+                    .name("UdpReceiver".to_string())
+                    .spawn(move || receive_udp(socket, &buffer_thread, &closed_thread))?;
+                local_addr
+            }
+            NetProtocol::Tcp => {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                let local_addr = listener.local_addr()?;
+                thread::Builder::new()
+                    .name("TcpReceiver".to_string())
+                    .spawn(move || receive_tcp(listener, &buffer_thread, &closed_thread))?;
+                local_addr
+            }
+        };
+        Ok(NetReceiver {
+            buffer,
+            closed,
+            local_addr,
+        })
+    }
+
+    // Get the address the receiver listens on
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Get the statistics of the jitter buffer
+    pub fn get_stats(&self) -> JitterStats {
+        self.buffer.lock().unwrap().get_stats()
+    }
+}
+
+fn push_packet(buffer: &Mutex<JitterBuffer>, bytes: &[u8]) {
+    let mut buffer = buffer.lock().unwrap();
+    match NetPacket::from_bytes(bytes) {
+        Ok(packet) => buffer.push(packet),
+        Err(_) => buffer.count_invalid(),
+    }
+}
+
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+    )
+}
+
+fn receive_udp(socket: UdpSocket, buffer: &Mutex<JitterBuffer>, closed: &AtomicBool) {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    while !closed.load(Ordering::Relaxed) {
+        match socket.recv(&mut buf) {
+            Ok(len) => push_packet(buffer, &buf[..len]),
+            Err(err) if is_timeout(&err) => {}
+            // Errors reported for earlier datagrams, for example a reset connection, do not stop the receiver
+            Err(_) => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+fn receive_tcp(listener: TcpListener, buffer: &Mutex<JitterBuffer>, closed: &AtomicBool) {
+    while !closed.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+        if stream.set_nonblocking(false).is_err()
+            || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+        {
+            continue;
+        }
+        receive_connection(stream, buffer, closed);
+    }
+}
+
+// Receive length prefixed packets until the connection is closed
+fn receive_connection(mut stream: TcpStream, buffer: &Mutex<JitterBuffer>, closed: &AtomicBool) {
+    let mut pending: Vec<u8> = Vec::new();
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    while !closed.load(Ordering::Relaxed) {
+        match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => pending.extend_from_slice(&buf[..len]),
+            Err(err) if is_timeout(&err) => continue,
+            Err(_) => return,
+        }
+        while pending.len() >= 4 {
+            let len = u32::from_le_bytes([pending[0], pending[1], pending[2], pending[3]]) as usize;
+            if len > MAX_PACKET_SIZE {
+                // The stream is out of sync, drop the connection
+                buffer.lock().unwrap().count_invalid();
+                return;
+            }
+            if pending.len() < 4 + len {
+                break;
+            }
+            push_packet(buffer, &pending[4..4 + len]);
+            pending.drain(..4 + len);
+        }
+    }
+}
+
+impl SimSource for NetReceiver {
+    fn read(&mut self, nbr_frames: usize, format: &WaveFormat) -> WasapiRes<Vec<u8>> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer
+            .get_format()
+            .is_some_and(|received| received != *format)
+        {
+            return Err(WasapiError::new(
+                "The format of the received stream does not match the stream format",
+            )
+            .into());
+        }
+        Ok(buffer.fill(nbr_frames))
+    }
+}
+
+impl Drop for NetReceiver {
+    fn drop(&mut self) {
+        // Let the receiving thread exit
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+// Simulated network problems, applied by the sender to test the receiving side.
+// The random choices use a fixed seed, so that a test sees the same losses every time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetImpairment {
+    // Probability that a packet is dropped
+    pub loss: f64,
+    // Probability that a packet is held back and sent after the next one
+    pub reorder: f64,
+    pub seed: u64,
+}
+
+impl NetImpairment {
+    pub fn new(loss: f64, reorder: f64, seed: u64) -> Self {
+        NetImpairment {
+            loss,
+            reorder,
+            seed,
+        }
+    }
+}
+
+// Statistics of a sending device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SenderStats {
+    // Packets sent
+    pub packets: u64,
+    // Packets dropped by the impairment
+    pub dropped: u64,
+    // Packets sent after the following one by the impairment
+    pub reordered: u64,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+// Sending side of a network render device, each period played by the device is sent as a packet.
+pub struct NetSender {
+    remote: SocketAddr,
+    protocol: NetProtocol,
+    impairment: Option<NetImpairment>,
+    rng_state: u64,
+    connection: Option<Connection>,
+    format: Option<WaveFormat>,
+    stream_id: u32,
+    sequence: u32,
+    timestamp: u64,
+    // A packet held back by the impairment
+    held: Option<Vec<u8>>,
+    stats: Arc<Mutex<SenderStats>>,
+}
+
+// Counter making the stream ids of streams started at the same time differ
+static STREAM_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) fn new_stream_id() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos())
+        .unwrap_or(0);
+    nanos
+        ^ STREAM_COUNTER
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_mul(0x9E37_79B9)
+}
+
+impl NetSender {
+    // Create a sender, the connection is made when a stream is initialized
+    pub fn new<A: ToSocketAddrs>(
+        remote: A,
+        protocol: NetProtocol,
+        impairment: Option<NetImpairment>,
+    ) -> WasapiRes<Self> {
+        let remote = resolve(remote)?;
+        Ok(NetSender {
+            remote,
+            protocol,
+            impairment,
+            rng_state: impairment.map(|imp| imp.seed.max(1)).unwrap_or(1),
+            connection: None,
+            format: None,
+            stream_id: 0,
+            sequence: 0,
+            timestamp: 0,
+            held: None,
+            stats: Arc::new(Mutex::new(SenderStats::default())),
+        })
+    }
+
+    // Get the statistics
+    pub fn get_stats(&self) -> SenderStats {
+        *self.stats.lock().unwrap()
+    }
+
+    // Random number between 0 and 1, from a xorshift generator
+    fn random(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        (self.rng_state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> WasapiRes<()> {
+        match &mut self.connection {
+            // Sending may fail when nothing is listening, that is just like a lost packet
+            Some(Connection::Udp(socket)) => {
+                let _ = socket.send(bytes);
+            }
+            Some(Connection::Tcp(stream)) => {
+                stream.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                stream.write_all(bytes)?;
+            }
+            None => return Err(WasapiError::new("The sender is not connected").into()),
+        }
+        self.stats.lock().unwrap().packets += 1;
+        Ok(())
+    }
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> WasapiRes<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| WasapiError::new("Unable to resolve address").into())
+}
+
+impl SimSink for NetSender {
+    fn open(&mut self, format: &WaveFormat) -> WasapiRes<()> {
+        self.close()?;
+        let connection = match self.protocol {
+            NetProtocol::Udp => {
+                let local: SocketAddr = if self.remote.is_ipv4() {
+                    "0.0.0.0:0".parse()?
+                } else {
+                    "[::]:0".parse()?
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(self.remote)?;
+                Connection::Udp(socket)
+            }
+            NetProtocol::Tcp => {
+                let stream = TcpStream::connect(self.remote)?;
+                stream.set_nodelay(true)?;
+                Connection::Tcp(stream)
+            }
+        };
+        self.connection = Some(connection);
+        self.format = Some(format.clone());
+        self.stream_id = new_stream_id();
+        self.sequence = 0;
+        self.timestamp = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> WasapiRes<()> {
+        let format = match &self.format {
+            Some(format) => format.clone(),
+            None => return Err(WasapiError::new("The sender is not connected").into()),
+        };
+        let packet = NetPacket {
+            stream_id: self.stream_id,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            format,
+            data: data.to_vec(),
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp += packet.nbr_frames();
+        let bytes = packet.to_bytes()?;
+        if let Some(impairment) = self.impairment {
+            if self.random() < impairment.loss {
+                self.stats.lock().unwrap().dropped += 1;
+                return Ok(());
+            }
+            if self.held.is_none() && self.random() < impairment.reorder {
+                self.stats.lock().unwrap().reordered += 1;
+                self.held = Some(bytes);
+                return Ok(());
+            }
+        }
+        self.send(&bytes)?;
+        if let Some(held) = self.held.take() {
+            self.send(&held)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> WasapiRes<()> {
+        if let Some(held) = self.held.take() {
+            self.send(&held)?;
+        }
+        if let Some(Connection::Tcp(stream)) = self.connection.take() {
+            // The other side may already have closed the connection
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.format = None;
+        Ok(())
+    }
+}
+
+type SharedStats = Arc<Mutex<SenderStats>>;
+type SharedBuffer = Arc<Mutex<JitterBuffer>>;
+
+// A backend with render devices that send their frames over the network,
+// and capture devices that receive them through a jitter buffer.
+// It is a simulated backend paced by the real clock,
+// so the devices negotiate formats, buffer sizes and periods like real ones,
+// and the existing playback and capture code can stream between machines unchanged.
+// The format of the received stream must be the format of the capture stream.
+#[derive(Clone)]
+pub struct NetworkBackend {
+    simulated: SimulatedBackend,
+    senders: Arc<Mutex<Vec<(String, SharedStats)>>>,
+    receivers: Arc<Mutex<Vec<(String, SharedBuffer)>>>,
+}
+
+impl Default for NetworkBackend {
+    fn default() -> Self {
+        NetworkBackend::new()
+    }
+}
+
+impl NetworkBackend {
+    // Create a backend without devices
+    pub fn new() -> Self {
+        let simulated = SimulatedBackend::new();
+        simulated.set_pacing(Pacing::RealTime);
+        NetworkBackend {
+            simulated,
+            senders: Arc::new(Mutex::new(Vec::new())),
+            receivers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Add a render device sending to a remote address, with optional simulated network problems
+    pub fn add_render_device<A: ToSocketAddrs>(
+        &self,
+        config: SimDeviceConfig,
+        remote: A,
+        protocol: NetProtocol,
+        impairment: Option<NetImpairment>,
+    ) -> WasapiRes<()> {
+        if config.direction != Direction::Render {
+            return Err(
+                WasapiError::new("The device configuration is not for a render device").into(),
+            );
+        }
+        let sender = NetSender::new(remote, protocol, impairment)?;
+        let name = config.name.clone();
+        let stats = sender.stats.clone();
+        self.simulated.add_device(config)?;
+        self.simulated.set_render_sink(&name, Box::new(sender))?;
+        self.senders.lock().unwrap().push((name, stats));
+        Ok(())
+    }
+
+    // Add a capture device receiving on a local address, through a jitter buffer.
+    // Returns the address that the device listens on, which tells the port when port 0 was given.
+    pub fn add_capture_device<A: ToSocketAddrs>(
+        &self,
+        config: SimDeviceConfig,
+        local: A,
+        protocol: NetProtocol,
+        jitter: JitterSettings,
+    ) -> WasapiRes<SocketAddr> {
+        if config.direction != Direction::Capture {
+            return Err(
+                WasapiError::new("The device configuration is not for a capture device").into(),
+            );
+        }
+        let receiver = NetReceiver::bind(local, protocol, jitter)?;
+        let name = config.name.clone();
+        let buffer = receiver.buffer.clone();
+        let local_addr = receiver.get_local_addr();
+        self.simulated.add_device(config)?;
+        self.simulated
+            .set_capture_source(&name, Box::new(receiver))?;
+        self.receivers.lock().unwrap().push((name, buffer));
+        Ok(local_addr)
+    }
+
+    // Add a render device sending an RTP stream, the device has the format of the stream.
+    // Returns the address that the packets are sent from, to use as the origin of the session description.
+    pub fn add_rtp_sender(&self, stream: &RtpStreamConfig) -> WasapiRes<SocketAddr> {
+        let sender = RtpSender::new(stream)?;
+        let stats = sender.stats.clone();
+        let local_addr = sender.get_local_addr()?;
+        let config = SimDeviceConfig::new(&stream.name, &Direction::Render, &stream.get_format());
+        self.simulated.add_device(config)?;
+        self.simulated
+            .set_render_sink(&stream.name, Box::new(sender))?;
+        self.senders
+            .lock()
+            .unwrap()
+            .push((stream.name.clone(), stats));
+        Ok(local_addr)
+    }
+
+    // Add a capture device receiving an RTP stream, through a jitter buffer.
+    // Returns the address that the device listens on.
+    pub fn add_rtp_receiver(
+        &self,
+        stream: &RtpStreamConfig,
+        jitter: JitterSettings,
+    ) -> WasapiRes<SocketAddr> {
+        let receiver = RtpReceiver::bind(stream, jitter)?;
+        let buffer = receiver.buffer.clone();
+        let local_addr = receiver.get_local_addr();
+        let config = SimDeviceConfig::new(&stream.name, &Direction::Capture, &stream.get_format());
+        self.simulated.add_device(config)?;
+        self.simulated
+            .set_capture_source(&stream.name, Box::new(receiver))?;
+        self.receivers
+            .lock()
+            .unwrap()
+            .push((stream.name.clone(), buffer));
+        Ok(local_addr)
+    }
+
+    // Get the statistics of a render device
+    pub fn get_sender_stats(&self, device: &str) -> WasapiRes<SenderStats> {
+        let senders = self.senders.lock().unwrap();
+        match senders.iter().find(|(name, _)| name == device) {
+            Some((_, stats)) => Ok(*stats.lock().unwrap()),
+            None => {
+                Err(WasapiError::new(format!("Unable to find device {}", device).as_str()).into())
+            }
+        }
+    }
+
+    // Get the statistics of a capture device
+    pub fn get_receiver_stats(&self, device: &str) -> WasapiRes<JitterStats> {
+        let receivers = self.receivers.lock().unwrap();
+        match receivers.iter().find(|(name, _)| name == device) {
+            Some((_, buffer)) => Ok(buffer.lock().unwrap().get_stats()),
+            None => {
+                Err(WasapiError::new(format!("Unable to find device {}", device).as_str()).into())
+            }
+        }
+    }
+
+    // Get the simulated backend, for example to count underruns
+    pub fn get_simulated(&self) -> &SimulatedBackend {
+        &self.simulated
+    }
+}
+
+impl Backend for NetworkBackend {
+    fn get_device_collection(
+        &self,
+        direction: &Direction,
+    ) -> WasapiRes<Box<dyn DeviceCollectionTrait>> {
+        self.simulated.get_device_collection(direction)
+    }
+
+    fn get_default_device(&self, direction: &Direction) -> WasapiRes<Box<dyn DeviceTrait>> {
+        self.simulated.get_default_device(direction)
+    }
+}
@@ -0,0 +1,643 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{
+    AudioClientTrait, Backend, CaptureClientTrait, DeviceCollectionTrait, DeviceTrait, HandleTrait,
+    RenderClientTrait,
+};
+use crate::conversion::{channels_to_bytes, silence};
+use crate::wasapi::{
+    BufferFlags, Direction, FormatSupported, SampleType, ShareMode, WasapiError, WasapiRes,
+    WaveFormat,
+};
+
+// Reserved names of the null devices
+pub const NULL_RENDER_NAME: &str = "Null Render";
+pub const NULL_CAPTURE_NAME: &str = "Null Capture";
+
+// What a null capture device produces.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NullPattern {
+    Silence,
+    // A waveform with values from -1.0 to 1.0, repeated and copied to all channels
+    Waveform(Vec<f64>),
+}
+
+// Settings and statistics of a null device.
+struct NullDevice {
+    name: String,
+    direction: Direction,
+    mix_format: WaveFormat,
+    pattern: NullPattern,
+    // Frames played or captured
+    nbr_frames: u64,
+    // Frames of silence played because the buffer was empty
+    underruns: u64,
+    // Captured packets lost because the buffer was full
+    overflows: u64,
+}
+
+type SharedDevice = Arc<Mutex<NullDevice>>;
+
+// An initialized stream. The device processes one period at each event,
+// and the events follow the monotonic clock from when the stream was started.
+struct NullStream {
+    device: SharedDevice,
+    direction: Direction,
+    format: WaveFormat,
+    sharemode: ShareMode,
+    period_frames: usize,
+    buffer_frames: usize,
+    started: Option<Instant>,
+    // Periods processed by the device, and events seen by the client, since the start
+    nbr_periods: u64,
+    nbr_signaled: u64,
+    // Frames written by the client and not yet played
+    queued: usize,
+    // Captured packets not yet read by the client
+    packets: VecDeque<(Vec<u8>, BufferFlags)>,
+    discontinuity: bool,
+    // One cycle of the capture pattern in the format of the stream, and the position in it
+    pattern: Vec<u8>,
+    pattern_pos: usize,
+}
+
+impl NullStream {
+    fn blockalign(&self) -> usize {
+        self.format.get_blockalign() as usize
+    }
+
+    fn padding(&self) -> usize {
+        match self.direction {
+            Direction::Render => self.queued,
+            Direction::Capture => self
+                .packets
+                .iter()
+                .map(|(data, _)| data.len() / self.blockalign())
+                .sum(),
+        }
+    }
+
+    // Number of periods from the start of the stream until a time
+    fn periods_at(&self, start: Instant, time: Instant) -> u64 {
+        let elapsed = time.saturating_duration_since(start).as_nanos();
+        (elapsed * self.format.get_samplespersec() as u128
+            / (self.period_frames as u128 * 1_000_000_000)) as u64
+    }
+
+    // Time of the n-th event after the start
+    fn event_time(&self, start: Instant, nbr_periods: u64) -> Instant {
+        let nanos = (nbr_periods as u128 * self.period_frames as u128 * 1_000_000_000)
+            .div_ceil(self.format.get_samplespersec() as u128);
+        start + Duration::from_nanos(nanos as u64)
+    }
+
+    // Let the device process the periods that have passed since the last update
+    fn update(&mut self) {
+        let start = match self.started {
+            Some(start) => start,
+            None => return,
+        };
+        let nbr_periods = self.periods_at(start, Instant::now());
+        let new_periods = nbr_periods - self.nbr_periods;
+        self.nbr_periods = nbr_periods;
+        if new_periods == 0 {
+            return;
+        }
+        let new_frames = new_periods * self.period_frames as u64;
+        let device = self.device.clone();
+        let mut device = device.lock().unwrap();
+        device.nbr_frames += new_frames;
+        match self.direction {
+            Direction::Render => {
+                let played = self.queued.min(new_frames as usize);
+                self.queued -= played;
+                device.underruns += new_frames - played as u64;
+            }
+            Direction::Capture => {
+                // When the client is not reading fast enough, the packets that do not fit are lost
+                let room = (self.buffer_frames - self.padding()) / self.period_frames;
+                let kept = new_periods.min(room as u64);
+                for _ in 0..kept {
+                    let data = self.next_packet();
+                    let flags = BufferFlags {
+                        data_discontinuity: self.discontinuity,
+                        ..Default::default()
+                    };
+                    self.discontinuity = false;
+                    self.packets.push_back((data, flags));
+                }
+                let lost = new_periods - kept;
+                if lost > 0 {
+                    device.overflows += lost;
+                    self.discontinuity = true;
+                    let lost_bytes = lost as usize * self.period_frames * self.blockalign();
+                    self.pattern_pos = (self.pattern_pos + lost_bytes) % self.pattern.len();
+                }
+            }
+        }
+    }
+
+    // One period of the capture pattern
+    fn next_packet(&mut self) -> Vec<u8> {
+        let nbr_bytes = self.period_frames * self.blockalign();
+        let mut data = Vec::with_capacity(nbr_bytes);
+        while data.len() < nbr_bytes {
+            let end = self
+                .pattern
+                .len()
+                .min(self.pattern_pos + nbr_bytes - data.len());
+            data.extend_from_slice(&self.pattern[self.pattern_pos..end]);
+            self.pattern_pos = end % self.pattern.len();
+        }
+        data
+    }
+}
+
+// Backend that adds a null render device and a null capture device to the devices of another backend.
+// The null render device consumes frames at exactly the nominal rate and discards them,
+// and the null capture device produces silence or a repeated waveform at the nominal rate.
+// They use the monotonic clock of the system instead of a real device,
+// and appear in the device collections under the reserved names `NULL_RENDER_NAME` and `NULL_CAPTURE_NAME`,
+// after the devices of the other backend.
+// This is meant for soak tests and benchmarks of code built on the backend traits.
+#[derive(Clone)]
+pub struct NullBackend<B: Backend> {
+    inner: B,
+    render: SharedDevice,
+    capture: SharedDevice,
+}
+
+impl<B: Backend> NullBackend<B> {
+    // Wrap a backend, the null devices use 32-bit float stereo at 48 kHz in shared mode
+    pub fn new(inner: B) -> Self {
+        let mix_format = WaveFormat::new(32, 32, &SampleType::Float, 48000, 2);
+        let device = |name: &str, direction: Direction| {
+            Arc::new(Mutex::new(NullDevice {
+                name: name.to_string(),
+                direction,
+                mix_format: mix_format.clone(),
+                pattern: NullPattern::Silence,
+                nbr_frames: 0,
+                underruns: 0,
+                overflows: 0,
+            }))
+        };
+        NullBackend {
+            inner,
+            render: device(NULL_RENDER_NAME, Direction::Render),
+            capture: device(NULL_CAPTURE_NAME, Direction::Capture),
+        }
+    }
+
+    // Get the wrapped backend
+    pub fn get_inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn device(&self, direction: &Direction) -> MutexGuard<'_, NullDevice> {
+        match direction {
+            Direction::Render => self.render.lock().unwrap(),
+            Direction::Capture => self.capture.lock().unwrap(),
+        }
+    }
+
+    // Set the format used in shared mode by the null device, for streams initialized after this
+    pub fn set_mix_format(&self, direction: &Direction, format: &WaveFormat) {
+        self.device(direction).mix_format = format.clone();
+    }
+
+    // Set what the null capture device produces, for streams initialized after this
+    pub fn set_capture_pattern(&self, pattern: NullPattern) {
+        self.device(&Direction::Capture).pattern = pattern;
+    }
+
+    // Get the number of frames played or captured by the null device
+    pub fn get_nbr_frames(&self, direction: &Direction) -> u64 {
+        self.device(direction).nbr_frames
+    }
+
+    // Get the number of frames of silence played by the null render device because its buffer was empty
+    pub fn get_underruns(&self) -> u64 {
+        self.device(&Direction::Render).underruns
+    }
+
+    // Get the number of packets lost by the null capture device because its buffer was full
+    pub fn get_overflows(&self) -> u64 {
+        self.device(&Direction::Capture).overflows
+    }
+}
+
+impl<B: Backend> Backend for NullBackend<B> {
+    fn get_device_collection(
+        &self,
+        direction: &Direction,
+    ) -> WasapiRes<Box<dyn DeviceCollectionTrait>> {
+        let device = match direction {
+            Direction::Render => self.render.clone(),
+            Direction::Capture => self.capture.clone(),
+        };
+        Ok(Box::new(NullDeviceCollection {
+            inner: self.inner.get_device_collection(direction)?,
+            device,
+        }))
+    }
+
+    // The default device of the other backend, or the null device if there is none
+    fn get_default_device(&self, direction: &Direction) -> WasapiRes<Box<dyn DeviceTrait>> {
+        match self.inner.get_default_device(direction) {
+            Ok(device) => Ok(device),
+            Err(_) => {
+                let collection = self.get_device_collection(direction)?;
+                let nbr_devices = collection.get_nbr_devices()?;
+                collection.get_device_at_index(nbr_devices - 1)
+            }
+        }
+    }
+}
+
+struct NullDeviceCollection {
+    inner: Box<dyn DeviceCollectionTrait>,
+    device: SharedDevice,
+}
+
+impl DeviceCollectionTrait for NullDeviceCollection {
+    fn get_nbr_devices(&self) -> WasapiRes<u32> {
+        Ok(self.inner.get_nbr_devices()? + 1)
+    }
+
+    fn get_device_at_index(&self, idx: u32) -> WasapiRes<Box<dyn DeviceTrait>> {
+        let nbr_inner = self.inner.get_nbr_devices()?;
+        if idx < nbr_inner {
+            self.inner.get_device_at_index(idx)
+        } else if idx == nbr_inner {
+            Ok(Box::new(NullDeviceHandle {
+                device: self.device.clone(),
+            }))
+        } else {
+            Err(WasapiError::new("Failed to get device").into())
+        }
+    }
+}
+
+struct NullDeviceHandle {
+    device: SharedDevice,
+}
+
+impl DeviceTrait for NullDeviceHandle {
+    fn get_iaudioclient(&self) -> WasapiRes<Box<dyn AudioClientTrait>> {
+        Ok(Box::new(NullAudioClient {
+            device: self.device.clone(),
+            stream: None,
+        }))
+    }
+
+    fn get_state(&self) -> WasapiRes<u32> {
+        // DEVICE_STATE_ACTIVE
+        Ok(1)
+    }
+
+    fn get_friendlyname(&self) -> WasapiRes<String> {
+        Ok(self.device.lock().unwrap().name.clone())
+    }
+
+    fn get_id(&self) -> WasapiRes<String> {
+        Ok(format!("{{null}}.{}", self.device.lock().unwrap().name))
+    }
+}
+
+type SharedStream = Arc<Mutex<NullStream>>;
+
+struct NullAudioClient {
+    device: SharedDevice,
+    stream: Option<SharedStream>,
+}
+
+impl NullAudioClient {
+    // Lock the stream and let the device catch up with the clock
+    fn stream(&self) -> WasapiRes<MutexGuard<'_, NullStream>> {
+        match &self.stream {
+            Some(stream) => {
+                let mut stream = stream.lock().unwrap();
+                stream.update();
+                Ok(stream)
+            }
+            None => Err(WasapiError::new("Client has not been initialized").into()),
+        }
+    }
+}
+
+// The null devices take any format in exclusive mode, and only the mix format in shared mode
+fn check_format(
+    mix_format: &WaveFormat,
+    wave_fmt: &WaveFormat,
+    sharemode: &ShareMode,
+) -> WasapiRes<FormatSupported> {
+    match sharemode {
+        ShareMode::Shared if wave_fmt != mix_format => {
+            Ok(FormatSupported::ClosestMatch(mix_format.clone()))
+        }
+        _ => {
+            wave_fmt.get_subformat()?;
+            Ok(FormatSupported::Yes)
+        }
+    }
+}
+
+// Convert a duration in 100-nanosecond units to frames
+fn period_to_frames(period: i64, format: &WaveFormat) -> usize {
+    (period as u64 * format.get_samplespersec() as u64).div_ceil(10_000_000) as usize
+}
+
+// Default and minimum periods in 100-nanosecond units
+const DEFAULT_PERIOD: i64 = 100000;
+const MIN_PERIOD: i64 = 30000;
+
+impl AudioClientTrait for NullAudioClient {
+    fn is_supported(
+        &self,
+        wave_fmt: &WaveFormat,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<FormatSupported> {
+        check_format(&self.device.lock().unwrap().mix_format, wave_fmt, sharemode)
+    }
+
+    fn get_periods(&self) -> WasapiRes<(i64, i64)> {
+        Ok((DEFAULT_PERIOD, MIN_PERIOD))
+    }
+
+    fn initialize_client(
+        &mut self,
+        wavefmt: &WaveFormat,
+        period: i64,
+        direction: &Direction,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<()> {
+        if self.stream.is_some() {
+            return Err(WasapiError::new("Client is already initialized").into());
+        }
+        let device = self.device.lock().unwrap();
+        match (&device.direction, direction) {
+            (Direction::Render, Direction::Capture) => {
+                return Err(WasapiError::new(
+                    "Loopback capture is not supported by the null devices",
+                )
+                .into());
+            }
+            (Direction::Capture, Direction::Render) => {
+                return Err(WasapiError::new("Cant render to a capture device").into());
+            }
+            _ => {}
+        }
+        if let FormatSupported::ClosestMatch(_) =
+            check_format(&device.mix_format, wavefmt, sharemode)?
+        {
+            return Err(WasapiError::new("Unsupported format").into());
+        }
+        let (period_frames, buffer_frames) = match sharemode {
+            ShareMode::Shared => {
+                let period_frames = period_to_frames(DEFAULT_PERIOD, wavefmt);
+                (
+                    period_frames,
+                    period_to_frames(period, wavefmt).max(2 * period_frames),
+                )
+            }
+            ShareMode::Exclusive => {
+                let period_frames = period_to_frames(period.max(MIN_PERIOD), wavefmt);
+                (period_frames, period_frames)
+            }
+        };
+        let pattern = match &device.pattern {
+            NullPattern::Waveform(wave) if !wave.is_empty() => {
+                let channels = vec![wave.clone(); wavefmt.get_nchannels() as usize];
+                channels_to_bytes(&channels, wavefmt)?
+            }
+            _ => silence(1, wavefmt),
+        };
+        drop(device);
+        self.stream = Some(Arc::new(Mutex::new(NullStream {
+            device: self.device.clone(),
+            direction: direction.clone(),
+            format: wavefmt.clone(),
+            sharemode: sharemode.clone(),
+            period_frames,
+            buffer_frames,
+            started: None,
+            nbr_periods: 0,
+            nbr_signaled: 0,
+            queued: 0,
+            packets: VecDeque::new(),
+            discontinuity: false,
+            pattern,
+            pattern_pos: 0,
+        })));
+        Ok(())
+    }
+
+    fn set_get_eventhandle(&self) -> WasapiRes<Box<dyn HandleTrait>> {
+        match &self.stream {
+            Some(stream) => Ok(Box::new(NullHandle {
+                stream: stream.clone(),
+            })),
+            None => Err(WasapiError::new("Client has not been initialized").into()),
+        }
+    }
+
+    fn get_bufferframecount(&self) -> WasapiRes<u32> {
+        Ok(self.stream()?.buffer_frames as u32)
+    }
+
+    fn get_current_padding(&self) -> WasapiRes<u32> {
+        Ok(self.stream()?.padding() as u32)
+    }
+
+    fn get_available_frames(&self) -> WasapiRes<u32> {
+        let stream = self.stream()?;
+        let frames = match stream.sharemode {
+            ShareMode::Exclusive => stream.buffer_frames,
+            ShareMode::Shared => stream.buffer_frames.saturating_sub(stream.padding()),
+        };
+        Ok(frames as u32)
+    }
+
+    fn start_stream(&self) -> WasapiRes<()> {
+        let mut stream = self.stream()?;
+        if stream.started.is_some() {
+            return Err(WasapiError::new("The stream is already running").into());
+        }
+        stream.started = Some(Instant::now());
+        stream.nbr_periods = 0;
+        stream.nbr_signaled = 0;
+        Ok(())
+    }
+
+    fn stop_stream(&self) -> WasapiRes<()> {
+        self.stream()?.started = None;
+        Ok(())
+    }
+
+    fn get_audiorenderclient(&self) -> WasapiRes<Box<dyn RenderClientTrait>> {
+        match (&self.stream()?.direction, &self.stream) {
+            (Direction::Render, Some(stream)) => Ok(Box::new(NullRenderClient {
+                stream: stream.clone(),
+            })),
+            _ => Err(WasapiError::new("Failed getting IAudioRenderClient").into()),
+        }
+    }
+
+    fn get_audiocaptureclient(&self) -> WasapiRes<Box<dyn CaptureClientTrait>> {
+        match (&self.stream()?.direction, &self.stream) {
+            (Direction::Capture, Some(stream)) => Ok(Box::new(NullCaptureClient {
+                stream: stream.clone(),
+            })),
+            _ => Err(WasapiError::new("Failed getting IAudioCaptureClient").into()),
+        }
+    }
+}
+
+fn lock_and_update(stream: &SharedStream) -> MutexGuard<'_, NullStream> {
+    let mut stream = stream.lock().unwrap();
+    stream.update();
+    stream
+}
+
+struct NullRenderClient {
+    stream: SharedStream,
+}
+
+impl RenderClientTrait for NullRenderClient {
+    fn write_to_device(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &[u8],
+    ) -> WasapiRes<()> {
+        let nbr_bytes = nbr_frames * byte_per_frame;
+        if nbr_bytes != data.len() {
+            return Err(WasapiError::new(
+                format!(
+                    "Wrong length of data, got {}, expected {}",
+                    data.len(),
+                    nbr_bytes
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        let mut stream = lock_and_update(&self.stream);
+        if byte_per_frame != stream.blockalign() {
+            return Err(WasapiError::new("Wrong number of bytes per frame").into());
+        }
+        if stream.padding() + nbr_frames > stream.buffer_frames {
+            return Err(WasapiError::new("The buffer is too large").into());
+        }
+        stream.queued += nbr_frames;
+        Ok(())
+    }
+
+    fn write_to_device_from_deque(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<()> {
+        let nbr_bytes = nbr_frames * byte_per_frame;
+        if nbr_bytes > data.len() {
+            return Err(WasapiError::new(
+                format!("To little data, got {}, need {}", data.len(), nbr_bytes).as_str(),
+            )
+            .into());
+        }
+        let chunk: Vec<u8> = data.drain(..nbr_bytes).collect();
+        self.write_to_device(nbr_frames, byte_per_frame, &chunk)
+    }
+}
+
+struct NullCaptureClient {
+    stream: SharedStream,
+}
+
+impl CaptureClientTrait for NullCaptureClient {
+    fn get_next_nbr_frames(&self) -> WasapiRes<u32> {
+        let stream = lock_and_update(&self.stream);
+        let blockalign = stream.blockalign();
+        Ok(stream
+            .packets
+            .front()
+            .map(|(data, _)| data.len() / blockalign)
+            .unwrap_or(0) as u32)
+    }
+
+    fn read_from_device(&self, bytes_per_frame: usize, data: &mut [u8]) -> WasapiRes<BufferFlags> {
+        let mut stream = lock_and_update(&self.stream);
+        let packet_len = stream
+            .packets
+            .front()
+            .map(|(packet, _)| packet.len())
+            .unwrap_or(0);
+        if data.len() != packet_len {
+            return Err(WasapiError::new(
+                format!(
+                    "Wrong length of data, got {} frames, expected {} frames",
+                    data.len() / bytes_per_frame,
+                    packet_len / bytes_per_frame
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        match stream.packets.pop_front() {
+            Some((packet, flags)) => {
+                data.copy_from_slice(&packet);
+                Ok(flags)
+            }
+            None => Ok(BufferFlags::default()),
+        }
+    }
+
+    fn read_from_device_to_deque(
+        &self,
+        _bytes_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<BufferFlags> {
+        let mut stream = lock_and_update(&self.stream);
+        match stream.packets.pop_front() {
+            Some((packet, flags)) => {
+                data.extend(packet);
+                Ok(flags)
+            }
+            None => Ok(BufferFlags::default()),
+        }
+    }
+}
+
+struct NullHandle {
+    stream: SharedStream,
+}
+
+impl HandleTrait for NullHandle {
+    // Sleep until the next period of the stream, events that were missed are only signaled once
+    fn wait_for_event(&self, timeout_ms: u32) -> WasapiRes<()> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        loop {
+            let mut stream = lock_and_update(&self.stream);
+            if let Some(start) = stream.started {
+                if stream.nbr_periods > stream.nbr_signaled {
+                    stream.nbr_signaled = stream.nbr_periods;
+                    return Ok(());
+                }
+                let next = stream.event_time(start, stream.nbr_signaled + 1);
+                if next <= deadline {
+                    drop(stream);
+                    thread::sleep(next.saturating_duration_since(Instant::now()));
+                    continue;
+                }
+            }
+            drop(stream);
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            return Err(WasapiError::new("Wait timed out").into());
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::conversion::silence;
+use crate::wasapi::{WasapiError, WasapiRes, WaveFormat};
+
+// Size of the reads from the input pipe, in bytes
+const READ_SIZE: usize = 16384;
+
+// What a source does when a device asks for more frames than the pipe has delivered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnderrunPolicy {
+    // Return the frames that are available, padded with silence
+    Silence,
+    // Wait until enough frames have arrived
+    Block,
+}
+
+// Data shared between a source and its reading thread
+struct PipeState {
+    data: VecDeque<u8>,
+    eof: bool,
+    closed: bool,
+    error: Option<String>,
+}
+
+type SharedState = Arc<(Mutex<PipeState>, Condvar)>;
+
+// Source of raw pcm data read from a pipe, for example stdin.
+// A separate thread reads the pipe into a buffer of limited size,
+// so that the writing program is held back when the buffer is full,
+// and the render loop does not have to wait for the pipe unless it asks to.
+pub struct PipeSource {
+    wave_fmt: WaveFormat,
+    policy: UnderrunPolicy,
+    state: SharedState,
+    nbr_frames: u64,
+    nbr_underruns: usize,
+    finished: bool,
+}
+
+impl PipeSource {
+    // Read from stdin
+    pub fn stdin(
+        wave_fmt: &WaveFormat,
+        policy: UnderrunPolicy,
+        buffer_frames: usize,
+    ) -> WasapiRes<Self> {
+        PipeSource::new(std::io::stdin(), wave_fmt, policy, buffer_frames)
+    }
+
+    // Start reading from a pipe, buffering at most the given number of frames
+    pub fn new<R: Read + Send + 'static>(
+        mut reader: R,
+        wave_fmt: &WaveFormat,
+        policy: UnderrunPolicy,
+        buffer_frames: usize,
+    ) -> WasapiRes<Self> {
+        let max_bytes = buffer_frames.max(1) * wave_fmt.get_blockalign() as usize;
+        let state: SharedState = Arc::new((
+            Mutex::new(PipeState {
+                data: VecDeque::with_capacity(max_bytes),
+                eof: false,
+                closed: false,
+                error: None,
+            }),
+            Condvar::new(),
+        ));
+        let state_reader = state.clone();
+        thread::Builder::new()
+            .name("PipeReader".to_string())
+            .spawn(move || {
+                let (lock, cvar) = &*state_reader;
+                let mut buf = vec![0u8; READ_SIZE];
+                loop {
+                    let result = reader.read(&mut buf);
+                    let mut state = lock.lock().unwrap();
+                    match result {
+                        Ok(0) => state.eof = true,
+                        Ok(len) => {
+                            state.data.extend(&buf[..len]);
+                        }
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            state.error = Some(err.to_string());
+                            state.eof = true;
+                        }
+                    }
+                    cvar.notify_all();
+                    if state.eof {
+                        break;
+                    }
+                    // Wait for room in the buffer before reading more
+                    while state.data.len() >= max_bytes && !state.closed {
+                        state = cvar.wait(state).unwrap();
+                    }
+                    if state.closed {
+                        break;
+                    }
+                }
+            })?;
+        Ok(PipeSource {
+            wave_fmt: wave_fmt.clone(),
+            policy,
+            state,
+            nbr_frames: 0,
+            nbr_underruns: 0,
+            finished: false,
+        })
+    }
+
+    // Get the format of the data
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the number of frames received from the pipe and returned so far, not counting padding
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.nbr_frames
+    }
+
+    // Get the number of times the output had to be padded with silence
+    pub fn get_nbr_underruns(&self) -> usize {
+        self.nbr_underruns
+    }
+
+    // Get the number of whole frames waiting in the buffer
+    pub fn get_buffered_frames(&self) -> usize {
+        let state = self.state.0.lock().unwrap();
+        state.data.len() / self.wave_fmt.get_blockalign() as usize
+    }
+
+    // Check if the pipe has ended and all its data has been returned
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Wait until a number of frames are buffered, or the pipe has ended.
+    // Returns false on a timeout. Used to fill the buffer before starting a stream.
+    pub fn wait_for_frames(&self, nbr_frames: usize, timeout: Duration) -> bool {
+        let bytes = nbr_frames * self.wave_fmt.get_blockalign() as usize;
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.data.len() < bytes && !state.eof {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    // Get the next frames for the device.
+    // With the Silence policy the result always has the requested length while the pipe is open,
+    // with Block this waits for the data. At the end of the pipe the remaining whole frames are returned,
+    // which may be fewer than requested or none, and an incomplete last frame is dropped.
+    pub fn fill(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        let blockalign = self.wave_fmt.get_blockalign() as usize;
+        let bytes = nbr_frames * blockalign;
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if self.policy == UnderrunPolicy::Block {
+            while state.data.len() < bytes && !state.eof {
+                state = cvar.wait(state).unwrap();
+            }
+        }
+        let available = bytes.min(state.data.len() - state.data.len() % blockalign);
+        let mut data: Vec<u8> = state.data.drain(..available).collect();
+        cvar.notify_all();
+        self.nbr_frames += (available / blockalign) as u64;
+        if data.len() < bytes {
+            if state.eof {
+                if let Some(err) = state.error.take() {
+                    return Err(WasapiError::new(&err).into());
+                }
+                state.data.clear();
+                self.finished = true;
+            } else {
+                self.nbr_underruns += 1;
+                data.extend(silence(nbr_frames - available / blockalign, &self.wave_fmt));
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl Drop for PipeSource {
+    fn drop(&mut self) {
+        // Let the reading thread exit. A thread waiting in a read ends when the pipe does.
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().closed = true;
+        cvar.notify_all();
+    }
+}
+
+// Sink writing raw pcm data to a pipe, for example stdout.
+// The writing is done in a separate thread so that a slow reader does not delay the capture loop.
+pub struct PipeSink {
+    wave_fmt: WaveFormat,
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    handle: Option<thread::JoinHandle<Result<u64, String>>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl PipeSink {
+    // Write to stdout
+    pub fn stdout(wave_fmt: &WaveFormat) -> WasapiRes<Self> {
+        PipeSink::new(std::io::stdout(), wave_fmt)
+    }
+
+    // Start the writing thread
+    pub fn new<W: Write + Send + 'static>(mut writer: W, wave_fmt: &WaveFormat) -> WasapiRes<Self> {
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let pending_writer = pending.clone();
+        let blockalign = wave_fmt.get_blockalign() as u64;
+        let handle = thread::Builder::new()
+            .name("PipeWriter".to_string())
+            .spawn(move || {
+                let mut nbr_bytes = 0;
+                for data in receiver.iter() {
+                    writer.write_all(&data).map_err(|err| err.to_string())?;
+                    // Pass the data on without waiting for more
+                    writer.flush().map_err(|err| err.to_string())?;
+                    nbr_bytes += data.len() as u64;
+                    pending_writer.fetch_sub(data.len(), Ordering::AcqRel);
+                }
+                writer.flush().map_err(|err| err.to_string())?;
+                Ok(nbr_bytes / blockalign)
+            })?;
+        Ok(PipeSink {
+            wave_fmt: wave_fmt.clone(),
+            sender: Some(sender),
+            handle: Some(handle),
+            pending,
+        })
+    }
+
+    // Get the format of the data
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the number of bytes that are queued but not yet written
+    pub fn get_pending_bytes(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    // Queue data for writing, whole frames only. This never waits for the pipe,
+    // and fails once the pipe has been closed by the reading side.
+    pub fn write_bytes(&self, data: &[u8]) -> WasapiRes<()> {
+        if !data
+            .len()
+            .is_multiple_of(self.wave_fmt.get_blockalign() as usize)
+        {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| WasapiError::new("The sink is closed"))?;
+        self.pending.fetch_add(data.len(), Ordering::AcqRel);
+        if sender.send(data.to_vec()).is_err() {
+            return Err(WasapiError::new("The pipe is closed").into());
+        }
+        Ok(())
+    }
+
+    // Write all queued data, and return the number of frames written
+    pub fn close(mut self) -> WasapiRes<u64> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> WasapiRes<u64> {
+        self.sender.take();
+        let handle = self
+            .handle
+            .take()
+            .ok_or_else(|| WasapiError::new("The sink is closed"))?;
+        match handle.join() {
+            Ok(Ok(nbr_frames)) => Ok(nbr_frames),
+            Ok(Err(err)) => Err(WasapiError::new(&err).into()),
+            Err(_) => Err(WasapiError::new("The writer thread panicked").into()),
+        }
+    }
+}
+
+impl Drop for PipeSink {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            let _ = self.finish();
+        }
+    }
+}
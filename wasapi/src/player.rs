@@ -0,0 +1,483 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use crate::audiofile::{open_audio_file, AudioFileReader};
+use crate::conversion::{channels_to_bytes, silence};
+use crate::resample::Resampler;
+use crate::wasapi::{WasapiError, WasapiRes, WaveFormat};
+
+// Settings for the player.
+#[derive(Clone, Debug)]
+pub struct PlayerSettings {
+    // Convert the sample rate and channels of files that differ from the output.
+    // If false, playback stops at the end of the previous file and a FormatChange event is given,
+    // so that the output can be re-initialized with the new format.
+    pub resample: bool,
+    // Number of output frames to decode ahead of playback
+    pub decode_ahead: usize,
+    // Number of frames read from a file at a time
+    pub read_size: usize,
+}
+
+impl PlayerSettings {
+    // Default settings, resampling and decoding 16384 frames ahead
+    pub fn new() -> Self {
+        PlayerSettings {
+            resample: true,
+            decode_ahead: 16384,
+            read_size: 4096,
+        }
+    }
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        PlayerSettings::new()
+    }
+}
+
+// Something that happened during playback, at the start of the output block given by the frame position.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlayerEvent {
+    // A track started playing, at an output frame
+    TrackStarted { index: usize, frame: u64 },
+    // A track finished playing, at an output frame
+    TrackFinished { index: usize, frame: u64 },
+    // The next track needs a new output format, the output should be re-initialized and set_output_format called
+    FormatChange(WaveFormat),
+    // All tracks have been played
+    QueueFinished,
+}
+
+// Playback position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerPosition {
+    // Index of the track that is playing, None when not playing
+    pub track: Option<usize>,
+    // Position in the track, in frames of the track
+    pub frame: u64,
+    // Position in the track in seconds
+    pub time: f64,
+    // Number of frames given to the output, including silence
+    pub output_frames: u64,
+}
+
+// Function called with the playback position.
+type PositionCallback = Box<dyn FnMut(&PlayerPosition)>;
+
+struct Track {
+    name: String,
+    reader: Box<dyn AudioFileReader>,
+}
+
+// The start of a track in the decoded output.
+#[derive(Clone, Copy, Debug)]
+struct Mark {
+    // Output frame where the track starts
+    frame: u64,
+    index: usize,
+    // Position in the track at that frame, and the sample rate of the track
+    offset: u64,
+    samplerate: usize,
+}
+
+// Playback engine for a queue of files, played back to back without gaps.
+// The output is pulled with fill, normally from the render loop.
+// Files are decoded ahead of playback and converted to the output format.
+// Tracks with the same sample rate share the resampler, so that the splice between them is seamless.
+pub struct Player {
+    output_format: WaveFormat,
+    settings: PlayerSettings,
+    tracks: Vec<Track>,
+    // Track being decoded, and if its start has been marked
+    decode_index: usize,
+    decode_started: bool,
+    // Convert the next track even if the format differs, after re-initializing the output
+    force_convert: bool,
+    resampler: Option<Resampler>,
+    // Input frames given to the resampler, and the output frame where its stream started
+    resample_input: u64,
+    resample_base: u64,
+    // Decoded output samples, one deque per output channel
+    buffer: Vec<VecDeque<f64>>,
+    // Number of output frames decoded, and played
+    decoded: u64,
+    played: u64,
+    marks: VecDeque<Mark>,
+    playing: Option<Mark>,
+    pending_format: Option<WaveFormat>,
+    paused: bool,
+    finished: bool,
+    events: Vec<PlayerEvent>,
+    callback: Option<PositionCallback>,
+}
+
+impl Player {
+    // Create a player with an output format
+    pub fn new(output_format: &WaveFormat, settings: PlayerSettings) -> Self {
+        Player {
+            output_format: output_format.clone(),
+            settings,
+            tracks: Vec::new(),
+            decode_index: 0,
+            decode_started: false,
+            force_convert: false,
+            resampler: None,
+            resample_input: 0,
+            resample_base: 0,
+            buffer: vec![VecDeque::new(); output_format.get_nchannels() as usize],
+            decoded: 0,
+            played: 0,
+            marks: VecDeque::new(),
+            playing: None,
+            pending_format: None,
+            paused: false,
+            finished: false,
+            events: Vec::new(),
+            callback: None,
+        }
+    }
+
+    // Add a track to the end of the queue
+    pub fn enqueue(&mut self, name: &str, reader: Box<dyn AudioFileReader>) {
+        self.tracks.push(Track {
+            name: name.to_string(),
+            reader,
+        });
+        self.finished = false;
+    }
+
+    // Add a file of any supported type to the end of the queue
+    pub fn enqueue_file<P: AsRef<Path>>(&mut self, path: P) -> WasapiRes<()> {
+        let reader = open_audio_file(&path)?;
+        self.enqueue(&path.as_ref().to_string_lossy(), reader);
+        Ok(())
+    }
+
+    // Get the number of tracks in the queue, including the ones already played
+    pub fn get_nbr_tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    // Get the name of a track
+    pub fn get_track_name(&self, index: usize) -> Option<&str> {
+        self.tracks.get(index).map(|track| track.name.as_str())
+    }
+
+    // Get the output format
+    pub fn get_output_format(&self) -> WaveFormat {
+        self.output_format.clone()
+    }
+
+    // Set a new output format after a FormatChange event, once the output has been re-initialized
+    pub fn set_output_format(&mut self, output_format: &WaveFormat) {
+        self.output_format = output_format.clone();
+        self.buffer = vec![VecDeque::new(); output_format.get_nchannels() as usize];
+        self.decoded = self.played;
+        self.marks.clear();
+        self.resampler = None;
+        self.pending_format = None;
+        self.force_convert = true;
+    }
+
+    // Set a function that is called with the position after every block of output
+    pub fn set_position_callback<F: FnMut(&PlayerPosition) + 'static>(&mut self, callback: F) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    // Pause playback, the output is silence until resumed
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    // Resume after a pause
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    // Check if playback is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Check if all tracks have been played
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Get the events since the last call
+    pub fn take_events(&mut self) -> Vec<PlayerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Get the current playback position
+    pub fn get_position(&self) -> PlayerPosition {
+        match self.playing {
+            Some(mark) => {
+                let output_rate = self.output_format.get_samplespersec() as u64;
+                let frame = mark.offset
+                    + self.played.saturating_sub(mark.frame) * mark.samplerate as u64 / output_rate;
+                PlayerPosition {
+                    track: Some(mark.index),
+                    frame,
+                    time: frame as f64 / mark.samplerate as f64,
+                    output_frames: self.played,
+                }
+            }
+            None => PlayerPosition {
+                track: None,
+                frame: 0,
+                time: 0.0,
+                output_frames: self.played,
+            },
+        }
+    }
+
+    // Drop everything that was decoded ahead, and restart decoding from a track
+    fn restart_from(&mut self, index: usize, frame: u64) -> WasapiRes<()> {
+        let last = self.decode_index.min(self.tracks.len().saturating_sub(1));
+        for track in self.tracks.iter_mut().take(last + 1).skip(index) {
+            track.reader.seek(0)?;
+        }
+        self.tracks[index].reader.seek(frame)?;
+        for chan in self.buffer.iter_mut() {
+            chan.clear();
+        }
+        self.decoded = self.played;
+        self.marks.clear();
+        self.resampler = None;
+        self.pending_format = None;
+        self.decode_index = index;
+        self.decode_started = false;
+        self.finished = false;
+        Ok(())
+    }
+
+    // Move to a frame in the track that is playing
+    pub fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        let mark = self
+            .playing
+            .ok_or_else(|| WasapiError::new("Nothing is playing"))?;
+        self.restart_from(mark.index, frame)
+    }
+
+    // Move to a time in seconds in the track that is playing
+    pub fn seek_time(&mut self, seconds: f64) -> WasapiRes<()> {
+        let mark = self
+            .playing
+            .ok_or_else(|| WasapiError::new("Nothing is playing"))?;
+        self.restart_from(
+            mark.index,
+            (seconds.max(0.0) * mark.samplerate as f64) as u64,
+        )
+    }
+
+    // Move to the start of a track
+    pub fn skip_to(&mut self, index: usize) -> WasapiRes<()> {
+        if index >= self.tracks.len() {
+            return Err(WasapiError::new("No such track").into());
+        }
+        self.restart_from(index, 0)
+    }
+
+    fn buffered(&self) -> usize {
+        self.buffer[0].len()
+    }
+
+    fn push_output(&mut self, frames: Vec<Vec<f64>>) {
+        let nbr_frames = frames.first().map(|chan| chan.len()).unwrap_or(0);
+        for (chan, values) in self.buffer.iter_mut().zip(frames) {
+            chan.extend(values);
+        }
+        self.decoded += nbr_frames as u64;
+    }
+
+    fn flush_resampler(&mut self) {
+        if let Some(mut resampler) = self.resampler.take() {
+            let tail = resampler.flush();
+            self.push_output(tail);
+        }
+    }
+
+    // Map the channels of a file to the output channels.
+    // Mono is copied to all outputs, otherwise extra channels are dropped and missing ones are silent.
+    fn map_channels(&self, frames: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+        let nbr_out = self.buffer.len();
+        if frames.len() == nbr_out {
+            return frames;
+        }
+        let len = frames[0].len();
+        (0..nbr_out)
+            .map(|chan| {
+                if frames.len() == 1 {
+                    frames[0].clone()
+                } else if chan < frames.len() {
+                    frames[chan].clone()
+                } else {
+                    vec![0.0; len]
+                }
+            })
+            .collect()
+    }
+
+    // Start decoding the track at decode_index, returns false if the output must be re-initialized first
+    fn start_track(&mut self) -> WasapiRes<bool> {
+        let output_rate = self.output_format.get_samplespersec() as usize;
+        let format = self.tracks[self.decode_index].reader.get_format();
+        let samplerate = format.get_samplespersec() as usize;
+        let channels = format.get_nchannels() as usize;
+        if !self.settings.resample
+            && !self.force_convert
+            && (samplerate != output_rate || channels != self.buffer.len())
+        {
+            self.flush_resampler();
+            self.pending_format = Some(format);
+            return Ok(false);
+        }
+        self.force_convert = false;
+        if self
+            .resampler
+            .as_ref()
+            .is_some_and(|resampler| resampler.get_in_rate() != samplerate)
+        {
+            self.flush_resampler();
+        }
+        if samplerate != output_rate && self.resampler.is_none() {
+            self.resampler = Some(Resampler::new(samplerate, output_rate, self.buffer.len())?);
+            self.resample_base = self.decoded;
+            self.resample_input = 0;
+        }
+        let frame = match self.resampler {
+            Some(_) => {
+                self.resample_base
+                    + (self.resample_input * output_rate as u64).div_ceil(samplerate as u64)
+            }
+            None => self.decoded,
+        };
+        self.marks.push_back(Mark {
+            frame,
+            index: self.decode_index,
+            offset: self.tracks[self.decode_index].reader.get_position(),
+            samplerate,
+        });
+        self.decode_started = true;
+        Ok(true)
+    }
+
+    // Decode until there are enough frames buffered, or the end of the queue is reached
+    fn decode_ahead(&mut self, needed: usize) -> WasapiRes<()> {
+        let target = needed.max(self.settings.decode_ahead);
+        while self.buffered() < target && self.pending_format.is_none() {
+            if self.decode_index >= self.tracks.len() {
+                self.flush_resampler();
+                break;
+            }
+            if !self.decode_started && !self.start_track()? {
+                break;
+            }
+            let read_size = self.settings.read_size;
+            let frames = self.tracks[self.decode_index]
+                .reader
+                .read_frames(read_size)?;
+            if frames.is_empty() || frames[0].is_empty() {
+                self.decode_index += 1;
+                self.decode_started = false;
+                continue;
+            }
+            let frames = self.map_channels(frames);
+            let output = match self.resampler.as_mut() {
+                Some(resampler) => {
+                    self.resample_input += frames[0].len() as u64;
+                    resampler.process(&frames)?
+                }
+                None => frames,
+            };
+            self.push_output(output);
+        }
+        Ok(())
+    }
+
+    // Get the next nbr_frames frames of output, in the output format.
+    // Less is returned only when the output must be re-initialized for a new format,
+    // the rest of the output after the last track is silence.
+    pub fn fill(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        if self.paused {
+            self.report_position();
+            return Ok(silence(nbr_frames, &self.output_format));
+        }
+        self.decode_ahead(nbr_frames)?;
+        let take = nbr_frames.min(self.buffered());
+        let end = self.played + take as u64;
+        while let Some(mark) = self.marks.front().copied() {
+            if mark.frame >= end && !(take == 0 && mark.frame == end) {
+                break;
+            }
+            self.marks.pop_front();
+            // A seek restarts the same track without new events
+            if self.playing.map(|playing| playing.index) != Some(mark.index) {
+                if let Some(previous) = self.playing {
+                    self.events.push(PlayerEvent::TrackFinished {
+                        index: previous.index,
+                        frame: mark.frame,
+                    });
+                }
+                self.events.push(PlayerEvent::TrackStarted {
+                    index: mark.index,
+                    frame: mark.frame,
+                });
+            }
+            self.playing = Some(mark);
+        }
+        let frames: Vec<Vec<f64>> = self
+            .buffer
+            .iter_mut()
+            .map(|chan| chan.drain(..take).collect())
+            .collect();
+        let mut data = channels_to_bytes(&frames, &self.output_format)?;
+        self.played = end;
+        if self.buffered() == 0 {
+            if let Some(format) = self.pending_format.clone() {
+                if let Some(previous) = self.playing.take() {
+                    self.events.push(PlayerEvent::TrackFinished {
+                        index: previous.index,
+                        frame: self.played,
+                    });
+                }
+                if !self
+                    .events
+                    .contains(&PlayerEvent::FormatChange(format.clone()))
+                {
+                    self.events.push(PlayerEvent::FormatChange(format));
+                }
+                self.report_position();
+                return Ok(data);
+            }
+            if self.decode_index >= self.tracks.len() && !self.finished {
+                if let Some(previous) = self.playing.take() {
+                    self.events.push(PlayerEvent::TrackFinished {
+                        index: previous.index,
+                        frame: self.played,
+                    });
+                }
+                self.events.push(PlayerEvent::QueueFinished);
+                self.finished = true;
+            }
+        }
+        if take < nbr_frames {
+            data.extend(silence(nbr_frames - take, &self.output_format));
+            self.played += (nbr_frames - take) as u64;
+            self.decoded = self.played;
+        }
+        self.report_position();
+        Ok(data)
+    }
+
+    fn report_position(&mut self) {
+        if self.callback.is_some() {
+            let position = self.get_position();
+            if let Some(callback) = self.callback.as_mut() {
+                callback(&position);
+            }
+        }
+    }
+}
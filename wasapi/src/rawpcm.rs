@@ -0,0 +1,354 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bwf::{from_hex, to_hex, utc_date_time};
+use crate::conversion::bytes_to_channels;
+use crate::wasapi::{BufferFlags, SampleType, WasapiError, WasapiRes, WaveFormat};
+
+// Version of the sidecar layout.
+const SIDECAR_VERSION: u32 = 1;
+
+// A captured buffer that had one or more flags set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PcmEvent {
+    // Position of the first frame of the buffer
+    pub position: u64,
+    // Length of the buffer in frames
+    pub nbr_frames: u64,
+    pub data_discontinuity: bool,
+    pub silent: bool,
+    pub timestamp_error: bool,
+}
+
+impl PcmEvent {
+    // Get the flags of the buffer
+    pub fn get_flags(&self) -> BufferFlags {
+        BufferFlags {
+            data_discontinuity: self.data_discontinuity,
+            silent: self.silent,
+            timestamp_error: self.timestamp_error,
+        }
+    }
+}
+
+// Contents of the JSON sidecar of a raw PCM dump.
+// The format is given both as plain fields, for scripts, and as the exact
+// WAVEFORMATEX(TENSIBLE) bytes in hex. When reading, the bytes take precedence if present.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PcmSidecar {
+    pub version: u32,
+    pub samplerate: usize,
+    pub channels: usize,
+    pub bits_per_sample: usize,
+    pub valid_bits_per_sample: usize,
+    // "int" or "float"
+    pub sample_type: String,
+    pub block_align: usize,
+    pub channel_mask: u32,
+    #[serde(default)]
+    pub wave_format: Option<String>,
+    #[serde(default)]
+    pub device_id: String,
+    // Start of the stream in UTC, as "yyyy-mm-ddThh:mm:ssZ"
+    #[serde(default)]
+    pub start_time: String,
+    // Start of the stream in seconds since 1970-01-01 UTC
+    #[serde(default)]
+    pub start_time_unix: f64,
+    // Length of the dump in frames
+    #[serde(default)]
+    pub nbr_frames: u64,
+    #[serde(default)]
+    pub events: Vec<PcmEvent>,
+}
+
+impl PcmSidecar {
+    // Create the sidecar for an empty dump
+    pub fn new(wave_fmt: &WaveFormat, device_id: &str, start_time: SystemTime) -> WasapiRes<Self> {
+        let sample_type = match wave_fmt.get_subformat()? {
+            SampleType::Float => "float",
+            SampleType::Int => "int",
+        };
+        let (date, time, _) = utc_date_time(start_time);
+        Ok(PcmSidecar {
+            version: SIDECAR_VERSION,
+            samplerate: wave_fmt.get_samplespersec() as usize,
+            channels: wave_fmt.get_nchannels() as usize,
+            bits_per_sample: wave_fmt.get_bitspersample() as usize,
+            valid_bits_per_sample: wave_fmt.get_validbitspersample() as usize,
+            sample_type: sample_type.to_string(),
+            block_align: wave_fmt.get_blockalign() as usize,
+            channel_mask: wave_fmt.get_dwchannelmask(),
+            wave_format: Some(to_hex(&wave_fmt.to_bytes())),
+            device_id: device_id.to_string(),
+            start_time: format!("{}T{}Z", date, time),
+            start_time_unix: start_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            nbr_frames: 0,
+            events: Vec::new(),
+        })
+    }
+
+    // Get the format of the dump
+    pub fn get_format(&self) -> WasapiRes<WaveFormat> {
+        if let Some(hex) = &self.wave_format {
+            return WaveFormat::from_bytes(&from_hex(hex)?);
+        }
+        let sample_type = match self.sample_type.as_str() {
+            "float" => SampleType::Float,
+            "int" => SampleType::Int,
+            _ => return Err(WasapiError::new("Unknown sample type").into()),
+        };
+        if self.channels == 0
+            || self.bits_per_sample == 0
+            || !self.bits_per_sample.is_multiple_of(8)
+        {
+            return Err(WasapiError::new("Invalid format in sidecar").into());
+        }
+        let mut wave_fmt = WaveFormat::new(
+            self.bits_per_sample,
+            self.valid_bits_per_sample,
+            &sample_type,
+            self.samplerate,
+            self.channels,
+        );
+        wave_fmt.set_dwchannelmask(self.channel_mask);
+        Ok(wave_fmt)
+    }
+
+    // Get the start time of the stream
+    pub fn get_start_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs_f64(self.start_time_unix.max(0.0))
+    }
+
+    // Get the sidecar as pretty-printed JSON
+    pub fn to_json(&self) -> WasapiRes<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    // Parse a sidecar from JSON
+    pub fn from_json(json: &str) -> WasapiRes<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+// Get the path of the sidecar of a dump, the name of the dump with ".json" appended.
+pub fn sidecar_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut name: OsString = path.as_ref().as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+// Writer of headerless PCM dumps.
+// The data is written exactly as given, and the sidecar is written when the writer is closed or dropped.
+pub struct RawPcmWriter<W: Write> {
+    writer: W,
+    sidecar: PcmSidecar,
+    blockalign: usize,
+    sidecar_path: Option<PathBuf>,
+    closed: bool,
+}
+
+impl RawPcmWriter<BufWriter<File>> {
+    // Create a dump file, with the sidecar next to it
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        wave_fmt: &WaveFormat,
+        device_id: &str,
+        start_time: SystemTime,
+    ) -> WasapiRes<Self> {
+        let mut writer = RawPcmWriter::new(
+            BufWriter::new(File::create(&path)?),
+            wave_fmt,
+            device_id,
+            start_time,
+        )?;
+        writer.sidecar_path = Some(sidecar_path(path));
+        Ok(writer)
+    }
+}
+
+impl<W: Write> RawPcmWriter<W> {
+    // Create a writer that only writes the data, the sidecar is available from get_sidecar
+    pub fn new(
+        writer: W,
+        wave_fmt: &WaveFormat,
+        device_id: &str,
+        start_time: SystemTime,
+    ) -> WasapiRes<Self> {
+        Ok(RawPcmWriter {
+            writer,
+            sidecar: PcmSidecar::new(wave_fmt, device_id, start_time)?,
+            blockalign: wave_fmt.get_blockalign() as usize,
+            sidecar_path: None,
+            closed: false,
+        })
+    }
+
+    // Get the sidecar as it is so far
+    pub fn get_sidecar(&self) -> &PcmSidecar {
+        &self.sidecar
+    }
+
+    // Get the number of frames written so far
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.sidecar.nbr_frames
+    }
+
+    // Get a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    // Write a buffer as returned by AudioCaptureClient::read_from_device, with its flags.
+    // Buffers with any flag set are recorded as events in the sidecar.
+    pub fn write_buffer(&mut self, data: &[u8], flags: &BufferFlags) -> WasapiRes<()> {
+        if flags.is_any_set() {
+            self.sidecar.events.push(PcmEvent {
+                position: self.sidecar.nbr_frames,
+                nbr_frames: (data.len() / self.blockalign) as u64,
+                data_discontinuity: flags.data_discontinuity,
+                silent: flags.silent,
+                timestamp_error: flags.timestamp_error,
+            });
+        }
+        self.write_bytes(data)
+    }
+
+    // Write raw data, whole frames only
+    pub fn write_bytes(&mut self, data: &[u8]) -> WasapiRes<()> {
+        if self.closed {
+            return Err(WasapiError::new("The writer is closed").into());
+        }
+        if !data.len().is_multiple_of(self.blockalign) {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        self.writer.write_all(data)?;
+        self.sidecar.nbr_frames += (data.len() / self.blockalign) as u64;
+        Ok(())
+    }
+
+    // Flush the data, and write the sidecar as it is so far
+    pub fn flush(&mut self) -> WasapiRes<()> {
+        self.writer.flush()?;
+        if let Some(path) = &self.sidecar_path {
+            std::fs::write(path, self.sidecar.to_json()?)?;
+        }
+        Ok(())
+    }
+
+    // Flush the data and write the final sidecar
+    pub fn close(&mut self) -> WasapiRes<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.flush()?;
+        self.closed = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for RawPcmWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+// Reader of headerless PCM dumps, with the format given by a sidecar.
+pub struct RawPcmReader<R: Read + Seek> {
+    reader: R,
+    sidecar: PcmSidecar,
+    wave_fmt: WaveFormat,
+    nbr_frames: u64,
+    position: u64,
+}
+
+impl RawPcmReader<BufReader<File>> {
+    // Open a dump file, with the sidecar next to it
+    pub fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        let json = std::fs::read_to_string(sidecar_path(&path))?;
+        let sidecar = PcmSidecar::from_json(&json)?;
+        RawPcmReader::new(BufReader::new(File::open(path)?), sidecar)
+    }
+}
+
+impl<R: Read + Seek> RawPcmReader<R> {
+    // Create a reader for the data of a dump.
+    // The length is given by the data, a partial frame at the end is ignored.
+    pub fn new(mut reader: R, sidecar: PcmSidecar) -> WasapiRes<Self> {
+        let wave_fmt = sidecar.get_format()?;
+        let data_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(RawPcmReader {
+            reader,
+            nbr_frames: data_len / wave_fmt.get_blockalign() as u64,
+            sidecar,
+            wave_fmt,
+            position: 0,
+        })
+    }
+
+    // Get the format of the data
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the sidecar
+    pub fn get_sidecar(&self) -> &PcmSidecar {
+        &self.sidecar
+    }
+
+    // Get the events that overlap a range of frames
+    pub fn get_events(&self, start: u64, nbr_frames: u64) -> Vec<PcmEvent> {
+        self.sidecar
+            .events
+            .iter()
+            .filter(|event| {
+                event.position < start + nbr_frames && event.position + event.nbr_frames > start
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Get the length of the dump in frames
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.nbr_frames
+    }
+
+    // Get the current position in frames
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    // Move to a frame
+    pub fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        let frame = frame.min(self.nbr_frames);
+        self.reader.seek(SeekFrom::Start(
+            frame * self.wave_fmt.get_blockalign() as u64,
+        ))?;
+        self.position = frame;
+        Ok(())
+    }
+
+    // Read raw data for up to nbr_frames frames, less at the end of the dump
+    pub fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        let nbr_frames = (nbr_frames as u64).min(self.nbr_frames - self.position);
+        let mut data = vec![0u8; nbr_frames as usize * self.wave_fmt.get_blockalign() as usize];
+        self.reader.read_exact(&mut data)?;
+        self.position += nbr_frames;
+        Ok(data)
+    }
+
+    // Read up to nbr_frames frames, as one vector per channel
+    pub fn read_frames(&mut self, nbr_frames: usize) -> WasapiRes<Vec<Vec<f64>>> {
+        let data = self.read_bytes(nbr_frames)?;
+        bytes_to_channels(&data, &self.wave_fmt)
+    }
+}
@@ -0,0 +1,169 @@
+use std::f64::consts::PI;
+
+use crate::wasapi::{WasapiError, WasapiRes};
+
+// Number of input samples on each side of an output sample used by the interpolation filter.
+const HALF_LENGTH: usize = 32;
+// Number of table points per input sample.
+const OVERSAMPLING: usize = 256;
+
+// Greatest common divisor, to reduce the ratio of the sample rates.
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Streaming sample rate converter, using a Blackman windowed sinc filter.
+// The output is aligned with the input, the first output sample is at the time of the first input sample,
+// and a stream of n input frames gives ceil(n * out_rate / in_rate) output frames.
+pub struct Resampler {
+    samplerate_in: usize,
+    // Ratio of the sample rates, reduced to the smallest integers
+    in_rate: u64,
+    out_rate: u64,
+    nbr_channels: usize,
+    // Filter kernel sampled at OVERSAMPLING points per input sample, from 0 to HALF_LENGTH
+    table: Vec<f64>,
+    // Input samples not yet fully used, and the input index of the first one
+    buffer: Vec<Vec<f64>>,
+    buffer_start: u64,
+    // Total number of input frames received, and output frames produced
+    nbr_input: u64,
+    nbr_output: u64,
+}
+
+impl Resampler {
+    // Create a resampler for a number of channels
+    pub fn new(in_rate: usize, out_rate: usize, nbr_channels: usize) -> WasapiRes<Self> {
+        if in_rate == 0 || out_rate == 0 || nbr_channels == 0 {
+            return Err(WasapiError::new("Invalid resampler parameters").into());
+        }
+        let divisor = gcd(in_rate as u64, out_rate as u64);
+        // Cut off a bit below the lowest of the two Nyquist frequencies
+        let cutoff = 0.95 * (out_rate as f64 / in_rate as f64).min(1.0);
+        let table = (0..=HALF_LENGTH * OVERSAMPLING + 1)
+            .map(|n| {
+                let x = n as f64 / OVERSAMPLING as f64;
+                if x >= HALF_LENGTH as f64 {
+                    return 0.0;
+                }
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * cutoff * x).sin() / (PI * cutoff * x)
+                };
+                let w = PI * (x / HALF_LENGTH as f64 + 1.0);
+                let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                cutoff * sinc * window
+            })
+            .collect();
+        Ok(Resampler {
+            samplerate_in: in_rate,
+            in_rate: in_rate as u64 / divisor,
+            out_rate: out_rate as u64 / divisor,
+            nbr_channels,
+            table,
+            buffer: vec![Vec::new(); nbr_channels],
+            buffer_start: 0,
+            nbr_input: 0,
+            nbr_output: 0,
+        })
+    }
+
+    // Get the input sample rate
+    pub fn get_in_rate(&self) -> usize {
+        self.samplerate_in
+    }
+
+    // Get the number of channels
+    pub fn get_nbr_channels(&self) -> usize {
+        self.nbr_channels
+    }
+
+    fn kernel(&self, distance: f64) -> f64 {
+        let position = distance.abs() * OVERSAMPLING as f64;
+        let index = position as usize;
+        if index >= HALF_LENGTH * OVERSAMPLING {
+            return 0.0;
+        }
+        let fraction = position - index as f64;
+        self.table[index] * (1.0 - fraction) + self.table[index + 1] * fraction
+    }
+
+    // Produce output frames while the input needed for them is available,
+    // up to a limit in output frames
+    fn produce(&mut self, available_end: u64, limit: u64) -> Vec<Vec<f64>> {
+        let mut output = vec![Vec::new(); self.nbr_channels];
+        let mut weights = vec![0.0; 2 * HALF_LENGTH];
+        while self.nbr_output < limit {
+            // Time of the output sample in input samples, as an integer part and a fraction
+            let numerator = self.nbr_output * self.in_rate;
+            let center = numerator / self.out_rate;
+            let fraction = (numerator % self.out_rate) as f64 / self.out_rate as f64;
+            if center + HALF_LENGTH as u64 >= available_end {
+                break;
+            }
+            let first = center as i64 - HALF_LENGTH as i64 + 1;
+            for (k, weight) in weights.iter_mut().enumerate() {
+                *weight = self.kernel(fraction + HALF_LENGTH as f64 - 1.0 - k as f64);
+            }
+            for (chan, out) in self.buffer.iter().zip(output.iter_mut()) {
+                let mut sum = 0.0;
+                for (k, weight) in weights.iter().enumerate() {
+                    let index = first + k as i64 - self.buffer_start as i64;
+                    if index >= 0 && (index as usize) < chan.len() {
+                        sum += chan[index as usize] * weight;
+                    }
+                }
+                out.push(sum);
+            }
+            self.nbr_output += 1;
+        }
+        // Drop the input that is no longer needed
+        let next_center = self.nbr_output * self.in_rate / self.out_rate;
+        let keep_from = (next_center + 1).saturating_sub(HALF_LENGTH as u64);
+        if keep_from > self.buffer_start {
+            let drop = ((keep_from - self.buffer_start) as usize).min(self.buffer[0].len());
+            for chan in self.buffer.iter_mut() {
+                chan.drain(..drop);
+            }
+            self.buffer_start += drop as u64;
+        }
+        output
+    }
+
+    // Add input frames, one vector per channel, and get the output frames that can be calculated
+    pub fn process(&mut self, input: &[Vec<f64>]) -> WasapiRes<Vec<Vec<f64>>> {
+        if input.len() != self.nbr_channels {
+            return Err(WasapiError::new("Wrong number of channels").into());
+        }
+        let nbr_frames = input[0].len();
+        if input.iter().any(|values| values.len() != nbr_frames) {
+            return Err(WasapiError::new("All channels must have the same length").into());
+        }
+        for (chan, values) in self.buffer.iter_mut().zip(input.iter()) {
+            chan.extend_from_slice(values);
+        }
+        self.nbr_input += nbr_frames as u64;
+        Ok(self.produce(self.nbr_input, u64::MAX))
+    }
+
+    // End the stream, and get the remaining output frames.
+    // The resampler is reset and can be used for a new stream.
+    pub fn flush(&mut self) -> Vec<Vec<f64>> {
+        let total = (self.nbr_input * self.out_rate).div_ceil(self.in_rate);
+        // Pad with silence so that all remaining output can be calculated
+        for chan in self.buffer.iter_mut() {
+            chan.resize(chan.len() + HALF_LENGTH + 1, 0.0);
+        }
+        let output = self.produce(self.nbr_input + HALF_LENGTH as u64 + 1, total);
+        self.buffer = vec![Vec::new(); self.nbr_channels];
+        self.buffer_start = 0;
+        self.nbr_input = 0;
+        self.nbr_output = 0;
+        output
+    }
+}
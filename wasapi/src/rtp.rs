@@ -0,0 +1,534 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::jitter::{JitterBuffer, JitterSettings, JitterStats};
+use crate::network::{new_stream_id, NetPacket, SenderStats};
+use crate::simulated::{SimSink, SimSource};
+use crate::wasapi::{SampleType, WasapiError, WasapiRes, WaveFormat};
+
+// Size of the fixed RTP header in bytes
+const RTP_HEADER_SIZE: usize = 12;
+// Largest datagram that is accepted
+const MAX_DATAGRAM_SIZE: usize = 65507;
+// How often the receiving thread checks if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// Largest jumps of the sequence numbers within a stream, for lost and for reordered packets, as in RFC 3550
+const MAX_DROPOUT: i32 = 3000;
+const MAX_MISORDER: i32 = 100;
+
+// Payload format, linear pcm in network byte order as in RFC 3190.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtpEncoding {
+    L16,
+    L24,
+}
+
+impl RtpEncoding {
+    // Get the number of bytes per sample
+    pub fn get_bytes_per_sample(&self) -> usize {
+        match self {
+            RtpEncoding::L16 => 2,
+            RtpEncoding::L24 => 3,
+        }
+    }
+
+    fn get_name(&self) -> &'static str {
+        match self {
+            RtpEncoding::L16 => "L16",
+            RtpEncoding::L24 => "L24",
+        }
+    }
+}
+
+// The fixed part of an RTP header, without contributing sources.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RtpHeader {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    // Serialize the header, version 2 without padding, extension or contributing sources
+    pub fn to_bytes(&self) -> [u8; RTP_HEADER_SIZE] {
+        let mut bytes = [0u8; RTP_HEADER_SIZE];
+        bytes[0] = 0x80;
+        bytes[1] = (self.marker as u8) << 7 | (self.payload_type & 0x7F);
+        bytes[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        bytes
+    }
+
+    // Parse an RTP packet, returns the header and the payload.
+    // Contributing sources, header extensions and padding are skipped.
+    pub fn parse(bytes: &[u8]) -> WasapiRes<(Self, &[u8])> {
+        if bytes.len() < RTP_HEADER_SIZE || bytes[0] >> 6 != 2 {
+            return Err(WasapiError::new("Not an RTP packet").into());
+        }
+        let csrc_count = (bytes[0] & 0x0F) as usize;
+        let mut start = RTP_HEADER_SIZE + 4 * csrc_count;
+        if bytes[0] & 0x10 != 0 && bytes.len() >= start + 4 {
+            let words = u16::from_be_bytes([bytes[start + 2], bytes[start + 3]]) as usize;
+            start += 4 + 4 * words;
+        }
+        let mut end = bytes.len();
+        if bytes[0] & 0x20 != 0 && end > 0 {
+            end = end.saturating_sub(bytes[end - 1] as usize);
+        }
+        if start > end {
+            return Err(WasapiError::new("Invalid RTP packet").into());
+        }
+        let header = RtpHeader {
+            marker: bytes[1] & 0x80 != 0,
+            payload_type: bytes[1] & 0x7F,
+            sequence: u16::from_be_bytes([bytes[2], bytes[3]]),
+            timestamp: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            ssrc: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        };
+        Ok((header, &bytes[start..end]))
+    }
+}
+
+// Convert samples from the little endian stream format to the big endian payload, or back.
+// Both have the same number of bytes per sample, so this reverses the bytes of each sample.
+pub fn swap_sample_bytes(data: &[u8], encoding: RtpEncoding) -> Vec<u8> {
+    data.chunks(encoding.get_bytes_per_sample())
+        .flat_map(|sample| sample.iter().rev().copied())
+        .collect()
+}
+
+// Description of an RTP stream, used by both the sender and the receiver.
+// The defaults follow AES67: 1 ms packets and 48 kHz.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpStreamConfig {
+    // Name of the session, and of the device
+    pub name: String,
+    // Where the packets are sent, a multicast group or a unicast address
+    pub address: SocketAddr,
+    pub encoding: RtpEncoding,
+    pub samplerate: usize,
+    pub channels: usize,
+    // Packet time in microseconds
+    pub ptime: u32,
+    pub payload_type: u8,
+    pub ssrc: u32,
+    // RTP timestamp of the first frame of the stream, the timestamp of a frame is this plus its position
+    pub media_clock_offset: u32,
+    // Time to live of multicast packets
+    pub ttl: u32,
+}
+
+impl RtpStreamConfig {
+    // A stream with 1 ms packets, dynamic payload type 96, and random ssrc and media clock offset
+    pub fn new(
+        name: &str,
+        address: SocketAddr,
+        encoding: RtpEncoding,
+        samplerate: usize,
+        channels: usize,
+    ) -> Self {
+        RtpStreamConfig {
+            name: name.to_string(),
+            address,
+            encoding,
+            samplerate,
+            channels,
+            ptime: 1000,
+            payload_type: 96,
+            ssrc: new_stream_id(),
+            media_clock_offset: new_stream_id(),
+            ttl: 32,
+        }
+    }
+
+    // Get the format of the devices, integer samples with the size of the payload samples
+    pub fn get_format(&self) -> WaveFormat {
+        let bits = 8 * self.encoding.get_bytes_per_sample();
+        WaveFormat::new(bits, bits, &SampleType::Int, self.samplerate, self.channels)
+    }
+
+    // Get the number of frames in a packet
+    pub fn get_frames_per_packet(&self) -> usize {
+        (self.samplerate as u64 * self.ptime as u64 / 1_000_000).max(1) as usize
+    }
+
+    // Get a session description (RFC 4566) of the stream, as used by AES67 devices.
+    // The origin is the address of the sending machine.
+    pub fn get_sdp(&self, origin: IpAddr) -> String {
+        let addrtype = |addr: &IpAddr| if addr.is_ipv4() { "IP4" } else { "IP6" };
+        let destination = self.address.ip();
+        let connection = match destination {
+            IpAddr::V4(addr) if addr.is_multicast() => format!("{}/{}", addr, self.ttl),
+            _ => destination.to_string(),
+        };
+        let ptime = if self.ptime.is_multiple_of(1000) {
+            format!("{}", self.ptime / 1000)
+        } else {
+            format!("{}", self.ptime as f64 / 1000.0)
+        };
+        let lines = [
+            "v=0".to_string(),
+            format!("o=- {} 0 IN {} {}", self.ssrc, addrtype(&origin), origin),
+            format!("s={}", self.name),
+            format!("c=IN {} {}", addrtype(&destination), connection),
+            "t=0 0".to_string(),
+            format!(
+                "m=audio {} RTP/AVP {}",
+                self.address.port(),
+                self.payload_type
+            ),
+            format!(
+                "a=rtpmap:{} {}/{}/{}",
+                self.payload_type,
+                self.encoding.get_name(),
+                self.samplerate,
+                self.channels
+            ),
+            format!("a=ptime:{}", ptime),
+            "a=ts-refclk:local".to_string(),
+            format!("a=mediaclk:direct={}", self.media_clock_offset),
+        ];
+        lines.iter().map(|line| format!("{}\r\n", line)).collect()
+    }
+
+    // Read the description of a stream made by get_sdp, or by another AES67 device.
+    // The connection, media, rtpmap, ptime and mediaclk lines are used, the others are ignored.
+    pub fn from_sdp(name: &str, sdp: &str) -> WasapiRes<Self> {
+        let invalid = |line: &str| WasapiError::new(format!("Invalid SDP line {}", line).as_str());
+        let mut ip = None;
+        let mut port = None;
+        let mut rtpmap = None;
+        let mut config = RtpStreamConfig::new(
+            name,
+            (Ipv4Addr::UNSPECIFIED, 0).into(),
+            RtpEncoding::L24,
+            48000,
+            2,
+        );
+        config.media_clock_offset = 0;
+        for line in sdp.lines().map(|line| line.trim()) {
+            if let Some(connection) = line.strip_prefix("c=") {
+                let addr = connection.split_whitespace().nth(2).ok_or(invalid(line))?;
+                let mut parts = addr.split('/');
+                ip = Some(
+                    parts
+                        .next()
+                        .unwrap_or(addr)
+                        .parse::<IpAddr>()
+                        .map_err(|_| invalid(line))?,
+                );
+                if let Some(ttl) = parts.next() {
+                    config.ttl = ttl.parse().map_err(|_| invalid(line))?;
+                }
+            } else if let Some(media) = line.strip_prefix("m=") {
+                let fields: Vec<&str> = media.split_whitespace().collect();
+                if fields.len() < 4 || fields[0] != "audio" {
+                    return Err(invalid(line).into());
+                }
+                port = Some(fields[1].parse::<u16>().map_err(|_| invalid(line))?);
+                config.payload_type = fields[3].parse().map_err(|_| invalid(line))?;
+            } else if let Some(map) = line.strip_prefix("a=rtpmap:") {
+                rtpmap = Some(map.to_string());
+            } else if let Some(ptime) = line.strip_prefix("a=ptime:") {
+                let ms: f64 = ptime.parse().map_err(|_| invalid(line))?;
+                config.ptime = (ms * 1000.0).round() as u32;
+            } else if let Some(offset) = line.strip_prefix("a=mediaclk:direct=") {
+                let offset = offset.split_whitespace().next().unwrap_or(offset);
+                config.media_clock_offset = offset.parse().map_err(|_| invalid(line))?;
+            }
+        }
+        let rtpmap = rtpmap.ok_or(WasapiError::new("The SDP has no rtpmap"))?;
+        let fields: Vec<&str> = rtpmap
+            .split([' ', '/'])
+            .filter(|field| !field.is_empty())
+            .collect();
+        if fields.len() < 3 || fields[0].parse::<u8>().ok() != Some(config.payload_type) {
+            return Err(invalid(&rtpmap).into());
+        }
+        config.encoding = match fields[1] {
+            "L16" => RtpEncoding::L16,
+            "L24" => RtpEncoding::L24,
+            _ => return Err(WasapiError::new("Only L16 and L24 payloads are supported").into()),
+        };
+        config.samplerate = fields[2].parse().map_err(|_| invalid(&rtpmap))?;
+        config.channels = match fields.get(3) {
+            Some(channels) => channels.parse().map_err(|_| invalid(&rtpmap))?,
+            None => 1,
+        };
+        match (ip, port) {
+            (Some(ip), Some(port)) => config.address = SocketAddr::new(ip, port),
+            _ => return Err(WasapiError::new("The SDP has no connection or media line").into()),
+        }
+        Ok(config)
+    }
+
+    fn check_format(&self, format: &WaveFormat) -> WasapiRes<()> {
+        if *format != self.get_format() {
+            return Err(WasapiError::new("The stream format does not match the RTP stream").into());
+        }
+        Ok(())
+    }
+}
+
+// Sending side of an RTP render device, the frames played by the device are sent in packets of ptime.
+pub struct RtpSender {
+    config: RtpStreamConfig,
+    socket: UdpSocket,
+    // Frames waiting for a complete packet
+    pending: Vec<u8>,
+    sequence: u16,
+    // Position in frames of the next packet since the start of the stream
+    position: u64,
+    open: bool,
+    pub(crate) stats: Arc<Mutex<SenderStats>>,
+}
+
+impl RtpSender {
+    // Create a sender for a stream
+    pub fn new(config: &RtpStreamConfig) -> WasapiRes<Self> {
+        let local: SocketAddr = match config.address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        if config.address.ip().is_multicast() && config.address.is_ipv4() {
+            socket.set_multicast_ttl_v4(config.ttl)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        socket.connect(config.address)?;
+        Ok(RtpSender {
+            config: config.clone(),
+            socket,
+            pending: Vec::new(),
+            sequence: 0,
+            position: 0,
+            open: false,
+            stats: Arc::new(Mutex::new(SenderStats::default())),
+        })
+    }
+
+    // Get the address the packets are sent from
+    pub fn get_local_addr(&self) -> WasapiRes<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // Get the statistics
+    pub fn get_stats(&self) -> SenderStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+impl SimSink for RtpSender {
+    fn open(&mut self, format: &WaveFormat) -> WasapiRes<()> {
+        self.config.check_format(format)?;
+        self.pending.clear();
+        self.sequence = new_stream_id() as u16;
+        self.position = 0;
+        self.open = true;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> WasapiRes<()> {
+        if !self.open {
+            return Err(WasapiError::new("The stream is not open").into());
+        }
+        self.pending.extend_from_slice(data);
+        let frames_per_packet = self.config.get_frames_per_packet();
+        let packet_bytes =
+            frames_per_packet * self.config.channels * self.config.encoding.get_bytes_per_sample();
+        while self.pending.len() >= packet_bytes {
+            let header = RtpHeader {
+                marker: self.position == 0,
+                payload_type: self.config.payload_type,
+                sequence: self.sequence,
+                timestamp: self
+                    .config
+                    .media_clock_offset
+                    .wrapping_add(self.position as u32),
+                ssrc: self.config.ssrc,
+            };
+            let mut packet = header.to_bytes().to_vec();
+            packet.extend(swap_sample_bytes(
+                &self.pending[..packet_bytes],
+                self.config.encoding,
+            ));
+            self.pending.drain(..packet_bytes);
+            // Nobody may be listening yet, that is just like a lost packet
+            let _ = self.socket.send(&packet);
+            self.stats.lock().unwrap().packets += 1;
+            self.sequence = self.sequence.wrapping_add(1);
+            self.position += frames_per_packet as u64;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> WasapiRes<()> {
+        self.open = false;
+        Ok(())
+    }
+}
+
+// Receiving side of an RTP capture device.
+// A separate thread receives the packets into a jitter buffer, which the device reads at its own pace.
+// The position of each packet in the stream is its timestamp minus the media clock offset.
+pub struct RtpReceiver {
+    pub(crate) buffer: Arc<Mutex<JitterBuffer>>,
+    closed: Arc<AtomicBool>,
+    local_addr: SocketAddr,
+}
+
+impl RtpReceiver {
+    // Start receiving a stream, joining the group if the address is an IPv4 multicast address,
+    // through a jitter buffer
+    pub fn bind(config: &RtpStreamConfig, jitter: JitterSettings) -> WasapiRes<Self> {
+        let socket = match config.address.ip() {
+            IpAddr::V4(group) if group.is_multicast() => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.address.port()))?;
+                socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+                socket
+            }
+            _ => UdpSocket::bind(config.address)?,
+        };
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local_addr = socket.local_addr()?;
+        let buffer = Arc::new(Mutex::new(JitterBuffer::new(jitter)));
+        let closed = Arc::new(AtomicBool::new(false));
+        let buffer_thread = buffer.clone();
+        let closed_thread = closed.clone();
+        let config = config.clone();
+        thread::Builder::new()
+            .name("RtpReceiver".to_string())
+            .spawn(move || receive_rtp(socket, &config, &buffer_thread, &closed_thread))?;
+        Ok(RtpReceiver {
+            buffer,
+            closed,
+            local_addr,
+        })
+    }
+
+    // Get the address the receiver listens on
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Get the statistics of the jitter buffer
+    pub fn get_stats(&self) -> JitterStats {
+        self.buffer.lock().unwrap().get_stats()
+    }
+}
+
+// What is known about the stream that is being received
+struct ReceivedStream {
+    ssrc: u32,
+    stream_id: u32,
+    // Highest sequence number seen
+    sequence: u16,
+    // Position of the last packet, the 32 bit timestamps are extended to 64 bits
+    position: u64,
+}
+
+impl ReceivedStream {
+    // Follow a packet of this stream, returns false if the packet is from another or a restarted sender
+    fn follow(&mut self, header: &RtpHeader, offset: u32, max_jump: i64) -> bool {
+        if header.ssrc != self.ssrc {
+            return false;
+        }
+        let sequence_delta = header.sequence.wrapping_sub(self.sequence) as i16 as i32;
+        let position_delta = offset.wrapping_sub(self.position as u32) as i32 as i64;
+        if !(-MAX_MISORDER..=MAX_DROPOUT).contains(&sequence_delta)
+            || position_delta.abs() > max_jump
+        {
+            return false;
+        }
+        // A marker on a packet newer than all others, that goes back in time, is a sender that restarted.
+        // The marker of a packet that was reordered or duplicated is ignored.
+        if header.marker && sequence_delta > 0 && position_delta < 0 {
+            return false;
+        }
+        if sequence_delta > 0 {
+            self.sequence = header.sequence;
+        }
+        self.position = self.position.saturating_add_signed(position_delta);
+        true
+    }
+}
+
+fn receive_rtp(
+    socket: UdpSocket,
+    config: &RtpStreamConfig,
+    buffer: &Mutex<JitterBuffer>,
+    closed: &AtomicBool,
+) {
+    let format = config.get_format();
+    let blockalign = format.get_blockalign() as usize;
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    // Larger jumps of the timestamps than of the sequence numbers are also a new stream
+    let max_jump = MAX_DROPOUT as i64 * config.get_frames_per_packet() as i64;
+    let mut stream: Option<ReceivedStream> = None;
+    // Counts the restarts, so that a sender that restarts with the same ssrc gets a new stream id
+    let mut generation = 0u32;
+    while !closed.load(Ordering::Relaxed) {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(_) => continue,
+        };
+        let mut buffer = buffer.lock().unwrap();
+        match RtpHeader::parse(&buf[..len]) {
+            Ok((header, payload))
+                if header.payload_type == config.payload_type
+                    && !payload.is_empty()
+                    && payload.len().is_multiple_of(blockalign) =>
+            {
+                let offset = header.timestamp.wrapping_sub(config.media_clock_offset);
+                let followed = stream
+                    .as_mut()
+                    .is_some_and(|stream| stream.follow(&header, offset, max_jump));
+                if !followed {
+                    generation = generation.wrapping_add(1);
+                    stream = Some(ReceivedStream {
+                        ssrc: header.ssrc,
+                        stream_id: header.ssrc ^ generation,
+                        sequence: header.sequence,
+                        position: offset as u64,
+                    });
+                }
+                if let Some(stream) = stream.as_ref() {
+                    buffer.push(NetPacket {
+                        stream_id: stream.stream_id,
+                        sequence: header.sequence as u32,
+                        timestamp: stream.position,
+                        format: format.clone(),
+                        data: swap_sample_bytes(payload, config.encoding),
+                    });
+                }
+            }
+            _ => buffer.count_invalid(),
+        }
+    }
+}
+
+impl SimSource for RtpReceiver {
+    fn read(&mut self, nbr_frames: usize, format: &WaveFormat) -> WasapiRes<Vec<u8>> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer
+            .get_format()
+            .is_some_and(|received| received != *format)
+        {
+            return Err(WasapiError::new("The stream format does not match the RTP stream").into());
+        }
+        Ok(buffer.fill(nbr_frames))
+    }
+}
+
+impl Drop for RtpReceiver {
+    fn drop(&mut self) {
+        // Let the receiving thread exit
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bwf::{utc_date_time, BextInfo};
+use crate::wasapi::{WasapiError, WasapiRes, WaveFormat};
+use crate::wav::WavWriter;
+
+// Settings for splitting a long recording into segments.
+#[derive(Clone, Debug)]
+pub struct SegmentSettings {
+    pub samplerate: usize,
+    // Longest segment in seconds
+    pub max_duration: Option<f64>,
+    // Largest amount of sample data in a segment, in bytes
+    pub max_bytes: Option<u64>,
+    // Start a new segment at every full hour, UTC
+    pub split_on_hour: bool,
+    // Template for the file names. {device}, {date}, {time} and {seq} are replaced by the device name,
+    // the UTC date and time of the first frame as yyyymmdd and hhmmss, and the sequence number.
+    pub template: String,
+    // Number of segments to keep, older ones are deleted
+    pub max_segments: Option<usize>,
+}
+
+impl SegmentSettings {
+    // Default settings, with one hour segments starting on the full hours, keeping all segments
+    pub fn new(samplerate: usize) -> Self {
+        SegmentSettings {
+            samplerate,
+            max_duration: Some(3600.0),
+            max_bytes: None,
+            split_on_hour: true,
+            template: "{device}_{date}_{time}_{seq}.wav".to_string(),
+            max_segments: None,
+        }
+    }
+}
+
+// Something that happened to the segment files.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SegmentEvent {
+    // A segment was started, with the stream position of its first frame
+    Opened { path: PathBuf, position: u64 },
+    // A segment was completed
+    Closed { path: PathBuf, nbr_frames: u64 },
+    // An old segment was deleted to respect the retention limit
+    Deleted(PathBuf),
+    // An old segment could not be deleted, the recording continues
+    DeleteFailed { path: PathBuf, error: String },
+}
+
+// Replace the characters that are not safe in file names.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Build a file name from a template.
+pub fn segment_name(template: &str, device: &str, start: SystemTime, sequence: u64) -> String {
+    let (date, time, _) = utc_date_time(start);
+    template
+        .replace("{device}", &sanitize(device))
+        .replace("{date}", &date.replace('-', ""))
+        .replace("{time}", &time.replace(':', ""))
+        .replace("{seq}", &format!("{:04}", sequence))
+}
+
+// Recorder that writes a continuous stream to a sequence of wav files.
+// The stream is split at exact frames, so the segments put together give back the complete stream.
+// Each segment has a bext chunk with the time reference of its first frame.
+pub struct SegmentedRecorder {
+    directory: PathBuf,
+    device: String,
+    wave_fmt: WaveFormat,
+    settings: SegmentSettings,
+    stream_start: SystemTime,
+    writer: Option<WavWriter<BufWriter<File>>>,
+    // Position of the first frame of the current segment, and of the next cut
+    segment_start: u64,
+    segment_end: u64,
+    position: u64,
+    sequence: u64,
+    segments: VecDeque<PathBuf>,
+}
+
+impl SegmentedRecorder {
+    // Create a recorder for a stream that started at stream_start, writing to a directory
+    pub fn new<P: AsRef<Path>>(
+        directory: P,
+        device: &str,
+        wave_fmt: &WaveFormat,
+        stream_start: SystemTime,
+        settings: SegmentSettings,
+    ) -> WasapiRes<Self> {
+        if settings
+            .max_duration
+            .is_some_and(|duration| duration <= 0.0)
+            || settings.max_bytes == Some(0)
+            || settings.max_segments == Some(0)
+        {
+            return Err(WasapiError::new("Invalid segment settings").into());
+        }
+        Ok(SegmentedRecorder {
+            directory: directory.as_ref().to_path_buf(),
+            device: device.to_string(),
+            wave_fmt: wave_fmt.clone(),
+            settings,
+            stream_start,
+            writer: None,
+            segment_start: 0,
+            segment_end: 0,
+            position: 0,
+            sequence: 0,
+            segments: VecDeque::new(),
+        })
+    }
+
+    // Get the number of frames recorded
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    // Get the segments that are kept, oldest first, including the current one
+    pub fn get_segments(&self) -> Vec<PathBuf> {
+        self.segments.iter().cloned().collect()
+    }
+
+    // Get the time of a position in the stream
+    fn time_at(&self, position: u64) -> SystemTime {
+        self.stream_start
+            + Duration::from_secs_f64(position as f64 / self.settings.samplerate as f64)
+    }
+
+    // Find the first frame at or after a position that is at the start of a new hour
+    fn next_hour_frame(&self, position: u64) -> u64 {
+        let samplerate = self.settings.samplerate as u128;
+        let start_ns = self
+            .stream_start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        // Time of a frame is start + frame / samplerate, find the first frame after the current hour
+        let now_ns = start_ns + position as u128 * 1_000_000_000 / samplerate;
+        let hour_ns = 3600 * 1_000_000_000u128;
+        let boundary_ns = (now_ns / hour_ns + 1) * hour_ns;
+        let frames = ((boundary_ns - start_ns) * samplerate).div_ceil(1_000_000_000);
+        (frames as u64).max(position + 1)
+    }
+
+    // Find the end of a segment that starts at a position
+    fn segment_end_at(&self, position: u64) -> u64 {
+        let mut end = u64::MAX;
+        if let Some(duration) = self.settings.max_duration {
+            let frames = (duration * self.settings.samplerate as f64).round() as u64;
+            end = end.min(position + frames.max(1));
+        }
+        if let Some(max_bytes) = self.settings.max_bytes {
+            let frames = max_bytes / self.wave_fmt.get_blockalign() as u64;
+            end = end.min(position + frames.max(1));
+        }
+        if self.settings.split_on_hour {
+            end = end.min(self.next_hour_frame(position));
+        }
+        end
+    }
+
+    fn open_segment(&mut self, events: &mut Vec<SegmentEvent>) -> WasapiRes<()> {
+        let start = self.time_at(self.position);
+        let name = segment_name(&self.settings.template, &self.device, start, self.sequence);
+        let path = self.directory.join(name);
+        // The template may put the segments in subdirectories
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = WavWriter::create(&path, &self.wave_fmt)?;
+        let description = format!("{} segment {}", self.device, self.sequence);
+        let bext = BextInfo::new(
+            &description,
+            "wasapi",
+            self.stream_start,
+            self.settings.samplerate,
+            self.position,
+        );
+        writer.add_bext(&bext)?;
+        self.writer = Some(writer);
+        self.segment_start = self.position;
+        self.segment_end = self.segment_end_at(self.position);
+        self.sequence += 1;
+        self.segments.push_back(path.clone());
+        events.push(SegmentEvent::Opened {
+            path,
+            position: self.position,
+        });
+        if let Some(max_segments) = self.settings.max_segments {
+            while self.segments.len() > max_segments {
+                if let Some(old) = self.segments.pop_front() {
+                    // A segment that was already moved or deleted by someone else needs no action
+                    match std::fs::remove_file(&old) {
+                        Ok(()) => events.push(SegmentEvent::Deleted(old)),
+                        Err(err) if err.kind() == ErrorKind::NotFound => {}
+                        Err(err) => events.push(SegmentEvent::DeleteFailed {
+                            path: old,
+                            error: err.to_string(),
+                        }),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close_segment(&mut self, events: &mut Vec<SegmentEvent>) -> WasapiRes<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.close()?;
+            if let Some(path) = self.segments.back() {
+                events.push(SegmentEvent::Closed {
+                    path: path.clone(),
+                    nbr_frames: self.position - self.segment_start,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Write raw data, whole frames only. New segments are started as needed.
+    pub fn write_bytes(&mut self, data: &[u8]) -> WasapiRes<Vec<SegmentEvent>> {
+        let blockalign = self.wave_fmt.get_blockalign() as usize;
+        if !data.len().is_multiple_of(blockalign) {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        let mut events = Vec::new();
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            if self.writer.is_some() && self.position >= self.segment_end {
+                self.close_segment(&mut events)?;
+            }
+            if self.writer.is_none() {
+                self.open_segment(&mut events)?;
+            }
+            let frames =
+                ((self.segment_end - self.position) as usize).min(remaining.len() / blockalign);
+            let (chunk, rest) = remaining.split_at(frames * blockalign);
+            if let Some(writer) = self.writer.as_mut() {
+                writer.write_bytes(chunk)?;
+            }
+            self.position += frames as u64;
+            remaining = rest;
+        }
+        Ok(events)
+    }
+
+    // Complete the current segment
+    pub fn close(&mut self) -> WasapiRes<Vec<SegmentEvent>> {
+        let mut events = Vec::new();
+        self.close_segment(&mut events)?;
+        Ok(events)
+    }
+}
+
+impl Drop for SegmentedRecorder {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
@@ -0,0 +1,285 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use memmap2::MmapMut;
+
+use crate::engine::PlaybackSource;
+use crate::wasapi::{SampleType, WasapiError, WasapiRes, WaveFormat};
+
+// Start of every ring
+const MAGIC: &[u8; 4] = b"WSHM";
+const VERSION: u32 = 1;
+// Layout of the header, the data follows it.
+// The counters are 8 byte aligned, so that they can be used as atomics by both processes.
+const HEADER_SIZE: usize = 64;
+const CAPACITY_OFFSET: usize = 8;
+const FORMAT_OFFSET: usize = 16;
+const WRITE_INDEX_OFFSET: usize = 32;
+const READ_INDEX_OFFSET: usize = 40;
+const OVERRUNS_OFFSET: usize = 48;
+const CLOSED_OFFSET: usize = 56;
+
+// A ring buffer in a memory mapped file, shared by one writing and one reading process.
+// The header holds the format, the read and write indices, and a counter of the frames that did not fit.
+// The indices count the bytes written and read since the start, so the ring is empty when they are equal.
+struct SharedRing {
+    map: MmapMut,
+    format: WaveFormat,
+    // Size of the data area in bytes, a whole number of frames
+    capacity: usize,
+}
+
+impl SharedRing {
+    fn create<P: AsRef<Path>>(path: P, format: &WaveFormat, nbr_frames: usize) -> WasapiRes<Self> {
+        let sample_type = match format.get_subformat()? {
+            SampleType::Int => 0u8,
+            SampleType::Float => 1u8,
+        };
+        let capacity = nbr_frames * format.get_blockalign() as usize;
+        if capacity == 0 {
+            return Err(WasapiError::new("The ring must hold at least one frame").into());
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((HEADER_SIZE + capacity) as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        map[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        map[CAPACITY_OFFSET..CAPACITY_OFFSET + 8].copy_from_slice(&(capacity as u64).to_le_bytes());
+        let mut header = vec![sample_type, 0];
+        header.extend_from_slice(&format.get_nchannels().to_le_bytes());
+        header.extend_from_slice(&format.get_samplespersec().to_le_bytes());
+        header.extend_from_slice(&format.get_bitspersample().to_le_bytes());
+        header.extend_from_slice(&format.get_validbitspersample().to_le_bytes());
+        map[FORMAT_OFFSET..FORMAT_OFFSET + header.len()].copy_from_slice(&header);
+        // The magic is written last, a ring with the magic is complete
+        fence(Ordering::Release);
+        map[0..4].copy_from_slice(MAGIC);
+        Ok(SharedRing {
+            map,
+            format: format.clone(),
+            capacity,
+        })
+    }
+
+    fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        if map.len() < HEADER_SIZE || &map[0..4] != MAGIC {
+            return Err(WasapiError::new("Not a shared memory ring").into());
+        }
+        fence(Ordering::Acquire);
+        let u16_at = |offset: usize| u16::from_le_bytes([map[offset], map[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                map[offset],
+                map[offset + 1],
+                map[offset + 2],
+                map[offset + 3],
+            ])
+        };
+        if u32_at(4) != VERSION {
+            return Err(WasapiError::new("Unsupported shared memory ring version").into());
+        }
+        let sample_type = match map[FORMAT_OFFSET] {
+            0 => SampleType::Int,
+            1 => SampleType::Float,
+            _ => return Err(WasapiError::new("Unknown sample type").into()),
+        };
+        let channels = u16_at(FORMAT_OFFSET + 2) as usize;
+        let samplerate = u32_at(FORMAT_OFFSET + 4) as usize;
+        let bits = u16_at(FORMAT_OFFSET + 8) as usize;
+        let validbits = u16_at(FORMAT_OFFSET + 10) as usize;
+        let format = WaveFormat::new(bits, validbits, &sample_type, samplerate, channels);
+        let capacity =
+            (u32_at(CAPACITY_OFFSET) as u64 | (u32_at(CAPACITY_OFFSET + 4) as u64) << 32) as usize;
+        if HEADER_SIZE + capacity != map.len()
+            || format.get_blockalign() == 0
+            || !capacity.is_multiple_of(format.get_blockalign() as usize)
+        {
+            return Err(WasapiError::new("Invalid size of shared memory ring").into());
+        }
+        Ok(SharedRing {
+            map,
+            format,
+            capacity,
+        })
+    }
+
+    fn counter(&self, offset: usize) -> &AtomicU64 {
+        // The map is page aligned and the offset is a multiple of 8
+        unsafe { &*(self.map.as_ptr().add(offset) as *const AtomicU64) }
+    }
+
+    fn closed(&self) -> &AtomicU32 {
+        unsafe { &*(self.map.as_ptr().add(CLOSED_OFFSET) as *const AtomicU32) }
+    }
+
+    fn blockalign(&self) -> usize {
+        self.format.get_blockalign() as usize
+    }
+
+    // Get the number of bytes waiting to be read
+    fn used(&self) -> usize {
+        let write = self.counter(WRITE_INDEX_OFFSET).load(Ordering::Acquire);
+        let read = self.counter(READ_INDEX_OFFSET).load(Ordering::Acquire);
+        write.wrapping_sub(read) as usize
+    }
+}
+
+// Writing side of a shared memory ring, for example in a process producing the audio for a render stream.
+// Writing never blocks, frames that do not fit are dropped and counted as overruns.
+pub struct SharedMemWriter {
+    ring: SharedRing,
+}
+
+impl SharedMemWriter {
+    // Create a ring holding nbr_frames frames in a file, replacing any existing file
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: &WaveFormat,
+        nbr_frames: usize,
+    ) -> WasapiRes<Self> {
+        Ok(SharedMemWriter {
+            ring: SharedRing::create(path, format, nbr_frames)?,
+        })
+    }
+
+    // Attach to a ring created by another process
+    pub fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        Ok(SharedMemWriter {
+            ring: SharedRing::open(path)?,
+        })
+    }
+
+    // Get the format of the ring
+    pub fn get_format(&self) -> WaveFormat {
+        self.ring.format.clone()
+    }
+
+    // Get the number of frames that can be written without overrun
+    pub fn get_free_frames(&self) -> usize {
+        (self.ring.capacity - self.ring.used()) / self.ring.blockalign()
+    }
+
+    // Get the number of frames that were dropped because the ring was full
+    pub fn get_overruns(&self) -> u64 {
+        self.ring.counter(OVERRUNS_OFFSET).load(Ordering::Relaxed)
+    }
+
+    // Write frames, returns the number of frames that fit
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let blockalign = self.ring.blockalign();
+        let nbr_frames = data.len() / blockalign;
+        let written = nbr_frames.min(self.get_free_frames());
+        let nbr_bytes = written * blockalign;
+        let write = self
+            .ring
+            .counter(WRITE_INDEX_OFFSET)
+            .load(Ordering::Relaxed);
+        let start = (write % self.ring.capacity as u64) as usize;
+        let first = nbr_bytes.min(self.ring.capacity - start);
+        let area = &mut self.ring.map[HEADER_SIZE..];
+        area[start..start + first].copy_from_slice(&data[..first]);
+        area[..nbr_bytes - first].copy_from_slice(&data[first..nbr_bytes]);
+        self.ring
+            .counter(WRITE_INDEX_OFFSET)
+            .store(write.wrapping_add(nbr_bytes as u64), Ordering::Release);
+        if written < nbr_frames {
+            self.ring
+                .counter(OVERRUNS_OFFSET)
+                .fetch_add((nbr_frames - written) as u64, Ordering::Relaxed);
+        }
+        written
+    }
+
+    // Tell the reader that nothing more will be written, this is also done when the writer is dropped
+    pub fn close(&mut self) {
+        self.ring.closed().store(1, Ordering::Release);
+    }
+}
+
+impl Drop for SharedMemWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Reading side of a shared memory ring.
+// It can be the source of a playback loop, so that another process feeds a render stream.
+pub struct SharedMemReader {
+    ring: SharedRing,
+}
+
+impl SharedMemReader {
+    // Create a ring holding nbr_frames frames in a file, replacing any existing file
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: &WaveFormat,
+        nbr_frames: usize,
+    ) -> WasapiRes<Self> {
+        Ok(SharedMemReader {
+            ring: SharedRing::create(path, format, nbr_frames)?,
+        })
+    }
+
+    // Attach to a ring created by another process
+    pub fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        Ok(SharedMemReader {
+            ring: SharedRing::open(path)?,
+        })
+    }
+
+    // Get the format of the ring
+    pub fn get_format(&self) -> WaveFormat {
+        self.ring.format.clone()
+    }
+
+    // Get the number of frames waiting to be read
+    pub fn get_available_frames(&self) -> usize {
+        self.ring.used() / self.ring.blockalign()
+    }
+
+    // Get the number of frames that were dropped because the ring was full
+    pub fn get_overruns(&self) -> u64 {
+        self.ring.counter(OVERRUNS_OFFSET).load(Ordering::Relaxed)
+    }
+
+    // Check if the writer has closed the ring
+    pub fn is_closed(&self) -> bool {
+        self.ring.closed().load(Ordering::Acquire) != 0
+    }
+
+    // Read up to nbr_frames frames
+    pub fn read(&mut self, nbr_frames: usize) -> Vec<u8> {
+        let blockalign = self.ring.blockalign();
+        let nbr_bytes = nbr_frames.min(self.get_available_frames()) * blockalign;
+        let read = self.ring.counter(READ_INDEX_OFFSET).load(Ordering::Relaxed);
+        let start = (read % self.ring.capacity as u64) as usize;
+        let first = nbr_bytes.min(self.ring.capacity - start);
+        let area = &self.ring.map[HEADER_SIZE..];
+        let mut data = Vec::with_capacity(nbr_bytes);
+        data.extend_from_slice(&area[start..start + first]);
+        data.extend_from_slice(&area[..nbr_bytes - first]);
+        self.ring
+            .counter(READ_INDEX_OFFSET)
+            .store(read.wrapping_add(nbr_bytes as u64), Ordering::Release);
+        data
+    }
+}
+
+impl PlaybackSource for SharedMemReader {
+    // Silence is played while the ring is empty, the source is finished when the writer has closed it
+    fn fill(&mut self, nbr_frames: usize, _format: &WaveFormat) -> Option<Vec<u8>> {
+        let closed = self.is_closed();
+        let data = self.read(nbr_frames);
+        if data.is_empty() && closed {
+            return None;
+        }
+        Some(data)
+    }
+}
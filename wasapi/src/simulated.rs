@@ -0,0 +1,1146 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{
+    AudioClientTrait, Backend, CaptureClientTrait, DeviceCollectionTrait, DeviceTrait, HandleTrait,
+    RenderClientTrait,
+};
+use crate::conversion::silence;
+use crate::wasapi::{
+    BufferFlags, Direction, FormatSupported, ShareMode, WasapiError, WasapiRes, WaveFormat,
+};
+
+// Description of a simulated device.
+#[derive(Clone, Debug)]
+pub struct SimDeviceConfig {
+    pub name: String,
+    pub id: String,
+    pub direction: Direction,
+    // The format used in shared mode
+    pub mix_format: WaveFormat,
+    // The formats supported in exclusive mode
+    pub formats: Vec<WaveFormat>,
+    // Default and minimum periods in 100-nanosecond units
+    pub default_period: i64,
+    pub min_period: i64,
+}
+
+impl SimDeviceConfig {
+    // A device with a 10 ms default and 3 ms minimum period, supporting only the mix format
+    pub fn new(name: &str, direction: &Direction, mix_format: &WaveFormat) -> Self {
+        SimDeviceConfig {
+            name: name.to_string(),
+            id: format!("{{sim}}.{}", name),
+            direction: direction.clone(),
+            mix_format: mix_format.clone(),
+            formats: vec![mix_format.clone()],
+            default_period: 100000,
+            min_period: 30000,
+        }
+    }
+}
+
+// A failure that can be injected in a simulated device.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    // The next wait for an event times out, while the clock moves on to the deadline
+    EventTimeout,
+    // The device disappears, all following calls on its clients fail with AUDCLNT_E_DEVICE_INVALIDATED
+    DeviceInvalidated,
+    // The next write to the playback buffer fails with AUDCLNT_E_BUFFER_TOO_LARGE
+    BufferTooLarge,
+    // The next stream initialization fails with AUDCLNT_E_UNSUPPORTED_FORMAT
+    FormatRejected,
+    // The next captured packet has the data discontinuity flag set
+    Discontinuity,
+    // The next captured packet has the silent flag set
+    SilentPacket,
+    // The device clock runs fast (positive) or slow (negative) by this many ppm
+    ClockDrift(f64),
+}
+
+// When a scheduled fault happens.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FaultTrigger {
+    // When the virtual clock reaches this time
+    Time(Duration),
+    // Just before the n-th call to the clients of the device, counting from 1
+    Call(u64),
+}
+
+// How waiting for events relates to real time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pacing {
+    // Waits return as soon as the event has been processed
+    AsFastAsPossible,
+    // Waits sleep until the time of the event, the virtual clock follows the real clock
+    RealTime,
+}
+
+// A source of data for a simulated capture device, used when the data added with `add_capture_data` runs out.
+pub trait SimSource: Send {
+    // Read up to nbr_frames frames in the format of the stream, an empty result means that there is no more data
+    fn read(&mut self, nbr_frames: usize, format: &WaveFormat) -> WasapiRes<Vec<u8>>;
+}
+
+// A destination for the frames played by a simulated render device, used instead of keeping them in memory.
+pub trait SimSink: Send {
+    // A stream was initialized on the device
+    fn open(&mut self, format: &WaveFormat) -> WasapiRes<()>;
+
+    // Frames were played
+    fn write(&mut self, data: &[u8]) -> WasapiRes<()>;
+
+    // The stream was closed
+    fn close(&mut self) -> WasapiRes<()>;
+}
+
+struct ScheduledFault {
+    device: usize,
+    trigger: FaultTrigger,
+    fault: Fault,
+}
+
+// Frames played by a render device, and the data to be returned by a capture device.
+struct SimDevice {
+    config: SimDeviceConfig,
+    rendered: Vec<u8>,
+    underruns: Vec<u64>,
+    capture_script: VecDeque<u8>,
+    overflows: usize,
+    played_frames: u64,
+    source: Option<Box<dyn SimSource>>,
+    sink: Option<Box<dyn SimSink>>,
+    // Number of calls to the clients of the device
+    calls: u64,
+    invalidated: bool,
+    // Error of the source or sink that made the device invalid
+    error: Option<String>,
+    drift_ppm: f64,
+    // Faults that have happened, waiting for the call they affect
+    armed: Vec<Fault>,
+}
+
+impl SimDevice {
+    // Consume an armed fault, returns true if it was armed
+    fn take_armed(&mut self, fault: &Fault) -> bool {
+        match self.armed.iter().position(|armed| armed == fault) {
+            Some(index) => {
+                self.armed.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// An initialized stream.
+struct SimStream {
+    device: usize,
+    direction: Direction,
+    format: WaveFormat,
+    sharemode: ShareMode,
+    period_frames: usize,
+    buffer_frames: usize,
+    started: bool,
+    signaled: bool,
+    start_time: u64,
+    nbr_events: u64,
+    // Frames written by the client and not yet played
+    render_queue: VecDeque<u8>,
+    // Captured packets not yet read by the client
+    packets: VecDeque<(Vec<u8>, BufferFlags)>,
+    discontinuity: bool,
+    drift_ppm: f64,
+    // A thread is waiting for the event of the stream
+    waiting: bool,
+}
+
+impl SimStream {
+    fn blockalign(&self) -> usize {
+        self.format.get_blockalign() as usize
+    }
+
+    fn padding(&self) -> usize {
+        match self.direction {
+            Direction::Render => self.render_queue.len() / self.blockalign(),
+            Direction::Capture => self
+                .packets
+                .iter()
+                .map(|(data, _)| data.len() / self.blockalign())
+                .sum(),
+        }
+    }
+
+    // Time of the n-th buffer event in nanoseconds, calculated from the start to not accumulate rounding errors
+    fn event_time(&self, nbr_events: u64) -> u64 {
+        let rate = self.format.get_samplespersec() as f64 * (1.0 + self.drift_ppm * 1.0e-6);
+        self.start_time
+            + (nbr_events as f64 * self.period_frames as f64 * 1.0e9 / rate).round() as u64
+    }
+
+    fn next_event(&self) -> u64 {
+        self.event_time(self.nbr_events + 1)
+    }
+}
+
+struct SimState {
+    time: u64,
+    devices: Vec<SimDevice>,
+    streams: Vec<Option<SimStream>>,
+    faults: Vec<ScheduledFault>,
+    pacing: Pacing,
+    // Real and virtual time when the pacing was last set
+    pacing_start: (Instant, u64),
+}
+
+impl SimState {
+    fn device_index(&self, name: &str) -> WasapiRes<usize> {
+        self.devices
+            .iter()
+            .position(|device| device.config.name == name)
+            .ok_or_else(|| {
+                WasapiError::new(format!("Unable to find device {}", name).as_str()).into()
+            })
+    }
+
+    fn stream(&mut self, index: usize) -> WasapiRes<&mut SimStream> {
+        match self.streams.get_mut(index) {
+            Some(Some(stream)) => Ok(stream),
+            _ => Err(WasapiError::new("Client has not been initialized").into()),
+        }
+    }
+
+    // Count a call to the clients of a device, and check that the device is still valid
+    fn enter(&mut self, device: usize) -> WasapiRes<()> {
+        self.devices[device].calls += 1;
+        let calls = self.devices[device].calls;
+        self.activate_due(|fault| {
+            fault.device == device && fault.trigger == FaultTrigger::Call(calls)
+        });
+        let device = &self.devices[device];
+        match &device.error {
+            Some(error) => Err(WasapiError::new(error).into()),
+            None if device.invalidated => {
+                Err(WasapiError::new("AUDCLNT_E_DEVICE_INVALIDATED").into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Remove the scheduled faults matching a condition, and make them happen
+    fn activate_due(&mut self, is_due: impl Fn(&ScheduledFault) -> bool) {
+        let (due, remaining): (Vec<ScheduledFault>, Vec<ScheduledFault>) =
+            self.faults.drain(..).partition(is_due);
+        self.faults = remaining;
+        for fault in due {
+            self.activate(fault.device, fault.fault);
+        }
+    }
+
+    fn activate(&mut self, device: usize, fault: Fault) {
+        let streams = self
+            .streams
+            .iter_mut()
+            .flatten()
+            .filter(|stream| stream.device == device);
+        match fault {
+            Fault::DeviceInvalidated => {
+                // The streams stop, and their events never come
+                for stream in streams {
+                    stream.started = false;
+                }
+                self.devices[device].invalidated = true;
+            }
+            Fault::ClockDrift(drift_ppm) => {
+                // Continue from the last event with the new rate
+                for stream in streams {
+                    stream.start_time = stream.event_time(stream.nbr_events);
+                    stream.nbr_events = 0;
+                    stream.drift_ppm = drift_ppm;
+                }
+                self.devices[device].drift_ppm = drift_ppm;
+            }
+            fault => self.devices[device].armed.push(fault),
+        }
+    }
+
+    // Play or capture one period of a stream
+    fn process_event(&mut self, index: usize) {
+        let stream = self.streams[index].as_mut().unwrap();
+        let device_index = stream.device;
+        let device = &mut self.devices[device_index];
+        let blockalign = stream.blockalign();
+        let nbr_bytes = stream.period_frames * blockalign;
+        let mut result = Ok(());
+        match stream.direction {
+            Direction::Render => {
+                let played = nbr_bytes.min(stream.render_queue.len());
+                let mut data: Vec<u8> = stream.render_queue.drain(..played).collect();
+                if played < nbr_bytes {
+                    device
+                        .underruns
+                        .push(device.played_frames + (played / blockalign) as u64);
+                    data.extend(silence((nbr_bytes - played) / blockalign, &stream.format));
+                }
+                device.played_frames += stream.period_frames as u64;
+                match &mut device.sink {
+                    Some(sink) => result = sink.write(&data),
+                    None => device.rendered.extend(data),
+                }
+            }
+            Direction::Capture => {
+                if device.capture_script.len() < nbr_bytes {
+                    if let Some(source) = &mut device.source {
+                        let missing = nbr_bytes - device.capture_script.len();
+                        match source.read(missing / blockalign, &stream.format) {
+                            Ok(data) => device.capture_script.extend(data),
+                            Err(err) => result = Err(err),
+                        }
+                    }
+                }
+                let mut flags = BufferFlags::default();
+                let available = nbr_bytes.min(device.capture_script.len());
+                let mut data: Vec<u8> = device.capture_script.drain(..available).collect();
+                if available == 0 || device.take_armed(&Fault::SilentPacket) {
+                    flags.silent = true;
+                }
+                if device.take_armed(&Fault::Discontinuity) {
+                    stream.discontinuity = true;
+                }
+                data.extend(silence(
+                    (nbr_bytes - available) / blockalign,
+                    &stream.format,
+                ));
+                if stream.padding() + stream.period_frames > stream.buffer_frames {
+                    // The client is not reading fast enough, the packet is lost
+                    device.overflows += 1;
+                    stream.discontinuity = true;
+                } else {
+                    flags.data_discontinuity = stream.discontinuity;
+                    stream.discontinuity = false;
+                    stream.packets.push_back((data, flags));
+                }
+            }
+        }
+        stream.nbr_events += 1;
+        stream.signaled = true;
+        if let Err(err) = result {
+            self.fail(device_index, err.to_string());
+        }
+    }
+
+    // A source or sink failed, the device becomes invalid and returns the error
+    fn fail(&mut self, device: usize, error: String) {
+        self.devices[device].error = Some(error);
+        self.activate(device, Fault::DeviceInvalidated);
+    }
+
+    // Time of the next event or scheduled fault
+    fn next_time(&self) -> Option<u64> {
+        let next_fault = self.next_fault_time();
+        let next_event = self.next_event().map(|(event_time, _)| event_time);
+        match (next_fault, next_event) {
+            (Some(fault_time), Some(event_time)) => Some(fault_time.min(event_time)),
+            (fault_time, event_time) => fault_time.or(event_time),
+        }
+    }
+
+    fn next_fault_time(&self) -> Option<u64> {
+        self.faults
+            .iter()
+            .filter_map(|fault| match fault.trigger {
+                FaultTrigger::Time(fault_time) => Some(fault_time.as_nanos() as u64),
+                FaultTrigger::Call(_) => None,
+            })
+            .min()
+    }
+
+    // Time and index of the next stream event
+    // At equal times, capture events come before render events,
+    // so that data captured at an event can be played from the same event.
+    fn next_event(&self) -> Option<(u64, usize)> {
+        self.streams
+            .iter()
+            .enumerate()
+            .filter_map(|(index, stream)| match stream {
+                Some(stream) if stream.started => Some((
+                    stream.next_event(),
+                    stream.direction == Direction::Render,
+                    index,
+                )),
+                _ => None,
+            })
+            .min()
+            .map(|(time, _render, index)| (time, index))
+    }
+
+    // Check if the threads of all running streams are waiting for events,
+    // a stream that got an event is busy until its thread has seen it
+    fn all_waiting(&self) -> bool {
+        self.streams
+            .iter()
+            .flatten()
+            .filter(|stream| stream.started)
+            .all(|stream| stream.waiting && !stream.signaled)
+    }
+
+    // Time left until the real clock reaches a time of the virtual clock
+    fn real_time_until(&self, time: u64) -> Duration {
+        match self.pacing {
+            Pacing::AsFastAsPossible => Duration::ZERO,
+            Pacing::RealTime => {
+                let (real_start, virtual_start) = self.pacing_start;
+                let target = real_start + Duration::from_nanos(time.saturating_sub(virtual_start));
+                target.saturating_duration_since(Instant::now())
+            }
+        }
+    }
+
+    // Process the next event or scheduled fault, if it comes before the time limit.
+    // Returns false if there is nothing to process.
+    fn step(&mut self, limit: u64) -> bool {
+        let next_fault = self.next_fault_time();
+        let next_event = self.next_event();
+        if let Some(fault_time) = next_fault {
+            if fault_time <= limit
+                && next_event.is_none_or(|(event_time, _)| fault_time <= event_time)
+            {
+                self.time = self.time.max(fault_time);
+                self.activate_due(|fault| {
+                    fault.trigger == FaultTrigger::Time(Duration::from_nanos(fault_time))
+                });
+                return true;
+            }
+        }
+        match next_event {
+            Some((event_time, index)) if event_time <= limit => {
+                self.time = self.time.max(event_time);
+                self.process_event(index);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Advance the clock, processing all events and faults up to the new time in order
+    fn advance_to(&mut self, time: u64) {
+        while self.step(time) {}
+        self.time = self.time.max(time);
+    }
+}
+
+// How long a wait lets the clock stand still for other running streams whose threads are busy.
+const LOCKSTEP_GRACE: Duration = Duration::from_millis(200);
+
+// The state shared by the backend and all its devices and clients.
+struct SimShared {
+    state: Mutex<SimState>,
+    // Notified when a stream starts waiting, stops or gets an event
+    changed: Condvar,
+}
+
+impl SimShared {
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().unwrap()
+    }
+}
+
+// An in-process audio backend with simulated devices, driven by a virtual clock.
+// Waiting for an event of a running stream moves the clock forward to that event,
+// playing and capturing one period on every running stream whose event comes before it.
+// Nothing ever waits in real time, so loops using the backend run as fast as possible and give the same result every time.
+// When several streams are running, the clock only moves while all of them are waiting for events,
+// so that a stream whose thread is busy does not miss its events.
+//
+// In shared mode the events come every default period of the device,
+// and the buffer holds the requested duration, at least two default periods.
+// In exclusive mode the events come every requested period, at least the minimum period,
+// and the buffer holds one period.
+// A render device records every played frame, and inserts silence when the client does not keep the buffer filled.
+// A capture device returns the data given to it, followed by silence flagged as silent.
+//
+// Failures seen with real devices can be scheduled with `add_fault`,
+// to happen at a given time of the virtual clock or at a given call to the clients of a device.
+// This makes it possible to test how the code using the backend handles and recovers from them.
+// An invalidated device can be brought back with `restore_device`.
+//
+// Instead of using data given in advance and keeping the played frames in memory,
+// the devices can also read from a `SimSource` and write to a `SimSink`, see for example the `filedevice` module.
+// With `Pacing::RealTime`, waiting for an event takes as long as it would with a real device.
+#[derive(Clone)]
+pub struct SimulatedBackend {
+    state: Arc<SimShared>,
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        SimulatedBackend::new()
+    }
+}
+
+impl SimulatedBackend {
+    // Create a backend without devices
+    pub fn new() -> Self {
+        SimulatedBackend {
+            state: Arc::new(SimShared {
+                state: Mutex::new(SimState {
+                    time: 0,
+                    devices: Vec::new(),
+                    streams: Vec::new(),
+                    faults: Vec::new(),
+                    pacing: Pacing::AsFastAsPossible,
+                    pacing_start: (Instant::now(), 0),
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock()
+    }
+
+    // Add a device, the first one in each direction is the default device
+    pub fn add_device(&self, config: SimDeviceConfig) -> WasapiRes<()> {
+        let mut state = self.lock();
+        if state.device_index(&config.name).is_ok() {
+            return Err(WasapiError::new("A device with this name already exists").into());
+        }
+        state.devices.push(SimDevice {
+            config,
+            rendered: Vec::new(),
+            underruns: Vec::new(),
+            capture_script: VecDeque::new(),
+            overflows: 0,
+            played_frames: 0,
+            source: None,
+            sink: None,
+            calls: 0,
+            invalidated: false,
+            error: None,
+            drift_ppm: 0.0,
+            armed: Vec::new(),
+        });
+        Ok(())
+    }
+
+    // Schedule a fault for a device, a fault at a time that has already passed happens immediately
+    pub fn add_fault(&self, device: &str, trigger: FaultTrigger, fault: Fault) -> WasapiRes<()> {
+        let mut state = self.lock();
+        let index = state.device_index(device)?;
+        match trigger {
+            FaultTrigger::Time(time) if time.as_nanos() as u64 <= state.time => {
+                state.activate(index, fault)
+            }
+            trigger => state.faults.push(ScheduledFault {
+                device: index,
+                trigger,
+                fault,
+            }),
+        }
+        Ok(())
+    }
+
+    // Bring back an invalidated device, as when it is plugged in again.
+    // The clients that were opened before stay unusable, new ones have to be opened.
+    pub fn restore_device(&self, device: &str) -> WasapiRes<()> {
+        let mut state = self.lock();
+        let index = state.device_index(device)?;
+        for stream in state.streams.iter_mut() {
+            if stream.as_ref().is_some_and(|stream| stream.device == index) {
+                *stream = None;
+            }
+        }
+        state.devices[index].invalidated = false;
+        state.devices[index].error = None;
+        self.state.changed.notify_all();
+        Ok(())
+    }
+
+    // Get the number of calls made so far to the clients of a device
+    pub fn get_nbr_calls(&self, device: &str) -> WasapiRes<u64> {
+        let state = self.lock();
+        let index = state.device_index(device)?;
+        Ok(state.devices[index].calls)
+    }
+
+    // Queue data to be returned by a capture device, in the format of the stream that will read it
+    pub fn add_capture_data(&self, device: &str, data: &[u8]) -> WasapiRes<()> {
+        let mut state = self.lock();
+        let index = state.device_index(device)?;
+        state.devices[index].capture_script.extend(data);
+        Ok(())
+    }
+
+    // Read the data of a capture device from a source, after the data added with `add_capture_data`
+    pub fn set_capture_source(&self, device: &str, source: Box<dyn SimSource>) -> WasapiRes<()> {
+        let mut state = self.lock();
+        let index = state.device_index(device)?;
+        state.devices[index].source = Some(source);
+        Ok(())
+    }
+
+    // Send the frames played by a render device to a sink, instead of keeping them in memory
+    pub fn set_render_sink(&self, device: &str, sink: Box<dyn SimSink>) -> WasapiRes<()> {
+        let mut state = self.lock();
+        let index = state.device_index(device)?;
+        state.devices[index].sink = Some(sink);
+        Ok(())
+    }
+
+    // Set how waiting for events relates to real time, the default is as fast as possible
+    pub fn set_pacing(&self, pacing: Pacing) {
+        let mut state = self.lock();
+        state.pacing = pacing;
+        state.pacing_start = (Instant::now(), state.time);
+    }
+
+    // Get all frames played by a render device so far
+    pub fn get_rendered(&self, device: &str) -> WasapiRes<Vec<u8>> {
+        let state = self.lock();
+        let index = state.device_index(device)?;
+        Ok(state.devices[index].rendered.clone())
+    }
+
+    // Get the positions in the rendered data where silence was inserted because of an underrun
+    pub fn get_underruns(&self, device: &str) -> WasapiRes<Vec<u64>> {
+        let state = self.lock();
+        let index = state.device_index(device)?;
+        Ok(state.devices[index].underruns.clone())
+    }
+
+    // Get the number of captured packets lost because the client did not read them in time
+    pub fn get_nbr_overflows(&self, device: &str) -> WasapiRes<usize> {
+        let state = self.lock();
+        let index = state.device_index(device)?;
+        Ok(state.devices[index].overflows)
+    }
+
+    // Get the time of the virtual clock
+    pub fn get_time(&self) -> Duration {
+        Duration::from_nanos(self.lock().time)
+    }
+
+    // Move the virtual clock forward, processing the events of all running streams
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.lock();
+        let time = state.time + duration.as_nanos() as u64;
+        state.advance_to(time);
+    }
+}
+
+impl Backend for SimulatedBackend {
+    fn get_device_collection(
+        &self,
+        direction: &Direction,
+    ) -> WasapiRes<Box<dyn DeviceCollectionTrait>> {
+        let state = self.lock();
+        let devices = state
+            .devices
+            .iter()
+            .enumerate()
+            .filter(|(_, device)| device.config.direction == *direction && !device.invalidated)
+            .map(|(index, _)| index)
+            .collect();
+        Ok(Box::new(SimDeviceCollection {
+            state: self.state.clone(),
+            devices,
+        }))
+    }
+
+    fn get_default_device(&self, direction: &Direction) -> WasapiRes<Box<dyn DeviceTrait>> {
+        let collection = self.get_device_collection(direction)?;
+        if collection.get_nbr_devices()? == 0 {
+            return Err(WasapiError::new("Failed to get default device").into());
+        }
+        collection.get_device_at_index(0)
+    }
+}
+
+struct SimDeviceCollection {
+    state: Arc<SimShared>,
+    devices: Vec<usize>,
+}
+
+impl DeviceCollectionTrait for SimDeviceCollection {
+    fn get_nbr_devices(&self) -> WasapiRes<u32> {
+        Ok(self.devices.len() as u32)
+    }
+
+    fn get_device_at_index(&self, idx: u32) -> WasapiRes<Box<dyn DeviceTrait>> {
+        match self.devices.get(idx as usize) {
+            Some(device) => Ok(Box::new(SimDeviceHandle {
+                state: self.state.clone(),
+                device: *device,
+            })),
+            None => Err(WasapiError::new("Failed to get device").into()),
+        }
+    }
+}
+
+struct SimDeviceHandle {
+    state: Arc<SimShared>,
+    device: usize,
+}
+
+impl SimDeviceHandle {
+    fn config(&self) -> SimDeviceConfig {
+        self.state.lock().devices[self.device].config.clone()
+    }
+
+    fn is_invalidated(&self) -> bool {
+        self.state.lock().devices[self.device].invalidated
+    }
+}
+
+impl DeviceTrait for SimDeviceHandle {
+    fn get_iaudioclient(&self) -> WasapiRes<Box<dyn AudioClientTrait>> {
+        if self.is_invalidated() {
+            return Err(WasapiError::new("AUDCLNT_E_DEVICE_INVALIDATED").into());
+        }
+        Ok(Box::new(SimAudioClient {
+            state: self.state.clone(),
+            device: self.device,
+            stream: None,
+        }))
+    }
+
+    fn get_state(&self) -> WasapiRes<u32> {
+        // DEVICE_STATE_NOTPRESENT or DEVICE_STATE_ACTIVE
+        if self.is_invalidated() {
+            Ok(4)
+        } else {
+            Ok(1)
+        }
+    }
+
+    fn get_friendlyname(&self) -> WasapiRes<String> {
+        Ok(self.config().name)
+    }
+
+    fn get_id(&self) -> WasapiRes<String> {
+        Ok(self.config().id)
+    }
+}
+
+struct SimAudioClient {
+    state: Arc<SimShared>,
+    device: usize,
+    stream: Option<usize>,
+}
+
+impl SimAudioClient {
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock()
+    }
+
+    // Lock the state for a call to the client
+    fn enter(&self) -> WasapiRes<MutexGuard<'_, SimState>> {
+        let mut state = self.lock();
+        state.enter(self.device)?;
+        Ok(state)
+    }
+
+    fn stream_index(&self) -> WasapiRes<usize> {
+        self.stream
+            .ok_or_else(|| WasapiError::new("Client has not been initialized").into())
+    }
+}
+
+// Check if a device supports a format
+fn check_format(
+    config: &SimDeviceConfig,
+    wave_fmt: &WaveFormat,
+    sharemode: &ShareMode,
+) -> WasapiRes<FormatSupported> {
+    match sharemode {
+        ShareMode::Shared if *wave_fmt == config.mix_format => Ok(FormatSupported::Yes),
+        ShareMode::Shared => Ok(FormatSupported::ClosestMatch(config.mix_format.clone())),
+        ShareMode::Exclusive if config.formats.contains(wave_fmt) => Ok(FormatSupported::Yes),
+        ShareMode::Exclusive => Err(WasapiError::new("Unsupported format").into()),
+    }
+}
+
+// Convert a duration in 100-nanosecond units to frames
+fn period_to_frames(period: i64, format: &WaveFormat) -> usize {
+    (period as u64 * format.get_samplespersec() as u64).div_ceil(10_000_000) as usize
+}
+
+impl AudioClientTrait for SimAudioClient {
+    fn is_supported(
+        &self,
+        wave_fmt: &WaveFormat,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<FormatSupported> {
+        let state = self.enter()?;
+        check_format(&state.devices[self.device].config, wave_fmt, sharemode)
+    }
+
+    fn get_periods(&self) -> WasapiRes<(i64, i64)> {
+        let state = self.enter()?;
+        let config = &state.devices[self.device].config;
+        Ok((config.default_period, config.min_period))
+    }
+
+    fn initialize_client(
+        &mut self,
+        wavefmt: &WaveFormat,
+        period: i64,
+        direction: &Direction,
+        sharemode: &ShareMode,
+    ) -> WasapiRes<()> {
+        let mut state = self.enter()?;
+        if self.stream.is_some() {
+            return Err(WasapiError::new("Client is already initialized").into());
+        }
+        let config = state.devices[self.device].config.clone();
+        match (&config.direction, direction, sharemode) {
+            (Direction::Render, Direction::Capture, ShareMode::Shared) => {
+                return Err(WasapiError::new(
+                    "Loopback capture is not supported by the simulation",
+                )
+                .into());
+            }
+            (Direction::Render, Direction::Capture, ShareMode::Exclusive) => {
+                return Err(WasapiError::new("Cant use Loopback with exclusive mode").into());
+            }
+            (Direction::Capture, Direction::Render, _) => {
+                return Err(WasapiError::new("Cant render to a capture device").into());
+            }
+            _ => {}
+        }
+        if let FormatSupported::ClosestMatch(_) = check_format(&config, wavefmt, sharemode)? {
+            return Err(WasapiError::new("Unsupported format").into());
+        }
+        if state.devices[self.device].take_armed(&Fault::FormatRejected) {
+            return Err(WasapiError::new("AUDCLNT_E_UNSUPPORTED_FORMAT").into());
+        }
+        let (period_frames, buffer_frames) = match sharemode {
+            ShareMode::Shared => {
+                let period_frames = period_to_frames(config.default_period, wavefmt);
+                (
+                    period_frames,
+                    period_to_frames(period, wavefmt).max(2 * period_frames),
+                )
+            }
+            ShareMode::Exclusive => {
+                let period_frames = period_to_frames(period.max(config.min_period), wavefmt);
+                (period_frames, period_frames)
+            }
+        };
+        if let (Direction::Render, Some(sink)) = (direction, &mut state.devices[self.device].sink) {
+            sink.open(wavefmt)?;
+        }
+        let drift_ppm = state.devices[self.device].drift_ppm;
+        state.streams.push(Some(SimStream {
+            device: self.device,
+            direction: direction.clone(),
+            format: wavefmt.clone(),
+            sharemode: sharemode.clone(),
+            period_frames,
+            buffer_frames,
+            started: false,
+            signaled: false,
+            start_time: 0,
+            nbr_events: 0,
+            render_queue: VecDeque::new(),
+            packets: VecDeque::new(),
+            discontinuity: false,
+            drift_ppm,
+            waiting: false,
+        }));
+        let index = state.streams.len() - 1;
+        drop(state);
+        self.stream = Some(index);
+        Ok(())
+    }
+
+    fn set_get_eventhandle(&self) -> WasapiRes<Box<dyn HandleTrait>> {
+        self.lock().enter(self.device)?;
+        Ok(Box::new(SimHandle {
+            state: self.state.clone(),
+            device: self.device,
+            stream: self.stream_index()?,
+        }))
+    }
+
+    fn get_bufferframecount(&self) -> WasapiRes<u32> {
+        let mut state = self.enter()?;
+        Ok(state.stream(self.stream_index()?)?.buffer_frames as u32)
+    }
+
+    fn get_current_padding(&self) -> WasapiRes<u32> {
+        let mut state = self.enter()?;
+        Ok(state.stream(self.stream_index()?)?.padding() as u32)
+    }
+
+    fn get_available_frames(&self) -> WasapiRes<u32> {
+        let mut state = self.enter()?;
+        let index = self.stream_index()?;
+        let stream = state.stream(index)?;
+        let frames = match stream.sharemode {
+            ShareMode::Exclusive => stream.buffer_frames,
+            ShareMode::Shared => stream.buffer_frames.saturating_sub(stream.padding()),
+        };
+        Ok(frames as u32)
+    }
+
+    fn start_stream(&self) -> WasapiRes<()> {
+        let mut state = self.enter()?;
+        let index = self.stream_index()?;
+        let time = state.time;
+        let stream = state.stream(index)?;
+        if stream.started {
+            return Err(WasapiError::new("The stream is already running").into());
+        }
+        stream.started = true;
+        stream.start_time = time;
+        stream.nbr_events = 0;
+        Ok(())
+    }
+
+    fn stop_stream(&self) -> WasapiRes<()> {
+        let mut state = self.enter()?;
+        state.stream(self.stream_index()?)?.started = false;
+        self.state.changed.notify_all();
+        Ok(())
+    }
+
+    fn get_audiorenderclient(&self) -> WasapiRes<Box<dyn RenderClientTrait>> {
+        let mut state = self.enter()?;
+        let index = self.stream_index()?;
+        match state.stream(index)?.direction {
+            Direction::Render => Ok(Box::new(SimRenderClient {
+                state: self.state.clone(),
+                device: self.device,
+                stream: index,
+            })),
+            Direction::Capture => Err(WasapiError::new("Failed getting IAudioRenderClient").into()),
+        }
+    }
+
+    fn get_audiocaptureclient(&self) -> WasapiRes<Box<dyn CaptureClientTrait>> {
+        let mut state = self.enter()?;
+        let index = self.stream_index()?;
+        match state.stream(index)?.direction {
+            Direction::Capture => Ok(Box::new(SimCaptureClient {
+                state: self.state.clone(),
+                device: self.device,
+                stream: index,
+            })),
+            Direction::Render => Err(WasapiError::new("Failed getting IAudioCaptureClient").into()),
+        }
+    }
+}
+
+impl Drop for SimAudioClient {
+    fn drop(&mut self) {
+        if let Some(index) = self.stream {
+            let mut state = self.lock();
+            let is_render = state.streams[index]
+                .as_ref()
+                .is_some_and(|stream| stream.direction == Direction::Render);
+            state.streams[index] = None;
+            self.state.changed.notify_all();
+            if let (true, Some(sink)) = (is_render, &mut state.devices[self.device].sink) {
+                if let Err(err) = sink.close() {
+                    state.fail(self.device, err.to_string());
+                }
+            }
+        }
+    }
+}
+
+struct SimRenderClient {
+    state: Arc<SimShared>,
+    device: usize,
+    stream: usize,
+}
+
+impl RenderClientTrait for SimRenderClient {
+    fn write_to_device(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &[u8],
+    ) -> WasapiRes<()> {
+        let nbr_bytes = nbr_frames * byte_per_frame;
+        if nbr_bytes != data.len() {
+            return Err(WasapiError::new(
+                format!(
+                    "Wrong length of data, got {}, expected {}",
+                    data.len(),
+                    nbr_bytes
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        let mut state = self.state.lock();
+        state.enter(self.device)?;
+        if state.devices[self.device].take_armed(&Fault::BufferTooLarge) {
+            return Err(WasapiError::new("AUDCLNT_E_BUFFER_TOO_LARGE").into());
+        }
+        let stream = state.stream(self.stream)?;
+        if byte_per_frame != stream.blockalign() {
+            return Err(WasapiError::new("Wrong number of bytes per frame").into());
+        }
+        if stream.padding() + nbr_frames > stream.buffer_frames {
+            return Err(WasapiError::new("The buffer is too large").into());
+        }
+        stream.render_queue.extend(data);
+        Ok(())
+    }
+
+    fn write_to_device_from_deque(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<()> {
+        let nbr_bytes = nbr_frames * byte_per_frame;
+        if nbr_bytes > data.len() {
+            return Err(WasapiError::new(
+                format!("To little data, got {}, need {}", data.len(), nbr_bytes).as_str(),
+            )
+            .into());
+        }
+        let chunk: Vec<u8> = data.iter().take(nbr_bytes).copied().collect();
+        self.write_to_device(nbr_frames, byte_per_frame, &chunk)?;
+        data.drain(..nbr_bytes);
+        Ok(())
+    }
+}
+
+struct SimCaptureClient {
+    state: Arc<SimShared>,
+    device: usize,
+    stream: usize,
+}
+
+impl CaptureClientTrait for SimCaptureClient {
+    fn get_next_nbr_frames(&self) -> WasapiRes<u32> {
+        let mut state = self.state.lock();
+        state.enter(self.device)?;
+        let stream = state.stream(self.stream)?;
+        let blockalign = stream.blockalign();
+        Ok(stream
+            .packets
+            .front()
+            .map(|(data, _)| data.len() / blockalign)
+            .unwrap_or(0) as u32)
+    }
+
+    fn read_from_device(&self, bytes_per_frame: usize, data: &mut [u8]) -> WasapiRes<BufferFlags> {
+        let mut state = self.state.lock();
+        state.enter(self.device)?;
+        let stream = state.stream(self.stream)?;
+        let packet_len = stream
+            .packets
+            .front()
+            .map(|(packet, _)| packet.len())
+            .unwrap_or(0);
+        if data.len() != packet_len {
+            return Err(WasapiError::new(
+                format!(
+                    "Wrong length of data, got {} frames, expected {} frames",
+                    data.len() / bytes_per_frame,
+                    packet_len / bytes_per_frame
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        match stream.packets.pop_front() {
+            Some((packet, flags)) => {
+                data.copy_from_slice(&packet);
+                Ok(flags)
+            }
+            None => Ok(BufferFlags::default()),
+        }
+    }
+
+    fn read_from_device_to_deque(
+        &self,
+        _bytes_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<BufferFlags> {
+        let mut state = self.state.lock();
+        state.enter(self.device)?;
+        let stream = state.stream(self.stream)?;
+        match stream.packets.pop_front() {
+            Some((packet, flags)) => {
+                data.extend(packet);
+                Ok(flags)
+            }
+            None => Ok(BufferFlags::default()),
+        }
+    }
+}
+
+struct SimHandle {
+    state: Arc<SimShared>,
+    device: usize,
+    stream: usize,
+}
+
+impl HandleTrait for SimHandle {
+    fn wait_for_event(&self, timeout_ms: u32) -> WasapiRes<()> {
+        let mut state = self.state.lock();
+        let deadline = state.time + timeout_ms as u64 * 1_000_000;
+        // Waiting on the event of an invalidated device just times out
+        let timed_out = state.enter(self.device).is_err()
+            || state.devices[self.device].take_armed(&Fault::EventTimeout);
+        state.stream(self.stream)?.waiting = true;
+        self.state.changed.notify_all();
+        // Step through the events of all streams until this one is signaled,
+        // a fault may change when that happens
+        let mut lockstep = true;
+        let result = loop {
+            match state.stream(self.stream) {
+                Ok(stream) if !timed_out && stream.signaled => {
+                    stream.signaled = false;
+                    break Ok(());
+                }
+                Ok(_) => {}
+                Err(err) => break Err(err),
+            }
+            let next = match state.next_time() {
+                Some(next) if !timed_out => next.min(deadline),
+                _ => deadline,
+            };
+            // With real time pacing, sleep without blocking the other streams
+            let sleep_time = state.real_time_until(next);
+            if !sleep_time.is_zero() {
+                drop(state);
+                thread::sleep(sleep_time);
+                state = self.state.lock();
+                continue;
+            }
+            // Give the threads of the other streams some time to start waiting
+            if !timed_out && lockstep && !state.all_waiting() {
+                let (guard, wait) = self
+                    .state
+                    .changed
+                    .wait_timeout(state, LOCKSTEP_GRACE)
+                    .unwrap();
+                state = guard;
+                lockstep = !wait.timed_out();
+                continue;
+            }
+            lockstep = true;
+            if timed_out || !state.step(deadline) {
+                state.advance_to(deadline);
+                break Err(WasapiError::new("Wait timed out").into());
+            }
+            self.state.changed.notify_all();
+        };
+        if let Ok(stream) = state.stream(self.stream) {
+            stream.waiting = false;
+        }
+        result
+    }
+}
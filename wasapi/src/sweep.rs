@@ -0,0 +1,385 @@
+use std::f64::consts::PI;
+use std::path::Path;
+
+use rustfft::num_complex::Complex;
+
+use crate::dsp::{convolve, fft_real, find_peak, ifft_real, next_pow2};
+use crate::generator::log_sweep;
+use crate::loopback::Loopback;
+use crate::wasapi::{SampleType, WasapiError, WasapiRes, WaveFormat};
+use crate::wav::save_wav;
+
+// An exponential sine sweep and its inverse filter, for measuring impulse responses with the Farina method.
+pub struct Sweep {
+    pub f_start: f64,
+    pub f_end: f64,
+    pub samplerate: usize,
+    signal: Vec<f64>,
+    inverse: Vec<f64>,
+    margin: usize,
+}
+
+impl Sweep {
+    // Generate a sweep and its inverse filter
+    pub fn new(
+        f_start: f64,
+        f_end: f64,
+        nbr_frames: usize,
+        samplerate: usize,
+        amplitude: f64,
+    ) -> WasapiRes<Self> {
+        if f_start <= 0.0
+            || f_end <= f_start
+            || f_end > samplerate as f64 / 2.0
+            || nbr_frames < 1024
+        {
+            return Err(WasapiError::new("Invalid sweep parameters").into());
+        }
+        let mut signal = log_sweep(f_start, f_end, nbr_frames, samplerate, amplitude);
+        // Short fades to avoid clicks, a few cycles of the start frequency and about a millisecond at the end.
+        let fade_in_frames = ((3.0 * samplerate as f64 / f_start) as usize).min(nbr_frames / 10);
+        let fade_out_frames = samplerate / 1000;
+        fade_in(&mut signal, fade_in_frames);
+        fade_out(&mut signal, fade_out_frames);
+
+        // The inverse filter is calculated in the frequency domain, as a regularized inverse of the sweep.
+        // This gives a flat response also for short sweeps, where the time reversed sweep
+        // with a 6 dB per octave slope is only approximately right.
+        // Outside the range of the sweep the inverse is faded out over one octave,
+        // to avoid amplifying noise and distortion products there.
+        // A delay of nbr_frames - 1 puts it in the same position as the time reversed sweep.
+        // The end of the sweep then ends up at time zero, and the band limiting makes it ring
+        // on both sides of it. An extra margin of delay keeps this ringing from wrapping around,
+        // and is removed again after deconvolution.
+        let margin = nbr_frames / 4;
+        let fft_len = next_pow2(4 * nbr_frames);
+        let spectrum = fft_real(&signal, fft_len);
+        let max_power = spectrum
+            .iter()
+            .map(|value| value.norm_sqr())
+            .fold(0.0, f64::max);
+        let eps = 1.0e-6 * max_power;
+        let band_weight = |freq: f64| -> f64 {
+            let octaves = if freq < f_start {
+                (f_start / freq.max(1.0e-9)).log2()
+            } else if freq > f_end {
+                (freq / f_end).log2()
+            } else {
+                0.0
+            };
+            if octaves >= 1.0 {
+                0.0
+            } else {
+                0.5 + 0.5 * (PI * octaves).cos()
+            }
+        };
+        let inverse_spectrum: Vec<Complex<f64>> = spectrum
+            .iter()
+            .enumerate()
+            .map(|(k, value)| {
+                let freq = k.min(fft_len - k) as f64 * samplerate as f64 / fft_len as f64;
+                let delay = Complex::from_polar(
+                    1.0,
+                    -2.0 * PI * (k * (nbr_frames - 1 + margin)) as f64 / fft_len as f64,
+                );
+                value.conj() / (value.norm_sqr() + eps) * delay * band_weight(freq)
+            })
+            .collect();
+        let inverse = ifft_real(&inverse_spectrum);
+        Ok(Sweep {
+            f_start,
+            f_end,
+            samplerate,
+            signal,
+            inverse,
+            margin,
+        })
+    }
+
+    // Get the sweep signal
+    pub fn get_signal(&self) -> &[f64] {
+        &self.signal
+    }
+
+    // Get the inverse filter. It includes an extra delay of get_margin() frames.
+    pub fn get_inverse(&self) -> &[f64] {
+        &self.inverse
+    }
+
+    // Get the extra delay of the inverse filter, in frames
+    pub fn get_margin(&self) -> usize {
+        self.margin
+    }
+
+    // Time in frames by which the response of a harmonic comes before the linear response
+    pub fn get_harmonic_offset(&self, order: usize) -> f64 {
+        self.signal.len() as f64 * (order as f64).ln() / (self.f_end / self.f_start).ln()
+    }
+
+    // Deconvolve a recorded response. The linear impulse response starts at index
+    // len - 1 + latency, and the harmonic distortion products come before it.
+    pub fn deconvolve(&self, recorded: &[f64]) -> Vec<f64> {
+        let mut result = convolve(recorded, &self.inverse);
+        result.drain(..self.margin.min(result.len()));
+        result
+    }
+}
+
+// Magnitude and phase response on a logarithmic frequency axis.
+#[derive(Clone, Debug)]
+pub struct FrequencyResponse {
+    pub frequencies: Vec<f64>,
+    pub magnitude_db: Vec<f64>,
+    pub phase_deg: Vec<f64>,
+}
+
+impl FrequencyResponse {
+    // Calculate the response of an impulse response at logarithmically spaced frequencies.
+    // The phase is given relative to a reference time of ref_frame frames into the impulse response.
+    pub fn from_impulse_response(
+        impulse_response: &[f64],
+        ref_frame: usize,
+        samplerate: usize,
+        f_start: f64,
+        f_end: f64,
+        points_per_octave: usize,
+    ) -> Self {
+        let fft_len = next_pow2(impulse_response.len().max(4096));
+        let spectrum = fft_real(impulse_response, fft_len);
+        let nbr_points = ((f_end / f_start).log2() * points_per_octave as f64).floor() as usize + 1;
+        let mut frequencies = Vec::with_capacity(nbr_points);
+        let mut magnitude_db = Vec::with_capacity(nbr_points);
+        let mut phase_deg = Vec::with_capacity(nbr_points);
+        for n in 0..nbr_points {
+            let freq = f_start * 2.0f64.powf(n as f64 / points_per_octave as f64);
+            let value = interpolate_spectrum(&spectrum, freq * fft_len as f64 / samplerate as f64);
+            let shift =
+                Complex::from_polar(1.0, 2.0 * PI * freq * ref_frame as f64 / samplerate as f64);
+            let value = value * shift;
+            frequencies.push(freq);
+            magnitude_db.push(20.0 * value.norm().max(1.0e-30).log10());
+            phase_deg.push(value.arg().to_degrees());
+        }
+        FrequencyResponse {
+            frequencies,
+            magnitude_db,
+            phase_deg,
+        }
+    }
+
+    // Get the magnitude at the point closest to a frequency
+    pub fn get_magnitude_at(&self, freq: f64) -> Option<f64> {
+        self.closest_index(freq).map(|idx| self.magnitude_db[idx])
+    }
+
+    // Get the phase at the point closest to a frequency
+    pub fn get_phase_at(&self, freq: f64) -> Option<f64> {
+        self.closest_index(freq).map(|idx| self.phase_deg[idx])
+    }
+
+    fn closest_index(&self, freq: f64) -> Option<usize> {
+        self.frequencies
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                (freq.ln() - a.ln())
+                    .abs()
+                    .partial_cmp(&(freq.ln() - b.ln()).abs())
+                    .unwrap()
+            })
+            .map(|(idx, _)| idx)
+    }
+}
+
+// Linear interpolation between the bins of a spectrum.
+fn interpolate_spectrum(spectrum: &[Complex<f64>], bin: f64) -> Complex<f64> {
+    let idx = (bin.floor() as usize).min(spectrum.len() / 2 - 1);
+    let frac = bin - idx as f64;
+    spectrum[idx] * (1.0 - frac) + spectrum[idx + 1] * frac
+}
+
+// Apply a half Hann window to the first frames of a signal.
+fn fade_in(data: &mut [f64], nbr_frames: usize) {
+    let nbr_frames = nbr_frames.min(data.len());
+    for (n, value) in data.iter_mut().take(nbr_frames).enumerate() {
+        *value *= 0.5 - 0.5 * (PI * n as f64 / nbr_frames as f64).cos();
+    }
+}
+
+// Apply a half Hann window to the last frames of a signal.
+fn fade_out(data: &mut [f64], nbr_frames: usize) {
+    let len = data.len();
+    let nbr_frames = nbr_frames.min(len);
+    for n in 0..nbr_frames {
+        data[len - 1 - n] *= 0.5 - 0.5 * (PI * n as f64 / nbr_frames as f64).cos();
+    }
+}
+
+// Impulse response of a harmonic distortion product.
+#[derive(Clone, Debug)]
+pub struct HarmonicResponse {
+    // Order of the harmonic, 2 for the second harmonic
+    pub order: usize,
+    pub impulse_response: Vec<f64>,
+    // Level of the harmonic relative to the linear response, as a function of the excitation frequency
+    pub distortion_db: Vec<f64>,
+}
+
+// Measured response of one channel.
+#[derive(Clone, Debug)]
+pub struct ChannelResponse {
+    pub channel: usize,
+    // Delay from playback to capture, in frames
+    pub latency: usize,
+    // The linear impulse response, with the peak at pre_frames
+    pub impulse_response: Vec<f64>,
+    pub pre_frames: usize,
+    pub response: FrequencyResponse,
+    pub harmonics: Vec<HarmonicResponse>,
+}
+
+impl ChannelResponse {
+    // Save the linear impulse response as a 32-bit float wav file, for use as convolution filter
+    pub fn save_impulse_response<P: AsRef<Path>>(
+        &self,
+        path: P,
+        samplerate: usize,
+    ) -> WasapiRes<()> {
+        let wave_fmt = WaveFormat::new(32, 32, &SampleType::Float, samplerate, 1);
+        save_wav(path, &wave_fmt, std::slice::from_ref(&self.impulse_response))
+    }
+}
+
+// Settings for an impulse and frequency response measurement with an exponential sweep.
+#[derive(Clone, Debug)]
+pub struct SweepMeasurement {
+    pub f_start: f64,
+    pub f_end: f64,
+    // Length of the sweep in frames
+    pub length: usize,
+    pub amplitude_dbfs: f64,
+    // Length of the extracted impulse responses in frames
+    pub ir_length: usize,
+    // Number of frames to keep before the peak of the impulse response
+    pub pre_frames: usize,
+    // Longest latency to look for, in frames
+    pub max_latency: usize,
+    // Highest harmonic to extract
+    pub nbr_harmonics: usize,
+    pub points_per_octave: usize,
+}
+
+impl SweepMeasurement {
+    // Create a measurement with default settings
+    pub fn new(f_start: f64, f_end: f64, length: usize) -> Self {
+        SweepMeasurement {
+            f_start,
+            f_end,
+            length,
+            amplitude_dbfs: -6.0,
+            ir_length: 8192,
+            pre_frames: 64,
+            max_latency: 48000,
+            nbr_harmonics: 5,
+            points_per_octave: 12,
+        }
+    }
+
+    // Measure one channel at a time, playing the sweep on that channel only
+    pub fn measure(&self, loopback: &mut dyn Loopback) -> WasapiRes<Vec<ChannelResponse>> {
+        let format = loopback.get_format();
+        let samplerate = format.get_samplespersec() as usize;
+        let nchannels = format.get_nchannels() as usize;
+        let amplitude = 10.0f64.powf(self.amplitude_dbfs / 20.0);
+        let sweep = Sweep::new(self.f_start, self.f_end, self.length, samplerate, amplitude)?;
+        let mut responses = Vec::with_capacity(nchannels);
+        for channel in 0..nchannels {
+            let mut data = vec![vec![0.0; self.length]; nchannels];
+            data[channel] = sweep.get_signal().to_vec();
+            let captured = loopback.play_and_capture(&data, self.max_latency + self.ir_length)?;
+            responses.push(self.analyze(&sweep, channel, &captured[channel])?);
+        }
+        Ok(responses)
+    }
+
+    // Extract the linear and harmonic impulse responses from a recorded sweep
+    pub fn analyze(
+        &self,
+        sweep: &Sweep,
+        channel: usize,
+        recorded: &[f64],
+    ) -> WasapiRes<ChannelResponse> {
+        let samplerate = sweep.samplerate;
+        let deconvolved = sweep.deconvolve(recorded);
+        let zero = self.length - 1;
+        let search_end = (zero + self.max_latency + 1).min(deconvolved.len());
+        let (peak, _) = find_peak(&deconvolved[zero..search_end])
+            .ok_or_else(|| WasapiError::new("No impulse response found"))?;
+        let peak = zero + peak;
+        let extract = |center: f64, len: usize| -> Vec<f64> {
+            let start = center.round() as isize - self.pre_frames as isize;
+            let mut segment: Vec<f64> = (0..len as isize)
+                .map(|n| {
+                    let idx = start + n;
+                    if idx >= 0 && (idx as usize) < deconvolved.len() {
+                        deconvolved[idx as usize]
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            fade_out(&mut segment, len / 4);
+            segment
+        };
+
+        let impulse_response = extract(peak as f64, self.ir_length);
+        let response = FrequencyResponse::from_impulse_response(
+            &impulse_response,
+            self.pre_frames,
+            samplerate,
+            self.f_start,
+            self.f_end,
+            self.points_per_octave,
+        );
+
+        let mut harmonics = Vec::new();
+        for order in 2..=self.nbr_harmonics {
+            // Each harmonic gets the space up to the start of the next one, at most ir_length
+            let offset = sweep.get_harmonic_offset(order);
+            let space = (sweep.get_harmonic_offset(order + 1) - offset) as usize;
+            let len = space.min(self.ir_length);
+            if len <= 2 * self.pre_frames || offset as usize > peak {
+                break;
+            }
+            let harm_ir = extract(peak as f64 - offset, len);
+            let harm_response = FrequencyResponse::from_impulse_response(
+                &harm_ir,
+                self.pre_frames,
+                samplerate,
+                order as f64 * self.f_start,
+                order as f64 * self.f_end,
+                self.points_per_octave,
+            );
+            // Harmonic response at k*f relative to the linear response at f
+            let distortion_db = harm_response
+                .magnitude_db
+                .iter()
+                .zip(response.magnitude_db.iter())
+                .map(|(harm, lin)| harm - lin)
+                .collect();
+            harmonics.push(HarmonicResponse {
+                order,
+                impulse_response: harm_ir,
+                distortion_db,
+            });
+        }
+        Ok(ChannelResponse {
+            channel,
+            latency: peak - zero,
+            impulse_response,
+            pre_frames: self.pre_frames,
+            response,
+            harmonics,
+        })
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::conversion::bytes_to_channels;
+use crate::dsp::{fft_real, next_pow2, rms, to_db};
+use crate::wasapi::{WasapiRes, WaveFormat};
+
+// Settings for the voice activity detector.
+#[derive(Clone, Debug)]
+pub struct VadSettings {
+    pub samplerate: usize,
+    // Length of the analysis blocks in frames
+    pub block_length: usize,
+    // Level needed to start, in dBFS where a full scale sine is 0 dBFS
+    pub start_level_dbfs: f64,
+    // Level below which a block counts as silence once started, lower than the start level
+    pub stop_level_dbfs: f64,
+    // Blocks with a higher spectral flatness than this are noise, 0 is a pure tone and 1 is white noise
+    pub max_flatness: f64,
+    // Frequency band for the spectral flatness, in Hz
+    pub f_low: f64,
+    pub f_high: f64,
+    // Number of consecutive active blocks needed to start
+    pub attack_blocks: usize,
+    // Length of silence before stopping, in seconds
+    pub hang_time: f64,
+}
+
+impl VadSettings {
+    // Default settings, with 20 ms blocks and one second hang time
+    pub fn new(samplerate: usize) -> Self {
+        VadSettings {
+            samplerate,
+            block_length: samplerate / 50,
+            start_level_dbfs: -40.0,
+            stop_level_dbfs: -50.0,
+            max_flatness: 0.3,
+            f_low: 100.0,
+            f_high: 8000.0,
+            attack_blocks: 2,
+            hang_time: 1.0,
+        }
+    }
+}
+
+// Spectral flatness of a signal in a frequency band, the geometric mean of the power spectrum
+// divided by the arithmetic mean.
+pub fn spectral_flatness(samples: &[f64], samplerate: usize, f_low: f64, f_high: f64) -> f64 {
+    let len = samples.len();
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(n, value)| value * (0.5 - 0.5 * (2.0 * PI * n as f64 / len as f64).cos()))
+        .collect();
+    let fft_len = next_pow2(len);
+    let spectrum = fft_real(&windowed, fft_len);
+    let bin_width = samplerate as f64 / fft_len as f64;
+    let start = ((f_low / bin_width).ceil() as usize).max(1);
+    let end = ((f_high / bin_width).floor() as usize).min(fft_len / 2);
+    if end <= start {
+        return 1.0;
+    }
+    let powers: Vec<f64> = spectrum[start..=end]
+        .iter()
+        .map(|value| value.norm_sqr() + 1.0e-30)
+        .collect();
+    let arithmetic = powers.iter().sum::<f64>() / powers.len() as f64;
+    let geometric =
+        (powers.iter().map(|value| value.ln()).sum::<f64>() / powers.len() as f64).exp();
+    geometric / arithmetic
+}
+
+// Change of the detector state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VadEventKind {
+    Start,
+    Stop,
+}
+
+// A detected start or stop of activity.
+#[derive(Clone, Debug)]
+pub struct VadEvent {
+    pub kind: VadEventKind,
+    // Position in frames from the start of the stream.
+    // For a start this is the first active frame, for a stop it is the end of the hang time.
+    pub position: u64,
+    pub time: f64,
+}
+
+// Voice activity detector, using the level and the spectral flatness of short blocks.
+// The stream is fed in chunks of any size.
+pub struct VoiceActivityDetector {
+    settings: VadSettings,
+    buffer: Vec<Vec<f64>>,
+    // Position of the first frame in the buffer
+    position: u64,
+    active: bool,
+    // Number of consecutive active blocks while inactive, and the position of the first one
+    attack_count: usize,
+    attack_start: u64,
+    // Number of silent frames while active
+    silent_frames: usize,
+}
+
+impl VoiceActivityDetector {
+    // Create a new detector
+    pub fn new(settings: VadSettings) -> Self {
+        VoiceActivityDetector {
+            settings,
+            buffer: Vec::new(),
+            position: 0,
+            active: false,
+            attack_count: 0,
+            attack_start: 0,
+            silent_frames: 0,
+        }
+    }
+
+    // Check if activity is currently detected
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // Get the number of frames between the start of a burst and the block where it is detected
+    pub fn get_max_delay(&self) -> usize {
+        (self.settings.attack_blocks + 1) * self.settings.block_length
+    }
+
+    // Analyze the next chunk of the stream, one vector per channel
+    pub fn process(&mut self, data: &[Vec<f64>]) -> Vec<VadEvent> {
+        if self.buffer.len() != data.len() {
+            self.buffer = vec![Vec::new(); data.len()];
+        }
+        for (buffer, samples) in self.buffer.iter_mut().zip(data.iter()) {
+            buffer.extend_from_slice(samples);
+        }
+        let block_length = self.settings.block_length.max(1);
+        let mut events = Vec::new();
+        while !self.buffer.is_empty() && self.buffer.iter().all(|chan| chan.len() >= block_length) {
+            let block: Vec<Vec<f64>> = self
+                .buffer
+                .iter_mut()
+                .map(|chan| chan.drain(..block_length).collect())
+                .collect();
+            if let Some(event) = self.process_block(&block) {
+                events.push(event);
+            }
+            self.position += block_length as u64;
+        }
+        events
+    }
+
+    fn process_block(&mut self, block: &[Vec<f64>]) -> Option<VadEvent> {
+        // Use the loudest channel
+        let (level, loudest) = block
+            .iter()
+            .map(|chan| to_db(rms(chan) * 2.0f64.sqrt()))
+            .enumerate()
+            .fold((f64::NEG_INFINITY, 0), |(best, idx), (n, level)| {
+                if level > best {
+                    (level, n)
+                } else {
+                    (best, idx)
+                }
+            });
+        let threshold = if self.active {
+            self.settings.stop_level_dbfs
+        } else {
+            self.settings.start_level_dbfs
+        };
+        let is_active = level > threshold
+            && spectral_flatness(
+                &block[loudest],
+                self.settings.samplerate,
+                self.settings.f_low,
+                self.settings.f_high,
+            ) < self.settings.max_flatness;
+        let block_length = self.settings.block_length;
+        if !self.active {
+            if !is_active {
+                self.attack_count = 0;
+                return None;
+            }
+            if self.attack_count == 0 {
+                self.attack_start = self.position;
+            }
+            self.attack_count += 1;
+            if self.attack_count >= self.settings.attack_blocks.max(1) {
+                self.active = true;
+                self.attack_count = 0;
+                self.silent_frames = 0;
+                return Some(self.event(VadEventKind::Start, self.attack_start));
+            }
+            return None;
+        }
+        if is_active {
+            self.silent_frames = 0;
+            return None;
+        }
+        self.silent_frames += block_length;
+        let hang_frames = (self.settings.hang_time * self.settings.samplerate as f64) as usize;
+        if self.silent_frames >= hang_frames {
+            self.active = false;
+            return Some(self.event(VadEventKind::Stop, self.position + block_length as u64));
+        }
+        None
+    }
+
+    fn event(&self, kind: VadEventKind, position: u64) -> VadEvent {
+        VadEvent {
+            kind,
+            position,
+            time: position as f64 / self.settings.samplerate as f64,
+        }
+    }
+}
+
+// Event from the automatic recorder.
+#[derive(Clone, Debug)]
+pub enum RecorderEvent {
+    // A recording started, the position is the first frame including the pre-roll
+    Started {
+        position: u64,
+        time: f64,
+    },
+    // Raw data in the capture format, to be appended to the current recording
+    Data(Vec<u8>),
+    // The recording stopped, the position is the frame after the last one
+    Stopped {
+        position: u64,
+        time: f64,
+        nbr_frames: u64,
+    },
+}
+
+// Recorder that starts when activity is detected and stops after the hang time of silence.
+// It is fed raw captured data, and keeps a pre-roll so that the onsets are not cut.
+pub struct AutoRecorder {
+    format: WaveFormat,
+    detector: VoiceActivityDetector,
+    pre_roll_frames: usize,
+    // Captured data that has not been passed on yet, starting at history_start
+    history: VecDeque<u8>,
+    history_start: u64,
+    position: u64,
+    // Start position of the current recording
+    recording_start: Option<u64>,
+}
+
+impl AutoRecorder {
+    // Create a recorder for data in the given format, with a pre-roll in seconds
+    pub fn new(format: &WaveFormat, settings: VadSettings, pre_roll: f64) -> Self {
+        let pre_roll_frames = (pre_roll * settings.samplerate as f64) as usize;
+        AutoRecorder {
+            format: format.clone(),
+            detector: VoiceActivityDetector::new(settings),
+            pre_roll_frames,
+            history: VecDeque::new(),
+            history_start: 0,
+            position: 0,
+            recording_start: None,
+        }
+    }
+
+    // Check if a recording is in progress
+    pub fn is_recording(&self) -> bool {
+        self.recording_start.is_some()
+    }
+
+    // Process the next chunk of captured data
+    pub fn process(&mut self, data: &[u8]) -> WasapiRes<Vec<RecorderEvent>> {
+        let blockalign = self.format.get_blockalign() as usize;
+        let channels = bytes_to_channels(data, &self.format)?;
+        let vad_events = self.detector.process(&channels);
+        self.history.extend(data.iter());
+        self.position += (data.len() / blockalign) as u64;
+
+        let mut events = Vec::new();
+        for vad_event in vad_events {
+            match vad_event.kind {
+                VadEventKind::Start => {
+                    let start = vad_event
+                        .position
+                        .saturating_sub(self.pre_roll_frames as u64)
+                        .max(self.history_start);
+                    self.drop_history(start);
+                    self.recording_start = Some(start);
+                    events.push(RecorderEvent::Started {
+                        position: start,
+                        time: self.to_seconds(start),
+                    });
+                }
+                VadEventKind::Stop => {
+                    if let Some(start) = self.recording_start.take() {
+                        let end = vad_event.position.min(self.position);
+                        let nbr_bytes = (end - self.history_start) as usize * blockalign;
+                        events.push(RecorderEvent::Data(
+                            self.history.drain(..nbr_bytes).collect(),
+                        ));
+                        self.history_start = end;
+                        events.push(RecorderEvent::Stopped {
+                            position: end,
+                            time: self.to_seconds(end),
+                            nbr_frames: end - start,
+                        });
+                    }
+                }
+            }
+        }
+        if self.recording_start.is_some() {
+            if !self.history.is_empty() {
+                events.push(RecorderEvent::Data(self.history.drain(..).collect()));
+            }
+            self.history_start = self.position;
+        } else {
+            // Keep enough for the pre-roll, plus the delay of the detector
+            let keep = (self.pre_roll_frames + self.detector.get_max_delay()) as u64;
+            self.drop_history(self.position.saturating_sub(keep));
+        }
+        Ok(events)
+    }
+
+    // Drop the history before a position
+    fn drop_history(&mut self, position: u64) {
+        if position > self.history_start {
+            let nbr_bytes =
+                (position - self.history_start) as usize * self.format.get_blockalign() as usize;
+            self.history.drain(..nbr_bytes.min(self.history.len()));
+            self.history_start = position;
+        }
+    }
+
+    fn to_seconds(&self, position: u64) -> f64 {
+        position as f64 / self.format.get_samplespersec() as f64
+    }
+}
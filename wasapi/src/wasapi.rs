@@ -1,21 +1,32 @@
 use std::mem;
+#[cfg(windows)]
 use std::ptr;
+#[cfg(windows)]
 use std::slice;
 use std::fmt;
+#[cfg(windows)]
 use std::collections::VecDeque;
+#[cfg(windows)]
 use widestring::U16CString;
+#[cfg(windows)]
 use windows::Interface;
+#[cfg(windows)]
+use windows::Guid;
 use std::error;
+#[cfg(windows)]
 use crate::{
     PKEY_Device_FriendlyName,
     Windows::Win32::Media::Audio::CoreAudio::{
         eConsole, eRender, eCapture, IAudioClient, IAudioRenderClient, IAudioCaptureClient, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator, IMMDeviceCollection,
         AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_LOOPBACK, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, DEVICE_STATE_ACTIVE, WAVE_FORMAT_EXTENSIBLE,
+        AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR,
     },
     Windows::Win32::Media::Multimedia::{
         WAVEFORMATEX,
         WAVEFORMATEXTENSIBLE,
         WAVEFORMATEXTENSIBLE_0,
+        WAVE_FORMAT_PCM,
+        WAVE_FORMAT_IEEE_FLOAT,
         KSDATAFORMAT_SUBTYPE_PCM,
         KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
     },
@@ -30,8 +41,24 @@ use crate::{
         WaitForSingleObject,
     },
 };
+// On other platforms only the formats and flags are available, using plain versions of the same structs.
+#[cfg(not(windows))]
+use crate::winstructs::{
+    Guid,
+    WAVEFORMATEX,
+    WAVEFORMATEXTENSIBLE,
+    WAVEFORMATEXTENSIBLE_0,
+    WAVE_FORMAT_EXTENSIBLE,
+    WAVE_FORMAT_PCM,
+    WAVE_FORMAT_IEEE_FLOAT,
+    KSDATAFORMAT_SUBTYPE_PCM,
+    KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
+    AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY,
+    AUDCLNT_BUFFERFLAGS_SILENT,
+    AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR,
+};
 
-type WasapiRes<T> = Result<T, Box<dyn error::Error>>;
+pub type WasapiRes<T> = Result<T, Box<dyn error::Error>>;
 
 // Error returned by the Wasapi crate.
 #[derive(Debug)]
@@ -60,14 +87,14 @@ impl WasapiError {
 }
 
 // Audio direction, playback or capture.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Direction {
     Render,
     Capture,
 }
 
 // Sharemode for device
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ShareMode {
     Shared,
     Exclusive,
@@ -80,7 +107,10 @@ pub enum SampleType {
     Int,
 }
 
+// The device wrappers below print their diagnostics to stderr, so that stdout can be used for audio data.
+
 // Get the default playback or capture device
+#[cfg(windows)]
 pub fn get_default_device(direction: &Direction) -> WasapiRes<Device> {
     let dir = match direction {
         Direction::Capture => eCapture,
@@ -92,7 +122,7 @@ pub fn get_default_device(direction: &Direction) -> WasapiRes<Device> {
         enumerator
             .GetDefaultAudioEndpoint(dir, eConsole, &mut device)
             .ok()?;
-        println!("{:?}", device);
+        eprintln!("{:?}", device);
     }
     match device {
         Some(dev) => Ok(Device{ device: dev, direction: direction.clone()}),
@@ -101,11 +131,13 @@ pub fn get_default_device(direction: &Direction) -> WasapiRes<Device> {
 }
 
 // Struct wrapping an IMMDeviceCollection.
+#[cfg(windows)]
 pub struct DeviceCollection {
     collection: IMMDeviceCollection,
     direction: Direction,
 }
 
+#[cfg(windows)]
 impl DeviceCollection {
     // Get an IMMDeviceCollection of all active playback or capture devices
     pub fn new(direction: &Direction) -> WasapiRes<DeviceCollection> {
@@ -147,7 +179,7 @@ impl DeviceCollection {
     pub fn get_device_with_name(&self, name: &str) -> WasapiRes<Device> {
         let mut count = 0;
         unsafe  { self.collection.GetCount(&mut count).ok()? };
-        println!("nbr devices {}", count);
+        eprintln!("nbr devices {}", count);
         for n in 0..count {
             let device = self.get_device_at_index(n)?;
             let devname = device.get_friendlyname()?;
@@ -160,11 +192,13 @@ impl DeviceCollection {
 }
 
 // Struct wrapping an IMMDevice.
+#[cfg(windows)]
 pub struct Device {
     device: IMMDevice,
     direction: Direction,
 }
 
+#[cfg(windows)]
 impl Device {
     // Get an IAudioClient from an IMMDevice
     pub fn get_iaudioclient(&self) -> WasapiRes<AudioClient> {
@@ -188,7 +222,7 @@ impl Device {
         unsafe  {
             self.device.GetState(&mut state).ok()?;
         }
-        println!("state: {:?}", state);
+        eprintln!("state: {:?}", state);
         Ok(state)
     }
 
@@ -212,7 +246,7 @@ impl Device {
         }
         let wide_name = unsafe { U16CString::from_ptr_str(propstr.0) };
         let name =  wide_name.to_string_lossy();
-        println!("name: {}", name);
+        eprintln!("name: {}", name);
         Ok(name)
     }
 
@@ -224,7 +258,7 @@ impl Device {
         }
         let wide_id = unsafe { U16CString::from_ptr_str(idstr.0) };
         let id = wide_id.to_string_lossy();
-        println!("id: {}", id);
+        eprintln!("id: {}", id);
         Ok(id)
     }
 }
@@ -238,12 +272,14 @@ pub enum FormatSupported {
 }
 
 // Struct wrapping an IAudioClient.
+#[cfg(windows)]
 pub struct AudioClient {
     client: IAudioClient,
     direction: Direction,
     sharemode: Option<ShareMode>,
 }
 
+#[cfg(windows)]
 impl AudioClient {
 
     // Check if a format is supported, return the nearest match (identical to requested for exclusive mode)
@@ -258,11 +294,11 @@ impl AudioClient {
                 let res = unsafe { self.client.IsFormatSupported(AUDCLNT_SHAREMODE_SHARED, wave_fmt.as_waveformatex_ptr(), &mut supported_format as *mut _ as *mut *mut WAVEFORMATEX) };
                 res.ok()?;
                 if res == S_OK {
-                    println!("supported");
+                    eprintln!("supported");
                     FormatSupported::Yes
                 }
                 else if res == S_FALSE {
-                    println!("not supported");
+                    eprintln!("not supported");
                     let new_fmt = unsafe {supported_format.assume_init().read()};
                     FormatSupported::ClosestMatch(WaveFormat{ wave_fmt: new_fmt })
                 }
@@ -279,7 +315,7 @@ impl AudioClient {
         let mut def_time = 0;
         let mut min_time = 0;
         unsafe { self.client.GetDevicePeriod(&mut def_time, &mut min_time).ok()? };
-        println!("default period {}, min period {}", def_time, min_time);
+        eprintln!("default period {}, min period {}", def_time, min_time);
         Ok((def_time, min_time))
     }
 
@@ -324,7 +360,7 @@ impl AudioClient {
     pub fn get_bufferframecount(&self) -> WasapiRes<u32> {
         let mut buffer_frame_count = 0;
         unsafe { self.client.GetBufferSize(&mut buffer_frame_count).ok()? };
-        println!("buffer_frame_count {}",buffer_frame_count);
+        eprintln!("buffer_frame_count {}",buffer_frame_count);
         Ok(buffer_frame_count)
     }
 
@@ -332,7 +368,7 @@ impl AudioClient {
     pub fn get_current_padding(&self) -> WasapiRes<u32> {
         let mut padding_count = 0;
         unsafe { self.client.GetCurrentPadding(&mut padding_count).ok()? };
-        println!("padding_count {}",padding_count);
+        eprintln!("padding_count {}",padding_count);
         Ok(padding_count)
     }
 
@@ -342,7 +378,7 @@ impl AudioClient {
             Some(ShareMode::Exclusive) => {
                 let mut buffer_frame_count = 0;
                 unsafe { self.client.GetBufferSize(&mut buffer_frame_count).ok()? };
-                println!("buffer_frame_count {}",buffer_frame_count);
+                eprintln!("buffer_frame_count {}",buffer_frame_count);
                 buffer_frame_count
             },
             Some(ShareMode::Shared) => {
@@ -388,10 +424,12 @@ impl AudioClient {
 }
 
 // Struct wrapping an IAudioRenderClient.
+#[cfg(windows)]
 pub struct AudioRenderClient {
     client: IAudioRenderClient,
 }
 
+#[cfg(windows)]
 impl AudioRenderClient {
     // Write raw bytes data to a device from a slice
     pub fn write_to_device(&self, nbr_frames: usize, byte_per_frame: usize, data: &[u8]) -> WasapiRes<()> {
@@ -409,7 +447,7 @@ impl AudioRenderClient {
         let bufferslice = unsafe { slice::from_raw_parts_mut(bufferptr, nbr_bytes) };
         bufferslice.copy_from_slice(data);
         unsafe { self.client.ReleaseBuffer(nbr_frames as u32, 0).ok()? };
-        println!("wrote frames");
+        eprintln!("wrote frames");
         Ok(())
     }
 
@@ -420,31 +458,33 @@ impl AudioRenderClient {
             return Err(WasapiError::new(format!("To little data, got {}, need {}", data.len(), nbr_bytes).as_str()).into());
         }
         let mut buffer = mem::MaybeUninit::uninit();
-        println!("get buffer");
+        eprintln!("get buffer");
         unsafe { 
             let val = self.client
                 .GetBuffer(nbr_frames as u32, buffer.as_mut_ptr());
-            println!("res {:?}", val);
+            eprintln!("res {:?}", val);
                 
             val.ok()?
         };
-        println!("copy to buffer");
+        eprintln!("copy to buffer");
         let bufferptr = unsafe { buffer.assume_init() };
         let bufferslice = unsafe { slice::from_raw_parts_mut(bufferptr, nbr_bytes) };
         for element in bufferslice.iter_mut() {
             *element = data.pop_front().unwrap();
         }
         unsafe { self.client.ReleaseBuffer(nbr_frames as u32, 0).ok()? };
-        println!("wrote frames");
+        eprintln!("wrote frames");
         Ok(())
     }
 }
 
 // Struct wrapping an IAudioCaptureClient.
+#[cfg(windows)]
 pub struct AudioCaptureClient {
     client: IAudioCaptureClient,
 }
 
+#[cfg(windows)]
 impl AudioCaptureClient {
     // Get number of frames in next packet, only works in shared mode
     pub fn get_next_nbr_frames(&self) -> WasapiRes<u32> {
@@ -453,37 +493,41 @@ impl AudioCaptureClient {
         Ok(nbr_frames)
     }
 
-    // Read raw bytes data from a device into a slice
-    pub fn read_from_device(&self, bytes_per_frame: usize, data: &mut [u8]) -> WasapiRes<()> {
+    // Read raw bytes data from a device into a slice, returns the flags of the buffer
+    pub fn read_from_device(&self, bytes_per_frame: usize, data: &mut [u8]) -> WasapiRes<BufferFlags> {
         let data_len_in_frames = data.len() / bytes_per_frame;
         let mut buffer = mem::MaybeUninit::uninit();
         let mut nbr_frames_returned = 0;
+        let mut flags = 0;
         unsafe { 
             self.client
-                .GetBuffer(buffer.as_mut_ptr(), &mut nbr_frames_returned, &mut 0, ptr::null_mut(), ptr::null_mut())
+                .GetBuffer(buffer.as_mut_ptr(), &mut nbr_frames_returned, &mut flags, ptr::null_mut(), ptr::null_mut())
                 .ok()?
         };
         if data_len_in_frames != nbr_frames_returned as usize {
+            unsafe { self.client.ReleaseBuffer(0).ok()? };
             return Err(WasapiError::new(format!("Wrong length of data, got {} frames, expected {} frames", data_len_in_frames, nbr_frames_returned).as_str()).into());
         }
+        let buffer_flags = BufferFlags::new(flags);
         let len_in_bytes = nbr_frames_returned as usize * bytes_per_frame;
         let bufferptr = unsafe { buffer.assume_init() };
         let bufferslice = unsafe { slice::from_raw_parts(bufferptr, len_in_bytes) };
         data.copy_from_slice(bufferslice);
         unsafe { self.client.ReleaseBuffer(nbr_frames_returned).ok()? };
-        println!("wrote frames");
-        Ok(())
+        Ok(buffer_flags)
     }
 
-    // Write raw bytes data to a device from a deque
-    pub fn read_from_device_to_deque(&self, bytes_per_frame: usize, data: &mut VecDeque<u8>) -> WasapiRes<()> {
+    // Read raw bytes data from a device into a deque, returns the flags of the buffer
+    pub fn read_from_device_to_deque(&self, bytes_per_frame: usize, data: &mut VecDeque<u8>) -> WasapiRes<BufferFlags> {
         let mut buffer = mem::MaybeUninit::uninit();
         let mut nbr_frames_returned = 0;
+        let mut flags = 0;
         unsafe { 
             self.client
-                .GetBuffer(buffer.as_mut_ptr(), &mut nbr_frames_returned, &mut 0, ptr::null_mut(), ptr::null_mut())
+                .GetBuffer(buffer.as_mut_ptr(), &mut nbr_frames_returned, &mut flags, ptr::null_mut(), ptr::null_mut())
                 .ok()?
         };
+        let buffer_flags = BufferFlags::new(flags);
         let len_in_bytes = nbr_frames_returned as usize * bytes_per_frame;
         let bufferptr = unsafe { buffer.assume_init() };
         let bufferslice = unsafe { slice::from_raw_parts(bufferptr, len_in_bytes) };
@@ -491,28 +535,78 @@ impl AudioCaptureClient {
             data.push_back(*element);
         }
         unsafe { self.client.ReleaseBuffer(nbr_frames_returned).ok()? };
-        //println!("wrote frames");
-        Ok(())
+        Ok(buffer_flags)
+    }
+}
+
+// Struct holding the flags of a captured buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BufferFlags {
+    // The data is not correlated with the previous buffer, because of a glitch
+    pub data_discontinuity: bool,
+    // The data should be treated as silence, whatever the actual values are
+    pub silent: bool,
+    // The device position and timestamp are uncertain
+    pub timestamp_error: bool,
+}
+
+impl BufferFlags {
+    // Create from the flags value returned by GetBuffer
+    pub fn new(flags: u32) -> Self {
+        BufferFlags {
+            data_discontinuity: flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32 > 0,
+            silent: flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 > 0,
+            timestamp_error: flags & AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR.0 as u32 > 0,
+        }
+    }
+
+    // Check if any flag is set
+    pub fn is_any_set(&self) -> bool {
+        self.data_discontinuity || self.silent || self.timestamp_error
+    }
+}
+
+impl fmt::Display for BufferFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = Vec::new();
+        if self.data_discontinuity {
+            names.push("data discontinuity");
+        }
+        if self.silent {
+            names.push("silent");
+        }
+        if self.timestamp_error {
+            names.push("timestamp error");
+        }
+        if names.is_empty() {
+            names.push("none");
+        }
+        write!(f, "{}", names.join(", "))
     }
 }
 
 // Struct wrapping a HANDLE (event handle).
+#[cfg(windows)]
 pub struct Handle {
     handle: HANDLE,
 }
 
+#[cfg(windows)]
 impl Handle {
     // Wait for an event on a handle
     pub fn wait_for_event(&self, timeout_ms: u32) -> WasapiRes<()> {
         let retval = unsafe { WaitForSingleObject(self.handle, timeout_ms) };
         if retval != WAIT_OBJECT_0
         {
-            return Err(WasapiError::new(format!("Wait timed out").as_str()).into());
+            return Err(WasapiError::new("Wait timed out").into());
         }
         Ok(())
     }
 }
 
+// Largest number of channels, the channel mask has one bit per channel.
+pub const MAX_CHANNELS: usize = 32;
+
 // Struct wrapping a WAVEFORMATEXTENSIBLE format descriptor.
 #[derive(Clone)]
 pub struct WaveFormat {
@@ -590,8 +684,11 @@ impl WaveFormat {
         self.wave_fmt.Format.wBitsPerSample
     }
 
-    // Read wValidBitsPerSample.
+    // Read wValidBitsPerSample, or wBitsPerSample for formats that are not WAVE_FORMAT_EXTENSIBLE.
     pub fn get_validbitspersample(&self) -> u16 {
+        if self.get_formattag() != WAVE_FORMAT_EXTENSIBLE as u16 {
+            return self.wave_fmt.Format.wBitsPerSample;
+        }
         unsafe { self.wave_fmt.Samples.wValidBitsPerSample }
     }
 
@@ -610,8 +707,26 @@ impl WaveFormat {
         self.wave_fmt.dwChannelMask
     }
 
-    // Read SubFormat.
+    // Read wFormatTag.
+    pub fn get_formattag(&self) -> u16 {
+        self.wave_fmt.Format.wFormatTag
+    }
+
+    // Set dwChannelMask.
+    pub fn set_dwchannelmask(&mut self, mask: u32) {
+        self.wave_fmt.dwChannelMask = mask;
+    }
+
+    // Read SubFormat, or the sample type given by the format tag for formats that are not WAVE_FORMAT_EXTENSIBLE.
     pub fn get_subformat(&self) -> WasapiRes<SampleType> {
+        match self.get_formattag() as u32 {
+            WAVE_FORMAT_PCM => return Ok(SampleType::Int),
+            WAVE_FORMAT_IEEE_FLOAT => return Ok(SampleType::Float),
+            WAVE_FORMAT_EXTENSIBLE => {},
+            tag => {
+                return Err(WasapiError::new(format!("Unknown format tag {}", tag).as_str()).into());
+            },
+        }
         let subfmt = match self.wave_fmt.SubFormat {
             KSDATAFORMAT_SUBTYPE_IEEE_FLOAT  => SampleType::Float,
             KSDATAFORMAT_SUBTYPE_PCM => SampleType::Int,
@@ -621,4 +736,128 @@ impl WaveFormat {
         };
         Ok(subfmt)
     }
+
+    // Convert to a plain WAVE_FORMAT_PCM or WAVE_FORMAT_IEEE_FLOAT format.
+    // This is only possible when all bits of each sample are valid, and the channel mask is lost.
+    pub fn to_waveformatex(&self) -> WasapiRes<Self> {
+        if self.get_validbitspersample() != self.get_bitspersample() {
+            return Err(WasapiError::new("Formats with padding bits need WAVE_FORMAT_EXTENSIBLE").into());
+        }
+        let tag = match self.get_subformat()? {
+            SampleType::Int => WAVE_FORMAT_PCM,
+            SampleType::Float => WAVE_FORMAT_IEEE_FLOAT,
+        };
+        let mut wave_fmt = self.clone();
+        wave_fmt.wave_fmt.Format.wFormatTag = tag as u16;
+        wave_fmt.wave_fmt.Format.cbSize = 0;
+        Ok(wave_fmt)
+    }
+
+    // Serialize to the contents of a fmt chunk, 16 bytes for WAVE_FORMAT_PCM,
+    // 18 bytes for WAVE_FORMAT_IEEE_FLOAT and 40 bytes for WAVE_FORMAT_EXTENSIBLE.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let tag = self.get_formattag();
+        let mut data = Vec::with_capacity(40);
+        data.extend_from_slice(&tag.to_le_bytes());
+        data.extend_from_slice(&self.get_nchannels().to_le_bytes());
+        data.extend_from_slice(&self.get_samplespersec().to_le_bytes());
+        data.extend_from_slice(&self.get_avgbytespersec().to_le_bytes());
+        data.extend_from_slice(&(self.get_blockalign() as u16).to_le_bytes());
+        data.extend_from_slice(&self.get_bitspersample().to_le_bytes());
+        if tag as u32 == WAVE_FORMAT_EXTENSIBLE {
+            data.extend_from_slice(&22u16.to_le_bytes());
+            data.extend_from_slice(&self.get_validbitspersample().to_le_bytes());
+            data.extend_from_slice(&self.get_dwchannelmask().to_le_bytes());
+            data.extend_from_slice(&guid_to_bytes(self.wave_fmt.SubFormat));
+        } else if tag as u32 != WAVE_FORMAT_PCM {
+            data.extend_from_slice(&0u16.to_le_bytes());
+        }
+        data
+    }
+
+    // Parse the contents of a fmt chunk, the inverse of to_bytes.
+    // Formats that are not WAVE_FORMAT_EXTENSIBLE keep their format tag.
+    pub fn from_bytes(data: &[u8]) -> WasapiRes<Self> {
+        if data.len() < 16 {
+            return Err(WasapiError::new("The format is too short").into());
+        }
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let read_u32 = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let tag = read_u16(0);
+        let channels = read_u16(2) as usize;
+        let samplerate = read_u32(4) as usize;
+        let storebits = read_u16(14) as usize;
+        if channels == 0 || channels > MAX_CHANNELS {
+            return Err(WasapiError::new(format!("Unsupported number of channels {}", channels).as_str()).into());
+        }
+        if read_u16(12) == 0 {
+            return Err(WasapiError::new("Invalid block alignment").into());
+        }
+        match tag as u32 {
+            WAVE_FORMAT_EXTENSIBLE => {
+                if data.len() < 40 || read_u16(16) < 22 {
+                    return Err(WasapiError::new("The format is too short for WAVE_FORMAT_EXTENSIBLE").into());
+                }
+                let validbits = read_u16(18) as usize;
+                let mut wave_fmt = WaveFormat::new(storebits, validbits, &SampleType::Int, samplerate, channels);
+                wave_fmt.wave_fmt.Format.nBlockAlign = read_u16(12);
+                wave_fmt.wave_fmt.Format.nAvgBytesPerSec = read_u32(8);
+                wave_fmt.wave_fmt.dwChannelMask = read_u32(20);
+                wave_fmt.wave_fmt.SubFormat = guid_from_bytes(&data[24..40]);
+                Ok(wave_fmt)
+            }
+            WAVE_FORMAT_PCM | WAVE_FORMAT_IEEE_FLOAT => {
+                let sample_type = if tag as u32 == WAVE_FORMAT_PCM { SampleType::Int } else { SampleType::Float };
+                let mut wave_fmt = WaveFormat::new(storebits, storebits, &sample_type, samplerate, channels);
+                wave_fmt.wave_fmt.Format.nBlockAlign = read_u16(12);
+                wave_fmt.wave_fmt.Format.nAvgBytesPerSec = read_u32(8);
+                wave_fmt.wave_fmt.Format.wFormatTag = tag;
+                wave_fmt.wave_fmt.Format.cbSize = 0;
+                Ok(wave_fmt)
+            }
+            _ => Err(WasapiError::new(format!("Unsupported format tag {}", tag).as_str()).into()),
+        }
+    }
+}
+
+impl PartialEq for WaveFormat {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+}
+
+impl fmt::Debug for WaveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaveFormat")
+            .field("formattag", &self.get_formattag())
+            .field("nchannels", &self.get_nchannels())
+            .field("samplespersec", &self.get_samplespersec())
+            .field("bitspersample", &self.get_bitspersample())
+            .field("validbitspersample", &self.get_validbitspersample())
+            .field("dwchannelmask", &self.get_dwchannelmask())
+            .finish()
+    }
+}
+
+// The bytes of a GUID as stored in a file, with the first three fields little endian.
+fn guid_to_bytes(guid: Guid) -> [u8; 16] {
+    let bytes: [u8; 16] = unsafe { mem::transmute(guid) };
+    let mut data = bytes;
+    if cfg!(target_endian = "big") {
+        data[0..4].reverse();
+        data[4..6].reverse();
+        data[6..8].reverse();
+    }
+    data
+}
+
+fn guid_from_bytes(data: &[u8]) -> Guid {
+    let mut data4 = [0u8; 8];
+    data4.copy_from_slice(&data[8..16]);
+    Guid::from_values(
+        u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+        u16::from_le_bytes([data[4], data[5]]),
+        u16::from_le_bytes([data[6], data[7]]),
+        data4,
+    )
 }
@@ -0,0 +1,598 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::bwf::{BextInfo, IxmlInfo};
+use crate::conversion::{bytes_to_channels, channels_to_bytes};
+use crate::wasapi::{WasapiError, WasapiRes, WaveFormat};
+
+// Size value used in the headers of a file that is still being written,
+// and in RF64 files where the real size is given by the ds64 chunk.
+const SIZE_PLACEHOLDER: u32 = 0xFFFF_FFFF;
+
+// Size of the contents of the ds64 chunk without a table, and of the JUNK chunk reserving space for it.
+const DS64_SIZE: usize = 28;
+
+// GUIDs of Sony Wave64 files, as stored in the file.
+const W64_RIFF: [u8; 16] = [
+    0x72, 0x69, 0x66, 0x66, 0x2E, 0x91, 0xCF, 0x11, 0xA5, 0xD6, 0x28, 0xDB, 0x04, 0xC1, 0x00, 0x00,
+];
+const W64_WAVE: [u8; 16] = [
+    0x77, 0x61, 0x76, 0x65, 0xF3, 0xAC, 0xD3, 0x11, 0x8C, 0xD1, 0x00, 0xC0, 0x4F, 0x8E, 0xDB, 0x8A,
+];
+// The chunk GUIDs of Wave64 start with the fourcc of the chunk and end with this.
+const W64_GUID_TAIL: [u8; 12] = [
+    0xF3, 0xAC, 0xD3, 0x11, 0x8C, 0xD1, 0x00, 0xC0, 0x4F, 0x8E, 0xDB, 0x8A,
+];
+
+// The container of a wav file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WavContainer {
+    // Plain RIFF, limited to 4 GB
+    Riff,
+    // EBU Tech 3306 RF64, with 64-bit sizes in a ds64 chunk
+    Rf64,
+    // ITU-R BS.2088 BW64, the same structure as RF64
+    Bw64,
+    // Sony Wave64, with GUIDs as chunk ids and 64-bit sizes everywhere
+    Wave64,
+}
+
+// A chunk of a RIFF file that is not handled by the reader or writer, kept as is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WavChunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+// Position and size of a chunk in a file.
+struct ChunkInfo {
+    id: [u8; 4],
+    // Offset of the chunk contents, after the chunk header
+    offset: u64,
+    // Size of the contents, limited to what is present in the file
+    size: u64,
+    // True if the size in the chunk header is a placeholder or larger than the file
+    incomplete: bool,
+}
+
+impl ChunkInfo {
+    fn new(id: [u8; 4], offset: u64, declared: Option<u64>, file_len: u64) -> Self {
+        let available = file_len.saturating_sub(offset);
+        let (size, incomplete) = match declared {
+            Some(declared) if declared <= available => (declared, false),
+            _ => (available, true),
+        };
+        ChunkInfo {
+            id,
+            offset,
+            size,
+            incomplete,
+        }
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// List the chunks of a wav file.
+fn read_chunk_list<R: Read + Seek>(reader: &mut R) -> WasapiRes<(WavContainer, Vec<ChunkInfo>)> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if header[0..12] == W64_RIFF[0..12] {
+        return Ok((
+            WavContainer::Wave64,
+            read_wave64_chunk_list(reader, file_len)?,
+        ));
+    }
+    let container = match &header[0..4] {
+        b"RIFF" => WavContainer::Riff,
+        b"RF64" => WavContainer::Rf64,
+        b"BW64" => WavContainer::Bw64,
+        _ => return Err(WasapiError::new("Not a wav file").into()),
+    };
+    if &header[8..12] != b"WAVE" {
+        return Err(WasapiError::new("Not a wav file").into());
+    }
+    let mut chunks = Vec::new();
+    let mut ds64_data_size = None;
+    let mut position = 12;
+    while position + 8 <= file_len {
+        reader.seek(SeekFrom::Start(position))?;
+        let mut id = [0u8; 4];
+        reader.read_exact(&mut id)?;
+        let declared = read_u32(reader)?;
+        let offset = position + 8;
+        if &id == b"ds64" && declared as usize >= DS64_SIZE {
+            let _riff_size = read_u64(reader)?;
+            ds64_data_size = Some(read_u64(reader)?);
+        }
+        let declared = match (&id, declared) {
+            (b"data", SIZE_PLACEHOLDER) => ds64_data_size,
+            (_, SIZE_PLACEHOLDER) => None,
+            (_, size) => Some(size as u64),
+        };
+        let chunk = ChunkInfo::new(id, offset, declared, file_len);
+        // Chunks are padded to an even size
+        position = offset + chunk.size + chunk.size % 2;
+        chunks.push(chunk);
+    }
+    Ok((container, chunks))
+}
+
+// List the chunks of a Wave64 file, where the sizes include the 24 byte chunk headers
+// and chunks are aligned to 8 bytes.
+fn read_wave64_chunk_list<R: Read + Seek>(
+    reader: &mut R,
+    file_len: u64,
+) -> WasapiRes<Vec<ChunkInfo>> {
+    let mut guid = [0u8; 16];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut guid)?;
+    let _riff_size = read_u64(reader)?;
+    reader.read_exact(&mut guid)?;
+    if guid != W64_WAVE {
+        return Err(WasapiError::new("Not a Wave64 wav file").into());
+    }
+    let mut chunks = Vec::new();
+    let mut position = 40;
+    while position + 24 <= file_len {
+        reader.seek(SeekFrom::Start(position))?;
+        reader.read_exact(&mut guid)?;
+        let declared = read_u64(reader)?;
+        let mut id = [0u8; 4];
+        id.copy_from_slice(&guid[0..4]);
+        if guid[4..16] != W64_GUID_TAIL {
+            // Not one of the standard chunks, make sure it is not mistaken for one
+            id = *b"    ";
+        }
+        let offset = position + 24;
+        let chunk = ChunkInfo::new(id, offset, declared.checked_sub(24), file_len);
+        position = offset + chunk.size + (8 - chunk.size % 8) % 8;
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+fn read_chunk_data<R: Read + Seek>(reader: &mut R, chunk: &ChunkInfo) -> WasapiRes<Vec<u8>> {
+    let mut data = vec![0u8; chunk.size as usize];
+    reader.seek(SeekFrom::Start(chunk.offset))?;
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// Streaming reader for RIFF WAVE files.
+pub struct WavReader<R: Read + Seek> {
+    reader: R,
+    container: WavContainer,
+    wave_fmt: WaveFormat,
+    data_offset: u64,
+    nbr_frames: u64,
+    position: u64,
+    chunks: Vec<WavChunk>,
+}
+
+impl WavReader<BufReader<File>> {
+    // Open a wav file
+    pub fn open<P: AsRef<Path>>(path: P) -> WasapiRes<Self> {
+        WavReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> WavReader<R> {
+    // Read the headers from a reader. A file that was not finalized is read up to the end.
+    pub fn new(mut reader: R) -> WasapiRes<Self> {
+        let (container, chunk_list) = read_chunk_list(&mut reader)?;
+        let mut wave_fmt = None;
+        let mut data_chunk = None;
+        let mut chunks = Vec::new();
+        for chunk in chunk_list.iter() {
+            match &chunk.id {
+                b"fmt " => {
+                    wave_fmt = Some(WaveFormat::from_bytes(&read_chunk_data(
+                        &mut reader,
+                        chunk,
+                    )?)?)
+                }
+                b"data" => data_chunk = Some(chunk),
+                // Space reserved for a ds64 chunk, and the ds64 chunk itself
+                b"JUNK" | b"ds64" => {}
+                _ => chunks.push(WavChunk {
+                    id: chunk.id,
+                    data: read_chunk_data(&mut reader, chunk)?,
+                }),
+            }
+        }
+        let wave_fmt = wave_fmt.ok_or_else(|| WasapiError::new("The file has no fmt chunk"))?;
+        let data_chunk =
+            data_chunk.ok_or_else(|| WasapiError::new("The file has no data chunk"))?;
+        let blockalign = wave_fmt.get_blockalign() as u64;
+        if blockalign == 0 {
+            return Err(WasapiError::new("Invalid block alignment").into());
+        }
+        reader.seek(SeekFrom::Start(data_chunk.offset))?;
+        Ok(WavReader {
+            reader,
+            container,
+            wave_fmt,
+            data_offset: data_chunk.offset,
+            nbr_frames: data_chunk.size / blockalign,
+            position: 0,
+            chunks,
+        })
+    }
+
+    // Get the format of the file
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the length of the file in frames
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.nbr_frames
+    }
+
+    // Get the current position in frames
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    // Get the container type of the file
+    pub fn get_container(&self) -> WavContainer {
+        self.container
+    }
+
+    // Get the chunks that are not fmt, data or padding
+    pub fn get_chunks(&self) -> &[WavChunk] {
+        &self.chunks
+    }
+
+    // Get the contents of the first chunk with the given id
+    pub fn get_chunk(&self, id: &[u8; 4]) -> Option<&[u8]> {
+        self.chunks
+            .iter()
+            .find(|chunk| &chunk.id == id)
+            .map(|chunk| chunk.data.as_slice())
+    }
+
+    // Get the contents of the bext chunk, if there is one
+    pub fn get_bext(&self) -> WasapiRes<Option<BextInfo>> {
+        self.get_chunk(b"bext").map(BextInfo::from_bytes).transpose()
+    }
+
+    // Get the contents of the iXML chunk, if there is one
+    pub fn get_ixml(&self) -> WasapiRes<Option<IxmlInfo>> {
+        self.get_chunk(b"iXML")
+            .map(|data| IxmlInfo::from_xml(&String::from_utf8_lossy(data)))
+            .transpose()
+    }
+
+    // Move to a frame
+    pub fn seek(&mut self, frame: u64) -> WasapiRes<()> {
+        let frame = frame.min(self.nbr_frames);
+        let blockalign = self.wave_fmt.get_blockalign() as u64;
+        self.reader
+            .seek(SeekFrom::Start(self.data_offset + frame * blockalign))?;
+        self.position = frame;
+        Ok(())
+    }
+
+    // Read raw data for up to nbr_frames frames, less at the end of the file
+    pub fn read_bytes(&mut self, nbr_frames: usize) -> WasapiRes<Vec<u8>> {
+        let nbr_frames = (nbr_frames as u64).min(self.nbr_frames - self.position);
+        let mut data = vec![0u8; (nbr_frames * self.wave_fmt.get_blockalign() as u64) as usize];
+        self.reader.read_exact(&mut data)?;
+        self.position += nbr_frames;
+        Ok(data)
+    }
+
+    // Read up to nbr_frames frames, as one vector per channel
+    pub fn read_frames(&mut self, nbr_frames: usize) -> WasapiRes<Vec<Vec<f64>>> {
+        let data = self.read_bytes(nbr_frames)?;
+        bytes_to_channels(&data, &self.wave_fmt)
+    }
+}
+
+// Streaming writer for RIFF WAVE files.
+// The header is written together with the first data, so that chunks can be added before it.
+// The sizes are filled in when the writer is closed or dropped.
+// Space for a ds64 chunk is reserved with a JUNK chunk, and a file that grows beyond 4 GB
+// is turned into an RF64 file when closed.
+// A file that was never closed, for example after a crash, can be fixed with repair_wav.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    wave_fmt: WaveFormat,
+    // Chunks to write before and after the data chunk
+    chunks_before: Vec<WavChunk>,
+    chunks_after: Vec<WavChunk>,
+    header_written: bool,
+    data_offset: u64,
+    data_bytes: u64,
+    closed: bool,
+}
+
+impl WavWriter<BufWriter<File>> {
+    // Create a wav file
+    pub fn create<P: AsRef<Path>>(path: P, wave_fmt: &WaveFormat) -> WasapiRes<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), wave_fmt)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    // Create a writer for the given format
+    pub fn new(writer: W, wave_fmt: &WaveFormat) -> WasapiRes<Self> {
+        if wave_fmt.get_blockalign() == 0 {
+            return Err(WasapiError::new("Invalid block alignment").into());
+        }
+        Ok(WavWriter {
+            writer,
+            wave_fmt: wave_fmt.clone(),
+            chunks_before: Vec::new(),
+            chunks_after: Vec::new(),
+            header_written: false,
+            data_offset: 0,
+            data_bytes: 0,
+            closed: false,
+        })
+    }
+
+    // Get the format of the file
+    pub fn get_format(&self) -> WaveFormat {
+        self.wave_fmt.clone()
+    }
+
+    // Get the number of frames written so far
+    pub fn get_nbr_frames(&self) -> u64 {
+        self.data_bytes / self.wave_fmt.get_blockalign() as u64
+    }
+
+    // Get a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    // Add a chunk. Chunks added before the first data go before the data chunk, others after it.
+    pub fn add_chunk(&mut self, id: &[u8; 4], data: &[u8]) -> WasapiRes<()> {
+        if id == b"fmt " || id == b"data" {
+            return Err(
+                WasapiError::new("The fmt and data chunks are written by the writer").into(),
+            );
+        }
+        let chunk = WavChunk {
+            id: *id,
+            data: data.to_vec(),
+        };
+        if self.header_written {
+            self.chunks_after.push(chunk);
+        } else {
+            self.chunks_before.push(chunk);
+        }
+        Ok(())
+    }
+
+    // Add a bext chunk. This should be done before writing any data, to place it before the data chunk.
+    pub fn add_bext(&mut self, bext: &BextInfo) -> WasapiRes<()> {
+        self.add_chunk(b"bext", &bext.to_bytes())
+    }
+
+    // Add an iXML chunk
+    pub fn add_ixml(&mut self, ixml: &IxmlInfo) -> WasapiRes<()> {
+        self.add_chunk(b"iXML", ixml.to_xml().as_bytes())
+    }
+
+    fn write_chunk(&mut self, id: &[u8; 4], data: &[u8]) -> WasapiRes<()> {
+        self.writer.write_all(id)?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)?;
+        if data.len() % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> WasapiRes<()> {
+        self.writer.write_all(b"RIFF")?;
+        self.writer.write_all(&SIZE_PLACEHOLDER.to_le_bytes())?;
+        self.writer.write_all(b"WAVE")?;
+        self.write_chunk(b"JUNK", &[0; DS64_SIZE])?;
+        self.write_chunk(b"fmt ", &self.wave_fmt.to_bytes())?;
+        for chunk in std::mem::take(&mut self.chunks_before).iter() {
+            self.write_chunk(&chunk.id, &chunk.data)?;
+        }
+        self.writer.write_all(b"data")?;
+        self.writer.write_all(&SIZE_PLACEHOLDER.to_le_bytes())?;
+        self.data_offset = self.writer.stream_position()?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    // Write raw data in the format of the file, whole frames only
+    pub fn write_bytes(&mut self, data: &[u8]) -> WasapiRes<()> {
+        if self.closed {
+            return Err(WasapiError::new("The writer is closed").into());
+        }
+        if !data
+            .len()
+            .is_multiple_of(self.wave_fmt.get_blockalign() as usize)
+        {
+            return Err(WasapiError::new("The data is not a whole number of frames").into());
+        }
+        if !self.header_written {
+            self.write_header()?;
+        }
+        self.writer.write_all(data)?;
+        self.data_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    // Write frames given as one vector per channel
+    pub fn write_frames(&mut self, channels: &[Vec<f64>]) -> WasapiRes<()> {
+        let data = channels_to_bytes(channels, &self.wave_fmt)?;
+        self.write_bytes(&data)
+    }
+
+    // Flush the written data to the file, without updating the header
+    pub fn flush(&mut self) -> WasapiRes<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // Pad the data, write the remaining chunks, and fill in the sizes in the header
+    pub fn close(&mut self) -> WasapiRes<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        if !self.header_written {
+            self.write_header()?;
+        }
+        if self.data_bytes % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        for chunk in std::mem::take(&mut self.chunks_after).iter() {
+            self.write_chunk(&chunk.id, &chunk.data)?;
+        }
+        let file_len = self.writer.stream_position()?;
+        let nbr_frames = self.get_nbr_frames();
+        write_sizes(
+            &mut self.writer,
+            file_len,
+            self.data_offset,
+            self.data_bytes,
+            nbr_frames,
+        )?;
+        self.writer.seek(SeekFrom::Start(file_len))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+// Fill in the sizes in the header of a file written by WavWriter.
+// Files larger than 4 GB become RF64, with the JUNK chunk at the start replaced by a ds64 chunk.
+fn write_sizes<W: Write + Seek>(
+    writer: &mut W,
+    file_len: u64,
+    data_offset: u64,
+    data_bytes: u64,
+    nbr_frames: u64,
+) -> WasapiRes<()> {
+    let riff_size = file_len - 8;
+    if riff_size <= u32::MAX as u64 {
+        writer.seek(SeekFrom::Start(4))?;
+        writer.write_all(&(riff_size as u32).to_le_bytes())?;
+        writer.seek(SeekFrom::Start(data_offset - 4))?;
+        writer.write_all(&(data_bytes as u32).to_le_bytes())?;
+        return Ok(());
+    }
+    writer.seek(SeekFrom::Start(0))?;
+    writer.write_all(b"RF64")?;
+    writer.write_all(&SIZE_PLACEHOLDER.to_le_bytes())?;
+    writer.seek(SeekFrom::Start(12))?;
+    writer.write_all(b"ds64")?;
+    writer.write_all(&(DS64_SIZE as u32).to_le_bytes())?;
+    writer.write_all(&riff_size.to_le_bytes())?;
+    writer.write_all(&data_bytes.to_le_bytes())?;
+    writer.write_all(&nbr_frames.to_le_bytes())?;
+    // No table entries for other chunks
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.seek(SeekFrom::Start(data_offset - 4))?;
+    writer.write_all(&SIZE_PLACEHOLDER.to_le_bytes())?;
+    Ok(())
+}
+
+// Fix the headers of a wav file that was not closed properly, for example after a crash.
+// The data chunk is assumed to extend to the end of the file, and an incomplete last frame is removed.
+// A file that is larger than 4 GB is turned into RF64, which needs the JUNK chunk written by WavWriter.
+// Returns the number of frames in the repaired file.
+pub fn repair_wav<P: AsRef<Path>>(path: P) -> WasapiRes<u64> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let (container, chunk_list) = read_chunk_list(&mut file)?;
+    if container == WavContainer::Wave64 {
+        return Err(WasapiError::new("Wave64 files can not be repaired").into());
+    }
+    let fmt_chunk = chunk_list
+        .iter()
+        .find(|chunk| &chunk.id == b"fmt ")
+        .ok_or_else(|| WasapiError::new("The file has no fmt chunk"))?;
+    let wave_fmt = WaveFormat::from_bytes(&read_chunk_data(&mut file, fmt_chunk)?)?;
+    let blockalign = wave_fmt.get_blockalign() as u64;
+    if blockalign == 0 {
+        return Err(WasapiError::new("Invalid block alignment").into());
+    }
+    let data_chunk = chunk_list
+        .iter()
+        .find(|chunk| &chunk.id == b"data")
+        .ok_or_else(|| WasapiError::new("The file has no data chunk"))?;
+    let nbr_frames = data_chunk.size / blockalign;
+    if !data_chunk.incomplete {
+        // Only the RIFF size can be wrong
+        if container == WavContainer::Riff {
+            let file_len = file.seek(SeekFrom::End(0))?;
+            file.seek(SeekFrom::Start(4))?;
+            file.write_all(&((file_len - 8).min(u32::MAX as u64) as u32).to_le_bytes())?;
+        }
+        return Ok(nbr_frames);
+    }
+    let data_bytes = nbr_frames * blockalign;
+    let mut file_len = data_chunk.offset + data_bytes;
+    file.set_len(file_len)?;
+    if data_bytes % 2 == 1 {
+        file.seek(SeekFrom::Start(file_len))?;
+        file.write_all(&[0])?;
+        file_len += 1;
+    }
+    let has_ds64_space = chunk_list.first().is_some_and(|chunk| {
+        (&chunk.id == b"JUNK" || &chunk.id == b"ds64") && chunk.size as usize >= DS64_SIZE
+    });
+    if file_len - 8 > u32::MAX as u64 && !has_ds64_space {
+        return Err(WasapiError::new(
+            "The file is too large for RIFF, and has no space for a ds64 chunk",
+        )
+        .into());
+    }
+    write_sizes(
+        &mut file,
+        file_len,
+        data_chunk.offset,
+        data_bytes,
+        nbr_frames,
+    )?;
+    file.flush()?;
+    Ok(nbr_frames)
+}
+
+// Save samples, one vector per channel, to a wav file with the given format.
+pub fn save_wav<P: AsRef<Path>>(
+    path: P,
+    wave_fmt: &WaveFormat,
+    channels: &[Vec<f64>],
+) -> WasapiRes<()> {
+    let mut writer = WavWriter::create(path, wave_fmt)?;
+    writer.write_frames(channels)?;
+    writer.close()
+}
+
+// Load a wav file, returning the format and the samples as one vector per channel.
+pub fn load_wav<P: AsRef<Path>>(path: P) -> WasapiRes<(WaveFormat, Vec<Vec<f64>>)> {
+    let mut reader = WavReader::open(path)?;
+    let nbr_frames = reader.get_nbr_frames() as usize;
+    let channels = reader.read_frames(nbr_frames)?;
+    Ok((reader.get_format(), channels))
+}
@@ -0,0 +1,72 @@
+#![allow(non_snake_case, non_camel_case_types, clippy::upper_case_acronyms)]
+
+// Plain Rust versions of the Windows structs and constants that are needed by the platform-neutral code,
+// used instead of the generated bindings on other platforms.
+// They have the same names and layout as the bindings, so that WaveFormat is the same on all platforms.
+
+// A GUID, as used for the subformat of a WAVEFORMATEXTENSIBLE.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    pub const fn from_values(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        Guid {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+}
+
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct WAVEFORMATEX {
+    pub wFormatTag: u16,
+    pub nChannels: u16,
+    pub nSamplesPerSec: u32,
+    pub nAvgBytesPerSec: u32,
+    pub nBlockAlign: u16,
+    pub wBitsPerSample: u16,
+    pub cbSize: u16,
+}
+
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub union WAVEFORMATEXTENSIBLE_0 {
+    pub wValidBitsPerSample: u16,
+    pub wSamplesPerBlock: u16,
+    pub wReserved: u16,
+}
+
+#[repr(C, packed(1))]
+#[derive(Clone, Copy)]
+pub struct WAVEFORMATEXTENSIBLE {
+    pub Format: WAVEFORMATEX,
+    pub Samples: WAVEFORMATEXTENSIBLE_0,
+    pub dwChannelMask: u32,
+    pub SubFormat: Guid,
+}
+
+pub const WAVE_FORMAT_PCM: u32 = 1;
+pub const WAVE_FORMAT_IEEE_FLOAT: u32 = 3;
+pub const WAVE_FORMAT_EXTENSIBLE: u32 = 65534;
+
+pub const KSDATAFORMAT_SUBTYPE_PCM: Guid =
+    Guid::from_values(1, 0, 16, [128, 0, 0, 170, 0, 56, 155, 113]);
+pub const KSDATAFORMAT_SUBTYPE_IEEE_FLOAT: Guid =
+    Guid::from_values(3, 0, 16, [128, 0, 0, 170, 0, 56, 155, 113]);
+
+// The flags of a captured buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct _AUDCLNT_BUFFERFLAGS(pub i32);
+
+pub const AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY: _AUDCLNT_BUFFERFLAGS = _AUDCLNT_BUFFERFLAGS(1);
+pub const AUDCLNT_BUFFERFLAGS_SILENT: _AUDCLNT_BUFFERFLAGS = _AUDCLNT_BUFFERFLAGS(2);
+pub const AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR: _AUDCLNT_BUFFERFLAGS = _AUDCLNT_BUFFERFLAGS(4);
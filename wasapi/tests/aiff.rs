@@ -0,0 +1,241 @@
+use std::env;
+use std::fs;
+use std::io::Cursor;
+
+use wasapi::aiff::{extended_to_f64, f64_to_extended, AiffEncoding, AiffReader, AiffWriter};
+use wasapi::audiofile::{open_audio_file, open_reader};
+use wasapi::generator::NoiseGenerator;
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::save_wav;
+
+// Write raw data in the WaveFormat layout to an in-memory aiff file
+fn write_aiff(wave_fmt: &WaveFormat, encoding: AiffEncoding, data: &[u8]) -> Vec<u8> {
+    let mut writer = AiffWriter::new(Cursor::new(Vec::new()), wave_fmt, encoding).unwrap();
+    writer.write_bytes(data).unwrap();
+    writer.close().unwrap();
+    writer.get_ref().get_ref().clone()
+}
+
+// Random samples, quantized to what the format can store
+fn test_signal(wave_fmt: &WaveFormat, nbr_frames: usize) -> Vec<Vec<f64>> {
+    let mut noise = NoiseGenerator::new(5);
+    let validbits = wave_fmt.get_validbitspersample() as i32;
+    (0..wave_fmt.get_nchannels())
+        .map(|_| {
+            noise
+                .generate(nbr_frames, 0.9)
+                .iter()
+                .map(|value| match wave_fmt.get_subformat().unwrap() {
+                    SampleType::Int => {
+                        let scale = 2.0f64.powi(validbits - 1);
+                        (value * scale).round() / scale
+                    }
+                    SampleType::Float if validbits == 32 => *value as f32 as f64,
+                    SampleType::Float => *value,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn extended_sample_rates() {
+    assert_eq!(
+        f64_to_extended(44100.0),
+        [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        f64_to_extended(48000.0),
+        [0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        f64_to_extended(8000.0),
+        [0x40, 0x0B, 0xFA, 0x00, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(f64_to_extended(0.0), [0; 10]);
+    for value in [44100.0, 96000.0, 22050.5, -1.5, 1.0e-310, 0.0] {
+        assert_eq!(extended_to_f64(&f64_to_extended(value)), value);
+    }
+    // A value with more precision than f64, rounded when read
+    assert_eq!(
+        extended_to_f64(&[0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 1]),
+        44100.0
+    );
+}
+
+#[test]
+fn aiff_16bit_header() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let data = [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xFE, 0xFF];
+    let file = write_aiff(&wave_fmt, AiffEncoding::BigEndian, &data);
+    #[rustfmt::skip]
+    let expected = vec![
+        b'F', b'O', b'R', b'M', 0, 0, 0, 54, b'A', b'I', b'F', b'F',
+        b'C', b'O', b'M', b'M', 0, 0, 0, 18,
+        0, 2, 0, 0, 0, 2, 0, 16, 0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0,
+        b'S', b'S', b'N', b'D', 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0,
+        0x00, 0x01, 0xFF, 0xFF, 0x12, 0x34, 0xFF, 0xFE,
+    ];
+    assert_eq!(file, expected);
+
+    let mut reader = AiffReader::new(Cursor::new(expected)).unwrap();
+    assert!(!reader.is_aifc());
+    assert_eq!(reader.get_encoding(), AiffEncoding::BigEndian);
+    assert_eq!(reader.get_format(), wave_fmt);
+    assert_eq!(reader.get_nbr_frames(), 2);
+    assert_eq!(reader.read_bytes(10).unwrap(), data);
+}
+
+#[test]
+fn aifc_sowt_24bit_header() {
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 48000, 1);
+    let data = [0x56, 0x34, 0x12];
+    let file = write_aiff(&wave_fmt, AiffEncoding::LittleEndian, &data);
+    #[rustfmt::skip]
+    let expected = vec![
+        b'F', b'O', b'R', b'M', 0, 0, 0, 68, b'A', b'I', b'F', b'C',
+        b'F', b'V', b'E', b'R', 0, 0, 0, 4, 0xA2, 0x80, 0x51, 0x40,
+        b'C', b'O', b'M', b'M', 0, 0, 0, 24,
+        0, 1, 0, 0, 0, 1, 0, 24, 0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0,
+        b's', b'o', b'w', b't', 0, 0,
+        b'S', b'S', b'N', b'D', 0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0, 0,
+        0x56, 0x34, 0x12, 0,
+    ];
+    assert_eq!(file, expected);
+
+    let mut reader = AiffReader::new(Cursor::new(expected)).unwrap();
+    assert!(reader.is_aifc());
+    assert_eq!(reader.get_encoding(), AiffEncoding::LittleEndian);
+    assert_eq!(reader.get_format(), wave_fmt);
+    assert_eq!(reader.read_bytes(10).unwrap(), data);
+}
+
+#[test]
+fn aifc_float_header() {
+    let wave_fmt = WaveFormat::new(32, 32, &SampleType::Float, 96000, 1);
+    let data = 0.5f32.to_le_bytes();
+    let file = write_aiff(&wave_fmt, AiffEncoding::Float32, &data);
+    let mut expected =
+        b"FORMxxxxAIFCFVER\x00\x00\x00\x04\xA2\x80\x51\x40COMM\x00\x00\x00\x2C".to_vec();
+    expected.extend_from_slice(&[
+        0, 1, 0, 0, 0, 1, 0, 32, 0x40, 0x0F, 0xBB, 0x80, 0, 0, 0, 0, 0, 0,
+    ]);
+    expected.extend_from_slice(b"fl32\x1532-bit floating point");
+    expected.extend_from_slice(b"SSND\x00\x00\x00\x0C\x00\x00\x00\x00\x00\x00\x00\x00");
+    expected.extend_from_slice(&0.5f32.to_be_bytes());
+    let form_size = (expected.len() as u32 - 8).to_be_bytes();
+    expected[4..8].copy_from_slice(&form_size);
+    assert_eq!(file, expected);
+
+    let mut reader = AiffReader::new(Cursor::new(expected)).unwrap();
+    assert_eq!(reader.get_encoding(), AiffEncoding::Float32);
+    assert_eq!(reader.get_format(), wave_fmt);
+    assert_eq!(reader.read_frames(1).unwrap(), vec![vec![0.5]]);
+}
+
+#[test]
+fn reads_hand_built_8bit_twos() {
+    // AIFC with an extra chunk before the sound data, and a non-zero SSND offset
+    #[rustfmt::skip]
+    let file = vec![
+        b'F', b'O', b'R', b'M', 0, 0, 0, 70, b'A', b'I', b'F', b'C',
+        b'N', b'A', b'M', b'E', 0, 0, 0, 3, b'a', b'b', b'c', 0,
+        b'C', b'O', b'M', b'M', 0, 0, 0, 24,
+        0, 1, 0, 0, 0, 3, 0, 8, 0x40, 0x0C, 0xFA, 0, 0, 0, 0, 0, 0, 0,
+        b't', b'w', b'o', b's', 0, 0,
+        b'S', b'S', b'N', b'D', 0, 0, 0, 13, 0, 0, 0, 2, 0, 0, 0, 0,
+        0xAA, 0xAA, 0x7F, 0x80, 0x00, 0,
+    ];
+    let mut reader = AiffReader::new(Cursor::new(file)).unwrap();
+    let wave_fmt = reader.get_format();
+    assert_eq!(wave_fmt.get_samplespersec(), 16000);
+    assert_eq!(wave_fmt.get_bitspersample(), 8);
+    assert_eq!(reader.get_nbr_frames(), 3);
+    // Signed 8-bit samples become unsigned
+    assert_eq!(reader.read_bytes(3).unwrap(), vec![0xFF, 0x00, 0x80]);
+    reader.seek(1).unwrap();
+    assert_eq!(reader.read_frames(2).unwrap(), vec![vec![-1.0, 0.0]]);
+}
+
+#[test]
+fn roundtrip_all_encodings() {
+    for (storebits, validbits, sample_type, encoding) in [
+        (8, 8, SampleType::Int, AiffEncoding::BigEndian),
+        (16, 16, SampleType::Int, AiffEncoding::BigEndian),
+        (24, 20, SampleType::Int, AiffEncoding::BigEndian),
+        (24, 24, SampleType::Int, AiffEncoding::BigEndian),
+        (32, 32, SampleType::Int, AiffEncoding::BigEndian),
+        (16, 16, SampleType::Int, AiffEncoding::LittleEndian),
+        (24, 24, SampleType::Int, AiffEncoding::LittleEndian),
+        (32, 32, SampleType::Float, AiffEncoding::Float32),
+        (64, 64, SampleType::Float, AiffEncoding::Float64),
+    ] {
+        let wave_fmt = WaveFormat::new(storebits, validbits, &sample_type, 44100, 3);
+        let signal = test_signal(&wave_fmt, 1001);
+        let mut writer = AiffWriter::new(Cursor::new(Vec::new()), &wave_fmt, encoding).unwrap();
+        writer.write_frames(&signal).unwrap();
+        writer.close().unwrap();
+        let file = writer.get_ref().get_ref().clone();
+        let mut reader = AiffReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.get_format(), wave_fmt);
+        assert_eq!(reader.get_encoding(), encoding);
+        assert_eq!(reader.read_frames(2000).unwrap(), signal);
+    }
+}
+
+#[test]
+fn rejects_unsupported_formats() {
+    let padded = WaveFormat::new(32, 24, &SampleType::Int, 44100, 2);
+    assert!(AiffWriter::new(Cursor::new(Vec::new()), &padded, AiffEncoding::BigEndian).is_err());
+    let float = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    assert!(AiffWriter::new(Cursor::new(Vec::new()), &float, AiffEncoding::BigEndian).is_err());
+    assert!(AiffWriter::new(Cursor::new(Vec::new()), &float, AiffEncoding::Float64).is_err());
+    assert_eq!(
+        AiffEncoding::from_format(&float).unwrap(),
+        AiffEncoding::Float32
+    );
+}
+
+#[test]
+fn unfinished_file_is_read_to_end() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 1);
+    let mut writer =
+        AiffWriter::new(Cursor::new(Vec::new()), &wave_fmt, AiffEncoding::BigEndian).unwrap();
+    writer.write_bytes(&[1, 0, 2, 0, 3, 0]).unwrap();
+    let file = writer.get_ref().get_ref().clone();
+    std::mem::forget(writer);
+    let mut reader = AiffReader::new(Cursor::new(file)).unwrap();
+    assert_eq!(reader.get_nbr_frames(), 3);
+    assert_eq!(reader.read_bytes(3).unwrap(), vec![1, 0, 2, 0, 3, 0]);
+}
+
+#[test]
+fn same_data_from_wav_and_aiff() {
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 48000, 2);
+    let signal = test_signal(&wave_fmt, 500);
+    let wav_path = env::temp_dir().join("wasapi_aiff_test_same.wav");
+    let aiff_path = env::temp_dir().join("wasapi_aiff_test_same.aiff");
+    save_wav(&wav_path, &wave_fmt, &signal).unwrap();
+    let mut writer = AiffWriter::create(&aiff_path, &wave_fmt, AiffEncoding::BigEndian).unwrap();
+    writer.write_frames(&signal).unwrap();
+    writer.close().unwrap();
+    drop(writer);
+
+    let mut wav = open_audio_file(&wav_path).unwrap();
+    let mut aiff = open_audio_file(&aiff_path).unwrap();
+    assert_eq!(wav.get_format(), aiff.get_format());
+    assert_eq!(wav.get_nbr_frames(), aiff.get_nbr_frames());
+    wav.seek(100).unwrap();
+    aiff.seek(100).unwrap();
+    assert_eq!(
+        wav.read_bytes(1000).unwrap(),
+        aiff.read_bytes(1000).unwrap()
+    );
+    assert_eq!(aiff.get_position(), 500);
+    drop(wav);
+    drop(aiff);
+    fs::remove_file(&wav_path).unwrap();
+    fs::remove_file(&aiff_path).unwrap();
+
+    assert!(open_reader(Cursor::new(b"OggS0000".to_vec())).is_err());
+}
@@ -0,0 +1,308 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use wasapi::backend::{
+    AudioClientTrait, Backend, CaptureClientTrait, DeviceCollectionTrait, DeviceTrait, HandleTrait,
+    RenderClientTrait,
+};
+use wasapi::loopback::{Loopback, WasapiLoopback};
+use wasapi::wasapi::{
+    BufferFlags, Direction, FormatSupported, SampleType, ShareMode, WasapiError, WasapiRes,
+    WaveFormat,
+};
+
+// A minimal backend with one playback and one capture device, connected by a shared buffer.
+// Each event lets the playback side write one period, and moves it to the capture side.
+type Wire = Arc<Mutex<(VecDeque<u8>, VecDeque<u8>)>>;
+
+const PERIOD: u32 = 64;
+
+struct WireBackend {
+    wire: Wire,
+    format: WaveFormat,
+}
+
+struct WireCollection {
+    wire: Wire,
+    format: WaveFormat,
+    direction: Direction,
+}
+
+struct WireDevice {
+    wire: Wire,
+    format: WaveFormat,
+    direction: Direction,
+}
+
+struct WireClient {
+    wire: Wire,
+    format: WaveFormat,
+    initialized: bool,
+}
+
+struct WireEvent {
+    wire: Wire,
+}
+
+struct WireRender {
+    wire: Wire,
+}
+
+struct WireCapture {
+    wire: Wire,
+    blockalign: usize,
+}
+
+impl Backend for WireBackend {
+    fn get_device_collection(
+        &self,
+        direction: &Direction,
+    ) -> WasapiRes<Box<dyn DeviceCollectionTrait>> {
+        Ok(Box::new(WireCollection {
+            wire: self.wire.clone(),
+            format: self.format.clone(),
+            direction: direction.clone(),
+        }))
+    }
+
+    fn get_default_device(&self, direction: &Direction) -> WasapiRes<Box<dyn DeviceTrait>> {
+        self.get_device_collection(direction)?
+            .get_device_at_index(0)
+    }
+}
+
+impl DeviceCollectionTrait for WireCollection {
+    fn get_nbr_devices(&self) -> WasapiRes<u32> {
+        Ok(1)
+    }
+
+    fn get_device_at_index(&self, idx: u32) -> WasapiRes<Box<dyn DeviceTrait>> {
+        if idx > 0 {
+            return Err(WasapiError::new("No such device").into());
+        }
+        Ok(Box::new(WireDevice {
+            wire: self.wire.clone(),
+            format: self.format.clone(),
+            direction: self.direction.clone(),
+        }))
+    }
+}
+
+impl DeviceTrait for WireDevice {
+    fn get_iaudioclient(&self) -> WasapiRes<Box<dyn AudioClientTrait>> {
+        Ok(Box::new(WireClient {
+            wire: self.wire.clone(),
+            format: self.format.clone(),
+            initialized: false,
+        }))
+    }
+
+    fn get_state(&self) -> WasapiRes<u32> {
+        Ok(1)
+    }
+
+    fn get_friendlyname(&self) -> WasapiRes<String> {
+        match self.direction {
+            Direction::Render => Ok("Wire out".to_string()),
+            Direction::Capture => Ok("Wire in".to_string()),
+        }
+    }
+
+    fn get_id(&self) -> WasapiRes<String> {
+        self.get_friendlyname()
+    }
+}
+
+impl AudioClientTrait for WireClient {
+    fn is_supported(
+        &self,
+        wave_fmt: &WaveFormat,
+        _sharemode: &ShareMode,
+    ) -> WasapiRes<FormatSupported> {
+        if *wave_fmt == self.format {
+            Ok(FormatSupported::Yes)
+        } else {
+            Ok(FormatSupported::ClosestMatch(self.format.clone()))
+        }
+    }
+
+    fn get_periods(&self) -> WasapiRes<(i64, i64)> {
+        Ok((100000, 30000))
+    }
+
+    fn initialize_client(
+        &mut self,
+        wavefmt: &WaveFormat,
+        _period: i64,
+        _direction: &Direction,
+        _sharemode: &ShareMode,
+    ) -> WasapiRes<()> {
+        if *wavefmt != self.format {
+            return Err(WasapiError::new("Unsupported format").into());
+        }
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn set_get_eventhandle(&self) -> WasapiRes<Box<dyn HandleTrait>> {
+        Ok(Box::new(WireEvent {
+            wire: self.wire.clone(),
+        }))
+    }
+
+    fn get_bufferframecount(&self) -> WasapiRes<u32> {
+        Ok(PERIOD)
+    }
+
+    fn get_current_padding(&self) -> WasapiRes<u32> {
+        let wire = self.wire.lock().unwrap();
+        Ok((wire.0.len() / self.format.get_blockalign() as usize) as u32)
+    }
+
+    fn get_available_frames(&self) -> WasapiRes<u32> {
+        if !self.initialized {
+            return Err(WasapiError::new("Client has not been initialized").into());
+        }
+        Ok(PERIOD - self.get_current_padding()?)
+    }
+
+    fn start_stream(&self) -> WasapiRes<()> {
+        Ok(())
+    }
+
+    fn stop_stream(&self) -> WasapiRes<()> {
+        Ok(())
+    }
+
+    fn get_audiorenderclient(&self) -> WasapiRes<Box<dyn RenderClientTrait>> {
+        Ok(Box::new(WireRender {
+            wire: self.wire.clone(),
+        }))
+    }
+
+    fn get_audiocaptureclient(&self) -> WasapiRes<Box<dyn CaptureClientTrait>> {
+        Ok(Box::new(WireCapture {
+            wire: self.wire.clone(),
+            blockalign: self.format.get_blockalign() as usize,
+        }))
+    }
+}
+
+impl HandleTrait for WireEvent {
+    // The device "plays" everything that was written, straight into the capture buffer
+    fn wait_for_event(&self, _timeout_ms: u32) -> WasapiRes<()> {
+        let mut wire = self.wire.lock().unwrap();
+        let played: Vec<u8> = wire.0.drain(..).collect();
+        wire.1.extend(played);
+        Ok(())
+    }
+}
+
+impl RenderClientTrait for WireRender {
+    fn write_to_device(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &[u8],
+    ) -> WasapiRes<()> {
+        if data.len() != nbr_frames * byte_per_frame {
+            return Err(WasapiError::new("Wrong length of data").into());
+        }
+        self.wire.lock().unwrap().0.extend(data);
+        Ok(())
+    }
+
+    fn write_to_device_from_deque(
+        &self,
+        nbr_frames: usize,
+        byte_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<()> {
+        let chunk: Vec<u8> = data.drain(..nbr_frames * byte_per_frame).collect();
+        self.write_to_device(nbr_frames, byte_per_frame, &chunk)
+    }
+}
+
+impl CaptureClientTrait for WireCapture {
+    fn get_next_nbr_frames(&self) -> WasapiRes<u32> {
+        let wire = self.wire.lock().unwrap();
+        Ok((wire.1.len() / self.blockalign).min(PERIOD as usize) as u32)
+    }
+
+    fn read_from_device(&self, bytes_per_frame: usize, data: &mut [u8]) -> WasapiRes<BufferFlags> {
+        let mut wire = self.wire.lock().unwrap();
+        if data.len() > wire.1.len() || !data.len().is_multiple_of(bytes_per_frame) {
+            return Err(WasapiError::new("Wrong length of data").into());
+        }
+        let nbr_bytes = data.len();
+        for (value, byte) in data.iter_mut().zip(wire.1.drain(..nbr_bytes)) {
+            *value = byte;
+        }
+        Ok(BufferFlags::default())
+    }
+
+    fn read_from_device_to_deque(
+        &self,
+        _bytes_per_frame: usize,
+        data: &mut VecDeque<u8>,
+    ) -> WasapiRes<BufferFlags> {
+        let nbr_bytes = self.get_next_nbr_frames()? as usize * self.blockalign;
+        data.extend(self.wire.lock().unwrap().1.drain(..nbr_bytes));
+        Ok(BufferFlags::default())
+    }
+}
+
+fn backend(format: &WaveFormat) -> Arc<dyn Backend> {
+    Arc::new(WireBackend {
+        wire: Arc::new(Mutex::new((VecDeque::new(), VecDeque::new()))),
+        format: format.clone(),
+    })
+}
+
+#[test]
+fn enumerate_devices() {
+    let format = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let backend = backend(&format);
+    let collection = backend.get_device_collection(&Direction::Capture).unwrap();
+    assert_eq!(collection.get_nbr_devices().unwrap(), 1);
+    let device = collection.get_device_with_name("Wire in").unwrap();
+    assert_eq!(device.get_id().unwrap(), "Wire in");
+    assert!(collection.get_device_with_name("Wire out").is_err());
+    let default = backend.get_default_device(&Direction::Render).unwrap();
+    assert_eq!(default.get_friendlyname().unwrap(), "Wire out");
+}
+
+#[test]
+fn loopback_through_backend() {
+    let format = WaveFormat::new(32, 32, &SampleType::Float, 48000, 2);
+    let mut loopback = WasapiLoopback::with_backend(
+        backend(&format),
+        "Wire out",
+        "Wire in",
+        &format,
+        &ShareMode::Shared,
+    );
+    let data = vec![
+        (0..1000).map(|n| n as f64 / 1000.0).collect::<Vec<f64>>(),
+        (0..1000).map(|n| -n as f64 / 2000.0).collect::<Vec<f64>>(),
+    ];
+    let captured = loopback.play_and_capture(&data, 100).unwrap();
+    assert_eq!(captured[0].len(), 1100);
+    for (chan, expected) in captured.iter().zip(data.iter()) {
+        for (value, expected) in chan.iter().zip(expected.iter()) {
+            assert!((value - expected).abs() < 1e-6);
+        }
+        assert!(chan[1000..].iter().all(|value| *value == 0.0));
+    }
+
+    // A format that the devices do not support
+    let other = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let mut loopback = WasapiLoopback::with_backend(
+        backend(&format),
+        "Wire out",
+        "Wire in",
+        &other,
+        &ShareMode::Shared,
+    );
+    assert!(loopback.play_and_capture(&data, 0).is_err());
+}
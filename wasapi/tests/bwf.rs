@@ -0,0 +1,122 @@
+use std::io::Cursor;
+use std::time::{Duration, UNIX_EPOCH};
+
+use wasapi::bwf::{utc_date_time, BextInfo, IxmlInfo};
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::{WavReader, WavWriter};
+
+#[test]
+fn utc_dates() {
+    let (date, time, since_midnight) = utc_date_time(UNIX_EPOCH);
+    assert_eq!(date, "1970-01-01");
+    assert_eq!(time, "00:00:00");
+    assert_eq!(since_midnight, Duration::from_secs(0));
+
+    // 2024-02-29 23:59:59.5, a leap day
+    let leap = UNIX_EPOCH + Duration::from_millis(1_709_251_199_500);
+    let (date, time, since_midnight) = utc_date_time(leap);
+    assert_eq!(date, "2024-02-29");
+    assert_eq!(time, "23:59:59");
+    assert_eq!(since_midnight, Duration::from_millis(86_399_500));
+
+    let (date, time, _) = utc_date_time(leap + Duration::from_secs(1));
+    assert_eq!(date, "2024-03-01");
+    assert_eq!(time, "00:00:00");
+
+    let (date, _, _) = utc_date_time(UNIX_EPOCH + Duration::from_secs(4_102_444_800));
+    assert_eq!(date, "2100-01-01");
+}
+
+#[test]
+fn time_reference_from_stream_start() {
+    // Stream started at 2021-06-15 12:30:10.25 UTC
+    let stream_start = UNIX_EPOCH + Duration::from_millis(1_623_760_210_250);
+    let bext = BextInfo::new("take 1", "tester", stream_start, 48000, 96000);
+    let since_midnight = (12 * 3600 + 30 * 60 + 10) * 48000 + 12000;
+    assert_eq!(bext.time_reference, since_midnight + 96000);
+    assert_eq!(bext.origination_date, "2021-06-15");
+    assert_eq!(bext.origination_time, "12:30:12");
+}
+
+#[test]
+fn bext_roundtrip() {
+    let mut bext = BextInfo::new(
+        "A description",
+        "An originator that is too long to fit",
+        UNIX_EPOCH + Duration::from_secs(1_000_000_000),
+        96000,
+        0,
+    );
+    bext.time_reference = 0x1_2345_6789;
+    bext.coding_history = "A=PCM,F=96000,W=24,M=stereo\r\n".to_string();
+    let data = bext.to_bytes();
+    assert_eq!(data.len(), 602 + bext.coding_history.len());
+    // Version
+    assert_eq!(&data[346..348], &[1, 0]);
+    let parsed = BextInfo::from_bytes(&data).unwrap();
+    assert_eq!(parsed.originator, "An originator that is too long t");
+    bext.originator = parsed.originator.clone();
+    assert_eq!(parsed, bext);
+    assert!(BextInfo::from_bytes(&data[..600]).is_err());
+}
+
+#[test]
+fn ixml_roundtrip() {
+    let mut wave_fmt = WaveFormat::new(32, 24, &SampleType::Int, 48000, 2);
+    wave_fmt.set_dwchannelmask(0x3);
+    let mut ixml = IxmlInfo::new(
+        "Speakers <USB & \"Line\">",
+        "{0.0.1.00000000}.{c7b2d4b1-6d0a-4b6f-9f0e-3c6a1f3c9e21}",
+        &wave_fmt,
+    );
+    let xml = ixml.to_xml();
+    assert!(xml.contains("<FILE_SAMPLE_RATE>48000</FILE_SAMPLE_RATE>"));
+    assert!(xml.contains("<TRACK_COUNT>2</TRACK_COUNT>"));
+    assert!(xml.contains("Speakers &lt;USB &amp; &quot;Line&quot;&gt;"));
+    assert_eq!(IxmlInfo::from_xml(&xml).unwrap(), ixml);
+
+    ixml.time_reference = Some(0x2_0000_0001);
+    let xml = ixml.to_xml();
+    assert!(xml
+        .contains("<TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_HI>2</TIMESTAMP_SAMPLES_SINCE_MIDNIGHT_HI>"));
+    assert_eq!(IxmlInfo::from_xml(&xml).unwrap(), ixml);
+    assert!(IxmlInfo::from_xml("<BWFXML></BWFXML>").is_err());
+}
+
+#[test]
+fn wav_with_metadata() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let stream_start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let bext = BextInfo::new("recording", "wasapi", stream_start, 44100, 4410);
+    let mut ixml = IxmlInfo::new("Microphone", "{device-id}", &wave_fmt);
+    ixml.time_reference = Some(bext.time_reference);
+
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), &wave_fmt).unwrap();
+    writer.add_bext(&bext).unwrap();
+    writer.add_ixml(&ixml).unwrap();
+    writer.write_bytes(&[1, 0, 2, 0, 3, 0, 4, 0]).unwrap();
+    writer.close().unwrap();
+    let data = writer.get_ref().get_ref().clone();
+
+    // The bext chunk goes before the data chunk
+    let bext_pos = data.windows(4).position(|id| id == b"bext").unwrap();
+    let data_pos = data.windows(4).position(|id| id == b"data").unwrap();
+    assert!(bext_pos < data_pos);
+
+    let reader = WavReader::new(Cursor::new(data)).unwrap();
+    assert_eq!(reader.get_bext().unwrap(), Some(bext));
+    assert_eq!(reader.get_ixml().unwrap(), Some(ixml));
+    assert_eq!(reader.get_nbr_frames(), 2);
+}
+
+#[test]
+fn wav_without_metadata() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 1);
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), &wave_fmt).unwrap();
+    writer.write_bytes(&[1, 0]).unwrap();
+    writer.close().unwrap();
+    let data = writer.get_ref().get_ref().clone();
+    let reader = WavReader::new(Cursor::new(data)).unwrap();
+    assert_eq!(reader.get_bext().unwrap(), None);
+    assert_eq!(reader.get_ixml().unwrap(), None);
+}
@@ -0,0 +1,138 @@
+use wasapi::distortion::{analyze_noise, analyze_tone, AnalysisSettings, DistortionMeasurement};
+use wasapi::generator::{sine, stepped_sine_frequencies, NoiseGenerator};
+use wasapi::loopback::SimulatedLoopback;
+use wasapi::wasapi::{SampleType, WaveFormat};
+
+const SAMPLERATE: usize = 48000;
+
+fn tone_with_harmonics(freq: f64, amplitudes: &[f64], len: usize) -> Vec<f64> {
+    let mut signal = vec![0.0; len];
+    for (n, amplitude) in amplitudes.iter().enumerate() {
+        for (value, harm) in signal
+            .iter_mut()
+            .zip(sine((n + 1) as f64 * freq, *amplitude, len, SAMPLERATE))
+        {
+            *value += harm;
+        }
+    }
+    signal
+}
+
+fn assert_close(value: f64, expected: f64, tolerance: f64) {
+    assert!(
+        (value - expected).abs() < tolerance,
+        "got {}, expected {}",
+        value,
+        expected
+    );
+}
+
+#[test]
+fn pure_tone() {
+    let settings = AnalysisSettings::new(SAMPLERATE);
+    let signal = sine(997.0, 1.0, 65536, SAMPLERATE);
+    let result = analyze_tone(&signal, 997.0, &settings).unwrap();
+    assert_close(result.frequency, 997.0, 1.0);
+    assert_close(result.level_dbfs, 0.0, 0.01);
+    assert!(result.thd_db < -150.0);
+    assert!(result.thdn_db < -150.0);
+}
+
+#[test]
+fn known_harmonics() {
+    let settings = AnalysisSettings::new(SAMPLERATE);
+    // Second harmonic at -40 dB and third at -60 dB relative to the fundamental
+    let signal = tone_with_harmonics(1000.0, &[0.5, 0.005, 0.0005], 65536);
+    let result = analyze_tone(&signal, 1000.0, &settings).unwrap();
+    assert_close(result.level_dbfs, -6.0206, 0.01);
+    assert_close(result.thd_percent, 1.004988, 1.0e-4);
+    assert_close(result.thd_db, -39.9568, 0.001);
+    assert_close(result.harmonics_db[0], -40.0, 0.01);
+    assert_close(result.harmonics_db[1], -60.0, 0.01);
+    assert!(result.harmonics_db[2] < -150.0);
+    assert_close(result.thdn_db, result.thd_db, 0.001);
+}
+
+#[test]
+fn harmonics_above_band_are_ignored() {
+    let settings = AnalysisSettings::new(SAMPLERATE);
+    // The third harmonic at 21 kHz is outside the 20 kHz analysis band
+    let signal = tone_with_harmonics(7000.0, &[0.5, 0.0, 0.05], 65536);
+    let result = analyze_tone(&signal, 7000.0, &settings).unwrap();
+    assert_eq!(result.harmonics_db.len(), 1);
+    assert!(result.thd_db < -150.0);
+    assert!(result.thdn_db < -150.0);
+}
+
+#[test]
+fn tone_with_noise() {
+    let settings = AnalysisSettings::new(SAMPLERATE);
+    let mut noise = NoiseGenerator::new(1);
+    // Uniform noise with peak 0.001 has an rms of 0.001/sqrt(3)
+    let signal: Vec<f64> = tone_with_harmonics(1000.0, &[0.5, 0.005], 65536)
+        .iter()
+        .zip(noise.generate(65536, 0.001))
+        .map(|(tone, noise)| tone + noise)
+        .collect();
+    let result = analyze_tone(&signal, 1000.0, &settings).unwrap();
+    let band_fraction = (20000.0f64 - 20.0) / 24000.0;
+    let noise_ms = band_fraction * 0.001f64.powi(2) / 3.0;
+    let expected_noise = 10.0 * (2.0 * noise_ms).log10();
+    assert_close(result.noise_dbfs, expected_noise, 0.2);
+    assert_close(result.thd_db, -40.0, 0.05);
+    let expected_thdn = 100.0 * ((0.005f64.powi(2) / 2.0 + noise_ms) / (0.5f64.powi(2) / 2.0)).sqrt();
+    assert_close(result.thdn_percent, expected_thdn, 0.01);
+
+    let floor = analyze_noise(&NoiseGenerator::new(2).generate(65536, 0.001), &settings);
+    assert_close(floor, expected_noise, 0.2);
+}
+
+#[test]
+fn stepped_sine_steps() {
+    let freqs = stepped_sine_frequencies(100.0, 1600.0, 3);
+    assert_eq!(freqs.len(), 13);
+    assert_close(freqs[3], 200.0, 1.0e-9);
+    assert_close(freqs[12], 1600.0, 1.0e-9);
+}
+
+#[test]
+fn measure_simulated_loopback() {
+    let format = WaveFormat::new(32, 32, &SampleType::Float, SAMPLERATE, 2);
+    let mut loopback = SimulatedLoopback::new(&format, 500);
+    loopback.set_noise_level(1.0e-5);
+    // Second order nonlinearity and crosstalk of -60 dB from left to right
+    // and -40 dB from right to left.
+    loopback.set_processor(Box::new(|data: &[Vec<f64>]| {
+        let left: Vec<f64> = data[0].iter().map(|x| x + 0.01 * x * x).collect();
+        let right: Vec<f64> = data[1].iter().map(|x| x + 0.01 * x * x).collect();
+        vec![
+            left.iter().zip(right.iter()).map(|(l, r)| l + 0.01 * r).collect(),
+            right.iter().zip(left.iter()).map(|(r, l)| r + 0.001 * l).collect(),
+        ]
+    }));
+    let mut measurement = DistortionMeasurement::new(&[1000.0, 3000.0]);
+    measurement.amplitude_dbfs = -6.0206;
+    let report = measurement.measure(&mut loopback).unwrap();
+    assert_eq!(report.channels.len(), 2);
+
+    let noise_ms = (20000.0f64 - 20.0) / 24000.0 * 1.0e-10 / 3.0;
+    let expected_noise = 10.0 * (2.0 * noise_ms).log10();
+    for channel in report.channels.iter() {
+        assert_close(channel.noise_floor_dbfs, expected_noise, 0.3);
+        assert_close(channel.snr_db, -expected_noise, 0.3);
+        assert_close(channel.dynamic_range_db, -expected_noise, 0.5);
+        for tone in channel.tones.iter() {
+            // x + 0.01 x^2 with x = 0.5 sin(wt) gives a second harmonic of 0.00125
+            assert_close(tone.analysis.level_dbfs, -6.0206, 0.01);
+            assert_close(tone.analysis.thd_percent, 0.25, 0.001);
+            assert_close(tone.analysis.harmonics_db[0], -52.04, 0.01);
+        }
+    }
+    assert_close(report.channels[0].tones[0].crosstalk[0].level_db, -60.0, 0.01);
+    assert_close(report.channels[1].tones[1].crosstalk[0].level_db, -40.0, 0.01);
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+    assert_eq!(json["samplerate"], 48000);
+    assert_eq!(json["channels"][1]["tones"][0]["crosstalk"][0]["channel"], 0);
+    assert!(json["channels"][0]["tones"][1]["analysis"]["thd_db"].is_number());
+}
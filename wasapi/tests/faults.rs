@@ -0,0 +1,388 @@
+use std::f64::consts::PI;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use wasapi::backend::Backend;
+use wasapi::conversion::channels_to_bytes;
+use wasapi::engine::{capture_loop, playback_loop, StreamSettings};
+use wasapi::glitch::GlitchKind;
+use wasapi::simulated::{Fault, FaultTrigger, SimDeviceConfig, SimulatedBackend};
+use wasapi::wasapi::{Direction, SampleType, ShareMode, WaveFormat};
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+}
+
+fn backend() -> SimulatedBackend {
+    let backend = SimulatedBackend::new();
+    backend
+        .add_device(SimDeviceConfig::new(
+            "Speakers",
+            &Direction::Render,
+            &format(),
+        ))
+        .unwrap();
+    backend
+        .add_device(SimDeviceConfig::new(
+            "Microphone",
+            &Direction::Capture,
+            &format(),
+        ))
+        .unwrap();
+    backend
+}
+
+fn sine_bytes(nbr_frames: usize) -> Vec<u8> {
+    let wave: Vec<f64> = (0..nbr_frames)
+        .map(|n| 0.5 * (2.0 * PI * 440.0 * n as f64 / 48000.0).sin())
+        .collect();
+    channels_to_bytes(&[wave.clone(), wave], &format()).unwrap()
+}
+
+// Frame n holds the number n + 1, so that silence can be told apart and every frame can be identified
+fn numbered_bytes(nbr_frames: usize) -> Vec<u8> {
+    (1..=nbr_frames as u32)
+        .flat_map(|n| n.to_le_bytes())
+        .collect()
+}
+
+fn frame_numbers(data: &[u8]) -> Vec<u32> {
+    data.chunks(4)
+        .map(|frame| u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]))
+        .collect()
+}
+
+// Play some data, returns the error message if the loop failed
+fn play(backend: &SimulatedBackend, nbr_frames: usize) -> Result<(), String> {
+    let settings = StreamSettings::new("Speakers", &format());
+    play_data(backend, &settings, sine_bytes(nbr_frames))
+}
+
+fn play_data(
+    backend: &SimulatedBackend,
+    settings: &StreamSettings,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    let (tx_play, rx_play) = mpsc::channel();
+    tx_play.send(data).unwrap();
+    drop(tx_play);
+    playback_loop(backend, settings, rx_play).map_err(|err| err.to_string())
+}
+
+// Wait until a device has been invalidated, and bring it back
+fn restore_when_invalidated(backend: &SimulatedBackend, device: &str, direction: &Direction) {
+    for _ in 0..1000 {
+        let collection = backend.get_device_collection(direction).unwrap();
+        if collection.get_device_with_name(device).is_err() {
+            backend.restore_device(device).unwrap();
+            return;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    panic!("The device was not invalidated");
+}
+
+// The captured data, the kind and position of the glitch events, and the result of the loop
+type CaptureResult = (Vec<u8>, Vec<(GlitchKind, u64)>, Result<(), String>);
+
+// Capture until the loop stops or enough chunks have been received
+fn capture(backend: &SimulatedBackend, nbr_chunks: usize) -> CaptureResult {
+    let mut settings = StreamSettings::new("Microphone", &format());
+    settings.chunksize = 480;
+    capture_with(backend, settings, nbr_chunks)
+}
+
+fn capture_with(
+    backend: &SimulatedBackend,
+    settings: StreamSettings,
+    nbr_chunks: usize,
+) -> CaptureResult {
+    let (tx_capt, rx_capt) = mpsc::sync_channel(nbr_chunks);
+    let backend_capt = backend.clone();
+    let handle = thread::spawn(move || {
+        let mut events = Vec::new();
+        let result = capture_loop(&backend_capt, &settings, tx_capt, &mut |event| {
+            events.push((event.kind, event.position))
+        });
+        (events, result.map_err(|err| err.to_string()))
+    });
+    let mut captured = Vec::new();
+    for _ in 0..nbr_chunks {
+        match rx_capt.recv() {
+            Ok(chunk) => captured.extend(chunk),
+            Err(_) => break,
+        }
+    }
+    drop(rx_capt);
+    let (events, result) = handle.join().unwrap();
+    (captured, events, result)
+}
+
+#[test]
+fn event_timeout() {
+    let backend = backend();
+    backend
+        .add_fault(
+            "Speakers",
+            FaultTrigger::Time(Duration::from_millis(45)),
+            Fault::EventTimeout,
+        )
+        .unwrap();
+    let result = play(&backend, 9600);
+    assert_eq!(result.unwrap_err(), "Timed out waiting for playback event");
+    // The device kept playing during the timeout, and ran out of data
+    assert_eq!(backend.get_time(), Duration::from_millis(1050));
+    let underruns = backend.get_underruns("Speakers").unwrap();
+    assert_eq!(underruns[0], 3360);
+    let rendered = backend.get_rendered("Speakers").unwrap();
+    assert!(rendered[..4 * 3360] == sine_bytes(3360)[..]);
+}
+
+#[test]
+fn event_timeout_retried() {
+    let backend = backend();
+    backend
+        .add_fault(
+            "Speakers",
+            FaultTrigger::Time(Duration::from_millis(45)),
+            Fault::EventTimeout,
+        )
+        .unwrap();
+    let mut settings = StreamSettings::new("Speakers", &format());
+    settings.max_retries = 1;
+    play_data(&backend, &settings, numbered_bytes(9600)).unwrap();
+    // Playback continued after the timeout, with a gap where the device ran out of data
+    let rendered = frame_numbers(&backend.get_rendered("Speakers").unwrap());
+    let underruns = backend.get_underruns("Speakers").unwrap();
+    assert_eq!(underruns[0], 3360);
+    let played: Vec<u32> = rendered.into_iter().filter(|n| *n != 0).collect();
+    assert_eq!(played, (1..=9600).collect::<Vec<u32>>());
+}
+
+#[test]
+fn device_invalidated() {
+    let backend = backend();
+    let script = sine_bytes(9600);
+    backend.add_capture_data("Microphone", &script).unwrap();
+    backend
+        .add_fault(
+            "Microphone",
+            FaultTrigger::Time(Duration::from_millis(100)),
+            Fault::DeviceInvalidated,
+        )
+        .unwrap();
+    let (captured, _events, result) = capture(&backend, 100);
+    assert_eq!(result.unwrap_err(), "AUDCLNT_E_DEVICE_INVALIDATED");
+    // All nine periods captured before the removal were sent
+    assert_eq!(captured.len(), 4 * 4320);
+    assert!(captured[..] == script[..4 * 4320]);
+
+    // The device is gone
+    let collection = backend.get_device_collection(&Direction::Capture).unwrap();
+    assert_eq!(collection.get_nbr_devices().unwrap(), 0);
+    assert!(backend.get_default_device(&Direction::Capture).is_err());
+    let settings = StreamSettings::new("Microphone", &format());
+    let (tx_capt, _rx_capt) = mpsc::sync_channel(1);
+    assert!(capture_loop(&backend, &settings, tx_capt, &mut |_| {}).is_err());
+}
+
+#[test]
+fn playback_after_device_invalidated() {
+    let backend = backend();
+    backend
+        .add_fault(
+            "Speakers",
+            FaultTrigger::Time(Duration::from_millis(45)),
+            Fault::DeviceInvalidated,
+        )
+        .unwrap();
+    let mut settings = StreamSettings::new("Speakers", &format());
+    settings.max_retries = 100;
+    settings.timeout = 20;
+    let backend_play = backend.clone();
+    let handle = thread::spawn(move || play_data(&backend_play, &settings, numbered_bytes(9600)));
+    restore_when_invalidated(&backend, "Speakers", &Direction::Render);
+    handle.join().unwrap().unwrap();
+    // The two periods in the buffer of the device when it was invalidated are lost, the rest is played
+    let rendered = frame_numbers(&backend.get_rendered("Speakers").unwrap());
+    let played: Vec<u32> = rendered.into_iter().filter(|n| *n != 0).collect();
+    let expected: Vec<u32> = (1..=1920).chain(2881..=9600).collect();
+    assert_eq!(played, expected);
+}
+
+#[test]
+fn capture_after_device_invalidated() {
+    let backend = backend();
+    let script = numbered_bytes(9600);
+    backend.add_capture_data("Microphone", &script).unwrap();
+    backend
+        .add_fault(
+            "Microphone",
+            FaultTrigger::Time(Duration::from_millis(100)),
+            Fault::DeviceInvalidated,
+        )
+        .unwrap();
+    let mut settings = StreamSettings::new("Microphone", &format());
+    settings.chunksize = 480;
+    settings.max_retries = 100;
+    settings.timeout = 20;
+    settings.max_frames = Some(9600);
+    let backend_capt = backend.clone();
+    let handle = thread::spawn(move || capture_with(&backend_capt, settings, 100));
+    restore_when_invalidated(&backend, "Microphone", &Direction::Capture);
+    let (captured, events, result) = handle.join().unwrap();
+    result.unwrap();
+    // The capture continued with the next frames of the device, and the gap is reported
+    let numbers = frame_numbers(&captured);
+    assert_eq!(numbers.len(), 9600);
+    assert_eq!(numbers[..4320], (1..=4320).collect::<Vec<u32>>()[..]);
+    assert!(numbers[4320] > 4320);
+    let discontinuities: Vec<u64> = events
+        .into_iter()
+        .filter(|(kind, _)| *kind == GlitchKind::Discontinuity)
+        .map(|(_, position)| position)
+        .collect();
+    assert_eq!(discontinuities, vec![4320]);
+}
+
+#[test]
+fn device_invalidated_for_open_client() {
+    let backend = backend();
+    let device = backend.get_default_device(&Direction::Render).unwrap();
+    let mut client = device.get_iaudioclient().unwrap();
+    client
+        .initialize_client(&format(), 0, &Direction::Render, &ShareMode::Shared)
+        .unwrap();
+    let event = client.set_get_eventhandle().unwrap();
+    let render = client.get_audiorenderclient().unwrap();
+    client.start_stream().unwrap();
+    assert_eq!(backend.get_nbr_calls("Speakers").unwrap(), 4);
+    backend
+        .add_fault("Speakers", FaultTrigger::Call(6), Fault::DeviceInvalidated)
+        .unwrap();
+    event.wait_for_event(100).unwrap();
+    assert_eq!(device.get_state().unwrap(), 1);
+    let err = render.write_to_device(0, 4, &[]).unwrap_err();
+    assert_eq!(err.to_string(), "AUDCLNT_E_DEVICE_INVALIDATED");
+    assert!(client.get_current_padding().is_err());
+    assert!(event.wait_for_event(100).is_err());
+    assert_eq!(device.get_state().unwrap(), 4);
+    assert!(device.get_iaudioclient().is_err());
+}
+
+#[test]
+fn buffer_too_large() {
+    let backend = backend();
+    backend
+        .add_fault(
+            "Speakers",
+            FaultTrigger::Time(Duration::from_millis(25)),
+            Fault::BufferTooLarge,
+        )
+        .unwrap();
+    let result = play(&backend, 9600);
+    assert_eq!(result.unwrap_err(), "AUDCLNT_E_BUFFER_TOO_LARGE");
+    // The fault only affects one write
+    backend.advance(Duration::from_millis(100));
+    assert!(play(&backend, 960).is_ok());
+}
+
+#[test]
+fn format_rejected() {
+    let backend = backend();
+    // The loop calls is_supported and get_periods before initializing
+    backend
+        .add_fault("Speakers", FaultTrigger::Call(3), Fault::FormatRejected)
+        .unwrap();
+    let result = play(&backend, 960);
+    assert_eq!(result.unwrap_err(), "AUDCLNT_E_UNSUPPORTED_FORMAT");
+    assert_eq!(backend.get_nbr_calls("Speakers").unwrap(), 3);
+    assert!(backend.get_rendered("Speakers").unwrap().is_empty());
+    assert!(play(&backend, 960).is_ok());
+}
+
+#[test]
+fn flagged_capture_packets() {
+    let backend = backend();
+    let script = sine_bytes(9600);
+    backend.add_capture_data("Microphone", &script).unwrap();
+    backend
+        .add_fault(
+            "Microphone",
+            FaultTrigger::Time(Duration::from_millis(25)),
+            Fault::Discontinuity,
+        )
+        .unwrap();
+    backend
+        .add_fault(
+            "Microphone",
+            FaultTrigger::Time(Duration::from_millis(55)),
+            Fault::SilentPacket,
+        )
+        .unwrap();
+    let (captured, events, result) = capture(&backend, 10);
+    assert!(result.is_ok());
+    // The flags do not change the data
+    assert!(captured[..] == script[..4 * 4800]);
+    let flagged: Vec<(GlitchKind, u64)> = events
+        .into_iter()
+        .filter(|(kind, _)| *kind == GlitchKind::Discontinuity || *kind == GlitchKind::SilentBuffer)
+        .collect();
+    assert_eq!(
+        flagged,
+        vec![
+            (GlitchKind::Discontinuity, 960),
+            (GlitchKind::SilentBuffer, 2400)
+        ]
+    );
+}
+
+#[test]
+fn clock_drift() {
+    let backend = backend();
+    let nominal = {
+        play(&backend, 9600).unwrap();
+        backend.get_time()
+    };
+    // A slow clock takes longer to play the same data
+    let backend = self::backend();
+    backend
+        .add_fault(
+            "Speakers",
+            FaultTrigger::Time(Duration::ZERO),
+            Fault::ClockDrift(-20000.0),
+        )
+        .unwrap();
+    play(&backend, 9600).unwrap();
+    let expected = nominal.as_secs_f64() / 0.98;
+    assert!((backend.get_time().as_secs_f64() - expected).abs() < 1.0e-6);
+
+    // Changing the drift of a running stream continues from the last event
+    let backend = self::backend();
+    let device = backend.get_default_device(&Direction::Render).unwrap();
+    let mut client = device.get_iaudioclient().unwrap();
+    client
+        .initialize_client(&format(), 0, &Direction::Render, &ShareMode::Shared)
+        .unwrap();
+    let event = client.set_get_eventhandle().unwrap();
+    client.start_stream().unwrap();
+    backend
+        .add_fault(
+            "Speakers",
+            FaultTrigger::Time(Duration::from_millis(45)),
+            Fault::ClockDrift(100000.0),
+        )
+        .unwrap();
+    for _ in 0..4 {
+        event.wait_for_event(100).unwrap();
+    }
+    assert_eq!(backend.get_time(), Duration::from_millis(40));
+    event.wait_for_event(100).unwrap();
+    assert_eq!(backend.get_time(), Duration::from_nanos(49_090_909));
+    // The rate is 10% higher, so 11 periods take 100 ms
+    for _ in 0..10 {
+        event.wait_for_event(100).unwrap();
+    }
+    assert_eq!(backend.get_time(), Duration::from_millis(140));
+}
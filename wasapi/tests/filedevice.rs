@@ -0,0 +1,204 @@
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wasapi::backend::Backend;
+use wasapi::engine::{capture_loop, playback_loop, StreamSettings};
+use wasapi::filedevice::FileBackend;
+use wasapi::glitch::GlitchKind;
+use wasapi::simulated::{Pacing, SimDeviceConfig};
+use wasapi::wasapi::{Direction, FormatSupported, SampleType, ShareMode, WaveFormat};
+use wasapi::wav::{WavReader, WavWriter};
+
+fn test_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("wasapi_filedevice_{}.wav", name))
+}
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+}
+
+// Frames without any zero bytes, so that the start of the data is easy to find
+fn test_data(nbr_frames: usize) -> Vec<u8> {
+    (0..4 * nbr_frames).map(|n| (n % 251) as u8 + 1).collect()
+}
+
+fn write_input(name: &str, nbr_frames: usize) -> (PathBuf, Vec<u8>) {
+    let path = test_path(name);
+    let data = test_data(nbr_frames);
+    let mut writer = WavWriter::create(&path, &format()).unwrap();
+    writer.write_bytes(&data).unwrap();
+    writer.close().unwrap();
+    (path, data)
+}
+
+fn read_output(path: &PathBuf) -> (WaveFormat, Vec<u8>) {
+    let mut reader = WavReader::open(path).unwrap();
+    let nbr_frames = reader.get_nbr_frames() as usize;
+    (reader.get_format(), reader.read_bytes(nbr_frames).unwrap())
+}
+
+#[test]
+fn file_to_file_pipeline() {
+    let (input, data) = write_input("pipeline_in", 24000);
+    let output = test_path("pipeline_out");
+    let backend = FileBackend::new(Pacing::AsFastAsPossible);
+    backend.add_capture_file("Input", &input).unwrap();
+    backend
+        .add_render_file(
+            SimDeviceConfig::new("Output", &Direction::Render, &format()),
+            &output,
+        )
+        .unwrap();
+
+    // The capture loop sends straight to the playback loop, and stops a bit before the end of the file.
+    // The chunks are one period long, so that the playback never waits for a chunk to fill up.
+    let (tx_capt, rx_play) = mpsc::sync_channel(2);
+    let mut settings = StreamSettings::new("Input", &format());
+    settings.chunksize = 480;
+    settings.max_frames = Some(23900);
+    let backend_capt = backend.clone();
+    let capture = thread::spawn(move || {
+        capture_loop(&backend_capt, &settings, tx_capt, &mut |_| {}).map_err(|e| e.to_string())
+    });
+    let settings = StreamSettings::new("Output", &format());
+    playback_loop(&backend, &settings, rx_play).unwrap();
+    capture.join().unwrap().unwrap();
+
+    // The output is the input, after the silence played while the streams were starting.
+    // When the capture was one chunk ahead at the start, the playback fills its buffer
+    // with that chunk and one period of silence.
+    let (output_format, rendered) = read_output(&output);
+    assert_eq!(output_format, format());
+    let data = &data[..4 * 23900];
+    let mut played = Vec::new();
+    let mut nbr_periods = 0;
+    for period in rendered.chunks(4 * 480) {
+        if nbr_periods >= 2 || period.iter().any(|value| *value != 0) {
+            played.extend_from_slice(period);
+            nbr_periods += 1;
+        }
+    }
+    assert!(played[..data.len()] == data[..]);
+    assert!(played[data.len()..].iter().all(|value| *value == 0));
+    assert!(backend
+        .get_simulated()
+        .get_underruns("Output")
+        .unwrap()
+        .is_empty());
+    // The virtual clock covered at least the half second of audio
+    assert!(backend.get_simulated().get_time() >= Duration::from_millis(500));
+}
+
+#[test]
+fn format_negotiation() {
+    let (input, _data) = write_input("negotiation_in", 480);
+    let output = test_path("negotiation_out");
+    let backend = FileBackend::new(Pacing::AsFastAsPossible);
+    backend.add_capture_file("Input", &input).unwrap();
+    let format_24 = WaveFormat::new(24, 24, &SampleType::Int, 96000, 2);
+    let mut config = SimDeviceConfig::new("Output", &Direction::Render, &format());
+    config.formats.push(format_24.clone());
+    backend.add_render_file(config, &output).unwrap();
+    let config = SimDeviceConfig::new("Other", &Direction::Capture, &format());
+    assert!(backend.add_render_file(config, &output).is_err());
+
+    // The capture device uses the format of the file
+    let device = backend.get_default_device(&Direction::Capture).unwrap();
+    let client = device.get_iaudioclient().unwrap();
+    assert!(matches!(
+        client
+            .is_supported(&format(), &ShareMode::Exclusive)
+            .unwrap(),
+        FormatSupported::Yes
+    ));
+    match client.is_supported(&format_24, &ShareMode::Shared).unwrap() {
+        FormatSupported::ClosestMatch(closest) => assert_eq!(closest, format()),
+        FormatSupported::Yes => panic!("only the format of the file is supported"),
+    }
+    assert!(client
+        .is_supported(&format_24, &ShareMode::Exclusive)
+        .is_err());
+
+    // The render device writes the file in the format of the stream
+    let device = backend.get_default_device(&Direction::Render).unwrap();
+    let mut client = device.get_iaudioclient().unwrap();
+    client
+        .initialize_client(&format_24, 0, &Direction::Render, &ShareMode::Exclusive)
+        .unwrap();
+    let nbr_frames = client.get_bufferframecount().unwrap() as usize;
+    assert_eq!(nbr_frames, 288);
+    let event = client.set_get_eventhandle().unwrap();
+    let render = client.get_audiorenderclient().unwrap();
+    let data: Vec<u8> = (0..6 * nbr_frames * 4)
+        .map(|n| (n % 253) as u8 + 1)
+        .collect();
+    client.start_stream().unwrap();
+    for chunk in data.chunks(6 * nbr_frames) {
+        render.write_to_device(nbr_frames, 6, chunk).unwrap();
+        event.wait_for_event(100).unwrap();
+    }
+    client.stop_stream().unwrap();
+    drop(render);
+    drop(client);
+    let (output_format, rendered) = read_output(&output);
+    assert_eq!(output_format, format_24);
+    assert!(rendered == data);
+}
+
+#[test]
+fn end_of_capture_file() {
+    let (input, data) = write_input("end_in", 2400);
+    let backend = FileBackend::new(Pacing::AsFastAsPossible);
+    backend.add_capture_file("Input", &input).unwrap();
+    let (tx_capt, rx_capt) = mpsc::sync_channel(10);
+    let mut settings = StreamSettings::new("Input", &format());
+    settings.chunksize = 480;
+    let backend_capt = backend.clone();
+    let capture = thread::spawn(move || {
+        let mut silent = Vec::new();
+        capture_loop(&backend_capt, &settings, tx_capt, &mut |event| {
+            if event.kind == GlitchKind::SilentBuffer {
+                silent.push(event.position);
+            }
+        })
+        .unwrap();
+        silent
+    });
+    let mut captured = Vec::new();
+    for _ in 0..8 {
+        captured.extend(rx_capt.recv().unwrap());
+    }
+    drop(rx_capt);
+    let silent = capture.join().unwrap();
+    assert!(captured[..data.len()] == data[..]);
+    assert!(captured[data.len()..].iter().all(|value| *value == 0));
+    assert_eq!(silent[0], 2400);
+}
+
+#[test]
+fn real_time_pacing() {
+    let output = test_path("realtime_out");
+    // The clock is paced from when the backend is created
+    let start = Instant::now();
+    let backend = FileBackend::new(Pacing::RealTime);
+    backend
+        .add_render_file(
+            SimDeviceConfig::new("Output", &Direction::Render, &format()),
+            &output,
+        )
+        .unwrap();
+    let data = test_data(4800);
+    let (tx_play, rx_play) = mpsc::channel();
+    tx_play.send(data.clone()).unwrap();
+    drop(tx_play);
+    let settings = StreamSettings::new("Output", &format());
+    playback_loop(&backend, &settings, rx_play).unwrap();
+    // A tenth of a second of audio takes a tenth of a second to play
+    let virtual_time = backend.get_simulated().get_time();
+    assert!(virtual_time >= Duration::from_millis(100));
+    assert!(start.elapsed() >= virtual_time - Duration::from_millis(1));
+    let (_format, rendered) = read_output(&output);
+    assert!(rendered[..data.len()] == data[..]);
+}
@@ -0,0 +1,269 @@
+use std::io::Cursor;
+
+use wasapi::audiofile::open_reader;
+use wasapi::flac::{FlacReader, FlacSettings, FlacWriter, ThreadedFlacWriter};
+use wasapi::wasapi::{SampleType, WaveFormat};
+
+// Simple pseudo-random generator, to get repeatable noise
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> i64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) as i64
+    }
+}
+
+// Generate interleaved samples, a mix of a sine with a different frequency per channel and noise
+fn make_samples(nbr_frames: usize, channels: usize, bits: u32, noise_bits: u32) -> Vec<i64> {
+    let mut rng = Lcg(12345);
+    let amplitude = (1i64 << (bits - 2)) as f64;
+    let max = (1i64 << (bits - 1)) - 1;
+    let min = -(1i64 << (bits - 1));
+    let mut samples = Vec::with_capacity(nbr_frames * channels);
+    for n in 0..nbr_frames {
+        for chan in 0..channels {
+            let phase = n as f64 * 0.01 * (chan + 1) as f64;
+            let noise = if noise_bits > 0 {
+                rng.next() % (1 << noise_bits) - (1 << (noise_bits - 1))
+            } else {
+                0
+            };
+            let value = (amplitude * phase.sin()) as i64 + noise;
+            samples.push(value.clamp(min, max));
+        }
+    }
+    samples
+}
+
+// Convert samples to little-endian bytes, with the values in the top bits of the storage
+fn to_bytes(samples: &[i64], storebits: u32, validbits: u32) -> Vec<u8> {
+    let bytes_per_sample = storebits as usize / 8;
+    let mut data = Vec::with_capacity(samples.len() * bytes_per_sample);
+    for value in samples.iter() {
+        let stored = value << (storebits - validbits);
+        data.extend_from_slice(&stored.to_le_bytes()[..bytes_per_sample]);
+    }
+    data
+}
+
+fn encode(data: &[u8], wave_fmt: &WaveFormat, settings: FlacSettings) -> Vec<u8> {
+    let mut writer = FlacWriter::new(Cursor::new(Vec::new()), wave_fmt, settings).unwrap();
+    // Write in uneven pieces, like data arriving from a device
+    let blockalign = wave_fmt.get_blockalign() as usize;
+    for piece in data.chunks(blockalign * 441) {
+        writer.write_bytes(piece).unwrap();
+    }
+    writer.close().unwrap();
+    writer.get_ref().get_ref().clone()
+}
+
+fn decode(flac: Vec<u8>) -> (WaveFormat, Vec<u8>) {
+    let mut reader = FlacReader::new(Cursor::new(flac)).unwrap();
+    assert!(reader.verify().unwrap());
+    let nbr_frames = reader.get_nbr_frames() as usize;
+    let data = reader.read_bytes(nbr_frames + 100).unwrap();
+    assert_eq!(reader.get_position(), nbr_frames as u64);
+    (reader.get_format(), data)
+}
+
+fn roundtrip(nbr_frames: usize, channels: usize, bits: u32, noise_bits: u32) -> usize {
+    let wave_fmt = WaveFormat::new(
+        bits as usize,
+        bits as usize,
+        &SampleType::Int,
+        48000,
+        channels,
+    );
+    let data = to_bytes(
+        &make_samples(nbr_frames, channels, bits, noise_bits),
+        bits,
+        bits,
+    );
+    let flac = encode(&data, &wave_fmt, FlacSettings::new());
+    let compressed = flac.len();
+    let (format, decoded) = decode(flac);
+    assert_eq!(format, wave_fmt);
+    assert!(decoded == data, "data differs for {} channels", channels);
+    compressed
+}
+
+#[test]
+fn roundtrip_16bit_all_channel_counts() {
+    for channels in 1..=8 {
+        roundtrip(5000, channels, 16, 6);
+    }
+}
+
+#[test]
+fn roundtrip_24bit_all_channel_counts() {
+    for channels in 1..=8 {
+        roundtrip(5000, channels, 24, 10);
+    }
+}
+
+#[test]
+fn sine_compresses() {
+    let compressed = roundtrip(20000, 2, 16, 0);
+    assert!(compressed < 20000 * 4 / 3, "size {}", compressed);
+}
+
+#[test]
+fn full_scale_noise() {
+    // Noise over the full range can only be stored verbatim
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let mut rng = Lcg(1);
+    let data: Vec<u8> = (0..4 * 3000).map(|_| rng.next() as u8).collect();
+    let flac = encode(&data, &wave_fmt, FlacSettings::new());
+    assert!(flac.len() < data.len() + 1000);
+    let (_, decoded) = decode(flac);
+    assert!(decoded == data);
+}
+
+#[test]
+fn silence_and_constant() {
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 96000, 2);
+    let mut samples = vec![0; 2 * 8192];
+    samples.extend(std::iter::repeat_n(-1234567, 2 * 8192));
+    let data = to_bytes(&samples, 24, 24);
+    let flac = encode(&data, &wave_fmt, FlacSettings::new());
+    // Four blocks of constant subframes
+    assert!(flac.len() < 200, "size {}", flac.len());
+    let (_, decoded) = decode(flac);
+    assert!(decoded == data);
+}
+
+#[test]
+fn partial_block_and_odd_block_size() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 3);
+    let data = to_bytes(&make_samples(1000, 3, 16, 4), 16, 16);
+    for block_size in [16, 192, 1000, 1152, 4096, 4999].iter() {
+        let mut settings = FlacSettings::new();
+        settings.block_size = *block_size;
+        settings.max_lpc_order = 12;
+        let flac = encode(&data, &wave_fmt, settings);
+        let (_, decoded) = decode(flac);
+        assert!(
+            decoded == data,
+            "data differs for block size {}",
+            block_size
+        );
+    }
+}
+
+#[test]
+fn without_stereo_decorrelation_or_lpc() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let data = to_bytes(&make_samples(6000, 2, 16, 8), 16, 16);
+    let mut settings = FlacSettings::new();
+    settings.stereo_decorrelation = false;
+    settings.max_lpc_order = 0;
+    let (_, decoded) = decode(encode(&data, &wave_fmt, settings));
+    assert!(decoded == data);
+}
+
+#[test]
+fn roundtrip_24_in_32() {
+    let wave_fmt = WaveFormat::new(32, 24, &SampleType::Int, 48000, 2);
+    let samples = make_samples(5000, 2, 24, 8);
+    let data = to_bytes(&samples, 32, 24);
+    let flac = encode(&data, &wave_fmt, FlacSettings::new());
+    let (format, decoded) = decode(flac);
+    // Decoded data uses the smallest storage size
+    assert_eq!(format, WaveFormat::new(24, 24, &SampleType::Int, 48000, 2));
+    assert!(decoded == to_bytes(&samples, 24, 24));
+}
+
+#[test]
+fn streaminfo() {
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 88200, 6);
+    let data = to_bytes(&make_samples(10000, 6, 24, 4), 24, 24);
+    let flac = encode(&data, &wave_fmt, FlacSettings::new());
+    assert_eq!(&flac[0..4], b"fLaC");
+    // Last metadata block, STREAMINFO with 34 bytes
+    assert_eq!(&flac[4..8], &[0x80, 0, 0, 34]);
+    let reader = FlacReader::new(Cursor::new(flac)).unwrap();
+    let info = reader.get_streaminfo();
+    assert_eq!(info.min_block_size, 4096);
+    assert_eq!(info.max_block_size, 4096);
+    assert_eq!(info.samplerate, 88200);
+    assert_eq!(info.channels, 6);
+    assert_eq!(info.bits_per_sample, 24);
+    assert_eq!(info.nbr_frames, 10000);
+    assert!(info.min_frame_size > 0 && info.min_frame_size <= info.max_frame_size);
+    let md5 = md5::compute(&data);
+    assert_eq!(info.md5, md5.0);
+}
+
+#[test]
+fn corrupted_data() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 1);
+    let data = to_bytes(&make_samples(3000, 1, 16, 6), 16, 16);
+    let mut flac = encode(&data, &wave_fmt, FlacSettings::new());
+    let len = flac.len();
+    flac[len - 100] ^= 0x10;
+    let mut reader = FlacReader::new(Cursor::new(flac)).unwrap();
+    assert!(reader.read_bytes(3000).is_err());
+}
+
+#[test]
+fn unsupported_formats() {
+    let settings = FlacSettings::new();
+    let float_fmt = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    assert!(FlacWriter::new(Cursor::new(Vec::new()), &float_fmt, settings.clone()).is_err());
+    let fmt_32 = WaveFormat::new(32, 32, &SampleType::Int, 44100, 2);
+    assert!(FlacWriter::new(Cursor::new(Vec::new()), &fmt_32, settings.clone()).is_err());
+    let fmt_9ch = WaveFormat::new(16, 16, &SampleType::Int, 44100, 9);
+    assert!(FlacWriter::new(Cursor::new(Vec::new()), &fmt_9ch, settings).is_err());
+}
+
+#[test]
+fn seek() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let data = to_bytes(&make_samples(20000, 2, 16, 6), 16, 16);
+    let flac = encode(&data, &wave_fmt, FlacSettings::new());
+    let mut reader = FlacReader::new(Cursor::new(flac)).unwrap();
+    for start in [10000usize, 5, 4096, 19990, 0, 12345].iter() {
+        reader.seek(*start as u64).unwrap();
+        assert_eq!(reader.get_position(), *start as u64);
+        let read = reader.read_bytes(100).unwrap();
+        let end = (start + 100).min(20000);
+        assert!(read == data[4 * start..4 * end], "wrong data at {}", start);
+    }
+}
+
+#[test]
+fn threaded_writer() {
+    let path = std::env::temp_dir().join("wasapi_test_threaded.flac");
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 48000, 2);
+    let data = to_bytes(&make_samples(30000, 2, 16, 6), 16, 16);
+    let writer = ThreadedFlacWriter::create(&path, &wave_fmt, FlacSettings::new()).unwrap();
+    for piece in data.chunks(4 * 480) {
+        writer.write_bytes(piece).unwrap();
+    }
+    assert!(writer.write_bytes(&[0, 0, 0]).is_err());
+    assert_eq!(writer.close().unwrap(), 30000);
+
+    let mut reader = FlacReader::open(&path).unwrap();
+    assert!(reader.verify().unwrap());
+    assert!(reader.read_bytes(30000).unwrap() == data);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn open_as_audio_file() {
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 44100, 2);
+    let data = to_bytes(&make_samples(5000, 2, 24, 6), 24, 24);
+    let flac = encode(&data, &wave_fmt, FlacSettings::new());
+    let mut reader = open_reader(Cursor::new(flac)).unwrap();
+    assert_eq!(reader.get_format(), wave_fmt);
+    assert_eq!(reader.get_nbr_frames(), 5000);
+    let frames = reader.read_frames(5000).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].len(), 5000);
+    let expected = (1 << 22) as f64 * 0.01f64.sin() / (1 << 23) as f64;
+    assert!((frames[0][1] - expected).abs() < 1e-4);
+}
@@ -0,0 +1,136 @@
+use wasapi::generator::{sine, NoiseGenerator};
+use wasapi::glitch::{find_glitches, GlitchDetector, GlitchEvent, GlitchKind, GlitchSettings};
+use wasapi::wasapi::BufferFlags;
+
+const SAMPLERATE: usize = 48000;
+
+// A 1 kHz tone with a little noise, like a clean capture
+fn clean_signal(len: usize) -> Vec<f64> {
+    let mut noise = NoiseGenerator::new(7);
+    sine(1000.0, 0.5, len, SAMPLERATE)
+        .iter()
+        .map(|value| value + 1.0e-4 * noise.next_value())
+        .collect()
+}
+
+fn events_of_kind(events: &[GlitchEvent], kind: GlitchKind) -> Vec<&GlitchEvent> {
+    events.iter().filter(|event| event.kind == kind).collect()
+}
+
+#[test]
+fn clean_signal_has_no_glitches() {
+    let events = find_glitches(&[clean_signal(96000)], GlitchSettings::new(SAMPLERATE));
+    assert!(events.is_empty(), "{:?}", events);
+}
+
+#[test]
+fn finds_dropout() {
+    // At 10006 frames the tone is close to its peak
+    let mut signal = clean_signal(48000);
+    for value in signal.iter_mut().skip(10006).take(200) {
+        *value = 0.0;
+    }
+    let events = find_glitches(&[signal], GlitchSettings::new(SAMPLERATE));
+    let zero_runs = events_of_kind(&events, GlitchKind::ZeroRun);
+    assert_eq!(zero_runs.len(), 1);
+    assert_eq!(zero_runs[0].position, 10006);
+    assert_eq!(zero_runs[0].length, 200);
+    assert_eq!(zero_runs[0].channel, Some(0));
+    assert!((zero_runs[0].time - 10006.0 / 48000.0).abs() < 1.0e-12);
+    let jumps = events_of_kind(&events, GlitchKind::Jump);
+    assert!(jumps.iter().any(|event| event.position == 10006));
+    assert!(jumps
+        .iter()
+        .all(|event| event.position >= 10006 && event.position <= 10206));
+    assert!(events_of_kind(&events, GlitchKind::DcStep).is_empty());
+}
+
+#[test]
+fn finds_single_sample_jump() {
+    let mut signal = clean_signal(48000);
+    signal[20000] += 0.1;
+    let events = find_glitches(&[signal], GlitchSettings::new(SAMPLERATE));
+    assert_eq!(events.len(), 1, "{:?}", events);
+    assert_eq!(events[0].kind, GlitchKind::Jump);
+    assert_eq!(events[0].position, 20000);
+    assert!((events[0].magnitude - 0.1).abs() < 0.01);
+}
+
+#[test]
+fn finds_stuck_samples() {
+    let mut signal = clean_signal(48000);
+    let stuck = signal[30000];
+    for value in signal.iter_mut().skip(30000).take(50) {
+        *value = stuck;
+    }
+    let events = find_glitches(&[signal], GlitchSettings::new(SAMPLERATE));
+    let stuck_events = events_of_kind(&events, GlitchKind::StuckSamples);
+    assert_eq!(stuck_events.len(), 1);
+    assert_eq!(stuck_events[0].position, 30000);
+    assert_eq!(stuck_events[0].length, 50);
+    assert_eq!(stuck_events[0].magnitude, stuck);
+}
+
+#[test]
+fn finds_dc_step() {
+    let mut signal = clean_signal(96000);
+    for value in signal.iter_mut().skip(50000) {
+        *value += 0.2;
+    }
+    let events = find_glitches(&[signal], GlitchSettings::new(SAMPLERATE));
+    let steps = events_of_kind(&events, GlitchKind::DcStep);
+    assert_eq!(steps.len(), 1);
+    assert!(
+        (steps[0].position as i64 - 50000).abs() <= 10,
+        "{}",
+        steps[0]
+    );
+    assert!((steps[0].magnitude - 0.2).abs() < 0.01);
+}
+
+#[test]
+fn chunked_processing_matches() {
+    let mut left = clean_signal(48000);
+    let mut right = clean_signal(48000);
+    for value in left.iter_mut().skip(12345).take(100) {
+        *value = 0.0;
+    }
+    right[40000] -= 0.2;
+    let data = vec![left, right];
+    let whole = find_glitches(&data, GlitchSettings::new(SAMPLERATE));
+
+    let mut detector = GlitchDetector::new(2, GlitchSettings::new(SAMPLERATE));
+    let mut chunked = Vec::new();
+    for start in (0..48000).step_by(441) {
+        let end = (start + 441).min(48000);
+        let chunk: Vec<Vec<f64>> = data.iter().map(|chan| chan[start..end].to_vec()).collect();
+        chunked.extend(detector.process(&chunk));
+    }
+    chunked.extend(detector.finish());
+    assert_eq!(detector.get_position(), 48000);
+    assert_eq!(whole.len(), chunked.len());
+    for (a, b) in whole.iter().zip(chunked.iter()) {
+        assert_eq!(a.kind, b.kind);
+        assert_eq!(a.position, b.position);
+        assert_eq!(a.channel, b.channel);
+        assert_eq!(a.length, b.length);
+    }
+    assert!(whole
+        .iter()
+        .any(|event| event.channel == Some(1) && event.position == 40000));
+}
+
+#[test]
+fn reports_buffer_flags() {
+    let detector = GlitchDetector::new(2, GlitchSettings::new(SAMPLERATE));
+    assert!(detector.report_flags(&BufferFlags::new(0), 0).is_empty());
+    let events = detector.report_flags(&BufferFlags::new(3), 24000);
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].kind, GlitchKind::Discontinuity);
+    assert_eq!(events[1].kind, GlitchKind::SilentBuffer);
+    assert_eq!(events[0].channel, None);
+    assert!((events[0].time - 0.5).abs() < 1.0e-12);
+    let flags = BufferFlags::new(4);
+    assert!(flags.timestamp_error && !flags.silent && !flags.data_discontinuity);
+    assert_eq!(flags.to_string(), "timestamp error");
+}
@@ -0,0 +1,175 @@
+use std::time::UNIX_EPOCH;
+
+use wasapi::conversion::bytes_to_channels;
+use wasapi::glitch::{GlitchDetector, GlitchKind, GlitchSettings};
+use wasapi::history::{HistoryBuffer, HistoryCapture, HistorySaver, HistorySettings};
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::WavReader;
+
+fn format() -> WaveFormat {
+    WaveFormat::new(32, 32, &SampleType::Int, 1000, 1)
+}
+
+// 1 second before and 0.5 seconds after the trigger
+fn buffer() -> HistoryBuffer {
+    let mut settings = HistorySettings::new(1000);
+    settings.history = 1.0;
+    settings.post = 0.5;
+    HistoryBuffer::new(&format(), &settings)
+}
+
+fn counter_frames(start: u32, nbr_frames: u32) -> Vec<u8> {
+    (start..start + nbr_frames)
+        .flat_map(|n| n.to_le_bytes().to_vec())
+        .collect()
+}
+
+fn counters(capture: &HistoryCapture) -> Vec<u32> {
+    capture
+        .data
+        .chunks(4)
+        .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+        .collect()
+}
+
+fn check_span(capture: &HistoryCapture, start: u32, end: u32) {
+    assert_eq!(capture.start, start as u64);
+    let expected: Vec<u32> = (start..end).collect();
+    assert!(counters(capture) == expected, "wrong span");
+}
+
+#[test]
+fn trigger_now() {
+    let mut history = buffer();
+    let mut captures = Vec::new();
+    let mut position = 0;
+    let mut size = 1;
+    while position < 10000 {
+        if position == 4321 {
+            history.trigger();
+        }
+        let frames = size.min(10000 - position);
+        captures.extend(history.process(&counter_frames(position, frames)).unwrap());
+        position += frames;
+        size = if position < 4321 {
+            (4321 - position).min(size * 3 + 1)
+        } else {
+            (size * 3 + 1) % 333 + 1
+        };
+    }
+    assert_eq!(captures.len(), 1);
+    assert_eq!(captures[0].trigger, 4321);
+    check_span(&captures[0], 3321, 4821);
+}
+
+#[test]
+fn trigger_from_other_thread() {
+    let mut history = buffer();
+    let handle = history.get_trigger_handle();
+    history.process(&counter_frames(0, 3000)).unwrap();
+    std::thread::spawn(move || handle.trigger()).join().unwrap();
+    // The trigger takes effect at the start of the next block
+    assert!(history
+        .process(&counter_frames(3000, 400))
+        .unwrap()
+        .is_empty());
+    assert_eq!(history.get_nbr_pending(), 1);
+    let captures = history.process(&counter_frames(3400, 1000)).unwrap();
+    assert_eq!(captures.len(), 1);
+    assert_eq!(captures[0].trigger, 3000);
+    check_span(&captures[0], 2000, 3500);
+}
+
+#[test]
+fn trigger_in_the_past() {
+    let mut history = buffer();
+    history.process(&counter_frames(0, 5000)).unwrap();
+    // An analyzer reports an event 300 frames back
+    history.trigger_at(4700).unwrap();
+    // Too far back, the history is already overwritten
+    assert!(history.trigger_at(4400).is_err());
+    let captures = history.process(&counter_frames(5000, 2000)).unwrap();
+    assert_eq!(captures.len(), 1);
+    check_span(&captures[0], 3700, 5200);
+
+    // Exactly the post-trigger length back, complete at once
+    history.trigger_at(6500).unwrap();
+    let captures = history.process(&[]).unwrap();
+    check_span(&captures[0], 5500, 7000);
+}
+
+#[test]
+fn overlapping_triggers_and_short_history() {
+    let mut history = buffer();
+    history.process(&counter_frames(0, 200)).unwrap();
+    // Less history than requested at the start of the stream
+    history.trigger();
+    history.process(&counter_frames(200, 300)).unwrap();
+    history.trigger();
+    history.trigger_at(900).unwrap();
+    // One block that completes all three, and would overwrite the first ones if stored at once
+    let captures = history.process(&counter_frames(500, 3000)).unwrap();
+    assert_eq!(captures.len(), 3);
+    check_span(&captures[0], 0, 700);
+    check_span(&captures[1], 0, 1000);
+    check_span(&captures[2], 0, 1400);
+    assert_eq!(history.get_position(), 3500);
+    assert!(history.process(&[0; 3]).is_err());
+}
+
+#[test]
+fn save_captures() {
+    let dir = std::env::temp_dir().join("wasapi_test_history");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut history = buffer();
+    let saver = HistorySaver::new(&dir, "glitch_{seq}.wav", "Mic", &format(), UNIX_EPOCH).unwrap();
+    history.process(&counter_frames(0, 2000)).unwrap();
+    history.trigger();
+    for capture in history.process(&counter_frames(2000, 1000)).unwrap() {
+        saver.save(capture).unwrap();
+    }
+    let paths = saver.close().unwrap();
+    assert_eq!(paths, vec![dir.join("glitch_0000.wav")]);
+    let mut reader = WavReader::open(&paths[0]).unwrap();
+    assert_eq!(reader.get_nbr_frames(), 1500);
+    assert!(reader.read_bytes(1500).unwrap() == counter_frames(1000, 1500));
+    // The time reference gives the stream position of the first frame
+    assert_eq!(reader.get_bext().unwrap().unwrap().time_reference, 1000);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn trigger_after_long_silence() {
+    // Default lengths, 10 seconds before and 5 seconds after the trigger
+    let mut history = HistoryBuffer::new(&format(), &HistorySettings::new(1000));
+    let mut detector = GlitchDetector::new(1, GlitchSettings::new(1000));
+    // One second of signal, 8 seconds of zeros, and signal again
+    let mut triggers = Vec::new();
+    let mut captures = Vec::new();
+    for block in 0..200 {
+        let data: Vec<u8> = (100 * block..100 * (block + 1))
+            .flat_map(|n: u32| {
+                let value = if (1000..9000).contains(&n) { 0 } else { n + 1 };
+                value.to_le_bytes()
+            })
+            .collect();
+        // The zero run is only reported when the signal returns, longer ago than the post-trigger length
+        for event in detector.process(&bytes_to_channels(&data, &format()).unwrap()) {
+            assert_eq!(event.kind, GlitchKind::ZeroRun);
+            assert_eq!(event.position, 1000);
+            assert!(history.trigger_at(event.position).is_err());
+            triggers.push(history.trigger_at_or_later(event.position));
+        }
+        captures.extend(history.process(&data).unwrap());
+    }
+    assert_eq!(triggers, vec![4000]);
+    assert_eq!(captures.len(), 1);
+    assert_eq!(captures[0].trigger, 4000);
+    // The capture holds the signal before the run, and the run up to where it was detected
+    let values = counters(&captures[0]);
+    assert_eq!(captures[0].start, 0);
+    assert_eq!(values.len(), 9000);
+    assert_eq!(values[999], 1000);
+    assert!(values[1000..].iter().all(|value| *value == 0));
+}
@@ -0,0 +1,297 @@
+use std::thread;
+use std::time::Duration;
+
+use wasapi::engine::PlaybackSource;
+use wasapi::jitter::{jitter_channel, Concealment, JitterBuffer, JitterSettings, JitterStats};
+use wasapi::network::NetPacket;
+use wasapi::wasapi::{SampleType, WaveFormat};
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+}
+
+fn frame_numbers(data: &[u8]) -> Vec<u32> {
+    data.chunks(4)
+        .map(|frame| u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]))
+        .collect()
+}
+
+// Frame n holds the number n + 1, so that silence can be told apart and every frame can be identified
+fn packet(sequence: u32, timestamp: u64, nbr_frames: usize) -> NetPacket {
+    let first = timestamp as u32 + 1;
+    let data = (first..first + nbr_frames as u32)
+        .flat_map(|n| n.to_le_bytes())
+        .collect();
+    NetPacket {
+        stream_id: 7,
+        sequence,
+        timestamp,
+        format: format(),
+        data,
+    }
+}
+
+// A packet where every sample has the same value
+fn constant_packet(timestamp: u64, nbr_frames: usize, value: i16) -> NetPacket {
+    NetPacket {
+        stream_id: 7,
+        sequence: 0,
+        timestamp,
+        format: format(),
+        data: value.to_le_bytes().repeat(2 * nbr_frames),
+    }
+}
+
+// Play a trace of packets of 480 frames, packet n arriving at the given time in milliseconds.
+// Frames are read 480 at a time every 10 ms, 5 ms after the packet with those frames was complete.
+// Returns the frames that were played, and the statistics halfway and at the end.
+fn run_trace(buffer: &mut JitterBuffer, arrivals: &[f64]) -> (Vec<u32>, JitterStats, JitterStats) {
+    let mut order: Vec<usize> = (0..arrivals.len()).collect();
+    order.sort_by(|a, b| arrivals[*a].partial_cmp(&arrivals[*b]).unwrap());
+    let mut next = 0;
+    let mut played = Vec::new();
+    let mut halfway = JitterStats::default();
+    for read in 0..arrivals.len() {
+        let time = 10.0 * read as f64 + 15.0;
+        while next < order.len() && arrivals[order[next]] <= time {
+            let n = order[next];
+            let arrival = Duration::from_secs_f64(arrivals[n] / 1000.0);
+            buffer.push_at(packet(n as u32, 480 * n as u64, 480), arrival);
+            next += 1;
+        }
+        // Nothing is known about the stream before the first packet
+        let data = buffer.fill(480);
+        assert_eq!(data.len(), if next == 0 { 0 } else { 4 * 480 });
+        played.extend(frame_numbers(&data));
+        if read == arrivals.len() / 2 {
+            halfway = buffer.get_stats();
+        }
+    }
+    (played, halfway, buffer.get_stats())
+}
+
+// Arrival times of packets of 10 ms, delayed by up to 60 ms in a repeatable way
+fn jittery_arrivals(nbr_packets: usize) -> Vec<f64> {
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    (0..nbr_packets)
+        .map(|n| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            10.0 * (n + 1) as f64 + (state % 60) as f64
+        })
+        .collect()
+}
+
+#[test]
+fn order_and_loss() {
+    let mut buffer = JitterBuffer::new(JitterSettings::new(300));
+    assert!(buffer.read(100).is_empty());
+    // Out of order, and the packet at 200 is missing
+    buffer.push(packet(1, 100, 100));
+    buffer.push(packet(0, 0, 100));
+    assert!(buffer.read(100).is_empty());
+    buffer.push(packet(3, 300, 100));
+    assert_eq!(buffer.get_buffered_frames(), 400);
+    assert_eq!(
+        frame_numbers(&buffer.read(150)),
+        (1..=150).collect::<Vec<u32>>()
+    );
+    let data = frame_numbers(&buffer.read(200));
+    assert_eq!(data[..50], (151..=200).collect::<Vec<u32>>()[..]);
+    assert!(data[50..150].iter().all(|n| *n == 0));
+    assert_eq!(data[150..], (301..=350).collect::<Vec<u32>>()[..]);
+    // A packet that arrives after its frames were due is dropped
+    buffer.push(packet(2, 200, 100));
+    // The buffer runs empty, and fills up again before continuing
+    assert_eq!(buffer.read(100).len(), 4 * 50);
+    assert!(buffer.read(100).is_empty());
+    let stats = buffer.get_stats();
+    assert_eq!(stats.packets, 4);
+    assert_eq!(stats.late_packets, 1);
+    assert_eq!(stats.lost_frames, 100);
+    assert_eq!(stats.underruns, 1);
+
+    // A new stream restarts the buffer
+    let mut other = packet(0, 0, 300);
+    other.stream_id = 8;
+    buffer.push(other);
+    assert_eq!(frame_numbers(&buffer.read(10))[0], 1);
+}
+
+#[test]
+fn late_packet_after_underrun() {
+    let mut buffer = JitterBuffer::new(JitterSettings::new(150));
+    buffer.push(packet(0, 0, 100));
+    buffer.push(packet(2, 200, 100));
+    let data = frame_numbers(&buffer.read(400));
+    assert_eq!(data.len(), 300);
+    assert!(data[100..200].iter().all(|n| *n == 0));
+    // The buffer ran empty, and the packet with the concealed frames arrives while it fills up again
+    buffer.push(packet(1, 100, 100));
+    buffer.push(packet(3, 300, 100));
+    buffer.push(packet(4, 400, 100));
+    // Only the frames that were not played yet are played
+    assert_eq!(
+        frame_numbers(&buffer.read(200)),
+        (301..=500).collect::<Vec<u32>>()
+    );
+    let stats = buffer.get_stats();
+    assert_eq!(stats.underruns, 1);
+    assert_eq!(stats.late_packets, 1);
+    assert_eq!(stats.lost_frames, 100);
+}
+
+#[test]
+fn duplicate_packets() {
+    let mut buffer = JitterBuffer::new(JitterSettings::new(200));
+    buffer.push(packet(0, 0, 100));
+    buffer.push(packet(0, 0, 100));
+    buffer.push(packet(1, 100, 100));
+    buffer.push(packet(2, 200, 100));
+    assert_eq!(buffer.read(150).len(), 4 * 150);
+    // Already played, and already buffered
+    buffer.push(packet(0, 0, 100));
+    buffer.push(packet(2, 200, 100));
+    assert_eq!(
+        frame_numbers(&buffer.read(150)),
+        (151..=300).collect::<Vec<u32>>()
+    );
+    let stats = buffer.get_stats();
+    assert_eq!(stats.packets, 6);
+    assert_eq!(stats.duplicate_packets, 3);
+    assert_eq!(stats.late_packets, 0);
+}
+
+#[test]
+fn overlapping_packets() {
+    let mut buffer = JitterBuffer::new(JitterSettings::new(100));
+    // A long packet, and a short one that overlaps it, from a sender that does not keep to one packet size
+    buffer.push(packet(0, 0, 100));
+    buffer.push(packet(1, 10, 5));
+    buffer.push(packet(2, 100, 20));
+    // The overlapping frames are played once
+    assert_eq!(
+        frame_numbers(&buffer.read(120)),
+        (1..=120).collect::<Vec<u32>>()
+    );
+    let stats = buffer.get_stats();
+    assert_eq!(stats.packets, 3);
+    assert_eq!(stats.lost_frames, 0);
+}
+
+#[test]
+fn repeat_concealment() {
+    let mut settings = JitterSettings::new(8);
+    settings.concealment = Concealment::Repeat;
+    let mut buffer = JitterBuffer::new(settings);
+    // Nothing to conceal with before the first packet
+    assert!(buffer.fill(4).is_empty());
+    buffer.push(packet(0, 0, 4));
+    buffer.push(packet(2, 10, 4));
+    // The missing frames repeat the last packet
+    assert_eq!(
+        frame_numbers(&buffer.read(14)),
+        [1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 11, 12, 13, 14]
+    );
+    // The buffer runs empty, and the rest is concealed too
+    assert_eq!(frame_numbers(&buffer.fill(6)), [11, 12, 13, 14, 11, 12]);
+    let stats = buffer.get_stats();
+    assert_eq!(stats.lost_frames, 6);
+    assert_eq!(stats.concealed_frames, 12);
+    assert_eq!(stats.underruns, 1);
+}
+
+#[test]
+fn fade_concealment() {
+    let mut settings = JitterSettings::new(8);
+    settings.concealment = Concealment::Fade;
+    let mut buffer = JitterBuffer::new(settings);
+    buffer.push_at(constant_packet(0, 4, 1000), Duration::ZERO);
+    buffer.push_at(constant_packet(10, 4, -1000), Duration::ZERO);
+    let data = buffer.read(14);
+    let samples: Vec<i16> = data
+        .chunks(2)
+        .step_by(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect();
+    // The last packet fades out over its length, then it is silent
+    let expected = [
+        1000, 1000, 1000, 1000, 750, 500, 250, 0, 0, 0, -1000, -1000, -1000, -1000,
+    ];
+    assert_eq!(samples.len(), expected.len());
+    for (sample, expected) in samples.iter().zip(expected) {
+        assert!((sample - expected).abs() <= 1);
+    }
+    assert_eq!(buffer.get_stats().concealed_frames, 6);
+}
+
+#[test]
+fn steady_trace_lowers_latency() {
+    let mut settings = JitterSettings::new(2400);
+    settings.adaptive = true;
+    settings.min_latency = 480;
+    let mut buffer = JitterBuffer::new(settings);
+    let arrivals: Vec<f64> = (0..500).map(|n| 10.0 * (n + 1) as f64).collect();
+    let (played, _, stats) = run_trace(&mut buffer, &arrivals);
+    assert!(stats.jitter < 1.0);
+    assert_eq!(stats.latency, 480);
+    assert_eq!(stats.underruns, 0);
+    assert_eq!(stats.lost_frames, 0);
+    // The surplus was skipped, everything else was played in order
+    assert!(stats.dropped_frames >= 1800);
+    let numbers: Vec<u32> = played.into_iter().filter(|n| *n != 0).collect();
+    assert!(numbers.windows(2).all(|pair| pair[1] > pair[0]));
+    let skipped = *numbers.last().unwrap() as u64 - numbers.len() as u64;
+    assert_eq!(skipped, stats.dropped_frames);
+}
+
+#[test]
+fn jittery_trace_raises_latency() {
+    let arrivals = jittery_arrivals(600);
+
+    // A fixed latency of one packet runs empty, and loses late packets until it has filled up enough
+    let mut buffer = JitterBuffer::new(JitterSettings::new(480));
+    let (_, _, fixed) = run_trace(&mut buffer, &arrivals);
+    assert_eq!(fixed.latency, 480);
+
+    // An adaptive buffer raises the latency, and then plays without problems
+    let mut settings = JitterSettings::new(480);
+    settings.adaptive = true;
+    settings.max_latency = 9600;
+    settings.concealment = Concealment::Fade;
+    let mut buffer = JitterBuffer::new(settings);
+    let (_, halfway, end) = run_trace(&mut buffer, &arrivals);
+    assert!(halfway.jitter > 480.0);
+    assert!(end.latency > 2400 && end.latency <= 9600);
+    assert!(end.late_packets < fixed.late_packets);
+    assert!(end.lost_frames < fixed.lost_frames);
+    assert_eq!(end.underruns, halfway.underruns);
+    assert_eq!(end.late_packets, halfway.late_packets);
+    assert_eq!(end.lost_frames, halfway.lost_frames);
+}
+
+#[test]
+fn channel_between_threads() {
+    let (mut sender, mut receiver) = jitter_channel(&format(), JitterSettings::new(960));
+    let handle = thread::spawn(move || {
+        for n in 0..4 {
+            let first = 480 * n + 1u32;
+            let chunk = (first..first + 480).flat_map(|n| n.to_le_bytes()).collect();
+            sender.send(chunk).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+    handle.join().unwrap();
+    // After the sending side is closed, the rest is played and the source is finished
+    let mut played = Vec::new();
+    while let Some(data) = receiver.fill(500, &format()) {
+        played.extend(frame_numbers(&data));
+    }
+    assert_eq!(played, (1..=1920).collect::<Vec<u32>>());
+    assert_eq!(receiver.get_stats().packets, 4);
+
+    let (mut sender, receiver) = jitter_channel(&format(), JitterSettings::new(960));
+    drop(receiver);
+    assert!(sender.send(vec![0; 4 * 480]).is_err());
+}
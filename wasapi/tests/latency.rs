@@ -0,0 +1,84 @@
+use wasapi::generator::mls;
+use wasapi::latency::{find_delay, LatencyMeasurement, LatencyReport, Stimulus};
+use wasapi::loopback::SimulatedLoopback;
+use wasapi::wasapi::{SampleType, WaveFormat};
+
+fn float_format() -> WaveFormat {
+    WaveFormat::new(32, 32, &SampleType::Float, 48000, 2)
+}
+
+#[test]
+fn mls_has_full_period() {
+    for order in 2..=16 {
+        let seq = mls(order).unwrap();
+        assert_eq!(seq.len(), (1 << order) - 1);
+        let ones = seq.iter().filter(|value| **value > 0.0).count();
+        assert_eq!(ones, 1 << (order - 1), "order {}", order);
+    }
+    assert!(mls(1).is_err());
+    assert!(mls(21).is_err());
+}
+
+#[test]
+fn finds_known_delay() {
+    let reference = mls(12).unwrap();
+    let mut captured = vec![0.0; 1234];
+    captured.extend(reference.iter().map(|value| 0.3 * value));
+    captured.resize(reference.len() + 4000, 0.0);
+    let estimate = find_delay(&reference, &captured, 4000).unwrap();
+    assert_eq!(estimate.delay, 1234);
+    assert!(estimate.peak_ratio > 10.0);
+}
+
+#[test]
+fn measures_fixed_delay_with_mls() {
+    let mut loopback = SimulatedLoopback::new(&float_format(), 777);
+    loopback.set_gain(0.5);
+    loopback.set_noise_level(0.05);
+    let mut measurement = LatencyMeasurement::new(Stimulus::Mls { order: 14 });
+    measurement.max_latency = 4800;
+    let report = measurement.measure(&mut loopback).unwrap();
+    assert_eq!(loopback.get_nbr_runs(), 5);
+    assert_eq!(report.delays, vec![777; 5]);
+    assert_eq!(report.median, 777.0);
+    assert_eq!(report.jitter_pp, 0);
+    assert_eq!(report.failed_runs, 0);
+}
+
+#[test]
+fn measures_jitter_with_chirp() {
+    let format = WaveFormat::new(16, 16, &SampleType::Int, 44100, 1);
+    let mut loopback = SimulatedLoopback::new(&format, 0);
+    loopback.set_delays(&[1000, 1003, 998, 1001, 1000, 1002]);
+    let mut measurement = LatencyMeasurement::new(Stimulus::Chirp {
+        f_start: 20.0,
+        f_end: 20000.0,
+        length: 16384,
+    });
+    measurement.runs = 6;
+    measurement.max_latency = 4410;
+    let report = measurement.measure(&mut loopback).unwrap();
+    assert_eq!(report.delays, vec![1000, 1003, 998, 1001, 1000, 1002]);
+    assert_eq!(report.median, 1000.5);
+    assert_eq!(report.jitter_pp, 5);
+    assert!((report.jitter_std - 1.7512).abs() < 1.0e-3);
+}
+
+#[test]
+fn missing_signal_fails() {
+    let mut loopback = SimulatedLoopback::new(&float_format(), 300);
+    loopback.set_gain(0.0);
+    let mut measurement = LatencyMeasurement::new(Stimulus::Mls { order: 12 });
+    measurement.runs = 2;
+    measurement.max_latency = 2000;
+    assert!(measurement.measure(&mut loopback).is_err());
+}
+
+#[test]
+fn report_statistics() {
+    let report = LatencyReport::new(&[480, 482, 481], 1, 48000);
+    assert_eq!(report.median, 481.0);
+    assert_eq!(report.jitter_pp, 2);
+    assert!((report.get_median_ms() - 10.0208).abs() < 1.0e-3);
+    assert!((report.get_jitter_ms() - 0.041667).abs() < 1.0e-5);
+}
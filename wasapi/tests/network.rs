@@ -0,0 +1,180 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wasapi::engine::{capture_loop, playback_loop, StreamSettings};
+use wasapi::jitter::{JitterSettings, JitterStats};
+use wasapi::network::{NetImpairment, NetPacket, NetProtocol, NetworkBackend};
+use wasapi::simulated::SimDeviceConfig;
+use wasapi::wasapi::{Direction, SampleType, WaveFormat};
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+}
+
+// Frame n holds the number n + 1, so that silence can be told apart and every frame can be identified
+fn numbered_frames(nbr_frames: usize) -> Vec<u8> {
+    (1..=nbr_frames as u32)
+        .flat_map(|n| n.to_le_bytes())
+        .collect()
+}
+
+fn frame_numbers(data: &[u8]) -> Vec<u32> {
+    data.chunks(4)
+        .map(|frame| u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]))
+        .collect()
+}
+
+// Stream numbered frames from a render device to a capture device over loopback,
+// returns the frame numbers received and the statistics of both sides
+fn stream(
+    protocol: NetProtocol,
+    impairment: Option<NetImpairment>,
+    nbr_frames: usize,
+) -> (Vec<u32>, u64, u64, JitterStats) {
+    let receiver = NetworkBackend::new();
+    let addr: SocketAddr = receiver
+        .add_capture_device(
+            SimDeviceConfig::new("Receiver", &Direction::Capture, &format()),
+            "127.0.0.1:0",
+            protocol,
+            JitterSettings::new(1920),
+        )
+        .unwrap();
+    let sender = NetworkBackend::new();
+    sender
+        .add_render_device(
+            SimDeviceConfig::new("Sender", &Direction::Render, &format()),
+            addr,
+            protocol,
+            impairment,
+        )
+        .unwrap();
+
+    let (tx_capt, rx_capt) = mpsc::sync_channel(200);
+    let mut settings = StreamSettings::new("Receiver", &format());
+    settings.chunksize = 480;
+    settings.max_frames = Some(2 * nbr_frames as u64);
+    let receiver_capt = receiver.clone();
+    let capture = thread::spawn(move || {
+        capture_loop(&receiver_capt, &settings, tx_capt, &mut |_| {}).map_err(|e| e.to_string())
+    });
+    let (tx_play, rx_play) = mpsc::channel();
+    tx_play.send(numbered_frames(nbr_frames)).unwrap();
+    drop(tx_play);
+    let settings = StreamSettings::new("Sender", &format());
+    playback_loop(&sender, &settings, rx_play).unwrap();
+    let captured: Vec<u8> = rx_capt.iter().flatten().collect();
+    capture.join().unwrap().unwrap();
+
+    let numbers = frame_numbers(&captured)
+        .into_iter()
+        .filter(|n| *n != 0)
+        .collect();
+    let sender_stats = sender.get_sender_stats("Sender").unwrap();
+    (
+        numbers,
+        sender_stats.dropped,
+        sender_stats.reordered,
+        receiver.get_receiver_stats("Receiver").unwrap(),
+    )
+}
+
+#[test]
+fn packet_roundtrip() {
+    let original = NetPacket {
+        stream_id: 0x1234_5678,
+        sequence: 3,
+        timestamp: 1 << 40,
+        format: WaveFormat::new(24, 24, &SampleType::Int, 96000, 2),
+        data: (0..60).collect(),
+    };
+    let bytes = original.to_bytes().unwrap();
+    assert_eq!(bytes.len(), 36 + 60);
+    assert_eq!(&bytes[..4], b"WNET");
+    assert_eq!(NetPacket::from_bytes(&bytes).unwrap(), original);
+    assert!(NetPacket::from_bytes(&bytes[..50]).is_err());
+    let mut corrupt = bytes.clone();
+    corrupt[0] = b'X';
+    assert!(NetPacket::from_bytes(&corrupt).is_err());
+}
+
+#[test]
+fn udp_with_loss_and_reordering() {
+    let nbr_frames = 14400;
+    let impairment = NetImpairment::new(0.1, 0.2, 42);
+    let (numbers, dropped, reordered, stats) =
+        stream(NetProtocol::Udp, Some(impairment), nbr_frames);
+    assert!(dropped > 0);
+    assert!(reordered > 0);
+    // The frames that arrived are in order, and the gaps are whole lost packets
+    let mut missing = (numbers[0] - 1) as usize;
+    for pair in numbers.windows(2) {
+        assert!(pair[1] > pair[0]);
+        missing += (pair[1] - pair[0] - 1) as usize;
+    }
+    missing += nbr_frames - *numbers.last().unwrap() as usize;
+    assert_eq!(missing % 480, 0);
+    assert!(missing > 0);
+    assert!(missing / 480 <= dropped as usize);
+    // The reordered packets arrived in time
+    assert_eq!(stats.late_packets, 0);
+    assert_eq!(stats.invalid_packets, 0);
+    assert!(stats.lost_frames > 0);
+}
+
+#[test]
+fn tcp_without_loss() {
+    let nbr_frames = 14400;
+    let (numbers, dropped, _reordered, stats) = stream(NetProtocol::Tcp, None, nbr_frames);
+    assert_eq!(dropped, 0);
+    assert_eq!(numbers, (1..=nbr_frames as u32).collect::<Vec<u32>>());
+    assert_eq!(stats.lost_frames, 0);
+    assert_eq!(stats.late_packets, 0);
+}
+
+#[test]
+fn packets_with_unsupported_format() {
+    let valid = NetPacket {
+        stream_id: 1,
+        sequence: 0,
+        timestamp: 0,
+        format: format(),
+        data: numbered_frames(480),
+    };
+    let valid = valid.to_bytes().unwrap();
+    // 64 channels
+    let mut too_many_channels = valid.clone();
+    too_many_channels[6..8].copy_from_slice(&64u16.to_le_bytes());
+    // 32 channels of 16384 bits, frames of 65536 bytes
+    let mut too_large_frames = valid[..36].to_vec();
+    too_large_frames[6..8].copy_from_slice(&32u16.to_le_bytes());
+    too_large_frames[12..14].copy_from_slice(&16384u16.to_le_bytes());
+    too_large_frames[32..36].copy_from_slice(&0u32.to_le_bytes());
+    assert!(NetPacket::from_bytes(&too_many_channels).is_err());
+    assert!(NetPacket::from_bytes(&too_large_frames).is_err());
+
+    // The receiver counts them as invalid, and keeps accepting valid packets
+    let receiver = NetworkBackend::new();
+    let addr = receiver
+        .add_capture_device(
+            SimDeviceConfig::new("Receiver", &Direction::Capture, &format()),
+            "127.0.0.1:0",
+            NetProtocol::Udp,
+            JitterSettings::new(1920),
+        )
+        .unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.send_to(&too_many_channels, addr).unwrap();
+    socket.send_to(&too_large_frames, addr).unwrap();
+    socket.send_to(&valid, addr).unwrap();
+    let start = Instant::now();
+    let mut stats = receiver.get_receiver_stats("Receiver").unwrap();
+    while stats.packets + stats.invalid_packets < 3 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+        stats = receiver.get_receiver_stats("Receiver").unwrap();
+    }
+    assert_eq!(stats.invalid_packets, 2);
+    assert_eq!(stats.packets, 1);
+}
@@ -0,0 +1,154 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wasapi::backend::Backend;
+use wasapi::conversion::channels_to_bytes;
+use wasapi::engine::{capture_loop, playback_loop, StreamSettings};
+use wasapi::nulldevice::{NullBackend, NullPattern, NULL_CAPTURE_NAME, NULL_RENDER_NAME};
+use wasapi::simulated::{SimDeviceConfig, SimulatedBackend};
+use wasapi::wasapi::{Direction, FormatSupported, SampleType, ShareMode, WaveFormat};
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+}
+
+fn backend() -> NullBackend<SimulatedBackend> {
+    let simulated = SimulatedBackend::new();
+    simulated
+        .add_device(SimDeviceConfig::new(
+            "Speakers",
+            &Direction::Render,
+            &format(),
+        ))
+        .unwrap();
+    let backend = NullBackend::new(simulated);
+    backend.set_mix_format(&Direction::Render, &format());
+    backend.set_mix_format(&Direction::Capture, &format());
+    backend
+}
+
+#[test]
+fn enumeration() {
+    let backend = backend();
+    let collection = backend.get_device_collection(&Direction::Render).unwrap();
+    assert_eq!(collection.get_nbr_devices().unwrap(), 2);
+    assert_eq!(
+        collection
+            .get_device_at_index(0)
+            .unwrap()
+            .get_friendlyname()
+            .unwrap(),
+        "Speakers"
+    );
+    let device = collection.get_device_with_name(NULL_RENDER_NAME).unwrap();
+    assert_eq!(device.get_id().unwrap(), "{null}.Null Render");
+    assert!(collection.get_device_at_index(2).is_err());
+    // The real default device is kept, the null device is the default when there is none
+    let default = backend.get_default_device(&Direction::Render).unwrap();
+    assert_eq!(default.get_friendlyname().unwrap(), "Speakers");
+    let default = backend.get_default_device(&Direction::Capture).unwrap();
+    assert_eq!(default.get_friendlyname().unwrap(), NULL_CAPTURE_NAME);
+
+    let client = device.get_iaudioclient().unwrap();
+    let other = WaveFormat::new(24, 24, &SampleType::Int, 96000, 2);
+    match client.is_supported(&other, &ShareMode::Shared).unwrap() {
+        FormatSupported::ClosestMatch(mix) => assert_eq!(mix, format()),
+        FormatSupported::Yes => panic!("only the mix format is supported in shared mode"),
+    }
+    assert!(matches!(
+        client.is_supported(&other, &ShareMode::Exclusive).unwrap(),
+        FormatSupported::Yes
+    ));
+}
+
+#[test]
+fn render_at_nominal_rate() {
+    let backend = backend();
+    let (tx_play, rx_play) = mpsc::channel();
+    tx_play.send(vec![1; 4 * 4800]).unwrap();
+    drop(tx_play);
+    let start = Instant::now();
+    let settings = StreamSettings::new(NULL_RENDER_NAME, &format());
+    playback_loop(&backend, &settings, rx_play).unwrap();
+    // The loop returns when the device has played everything, a tenth of a second
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert!(backend.get_nbr_frames(&Direction::Render) >= 4800);
+    assert_eq!(backend.get_underruns(), 0);
+    // Nothing was played on the other device
+    let rendered = backend.get_inner().get_rendered("Speakers").unwrap();
+    assert!(rendered.is_empty());
+}
+
+#[test]
+fn capture_pattern() {
+    let backend = backend();
+    let wave: Vec<f64> = (0..100).map(|n| n as f64 / 100.0).collect();
+    backend.set_capture_pattern(NullPattern::Waveform(wave.clone()));
+    let mut settings = StreamSettings::new(NULL_CAPTURE_NAME, &format());
+    settings.chunksize = 480;
+    settings.max_frames = Some(4800);
+    let (tx_capt, rx_capt) = mpsc::sync_channel(20);
+    let start = Instant::now();
+    let backend_capt = backend.clone();
+    let handle = thread::spawn(move || {
+        capture_loop(&backend_capt, &settings, tx_capt, &mut |_| {}).map_err(|e| e.to_string())
+    });
+    let captured: Vec<u8> = rx_capt.iter().flatten().collect();
+    handle.join().unwrap().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(backend.get_overflows(), 0);
+    let pattern = channels_to_bytes(&[wave.clone(), wave], &format()).unwrap();
+    assert_eq!(captured.len(), 4 * 4800);
+    assert!(captured
+        .chunks(pattern.len())
+        .all(|cycle| *cycle == pattern[..cycle.len()]));
+}
+
+#[test]
+fn capture_overflow() {
+    let backend = backend();
+    let device = backend.get_default_device(&Direction::Capture).unwrap();
+    let mut client = device.get_iaudioclient().unwrap();
+    client
+        .initialize_client(&format(), 0, &Direction::Capture, &ShareMode::Shared)
+        .unwrap();
+    let event = client.set_get_eventhandle().unwrap();
+    let capture = client.get_audiocaptureclient().unwrap();
+    // Nothing happens before the stream is started
+    assert!(event.wait_for_event(20).is_err());
+    assert_eq!(client.get_current_padding().unwrap(), 0);
+    client.start_stream().unwrap();
+
+    // Not reading for a while, the buffer holds two periods
+    thread::sleep(Duration::from_millis(55));
+    assert_eq!(client.get_current_padding().unwrap(), 960);
+    assert!(backend.get_overflows() >= 3);
+    // The missed events are signaled once
+    event.wait_for_event(100).unwrap();
+    let mut data = vec![0; 4 * 480];
+    assert!(
+        !capture
+            .read_from_device(4, &mut data)
+            .unwrap()
+            .data_discontinuity
+    );
+    assert!(
+        !capture
+            .read_from_device(4, &mut data)
+            .unwrap()
+            .data_discontinuity
+    );
+    event.wait_for_event(100).unwrap();
+    assert_eq!(capture.get_next_nbr_frames().unwrap(), 480);
+    assert!(
+        capture
+            .read_from_device(4, &mut data)
+            .unwrap()
+            .data_discontinuity
+    );
+    assert!(data.iter().all(|value| *value == 0));
+
+    client.stop_stream().unwrap();
+    assert!(event.wait_for_event(20).is_err());
+}
@@ -0,0 +1,155 @@
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use wasapi::pipe::{PipeSink, PipeSource, UnderrunPolicy};
+use wasapi::wasapi::{SampleType, WaveFormat};
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 44100, 2)
+}
+
+fn counter_bytes(nbr_bytes: usize) -> Vec<u8> {
+    (0..nbr_bytes).map(|n| (n % 253) as u8).collect()
+}
+
+// Writer that keeps the data where the test can see it
+#[derive(Clone)]
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn drain_at_eof() {
+    // 1000 frames and a partial frame at the end
+    let data = counter_bytes(4 * 1000 + 3);
+    let mut source = PipeSource::new(
+        Cursor::new(data.clone()),
+        &format(),
+        UnderrunPolicy::Block,
+        300,
+    )
+    .unwrap();
+    let mut output = Vec::new();
+    let mut sizes = Vec::new();
+    while !source.is_finished() {
+        let chunk = source.fill(256).unwrap();
+        sizes.push(chunk.len() / 4);
+        output.extend(chunk);
+    }
+    assert_eq!(sizes, vec![256, 256, 256, 232]);
+    assert!(output == data[..4000]);
+    assert_eq!(source.get_nbr_frames(), 1000);
+    assert_eq!(source.get_nbr_underruns(), 0);
+    // Nothing more after the end
+    assert!(source.fill(256).unwrap().is_empty());
+}
+
+#[test]
+fn underrun_with_silence() {
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    let mut source = PipeSource::new(reader, &format(), UnderrunPolicy::Silence, 1000).unwrap();
+    let data = counter_bytes(4 * 100);
+    writer.write_all(&data).unwrap();
+    assert!(source.wait_for_frames(100, Duration::from_secs(5)));
+    // The 100 frames that arrived, then silence
+    let chunk = source.fill(150).unwrap();
+    assert_eq!(chunk.len(), 600);
+    assert!(chunk[..400] == data[..]);
+    assert!(chunk[400..].iter().all(|value| *value == 0));
+    assert_eq!(source.get_nbr_underruns(), 1);
+    assert_eq!(source.get_nbr_frames(), 100);
+
+    // Nothing written, a full period of silence
+    assert!(!source.wait_for_frames(1, Duration::from_millis(50)));
+    assert!(source.fill(10).unwrap() == vec![0; 40]);
+    assert_eq!(source.get_nbr_underruns(), 2);
+
+    // No padding at the end of the pipe
+    writer.write_all(&data[..40]).unwrap();
+    drop(writer);
+    assert!(source.wait_for_frames(1000, Duration::from_secs(5)));
+    assert!(source.fill(150).unwrap() == data[..40]);
+    assert!(source.is_finished());
+}
+
+#[test]
+fn block_until_data() {
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    let mut source = PipeSource::new(reader, &format(), UnderrunPolicy::Block, 1000).unwrap();
+    let data = counter_bytes(4 * 500);
+    let data_writer = data.clone();
+    let handle = thread::spawn(move || {
+        // Slow writer, delivering odd sizes that split frames
+        for chunk in data_writer.chunks(37) {
+            writer.write_all(chunk).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+    let mut output = Vec::new();
+    while !source.is_finished() {
+        let chunk = source.fill(64).unwrap();
+        assert!(chunk.len() == 256 || source.is_finished());
+        output.extend(chunk);
+    }
+    handle.join().unwrap();
+    assert!(output == data);
+    assert_eq!(source.get_nbr_underruns(), 0);
+}
+
+#[test]
+fn bounded_buffer() {
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    let source = PipeSource::new(reader, &format(), UnderrunPolicy::Block, 100).unwrap();
+    let handle = thread::spawn(move || {
+        // Much more than the buffer, the writer is held back by the pipe
+        let _ = writer.write_all(&counter_bytes(4 * 100000));
+    });
+    assert!(source.wait_for_frames(100, Duration::from_secs(5)));
+    thread::sleep(Duration::from_millis(50));
+    // The reader stops after the read that filled the buffer
+    assert!(source.get_buffered_frames() < 100 + 16384 / 4);
+    drop(source);
+    handle.join().unwrap();
+}
+
+#[test]
+fn sink_writes_everything() {
+    let output = SharedWriter(Arc::new(Mutex::new(Vec::new())));
+    let sink = PipeSink::new(output.clone(), &format()).unwrap();
+    let data = counter_bytes(4 * 1000);
+    for chunk in data.chunks(4 * 64) {
+        sink.write_bytes(chunk).unwrap();
+    }
+    assert!(sink.write_bytes(&[0; 3]).is_err());
+    assert_eq!(sink.close().unwrap(), 1000);
+    assert!(*output.0.lock().unwrap() == data);
+}
+
+#[test]
+fn sink_with_closed_pipe() {
+    let (reader, writer) = std::io::pipe().unwrap();
+    let sink = PipeSink::new(writer, &format()).unwrap();
+    // The reading program exits
+    drop(reader);
+    let mut failed = false;
+    for _ in 0..1000 {
+        if sink.write_bytes(&[0; 400]).is_err() {
+            failed = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(failed);
+    assert!(sink.close().is_err());
+}
@@ -0,0 +1,307 @@
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::io::Cursor;
+use std::rc::Rc;
+
+use wasapi::aiff::{AiffEncoding, AiffWriter};
+use wasapi::audiofile::{open_reader, AudioFileReader};
+use wasapi::conversion::{bytes_to_channels, channels_to_bytes};
+use wasapi::flac::{FlacSettings, FlacWriter};
+use wasapi::player::{Player, PlayerEvent, PlayerPosition, PlayerSettings};
+use wasapi::resample::Resampler;
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::WavWriter;
+
+fn wav_file(data: &[u8], wave_fmt: &WaveFormat) -> Box<dyn AudioFileReader> {
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), wave_fmt).unwrap();
+    writer.write_bytes(data).unwrap();
+    writer.close().unwrap();
+    open_reader(Cursor::new(writer.get_ref().get_ref().clone())).unwrap()
+}
+
+fn aiff_file(data: &[u8], wave_fmt: &WaveFormat) -> Box<dyn AudioFileReader> {
+    let mut writer =
+        AiffWriter::new(Cursor::new(Vec::new()), wave_fmt, AiffEncoding::BigEndian).unwrap();
+    writer.write_bytes(data).unwrap();
+    writer.close().unwrap();
+    open_reader(Cursor::new(writer.get_ref().get_ref().clone())).unwrap()
+}
+
+fn flac_file(data: &[u8], wave_fmt: &WaveFormat) -> Box<dyn AudioFileReader> {
+    let mut writer =
+        FlacWriter::new(Cursor::new(Vec::new()), wave_fmt, FlacSettings::new()).unwrap();
+    writer.write_bytes(data).unwrap();
+    writer.close().unwrap();
+    open_reader(Cursor::new(writer.get_ref().get_ref().clone())).unwrap()
+}
+
+// 16-bit stereo data with a different pattern per track
+fn pattern(nbr_frames: usize, seed: i32) -> Vec<u8> {
+    let mut data = Vec::new();
+    for n in 0..nbr_frames as i32 {
+        let left = ((n * 37 + seed * 1000) % 20000 - 10000) as i16;
+        let right = ((n * 101 + seed) % 30000 - 15000) as i16;
+        data.extend_from_slice(&left.to_le_bytes());
+        data.extend_from_slice(&right.to_le_bytes());
+    }
+    data
+}
+
+fn sine(nbr_frames: usize, freq: f64, samplerate: usize, start: usize) -> Vec<f64> {
+    (start..start + nbr_frames)
+        .map(|n| 0.5 * (2.0 * PI * freq * n as f64 / samplerate as f64).sin())
+        .collect()
+}
+
+// A simulated render device, pulling a period of frames at a time until the queue is finished
+fn render(player: &mut Player, period: usize, max_periods: usize) -> (Vec<u8>, Vec<PlayerEvent>) {
+    let mut output = Vec::new();
+    let mut events = Vec::new();
+    for _ in 0..max_periods {
+        output.extend(player.fill(period).unwrap());
+        events.extend(player.take_events());
+        if player.is_finished() {
+            break;
+        }
+    }
+    (output, events)
+}
+
+#[test]
+fn gapless_mixed_file_types() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let tracks = [pattern(5000, 1), pattern(3333, 2), pattern(7001, 3)];
+    let mut settings = PlayerSettings::new();
+    settings.decode_ahead = 1000;
+    settings.read_size = 777;
+    let mut player = Player::new(&wave_fmt, settings);
+    player.enqueue("one.wav", wav_file(&tracks[0], &wave_fmt));
+    player.enqueue("two.aiff", aiff_file(&tracks[1], &wave_fmt));
+    player.enqueue("three.flac", flac_file(&tracks[2], &wave_fmt));
+    assert_eq!(player.get_nbr_tracks(), 3);
+    assert_eq!(player.get_track_name(1), Some("two.aiff"));
+    let (output, events) = render(&mut player, 441, 1000);
+    let expected = tracks.concat();
+    assert!(output[..expected.len()] == expected[..], "not gapless");
+    assert!(output[expected.len()..].iter().all(|value| *value == 0));
+    assert_eq!(
+        events,
+        vec![
+            PlayerEvent::TrackStarted { index: 0, frame: 0 },
+            PlayerEvent::TrackFinished {
+                index: 0,
+                frame: 5000
+            },
+            PlayerEvent::TrackStarted {
+                index: 1,
+                frame: 5000
+            },
+            PlayerEvent::TrackFinished {
+                index: 1,
+                frame: 8333
+            },
+            PlayerEvent::TrackStarted {
+                index: 2,
+                frame: 8333
+            },
+            PlayerEvent::TrackFinished {
+                index: 2,
+                frame: 15334
+            },
+            PlayerEvent::QueueFinished,
+        ]
+    );
+}
+
+#[test]
+fn resampler_accuracy_and_streaming() {
+    let input = sine(4410, 1000.0, 44100, 0);
+    let mut resampler = Resampler::new(44100, 48000, 1).unwrap();
+    let mut output = resampler.process(std::slice::from_ref(&input)).unwrap();
+    output[0].extend(resampler.flush().remove(0));
+    assert_eq!(output[0].len(), 4800);
+    let expected = sine(4800, 1000.0, 48000, 0);
+    for n in 100..4700 {
+        assert!((output[0][n] - expected[n]).abs() < 1e-3, "error at {}", n);
+    }
+
+    // Feeding in small pieces gives the same result
+    let mut chunked = Vec::new();
+    for piece in input.chunks(93) {
+        chunked.extend(resampler.process(&[piece.to_vec()]).unwrap().remove(0));
+    }
+    chunked.extend(resampler.flush().remove(0));
+    assert_eq!(chunked, output[0]);
+
+    // Downsampling removes content above the new Nyquist frequency
+    let mut resampler = Resampler::new(96000, 44100, 1).unwrap();
+    let mut output = resampler
+        .process(&[sine(9600, 30000.0, 96000, 0)])
+        .unwrap()
+        .remove(0);
+    output.extend(resampler.flush().remove(0));
+    assert_eq!(output.len(), 4410);
+    assert!(output[100..4300].iter().all(|value| value.abs() < 1e-3));
+}
+
+#[test]
+fn resampled_splice() {
+    let out_fmt = WaveFormat::new(32, 32, &SampleType::Float, 48000, 1);
+    let fmt_44 = WaveFormat::new(32, 32, &SampleType::Float, 44100, 1);
+    // One sine split over two 44.1 kHz tracks, then a 48 kHz track
+    let first = channels_to_bytes(&[sine(2205, 500.0, 44100, 0)], &fmt_44).unwrap();
+    let second = channels_to_bytes(&[sine(2205, 500.0, 44100, 2205)], &fmt_44).unwrap();
+    let third = channels_to_bytes(&[sine(960, 1000.0, 48000, 0)], &out_fmt).unwrap();
+    let mut player = Player::new(&out_fmt, PlayerSettings::new());
+    player.enqueue("a", wav_file(&first, &fmt_44));
+    player.enqueue("b", wav_file(&second, &fmt_44));
+    player.enqueue("c", wav_file(&third, &out_fmt));
+    let (output, events) = render(&mut player, 480, 100);
+    let output = bytes_to_channels(&output, &out_fmt).unwrap().remove(0);
+    // The splice of the two 44.1 kHz tracks is seamless
+    let expected = sine(4800, 500.0, 48000, 0);
+    for n in 100..4700 {
+        assert!((output[n] - expected[n]).abs() < 1e-3, "error at {}", n);
+    }
+    // The 48 kHz track follows exactly after the resampled ones
+    let expected = sine(960, 1000.0, 48000, 0);
+    for n in 0..960 {
+        assert!((output[4800 + n] - expected[n]).abs() < 1e-6);
+    }
+    assert!(events.contains(&PlayerEvent::TrackStarted {
+        index: 1,
+        frame: 2400
+    }));
+    assert!(events.contains(&PlayerEvent::TrackStarted {
+        index: 2,
+        frame: 4800
+    }));
+}
+
+#[test]
+fn reinitialize_on_format_change() {
+    let fmt_44 = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let fmt_48 = WaveFormat::new(24, 24, &SampleType::Int, 48000, 1);
+    let first = pattern(1000, 1);
+    let second: Vec<u8> = (0..3 * 700).map(|n| (n % 251) as u8).collect();
+    let mut settings = PlayerSettings::new();
+    settings.resample = false;
+    let mut player = Player::new(&fmt_44, settings);
+    player.enqueue("a", wav_file(&first, &fmt_44));
+    player.enqueue("b", wav_file(&second, &fmt_48));
+
+    // The output stops at the end of the first track
+    let mut output = Vec::new();
+    loop {
+        let data = player.fill(300).unwrap();
+        let short = data.len() < 300 * 4;
+        output.extend(data);
+        if short {
+            break;
+        }
+    }
+    assert!(output == first);
+    let events = player.take_events();
+    assert_eq!(
+        events.last(),
+        Some(&PlayerEvent::FormatChange(fmt_48.clone()))
+    );
+
+    // Re-initialize the simulated device with the new format
+    player.set_output_format(&fmt_48);
+    let (output, events) = render(&mut player, 480, 10);
+    assert!(output[..second.len()] == second[..]);
+    assert_eq!(
+        events[0],
+        PlayerEvent::TrackStarted {
+            index: 1,
+            frame: 1000
+        }
+    );
+}
+
+#[test]
+fn seek_pause_and_position_callback() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 1000, 2);
+    let track = pattern(5000, 4);
+    let mut player = Player::new(&wave_fmt, PlayerSettings::new());
+    player.enqueue("a", wav_file(&track, &wave_fmt));
+    let positions: Rc<RefCell<Vec<PlayerPosition>>> = Rc::new(RefCell::new(Vec::new()));
+    let positions_cb = positions.clone();
+    player.set_position_callback(move |position| positions_cb.borrow_mut().push(position.clone()));
+    assert!(player.seek(10).is_err());
+
+    let data = player.fill(100).unwrap();
+    assert!(data == track[..400]);
+    player.seek(3000).unwrap();
+    let data = player.fill(100).unwrap();
+    assert!(data == track[4 * 3000..4 * 3100]);
+    assert_eq!(player.get_position().frame, 3100);
+
+    player.pause();
+    assert!(player.is_paused());
+    assert!(player.fill(50).unwrap().iter().all(|value| *value == 0));
+    assert_eq!(player.get_position().frame, 3100);
+    player.resume();
+    let data = player.fill(100).unwrap();
+    assert!(data == track[4 * 3100..4 * 3200]);
+
+    // Seeking gives no new track events
+    let events = player.take_events();
+    assert_eq!(
+        events,
+        vec![PlayerEvent::TrackStarted { index: 0, frame: 0 }]
+    );
+
+    let positions = positions.borrow();
+    let frames: Vec<u64> = positions.iter().map(|position| position.frame).collect();
+    assert_eq!(frames, vec![100, 3100, 3100, 3200]);
+    assert_eq!(positions[3].track, Some(0));
+    assert!((positions[3].time - 3.2).abs() < 1e-9);
+    assert_eq!(positions[3].output_frames, 300);
+}
+
+#[test]
+fn skip_and_enqueue_after_end() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let tracks = [pattern(500, 1), pattern(650, 2), pattern(700, 3)];
+    let mut player = Player::new(&wave_fmt, PlayerSettings::new());
+    player.enqueue("a", wav_file(&tracks[0], &wave_fmt));
+    player.enqueue("b", wav_file(&tracks[1], &wave_fmt));
+    player.fill(100).unwrap();
+    // The second track is already decoded ahead, but is rewound
+    player.skip_to(1).unwrap();
+    let (output, _) = render(&mut player, 100, 100);
+    assert!(output[..2600] == tracks[1][..]);
+    assert!(player.is_finished());
+
+    player.enqueue("c", wav_file(&tracks[2], &wave_fmt));
+    assert!(!player.is_finished());
+    let (output, events) = render(&mut player, 100, 100);
+    assert!(output[..2800] == tracks[2][..]);
+    // The new track starts after the silence that completed the last period
+    assert_eq!(
+        events[0],
+        PlayerEvent::TrackStarted {
+            index: 2,
+            frame: 800
+        }
+    );
+}
+
+#[test]
+fn mono_file_to_stereo_output() {
+    let mono = WaveFormat::new(16, 16, &SampleType::Int, 44100, 1);
+    let stereo = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let data: Vec<u8> = (0..200i16)
+        .flat_map(|n| (n * 100).to_le_bytes().to_vec())
+        .collect();
+    let mut player = Player::new(&stereo, PlayerSettings::new());
+    player.enqueue("mono", wav_file(&data, &mono));
+    let output = player.fill(200).unwrap();
+    let expected: Vec<u8> = data
+        .chunks(2)
+        .flat_map(|sample| [sample[0], sample[1], sample[0], sample[1]].to_vec())
+        .collect();
+    assert!(output == expected);
+}
@@ -0,0 +1,159 @@
+use std::io::Cursor;
+use std::time::{Duration, UNIX_EPOCH};
+
+use wasapi::audiofile::open_audio_file;
+use wasapi::rawpcm::{sidecar_path, PcmSidecar, RawPcmReader, RawPcmWriter};
+use wasapi::wasapi::{BufferFlags, SampleType, WaveFormat};
+use wasapi::wav::save_wav;
+
+fn buffers(blockalign: usize) -> Vec<Vec<u8>> {
+    (0..5)
+        .map(|n| {
+            (0..(100 + 10 * n) * blockalign)
+                .map(|m| (m * 7 + n) as u8)
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn dump_roundtrip_with_events() {
+    let mut wave_fmt = WaveFormat::new(32, 24, &SampleType::Int, 48000, 6);
+    wave_fmt.set_dwchannelmask(0x3F);
+    let blockalign = wave_fmt.get_blockalign() as usize;
+    let start = UNIX_EPOCH + Duration::from_millis(1_623_760_210_250);
+    let mut writer =
+        RawPcmWriter::new(Cursor::new(Vec::new()), &wave_fmt, "{device-id}", start).unwrap();
+    let discontinuity = BufferFlags {
+        data_discontinuity: true,
+        ..Default::default()
+    };
+    let silent = BufferFlags {
+        silent: true,
+        ..Default::default()
+    };
+    let flags = [
+        BufferFlags::default(),
+        discontinuity,
+        BufferFlags::default(),
+        silent,
+        BufferFlags::default(),
+    ];
+    let data = buffers(blockalign);
+    for (buffer, flag) in data.iter().zip(flags.iter()) {
+        writer.write_buffer(buffer, flag).unwrap();
+    }
+    assert!(writer.write_bytes(&[0; 5]).is_err());
+    writer.close().unwrap();
+    let sidecar = writer.get_sidecar().clone();
+    assert_eq!(sidecar.nbr_frames, 100 + 110 + 120 + 130 + 140);
+    assert_eq!(sidecar.events.len(), 2);
+    assert_eq!(sidecar.events[0].position, 100);
+    assert_eq!(sidecar.events[0].nbr_frames, 110);
+    assert_eq!(sidecar.events[0].get_flags(), discontinuity);
+    assert_eq!(sidecar.events[1].position, 330);
+    assert_eq!(sidecar.events[1].get_flags(), silent);
+    assert_eq!(sidecar.start_time, "2021-06-15T12:30:10Z");
+    assert_eq!(sidecar.device_id, "{device-id}");
+
+    // The dump is exactly the captured bytes
+    let dump = writer.get_ref().get_ref().clone();
+    assert!(dump == data.concat());
+
+    let sidecar = PcmSidecar::from_json(&sidecar.to_json().unwrap()).unwrap();
+    assert_eq!(sidecar.get_start_time(), start);
+    let mut reader = RawPcmReader::new(Cursor::new(dump), sidecar).unwrap();
+    assert_eq!(reader.get_format(), wave_fmt);
+    assert_eq!(reader.get_nbr_frames(), 600);
+    assert_eq!(reader.get_events(0, 100).len(), 0);
+    assert_eq!(reader.get_events(50, 300).len(), 2);
+    assert_eq!(reader.get_events(209, 1).len(), 1);
+    reader.seek(100).unwrap();
+    let read = reader.read_bytes(110).unwrap();
+    assert!(read == data[1]);
+    reader.seek(590).unwrap();
+    assert_eq!(reader.read_bytes(100).unwrap().len(), 10 * blockalign);
+}
+
+#[test]
+fn sidecar_json_fields() {
+    let wave_fmt = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    let sidecar = PcmSidecar::new(&wave_fmt, "id", UNIX_EPOCH).unwrap();
+    let value: serde_json::Value = serde_json::from_str(&sidecar.to_json().unwrap()).unwrap();
+    assert_eq!(value["samplerate"], 44100);
+    assert_eq!(value["channels"], 2);
+    assert_eq!(value["bits_per_sample"], 32);
+    assert_eq!(value["sample_type"], "float");
+    assert_eq!(value["block_align"], 8);
+    assert_eq!(value["start_time"], "1970-01-01T00:00:00Z");
+    assert_eq!(
+        value["wave_format"].as_str().unwrap().len(),
+        2 * wave_fmt.to_bytes().len()
+    );
+    assert!(value["events"].as_array().unwrap().is_empty());
+}
+
+#[test]
+fn sidecar_written_by_script() {
+    // A minimal sidecar without the format bytes
+    let json = r#"{"version": 1, "samplerate": 96000, "channels": 1, "bits_per_sample": 24,
+        "valid_bits_per_sample": 24, "sample_type": "int", "block_align": 3, "channel_mask": 4}"#;
+    let sidecar = PcmSidecar::from_json(json).unwrap();
+    let mut expected = WaveFormat::new(24, 24, &SampleType::Int, 96000, 1);
+    expected.set_dwchannelmask(4);
+    assert_eq!(sidecar.get_format().unwrap(), expected);
+    let reader = RawPcmReader::new(Cursor::new(vec![0; 31]), sidecar).unwrap();
+    // The partial frame at the end is ignored
+    assert_eq!(reader.get_nbr_frames(), 10);
+
+    let bad = json.replace("\"int\"", "\"double\"");
+    assert!(PcmSidecar::from_json(&bad).unwrap().get_format().is_err());
+}
+
+#[test]
+fn dump_files() {
+    let path = std::env::temp_dir().join("wasapi_test_dump.pcm");
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let data: Vec<u8> = (0..4000).map(|n| n as u8).collect();
+    {
+        let mut writer = RawPcmWriter::create(&path, &wave_fmt, "dev", UNIX_EPOCH).unwrap();
+        writer.write_bytes(&data).unwrap();
+        // The sidecar is written when the writer is dropped
+    }
+    let sidecar = sidecar_path(&path);
+    assert_eq!(
+        sidecar,
+        std::env::temp_dir().join("wasapi_test_dump.pcm.json")
+    );
+    let mut reader = RawPcmReader::open(&path).unwrap();
+    assert_eq!(reader.get_sidecar().nbr_frames, 1000);
+    assert!(reader.read_bytes(1000).unwrap() == data);
+
+    // Dumps can be played like any other file
+    let mut file = open_audio_file(&path).unwrap();
+    assert_eq!(file.get_format(), wave_fmt);
+    let frames = file.read_frames(2).unwrap();
+    assert_eq!(frames[0][1], i16::from_le_bytes([4, 5]) as f64 / 32768.0);
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&sidecar).unwrap();
+}
+
+#[test]
+fn wav_with_unrelated_sidecar() {
+    let path = std::env::temp_dir().join("wasapi_test_sidecar.wav");
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 48000, 1);
+    save_wav(&path, &wave_fmt, &[vec![0.5; 100]]).unwrap();
+    // Some other tool has left a json file next to it
+    let sidecar = sidecar_path(&path);
+    std::fs::write(&sidecar, "{\"title\": \"not a dump\"}").unwrap();
+
+    // The contents decide, the file is still opened as a wav file
+    let mut file = open_audio_file(&path).unwrap();
+    assert_eq!(file.get_format(), wave_fmt);
+    assert_eq!(file.get_nbr_frames(), 100);
+    assert_eq!(file.read_frames(1).unwrap()[0][0], 0.5);
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&sidecar).unwrap();
+}
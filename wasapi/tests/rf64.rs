@@ -0,0 +1,249 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::{repair_wav, WavContainer, WavReader, WavWriter};
+
+// Behaves like a sparse file. Small writes are stored, large writes only move the position.
+// Reading gives the stored bytes, and zeros everywhere else.
+struct SparseSink {
+    writes: Vec<(u64, Vec<u8>)>,
+    position: u64,
+    len: u64,
+}
+
+impl SparseSink {
+    fn new() -> Self {
+        SparseSink {
+            writes: Vec::new(),
+            position: 0,
+            len: 0,
+        }
+    }
+}
+
+impl Write for SparseSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() <= 4096 {
+            self.writes.push((self.position, buf.to_vec()));
+        }
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SparseSink {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+            SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+        };
+        Ok(self.position)
+    }
+}
+
+impl Read for SparseSink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.len.saturating_sub(self.position)) as usize;
+        let start = self.position;
+        let end = start + len as u64;
+        for value in buf[..len].iter_mut() {
+            *value = 0;
+        }
+        for (offset, data) in self.writes.iter() {
+            let write_end = offset + data.len() as u64;
+            if *offset < end && write_end > start {
+                for pos in (*offset).max(start)..write_end.min(end) {
+                    buf[(pos - start) as usize] = data[(pos - offset) as usize];
+                }
+            }
+        }
+        self.position = end;
+        Ok(len)
+    }
+}
+
+fn read_u32(sink: &mut SparseSink, offset: u64) -> u32 {
+    let mut bytes = [0u8; 4];
+    sink.seek(SeekFrom::Start(offset)).unwrap();
+    sink.read_exact(&mut bytes).unwrap();
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(sink: &mut SparseSink, offset: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+    sink.seek(SeekFrom::Start(offset)).unwrap();
+    sink.read_exact(&mut bytes).unwrap();
+    u64::from_le_bytes(bytes)
+}
+
+fn read_id(sink: &mut SparseSink, offset: u64) -> [u8; 4] {
+    let mut id = [0u8; 4];
+    sink.seek(SeekFrom::Start(offset)).unwrap();
+    sink.read_exact(&mut id).unwrap();
+    id
+}
+
+// 8 channels of 32-bit float at 192 kHz
+fn large_format() -> WaveFormat {
+    WaveFormat::new(32, 32, &SampleType::Float, 192000, 8)
+}
+
+// Write a number of bytes of silence, followed by a few frames with a ramp
+fn write_large(nbr_bytes: u64) -> (SparseSink, Vec<Vec<f64>>) {
+    let mut sink = SparseSink::new();
+    let mut writer = WavWriter::new(&mut sink, &large_format()).unwrap();
+    let block = vec![0u8; 64 * 1024 * 1024];
+    let mut written = 0;
+    while written < nbr_bytes {
+        let len = (nbr_bytes - written).min(block.len() as u64) as usize;
+        writer.write_bytes(&block[..len]).unwrap();
+        written += len as u64;
+    }
+    let tail: Vec<Vec<f64>> = (0..8)
+        .map(|chan| (0..10).map(|n| (chan * 10 + n) as f64 / 100.0).collect())
+        .collect();
+    writer.write_frames(&tail).unwrap();
+    writer.close().unwrap();
+    drop(writer);
+    (sink, tail)
+}
+
+fn check_tail(reader: &mut WavReader<&mut SparseSink>, tail: &[Vec<f64>]) {
+    let nbr_frames = reader.get_nbr_frames();
+    reader.seek(nbr_frames - 10).unwrap();
+    let read = reader.read_frames(100).unwrap();
+    for (read_chan, chan) in read.iter().zip(tail.iter()) {
+        for (a, b) in read_chan.iter().zip(chan.iter()) {
+            assert_eq!(*a, *b as f32 as f64);
+        }
+        assert_eq!(read_chan.len(), 10);
+    }
+}
+
+#[test]
+fn becomes_rf64_above_4gb() {
+    let nbr_bytes = 4_400_000_000u64;
+    let (mut sink, tail) = write_large(nbr_bytes);
+    let data_bytes = nbr_bytes + 10 * 32;
+    assert_eq!(&read_id(&mut sink, 0), b"RF64");
+    assert_eq!(read_u32(&mut sink, 4), 0xFFFF_FFFF);
+    assert_eq!(&read_id(&mut sink, 8), b"WAVE");
+    assert_eq!(&read_id(&mut sink, 12), b"ds64");
+    assert_eq!(read_u32(&mut sink, 16), 28);
+    assert_eq!(read_u64(&mut sink, 20), sink.len - 8);
+    assert_eq!(read_u64(&mut sink, 28), data_bytes);
+    assert_eq!(read_u64(&mut sink, 36), data_bytes / 32);
+    // fmt chunk follows, then the data chunk
+    assert_eq!(&read_id(&mut sink, 48), b"fmt ");
+    assert_eq!(&read_id(&mut sink, 96), b"data");
+    assert_eq!(read_u32(&mut sink, 100), 0xFFFF_FFFF);
+    assert_eq!(sink.len, 104 + data_bytes);
+
+    let mut reader = WavReader::new(&mut sink).unwrap();
+    assert_eq!(reader.get_container(), WavContainer::Rf64);
+    assert_eq!(reader.get_format(), large_format());
+    assert_eq!(reader.get_nbr_frames(), data_bytes / 32);
+    assert!(reader.get_chunks().is_empty());
+    check_tail(&mut reader, &tail);
+}
+
+#[test]
+fn stays_riff_below_4gb() {
+    let nbr_bytes = 4_000_000_000u64;
+    let (mut sink, tail) = write_large(nbr_bytes);
+    assert_eq!(&read_id(&mut sink, 0), b"RIFF");
+    assert_eq!(read_u32(&mut sink, 4) as u64, sink.len - 8);
+    assert_eq!(&read_id(&mut sink, 12), b"JUNK");
+    assert_eq!(read_u32(&mut sink, 100) as u64, nbr_bytes + 320);
+    let mut reader = WavReader::new(&mut sink).unwrap();
+    assert_eq!(reader.get_container(), WavContainer::Riff);
+    assert_eq!(reader.get_nbr_frames(), (nbr_bytes + 320) / 32);
+    check_tail(&mut reader, &tail);
+}
+
+#[test]
+fn reads_bw64() {
+    let (mut sink, tail) = write_large(4_300_000_000);
+    sink.seek(SeekFrom::Start(0)).unwrap();
+    sink.write_all(b"BW64").unwrap();
+    let mut reader = WavReader::new(&mut sink).unwrap();
+    assert_eq!(reader.get_container(), WavContainer::Bw64);
+    assert_eq!(reader.get_nbr_frames(), (4_300_000_000 + 320) / 32);
+    check_tail(&mut reader, &tail);
+}
+
+// Build a Wave64 chunk, with the fourcc followed by the common GUID tail
+fn wave64_chunk(fourcc: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = fourcc.to_vec();
+    chunk.extend_from_slice(&[
+        0xF3, 0xAC, 0xD3, 0x11, 0x8C, 0xD1, 0x00, 0xC0, 0x4F, 0x8E, 0xDB, 0x8A,
+    ]);
+    chunk.extend_from_slice(&(data.len() as u64 + 24).to_le_bytes());
+    chunk.extend_from_slice(data);
+    while !chunk.len().is_multiple_of(8) {
+        chunk.push(0);
+    }
+    chunk
+}
+
+#[test]
+fn reads_wave64() {
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 48000, 1);
+    let data: Vec<u8> = (0..15).collect();
+    let mut body = vec![
+        0x77, 0x61, 0x76, 0x65, 0xF3, 0xAC, 0xD3, 0x11, 0x8C, 0xD1, 0x00, 0xC0, 0x4F, 0x8E, 0xDB,
+        0x8A,
+    ];
+    body.extend(wave64_chunk(b"fmt ", &wave_fmt.to_bytes()));
+    body.extend(wave64_chunk(b"levl", &[1, 2, 3]));
+    body.extend(wave64_chunk(b"data", &data));
+    let mut file = vec![
+        0x72, 0x69, 0x66, 0x66, 0x2E, 0x91, 0xCF, 0x11, 0xA5, 0xD6, 0x28, 0xDB, 0x04, 0xC1, 0x00,
+        0x00,
+    ];
+    file.extend_from_slice(&(body.len() as u64 + 24).to_le_bytes());
+    file.extend(body);
+
+    let mut reader = WavReader::new(Cursor::new(file)).unwrap();
+    assert_eq!(reader.get_container(), WavContainer::Wave64);
+    assert_eq!(reader.get_format(), wave_fmt);
+    assert_eq!(reader.get_nbr_frames(), 5);
+    assert_eq!(reader.get_chunk(b"levl"), Some(&[1u8, 2, 3][..]));
+    assert_eq!(reader.read_bytes(10).unwrap(), data);
+}
+
+#[test]
+fn repairs_large_sparse_file() {
+    let path = env::temp_dir().join("wasapi_rf64_test_repair.wav");
+    let wave_fmt = large_format();
+    let mut writer = WavWriter::create(&path, &wave_fmt).unwrap();
+    writer.write_bytes(&[0u8; 3200]).unwrap();
+    writer.flush().unwrap();
+    // Simulate a crash after writing a bit more than 4 GB
+    std::mem::forget(writer);
+    let file_len = 104 + 4_500_000_000u64 + 7;
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(file_len)
+        .unwrap();
+
+    let frames = repair_wav(&path).unwrap();
+    assert_eq!(frames, 4_500_000_000 / 32);
+    let reader = WavReader::open(&path).unwrap();
+    assert_eq!(reader.get_container(), WavContainer::Rf64);
+    assert_eq!(reader.get_nbr_frames(), frames);
+    drop(reader);
+    let repaired_len = fs::metadata(&path).unwrap().len();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(repaired_len, 104 + 4_500_000_000);
+}
@@ -0,0 +1,292 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use wasapi::engine::{capture_loop, playback_loop, StreamSettings};
+use wasapi::jitter::JitterSettings;
+use wasapi::network::NetworkBackend;
+use wasapi::rtp::{
+    swap_sample_bytes, RtpEncoding, RtpHeader, RtpReceiver, RtpSender, RtpStreamConfig,
+};
+use wasapi::simulated::{SimSink, SimSource};
+use wasapi::wasapi::{SampleType, WaveFormat};
+
+fn config(address: SocketAddr) -> RtpStreamConfig {
+    let mut config = RtpStreamConfig::new("Studio", address, RtpEncoding::L24, 48000, 2);
+    config.ssrc = 0x1234_5678;
+    config.media_clock_offset = 0xFFFF_FF00;
+    config
+}
+
+// Frame n holds n + 1 in the first channel and its complement in the second,
+// so that every frame can be identified and all bytes of the samples are used
+fn numbered_frames(nbr_frames: usize) -> Vec<u8> {
+    (1..=nbr_frames as u32)
+        .flat_map(|n| {
+            let mut frame = n.to_le_bytes()[..3].to_vec();
+            frame.extend_from_slice(&(n ^ 0xFF_FFFF).to_le_bytes()[..3]);
+            frame
+        })
+        .collect()
+}
+
+fn frame_numbers(data: &[u8]) -> Vec<u32> {
+    data.chunks(6)
+        .map(|frame| {
+            let n = u32::from_le_bytes([frame[0], frame[1], frame[2], 0]);
+            let complement = u32::from_le_bytes([frame[3], frame[4], frame[5], 0]);
+            if n != 0 {
+                assert_eq!(complement, n ^ 0xFF_FFFF);
+            }
+            n
+        })
+        .collect()
+}
+
+#[test]
+fn header_roundtrip() {
+    let header = RtpHeader {
+        marker: true,
+        payload_type: 97,
+        sequence: 65535,
+        timestamp: 0xDEAD_BEEF,
+        ssrc: 42,
+    };
+    let bytes = header.to_bytes();
+    assert_eq!(
+        bytes,
+        [0x80, 0xE1, 0xFF, 0xFF, 0xDE, 0xAD, 0xBE, 0xEF, 0, 0, 0, 42]
+    );
+    let mut packet = bytes.to_vec();
+    packet.extend_from_slice(&[1, 2, 3]);
+    assert_eq!(RtpHeader::parse(&packet).unwrap(), (header, &[1, 2, 3][..]));
+
+    // One contributing source, a header extension of one word, and two bytes of padding
+    let mut packet = bytes.to_vec();
+    packet[0] |= 0x30 | 1;
+    packet.extend_from_slice(&[0, 0, 0, 7]);
+    packet.extend_from_slice(&[0xBE, 0xDE, 0, 1, 9, 9, 9, 9]);
+    packet.extend_from_slice(&[1, 2, 3, 0, 2]);
+    assert_eq!(RtpHeader::parse(&packet).unwrap().1, &[1, 2, 3]);
+
+    assert!(RtpHeader::parse(&bytes[..10]).is_err());
+    let mut wrong_version = bytes;
+    wrong_version[0] = 0x40;
+    assert!(RtpHeader::parse(&wrong_version).is_err());
+}
+
+#[test]
+fn session_description() {
+    let mut stream = config("239.69.1.2:5004".parse().unwrap());
+    stream.ptime = 250;
+    let sdp = stream.get_sdp("192.168.1.10".parse().unwrap());
+    assert_eq!(
+        sdp,
+        "v=0\r\n\
+         o=- 305419896 0 IN IP4 192.168.1.10\r\n\
+         s=Studio\r\n\
+         c=IN IP4 239.69.1.2/32\r\n\
+         t=0 0\r\n\
+         m=audio 5004 RTP/AVP 96\r\n\
+         a=rtpmap:96 L24/48000/2\r\n\
+         a=ptime:0.25\r\n\
+         a=ts-refclk:local\r\n\
+         a=mediaclk:direct=4294967040\r\n"
+    );
+    assert_eq!(stream.get_frames_per_packet(), 12);
+    assert_eq!(
+        stream.get_format(),
+        WaveFormat::new(24, 24, &SampleType::Int, 48000, 2)
+    );
+
+    let mut parsed = RtpStreamConfig::from_sdp("Studio", &sdp).unwrap();
+    parsed.ssrc = stream.ssrc;
+    assert_eq!(parsed, stream);
+    assert!(RtpStreamConfig::from_sdp("Studio", "v=0\r\n").is_err());
+    let unsupported = sdp.replace("L24", "L8");
+    assert!(RtpStreamConfig::from_sdp("Studio", &unsupported).is_err());
+}
+
+#[test]
+fn sender_packets() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut stream = config(socket.local_addr().unwrap());
+    stream.encoding = RtpEncoding::L16;
+    let mut sender = RtpSender::new(&stream).unwrap();
+    let format = stream.get_format();
+    assert!(sender
+        .open(&WaveFormat::new(24, 24, &SampleType::Int, 48000, 2))
+        .is_err());
+    sender.open(&format).unwrap();
+    // Two and a half packets of 48 frames
+    let data: Vec<u8> = (0..4 * 120).map(|n| n as u8).collect();
+    sender.write(&data[..100]).unwrap();
+    sender.write(&data[100..]).unwrap();
+    assert_eq!(sender.get_stats().packets, 2);
+
+    let mut buf = [0u8; 1500];
+    let mut headers = Vec::new();
+    for packet in 0..2 {
+        let len = socket.recv(&mut buf).unwrap();
+        let (header, payload) = RtpHeader::parse(&buf[..len]).unwrap();
+        headers.push(header);
+        // The samples are sent big endian
+        let expected: Vec<u8> = data[4 * 48 * packet..4 * 48 * (packet + 1)]
+            .chunks(2)
+            .flat_map(|sample| [sample[1], sample[0]])
+            .collect();
+        assert_eq!(payload, &expected[..]);
+    }
+    assert!(headers[0].marker);
+    assert!(!headers[1].marker);
+    assert_eq!(headers[1].sequence, headers[0].sequence.wrapping_add(1));
+    // The timestamps are the media clock offset plus the position, and wrap around
+    assert_eq!(headers[0].timestamp, 0xFFFF_FF00);
+    assert_eq!(headers[1].timestamp, 0xFFFF_FF30);
+    assert!(headers
+        .iter()
+        .all(|header| header.ssrc == 0x1234_5678 && header.payload_type == 96));
+    sender.close().unwrap();
+}
+
+#[test]
+fn loopback_stream() {
+    let nbr_frames = 14400;
+    let receiver = NetworkBackend::new();
+    let stream = config("127.0.0.1:0".parse().unwrap());
+    let addr = receiver
+        .add_rtp_receiver(&stream, JitterSettings::new(1920))
+        .unwrap();
+    let sender = NetworkBackend::new();
+    let mut stream = stream.clone();
+    stream.address = addr;
+    let origin = sender.add_rtp_sender(&stream).unwrap();
+    assert_eq!(origin.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+    let format = stream.get_format();
+
+    let (tx_capt, rx_capt) = mpsc::sync_channel(200);
+    let mut settings = StreamSettings::new("Studio", &format);
+    settings.chunksize = 480;
+    settings.max_frames = Some(2 * nbr_frames as u64);
+    let receiver_capt = receiver.clone();
+    let capture = thread::spawn(move || {
+        capture_loop(&receiver_capt, &settings, tx_capt, &mut |_| {}).map_err(|e| e.to_string())
+    });
+    let (tx_play, rx_play) = mpsc::channel();
+    tx_play.send(numbered_frames(nbr_frames)).unwrap();
+    drop(tx_play);
+    playback_loop(&sender, &StreamSettings::new("Studio", &format), rx_play).unwrap();
+    let captured: Vec<u8> = rx_capt.iter().flatten().collect();
+    capture.join().unwrap().unwrap();
+
+    // Every frame arrives exactly as it was played
+    let numbers: Vec<u32> = frame_numbers(&captured)
+        .into_iter()
+        .filter(|n| *n != 0)
+        .collect();
+    assert_eq!(numbers, (1..=nbr_frames as u32).collect::<Vec<u32>>());
+    let stats = receiver.get_receiver_stats("Studio").unwrap();
+    assert_eq!(stats.packets, nbr_frames as u64 / 48);
+    assert_eq!(stats.invalid_packets, 0);
+    assert_eq!(stats.lost_frames, 0);
+    assert_eq!(
+        sender.get_sender_stats("Studio").unwrap().packets,
+        nbr_frames as u64 / 48
+    );
+}
+
+// Send a packet of 48 frames, numbered from first, at a position in the stream of config()
+fn send_packet(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    sequence: u16,
+    position: u32,
+    marker: bool,
+    first: u32,
+) {
+    let header = RtpHeader {
+        marker,
+        payload_type: 96,
+        sequence,
+        timestamp: 0xFFFF_FF00u32.wrapping_add(position),
+        ssrc: 0x1234_5678,
+    };
+    let frames: Vec<u8> =
+        numbered_frames((first + 47) as usize)[6 * (first as usize - 1)..].to_vec();
+    let mut packet = header.to_bytes().to_vec();
+    packet.extend_from_slice(&swap_sample_bytes(&frames, RtpEncoding::L24));
+    socket.send_to(&packet, addr).unwrap();
+}
+
+// Wait for the receiving thread, and read the frames of six packets
+fn receive_frames(receiver: &mut RtpReceiver, format: &WaveFormat) -> Vec<u32> {
+    thread::sleep(Duration::from_millis(200));
+    frame_numbers(&receiver.read(288, format).unwrap())
+}
+
+#[test]
+fn reordered_marker_and_restarts() {
+    let stream = config("127.0.0.1:0".parse().unwrap());
+    let format = stream.get_format();
+    let mut receiver = RtpReceiver::bind(&stream, JitterSettings::new(288)).unwrap();
+    let addr = receiver.get_local_addr();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    // The first packet, with the marker, arrives late and again as a duplicate
+    for (k, marker) in [
+        (1, false),
+        (0, true),
+        (2, false),
+        (3, false),
+        (0, true),
+        (4, false),
+        (5, false),
+    ] {
+        send_packet(&socket, addr, 100 + k as u16, 48 * k, marker, 48 * k + 1);
+    }
+    assert_eq!(
+        receive_frames(&mut receiver, &format),
+        (1..=288).collect::<Vec<u32>>()
+    );
+    let stats = receiver.get_stats();
+    assert_eq!(stats.packets, 7);
+    assert_eq!(stats.duplicate_packets, 1);
+    assert_eq!(stats.late_packets, 0);
+
+    // The sender restarts with new sequence numbers, from the start of the media clock
+    for k in 0..6 {
+        send_packet(
+            &socket,
+            addr,
+            40000 + k as u16,
+            48 * k,
+            k == 0,
+            48 * k + 1001,
+        );
+    }
+    assert_eq!(
+        receive_frames(&mut receiver, &format),
+        (1001..=1288).collect::<Vec<u32>>()
+    );
+
+    // And again, this time continuing the sequence numbers
+    for k in 0..6 {
+        send_packet(
+            &socket,
+            addr,
+            40006 + k as u16,
+            48 * k,
+            k == 0,
+            48 * k + 2001,
+        );
+    }
+    assert_eq!(
+        receive_frames(&mut receiver, &format),
+        (2001..=2288).collect::<Vec<u32>>()
+    );
+    assert_eq!(receiver.get_stats().late_packets, 0);
+}
@@ -0,0 +1,242 @@
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use wasapi::segment::{segment_name, SegmentEvent, SegmentSettings, SegmentedRecorder};
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::WavReader;
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Stereo 32-bit frames holding a counter in both channels
+fn counter_frames(start: u32, nbr_frames: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    for n in start..start + nbr_frames {
+        data.extend_from_slice(&n.to_le_bytes());
+        data.extend_from_slice(&(!n).to_le_bytes());
+    }
+    data
+}
+
+// Read back the counter values of all segments
+fn read_counters(paths: &[PathBuf]) -> Vec<Vec<u32>> {
+    paths
+        .iter()
+        .map(|path| {
+            let mut reader = WavReader::open(path).unwrap();
+            let frames = reader.get_nbr_frames() as usize;
+            let data = reader.read_bytes(frames).unwrap();
+            data.chunks(8)
+                .map(|frame| {
+                    let value = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+                    let inverted = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+                    assert_eq!(!value, inverted);
+                    value
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Write the counter in chunks of varying size, to get cuts in the middle of chunks
+fn write_counter(recorder: &mut SegmentedRecorder, total: u32) -> Vec<SegmentEvent> {
+    let mut events = Vec::new();
+    let mut position = 0;
+    let mut size = 1;
+    while position < total {
+        let frames = size.min(total - position);
+        events.extend(
+            recorder
+                .write_bytes(&counter_frames(position, frames))
+                .unwrap(),
+        );
+        position += frames;
+        size = (size * 7 + 3) % 251;
+    }
+    events.extend(recorder.close().unwrap());
+    events
+}
+
+fn check_continuity(segments: &[Vec<u32>], first: u32, total: u32) {
+    let all: Vec<u32> = segments.concat();
+    let expected: Vec<u32> = (first..total).collect();
+    assert!(all == expected, "frames lost or duplicated");
+}
+
+fn format() -> WaveFormat {
+    WaveFormat::new(32, 32, &SampleType::Int, 1000, 2)
+}
+
+#[test]
+fn split_by_duration() {
+    let dir = test_dir("wasapi_test_segment_duration");
+    let mut settings = SegmentSettings::new(1000);
+    settings.max_duration = Some(1.5);
+    settings.split_on_hour = false;
+    let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let mut recorder = SegmentedRecorder::new(&dir, "Mic", &format(), start, settings).unwrap();
+    let events = write_counter(&mut recorder, 5000);
+    let segments = recorder.get_segments();
+    assert_eq!(segments.len(), 4);
+    let counters = read_counters(&segments);
+    let lengths: Vec<usize> = counters.iter().map(|seg| seg.len()).collect();
+    assert_eq!(lengths, vec![1500, 1500, 1500, 500]);
+    check_continuity(&counters, 0, 5000);
+    let closed: Vec<u64> = events
+        .iter()
+        .filter_map(|event| match event {
+            SegmentEvent::Closed { nbr_frames, .. } => Some(*nbr_frames),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(closed, vec![1500, 1500, 1500, 500]);
+
+    // The time reference of each segment points at its first frame
+    let reader = WavReader::open(&segments[1]).unwrap();
+    let bext = reader.get_bext().unwrap().unwrap();
+    let since_midnight = (1_600_000_000 % 86400) * 1000;
+    assert_eq!(bext.time_reference, since_midnight + 1500);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn split_by_size() {
+    let dir = test_dir("wasapi_test_segment_size");
+    let mut settings = SegmentSettings::new(1000);
+    settings.max_duration = None;
+    settings.split_on_hour = false;
+    // Not a whole number of frames, segments get 1000 frames
+    settings.max_bytes = Some(8003);
+    let mut recorder =
+        SegmentedRecorder::new(&dir, "Mic", &format(), UNIX_EPOCH, settings).unwrap();
+    write_counter(&mut recorder, 3500);
+    let counters = read_counters(&recorder.get_segments());
+    let lengths: Vec<usize> = counters.iter().map(|seg| seg.len()).collect();
+    assert_eq!(lengths, vec![1000, 1000, 1000, 500]);
+    check_continuity(&counters, 0, 3500);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn split_on_hour() {
+    let dir = test_dir("wasapi_test_segment_hour");
+    let mut settings = SegmentSettings::new(1000);
+    settings.max_duration = Some(3600.0);
+    // Start 2.5 s before 13:00, 2020-09-13
+    let start = UNIX_EPOCH + Duration::from_millis(1_600_002_000_000 - 2500);
+    let mut recorder = SegmentedRecorder::new(&dir, "Mic", &format(), start, settings).unwrap();
+    write_counter(&mut recorder, 4000);
+    let segments = recorder.get_segments();
+    let counters = read_counters(&segments);
+    let lengths: Vec<usize> = counters.iter().map(|seg| seg.len()).collect();
+    assert_eq!(lengths, vec![2500, 1500]);
+    check_continuity(&counters, 0, 4000);
+    let names: Vec<String> = segments
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names[0], "Mic_20200913_125957_0000.wav");
+    assert_eq!(names[1], "Mic_20200913_130000_0001.wav");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn retention() {
+    let dir = test_dir("wasapi_test_segment_retention");
+    let mut settings = SegmentSettings::new(1000);
+    settings.max_duration = Some(0.5);
+    settings.split_on_hour = false;
+    settings.max_segments = Some(3);
+    settings.template = "seg_{seq}.wav".to_string();
+    let mut recorder =
+        SegmentedRecorder::new(&dir, "Mic", &format(), UNIX_EPOCH, settings).unwrap();
+    let events = write_counter(&mut recorder, 3200);
+    let deleted = events
+        .iter()
+        .filter(|event| matches!(event, SegmentEvent::Deleted(_)))
+        .count();
+    assert_eq!(deleted, 4);
+    let mut files: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["seg_0004.wav", "seg_0005.wav", "seg_0006.wav"]);
+    let counters = read_counters(&recorder.get_segments());
+    check_continuity(&counters, 2000, 3200);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn retention_with_missing_segments() {
+    let dir = test_dir("wasapi_test_segment_missing");
+    let mut settings = SegmentSettings::new(1000);
+    settings.max_duration = Some(0.5);
+    settings.split_on_hour = false;
+    settings.max_segments = Some(3);
+    settings.template = "seg_{seq}.wav".to_string();
+    let mut recorder =
+        SegmentedRecorder::new(&dir, "Mic", &format(), UNIX_EPOCH, settings).unwrap();
+    let mut events = Vec::new();
+    for n in 0..32 {
+        if n == 12 {
+            // The first segment was moved away, and the second can not be deleted
+            std::fs::remove_file(dir.join("seg_0000.wav")).unwrap();
+            std::fs::remove_file(dir.join("seg_0001.wav")).unwrap();
+            std::fs::create_dir(dir.join("seg_0001.wav")).unwrap();
+        }
+        events.extend(recorder.write_bytes(&counter_frames(100 * n, 100)).unwrap());
+    }
+    events.extend(recorder.close().unwrap());
+    let deleted: Vec<&SegmentEvent> = events
+        .iter()
+        .filter(|event| {
+            matches!(
+                event,
+                SegmentEvent::Deleted(_) | SegmentEvent::DeleteFailed { .. }
+            )
+        })
+        .collect();
+    assert_eq!(deleted.len(), 3);
+    assert!(
+        matches!(deleted[0], SegmentEvent::DeleteFailed { path, .. } if path.ends_with("seg_0001.wav"))
+    );
+    assert_eq!(deleted[1], &SegmentEvent::Deleted(dir.join("seg_0002.wav")));
+    assert_eq!(deleted[2], &SegmentEvent::Deleted(dir.join("seg_0003.wav")));
+    // The recording continued
+    let counters = read_counters(&recorder.get_segments());
+    check_continuity(&counters, 2000, 3200);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn names_from_template() {
+    let start = UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+    let name = segment_name(
+        "{date}/{device}-{time}-{seq}.wav",
+        "Speakers (USB: 2)",
+        start,
+        12,
+    );
+    assert_eq!(name, "20240229/Speakers__USB__2_-235959-0012.wav");
+}
+
+#[test]
+fn invalid_settings() {
+    let mut settings = SegmentSettings::new(1000);
+    settings.max_bytes = Some(0);
+    assert!(SegmentedRecorder::new(".", "Mic", &format(), UNIX_EPOCH, settings).is_err());
+    let mut recorder = SegmentedRecorder::new(
+        std::env::temp_dir(),
+        "Mic",
+        &format(),
+        UNIX_EPOCH,
+        SegmentSettings::new(1000),
+    )
+    .unwrap();
+    assert!(recorder.write_bytes(&[0; 3]).is_err());
+}
@@ -0,0 +1,179 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use wasapi::engine::{playback_loop_from, StreamSettings};
+use wasapi::sharedmem::{SharedMemReader, SharedMemWriter};
+use wasapi::simulated::{SimDeviceConfig, SimulatedBackend};
+use wasapi::wasapi::{Direction, SampleType, WaveFormat};
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+}
+
+fn ring_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "wasapi_sharedmem_{}_{}.ring",
+        name,
+        std::process::id()
+    ))
+}
+
+// Frames first to first + nbr_frames - 1, each holding its number
+fn numbered_frames(first: usize, nbr_frames: usize) -> Vec<u8> {
+    (first as u32..(first + nbr_frames) as u32)
+        .flat_map(|n| n.to_le_bytes())
+        .collect()
+}
+
+fn frame_numbers(data: &[u8]) -> Vec<u32> {
+    data.chunks(4)
+        .map(|frame| u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]))
+        .collect()
+}
+
+// Start a separate process that writes numbered frames to a ring, see child_writer
+fn spawn_writer(path: &Path, nbr_frames: usize, wait: bool) -> Child {
+    Command::new(env::current_exe().unwrap())
+        .args(["child_writer", "--exact", "--ignored", "--quiet"])
+        .env("WASAPI_SHM_RING", path)
+        .env("WASAPI_SHM_FRAMES", nbr_frames.to_string())
+        .env("WASAPI_SHM_WAIT", if wait { "1" } else { "0" })
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+// Run in a separate process by the other tests.
+// Writes frames 1 to WASAPI_SHM_FRAMES to the ring in chunks of 480,
+// waiting for the reader to make room when WASAPI_SHM_WAIT is 1.
+#[test]
+#[ignore]
+fn child_writer() {
+    let path = match env::var("WASAPI_SHM_RING") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let nbr_frames: usize = env::var("WASAPI_SHM_FRAMES").unwrap().parse().unwrap();
+    let wait = env::var("WASAPI_SHM_WAIT").unwrap() == "1";
+    let mut writer = SharedMemWriter::open(path).unwrap();
+    assert_eq!(writer.get_format(), format());
+    let mut next = 1;
+    while next <= nbr_frames {
+        let chunk = 480.min(nbr_frames + 1 - next);
+        if wait {
+            while writer.get_free_frames() < chunk {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        writer.write(&numbered_frames(next, chunk));
+        next += chunk;
+    }
+    writer.close();
+}
+
+#[test]
+fn ring_in_one_process() {
+    let path = ring_path("local");
+    let mut reader = SharedMemReader::create(&path, &format(), 1000).unwrap();
+    let mut writer = SharedMemWriter::open(&path).unwrap();
+    assert_eq!(writer.get_format(), format());
+    assert_eq!(writer.get_free_frames(), 1000);
+    assert!(reader.read(10).is_empty());
+
+    // Go around the end of the ring a few times
+    let mut next = 1;
+    for _ in 0..10 {
+        assert_eq!(writer.write(&numbered_frames(next, 700)), 700);
+        assert_eq!(reader.get_available_frames(), 700);
+        assert_eq!(
+            frame_numbers(&reader.read(1000)),
+            (next as u32..next as u32 + 700).collect::<Vec<u32>>()
+        );
+        next += 700;
+    }
+    // Frames that do not fit are dropped and counted
+    assert_eq!(writer.write(&numbered_frames(next, 1500)), 1000);
+    assert_eq!(writer.get_free_frames(), 0);
+    assert_eq!(reader.get_overruns(), 500);
+    assert_eq!(frame_numbers(&reader.read(1))[0], next as u32);
+    assert!(!reader.is_closed());
+    drop(writer);
+    assert!(reader.is_closed());
+    assert_eq!(reader.get_available_frames(), 999);
+
+    std::fs::write(&path, vec![0; 200]).unwrap();
+    assert!(SharedMemReader::open(&path).is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn writer_in_other_process() {
+    let path = ring_path("stream");
+    let nbr_frames = 48000;
+    let mut reader = SharedMemReader::create(&path, &format(), 4800).unwrap();
+    let mut child = spawn_writer(&path, nbr_frames, true);
+    let mut received = Vec::new();
+    loop {
+        let closed = reader.is_closed();
+        let data = reader.read(1000);
+        if data.is_empty() {
+            if closed {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        received.extend(frame_numbers(&data));
+    }
+    assert!(child.wait().unwrap().success());
+    assert_eq!(received, (1..=nbr_frames as u32).collect::<Vec<u32>>());
+    assert_eq!(reader.get_overruns(), 0);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn overrun_in_other_process() {
+    let path = ring_path("overrun");
+    let mut reader = SharedMemReader::create(&path, &format(), 1000).unwrap();
+    let mut child = spawn_writer(&path, 3000, false);
+    assert!(child.wait().unwrap().success());
+    assert!(reader.is_closed());
+    assert_eq!(reader.get_overruns(), 2000);
+    assert_eq!(
+        frame_numbers(&reader.read(2000)),
+        (1..=1000).collect::<Vec<u32>>()
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn render_from_other_process() {
+    let path = ring_path("render");
+    let nbr_frames = 9600;
+    let mut reader = SharedMemReader::create(&path, &format(), 4800).unwrap();
+    let backend = SimulatedBackend::new();
+    backend
+        .add_device(SimDeviceConfig::new(
+            "Speakers",
+            &Direction::Render,
+            &format(),
+        ))
+        .unwrap();
+    let mut child = spawn_writer(&path, nbr_frames, true);
+    // Plays until the other process has closed the ring, and everything is played
+    playback_loop_from(
+        &backend,
+        &StreamSettings::new("Speakers", &format()),
+        &mut reader,
+    )
+    .unwrap();
+    assert!(child.wait().unwrap().success());
+    let played: Vec<u32> = frame_numbers(&backend.get_rendered("Speakers").unwrap())
+        .into_iter()
+        .filter(|n| *n != 0)
+        .collect();
+    assert_eq!(played, (1..=nbr_frames as u32).collect::<Vec<u32>>());
+    let _ = std::fs::remove_file(&path);
+}
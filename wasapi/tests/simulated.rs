@@ -0,0 +1,291 @@
+use std::f64::consts::PI;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use wasapi::backend::Backend;
+use wasapi::conversion::channels_to_bytes;
+use wasapi::engine::{capture_loop, get_stream_format, playback_loop, StreamSettings};
+use wasapi::glitch::GlitchKind;
+use wasapi::simulated::{SimDeviceConfig, SimulatedBackend};
+use wasapi::wasapi::{Direction, FormatSupported, SampleType, ShareMode, WaveFormat};
+
+fn format() -> WaveFormat {
+    WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+}
+
+// A backend with one speaker and one microphone, 10 ms default period
+fn backend() -> SimulatedBackend {
+    let backend = SimulatedBackend::new();
+    let mut speakers = SimDeviceConfig::new("Speakers", &Direction::Render, &format());
+    speakers
+        .formats
+        .push(WaveFormat::new(24, 24, &SampleType::Int, 96000, 2));
+    backend.add_device(speakers).unwrap();
+    backend
+        .add_device(SimDeviceConfig::new(
+            "Microphone",
+            &Direction::Capture,
+            &format(),
+        ))
+        .unwrap();
+    backend
+}
+
+fn counter_bytes(nbr_frames: usize) -> Vec<u8> {
+    (0..4 * nbr_frames).map(|n| (n % 251) as u8).collect()
+}
+
+fn sine_bytes(nbr_frames: usize) -> Vec<u8> {
+    let wave: Vec<f64> = (0..nbr_frames)
+        .map(|n| 0.5 * (2.0 * PI * 440.0 * n as f64 / 48000.0).sin())
+        .collect();
+    channels_to_bytes(&[wave.clone(), wave], &format()).unwrap()
+}
+
+#[test]
+fn devices_and_formats() {
+    let backend = backend();
+    let collection = backend.get_device_collection(&Direction::Render).unwrap();
+    assert_eq!(collection.get_nbr_devices().unwrap(), 1);
+    let device = collection.get_device_with_name("Speakers").unwrap();
+    assert_eq!(device.get_id().unwrap(), "{sim}.Speakers");
+    assert!(collection.get_device_with_name("Microphone").is_err());
+    let default = backend.get_default_device(&Direction::Capture).unwrap();
+    assert_eq!(default.get_friendlyname().unwrap(), "Microphone");
+
+    let client = device.get_iaudioclient().unwrap();
+    let other = WaveFormat::new(24, 24, &SampleType::Int, 96000, 2);
+    match client.is_supported(&other, &ShareMode::Shared).unwrap() {
+        FormatSupported::ClosestMatch(mix) => assert_eq!(mix, format()),
+        FormatSupported::Yes => panic!("only the mix format is supported in shared mode"),
+    }
+    assert!(matches!(
+        client.is_supported(&other, &ShareMode::Exclusive).unwrap(),
+        FormatSupported::Yes
+    ));
+    let unsupported = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    assert!(client
+        .is_supported(&unsupported, &ShareMode::Exclusive)
+        .is_err());
+    assert_eq!(client.get_periods().unwrap(), (100000, 30000));
+}
+
+#[test]
+fn shared_render_padding_and_underrun() {
+    let backend = backend();
+    let device = backend
+        .get_device_collection(&Direction::Render)
+        .unwrap()
+        .get_device_with_name("Speakers")
+        .unwrap();
+    let mut client = device.get_iaudioclient().unwrap();
+    assert!(client.get_available_frames().is_err());
+    client
+        .initialize_client(&format(), 30000, &Direction::Render, &ShareMode::Shared)
+        .unwrap();
+    // Events every 10 ms, and room for two periods
+    assert_eq!(client.get_bufferframecount().unwrap(), 960);
+    let event = client.set_get_eventhandle().unwrap();
+    let render = client.get_audiorenderclient().unwrap();
+    let data = counter_bytes(2000);
+    render.write_to_device(960, 4, &data[..3840]).unwrap();
+    assert!(render.write_to_device(1, 4, &data[..4]).is_err());
+    // Nothing happens before the stream is started
+    assert!(event.wait_for_event(100).is_err());
+    assert_eq!(backend.get_time(), Duration::from_millis(100));
+    client.start_stream().unwrap();
+
+    event.wait_for_event(100).unwrap();
+    assert_eq!(backend.get_time(), Duration::from_millis(110));
+    assert_eq!(client.get_current_padding().unwrap(), 480);
+    assert_eq!(client.get_available_frames().unwrap(), 480);
+    render.write_to_device(480, 4, &data[3840..5760]).unwrap();
+    event.wait_for_event(100).unwrap();
+    event.wait_for_event(100).unwrap();
+    // Only 200 frames for the last period
+    render.write_to_device(200, 4, &data[5760..6560]).unwrap();
+    event.wait_for_event(100).unwrap();
+    event.wait_for_event(100).unwrap();
+    assert_eq!(backend.get_time(), Duration::from_millis(150));
+
+    let rendered = backend.get_rendered("Speakers").unwrap();
+    assert_eq!(rendered.len(), 5 * 480 * 4);
+    assert!(rendered[..6560] == data[..6560]);
+    assert!(rendered[6560..].iter().all(|value| *value == 0));
+    assert_eq!(backend.get_underruns("Speakers").unwrap(), vec![1640, 1920]);
+    client.stop_stream().unwrap();
+    assert!(event.wait_for_event(100).is_err());
+}
+
+#[test]
+fn exclusive_render() {
+    let backend = backend();
+    let format_96 = WaveFormat::new(24, 24, &SampleType::Int, 96000, 2);
+    let device = backend.get_default_device(&Direction::Render).unwrap();
+    let mut client = device.get_iaudioclient().unwrap();
+    // Shorter than the minimum period
+    client
+        .initialize_client(&format_96, 10000, &Direction::Render, &ShareMode::Exclusive)
+        .unwrap();
+    assert_eq!(client.get_bufferframecount().unwrap(), 288);
+    let event = client.set_get_eventhandle().unwrap();
+    let render = client.get_audiorenderclient().unwrap();
+    client.start_stream().unwrap();
+    let data: Vec<u8> = (0..288 * 6 * 10).map(|n| (n % 241) as u8).collect();
+    for chunk in data.chunks(288 * 6) {
+        // The whole buffer is free at every event
+        assert_eq!(client.get_available_frames().unwrap(), 288);
+        render.write_to_device(288, 6, chunk).unwrap();
+        event.wait_for_event(100).unwrap();
+    }
+    assert_eq!(backend.get_time(), Duration::from_millis(30));
+    assert!(backend.get_rendered("Speakers").unwrap() == data);
+    assert!(backend.get_underruns("Speakers").unwrap().is_empty());
+}
+
+#[test]
+fn scripted_capture() {
+    let backend = backend();
+    let script = counter_bytes(1000);
+    backend.add_capture_data("Microphone", &script).unwrap();
+    let device = backend.get_default_device(&Direction::Capture).unwrap();
+    let mut client = device.get_iaudioclient().unwrap();
+    assert!(client
+        .initialize_client(&format(), 0, &Direction::Render, &ShareMode::Shared)
+        .is_err());
+    client
+        .initialize_client(&format(), 0, &Direction::Capture, &ShareMode::Shared)
+        .unwrap();
+    let event = client.set_get_eventhandle().unwrap();
+    let capture = client.get_audiocaptureclient().unwrap();
+    assert!(client.get_audiorenderclient().is_err());
+    client.start_stream().unwrap();
+
+    let mut captured = Vec::new();
+    let mut flags = Vec::new();
+    for _ in 0..3 {
+        event.wait_for_event(100).unwrap();
+        let nbr_frames = capture.get_next_nbr_frames().unwrap() as usize;
+        assert_eq!(nbr_frames, 480);
+        let mut data = vec![0; 4 * nbr_frames];
+        flags.push(capture.read_from_device(4, &mut data).unwrap());
+        captured.extend(data);
+        assert_eq!(capture.get_next_nbr_frames().unwrap(), 0);
+    }
+    // The script, then silence
+    assert!(captured[..4000] == script[..]);
+    assert!(captured[4000..].iter().all(|value| *value == 0));
+    assert!(!flags[0].silent && !flags[1].silent && !flags[2].silent);
+    event.wait_for_event(100).unwrap();
+    let mut queue = std::collections::VecDeque::new();
+    assert!(
+        capture
+            .read_from_device_to_deque(4, &mut queue)
+            .unwrap()
+            .silent
+    );
+
+    // Not reading for a while, the buffer holds two periods
+    backend.advance(Duration::from_millis(50));
+    assert_eq!(client.get_current_padding().unwrap(), 960);
+    assert_eq!(backend.get_nbr_overflows("Microphone").unwrap(), 3);
+    capture.read_from_device_to_deque(4, &mut queue).unwrap();
+    capture.read_from_device_to_deque(4, &mut queue).unwrap();
+    // The event is still signaled from the missed periods
+    event.wait_for_event(100).unwrap();
+    assert_eq!(capture.get_next_nbr_frames().unwrap(), 0);
+    event.wait_for_event(100).unwrap();
+    let flags = capture.read_from_device_to_deque(4, &mut queue).unwrap();
+    assert!(flags.data_discontinuity);
+}
+
+#[test]
+fn playback_loop_plays_everything() {
+    let backend = backend();
+    let data = counter_bytes(10000);
+    let (tx_play, rx_play) = mpsc::channel();
+    for chunk in data.chunks(4 * 1024) {
+        tx_play.send(chunk.to_vec()).unwrap();
+    }
+    drop(tx_play);
+    let settings = StreamSettings::new("Speakers", &format());
+    playback_loop(&backend, &settings, rx_play).unwrap();
+    let rendered = backend.get_rendered("Speakers").unwrap();
+    // All data without gaps, and the last period completed with silence
+    assert_eq!(rendered.len(), 4 * 10080);
+    assert!(rendered[..data.len()] == data[..]);
+    assert!(rendered[data.len()..].iter().all(|value| *value == 0));
+    assert!(backend.get_underruns("Speakers").unwrap().is_empty());
+    assert_eq!(backend.get_time(), Duration::from_millis(210));
+}
+
+#[test]
+fn playback_loop_closed_channel_and_missing_device() {
+    let backend = backend();
+    let (tx_play, rx_play) = mpsc::channel::<Vec<u8>>();
+    drop(tx_play);
+    let settings = StreamSettings::new("Speakers", &format());
+    playback_loop(&backend, &settings, rx_play).unwrap();
+    assert!(backend.get_rendered("Speakers").unwrap().is_empty());
+
+    let settings = StreamSettings::new("Nonexistent", &format());
+    let (_tx_play, rx_play) = mpsc::channel::<Vec<u8>>();
+    assert!(playback_loop(&backend, &settings, rx_play).is_err());
+}
+
+#[test]
+fn stream_format_closest_match() {
+    let backend = backend();
+    let wanted = WaveFormat::new(32, 32, &SampleType::Float, 44100, 2);
+    let mut settings = StreamSettings::new("Speakers", &wanted);
+    // The closest match is used, and the caller can see that it differs
+    let used = get_stream_format(&backend, &settings, &Direction::Render).unwrap();
+    assert_eq!(used.get_samplespersec(), 48000);
+    assert_eq!(used.get_bitspersample(), 16);
+
+    // A stream that must keep its format fails before playing anything
+    settings.exact_format = true;
+    assert!(get_stream_format(&backend, &settings, &Direction::Render).is_err());
+    let (tx_play, rx_play) = mpsc::channel();
+    tx_play.send(vec![0; 4 * 480]).unwrap();
+    drop(tx_play);
+    assert!(playback_loop(&backend, &settings, rx_play).is_err());
+    assert!(backend.get_rendered("Speakers").unwrap().is_empty());
+
+    // The format is used as is when the device supports it
+    let settings = StreamSettings::new("Speakers", &format());
+    let used = get_stream_format(&backend, &settings, &Direction::Render).unwrap();
+    assert_eq!(used.get_samplespersec(), 48000);
+}
+
+#[test]
+fn capture_loop_sends_chunks() {
+    let backend = backend();
+    let script = sine_bytes(4800);
+    backend.add_capture_data("Microphone", &script).unwrap();
+    let mut settings = StreamSettings::new("Microphone", &format());
+    settings.chunksize = 1000;
+    let (tx_capt, rx_capt) = mpsc::sync_channel(2);
+    let backend_capt = backend.clone();
+    let handle = thread::spawn(move || {
+        let mut silent_positions = Vec::new();
+        let result = capture_loop(&backend_capt, &settings, tx_capt, &mut |event| {
+            if let GlitchKind::SilentBuffer = event.kind {
+                silent_positions.push(event.position);
+            }
+        });
+        result
+            .map(|_| silent_positions)
+            .map_err(|err| err.to_string())
+    });
+    let mut captured = Vec::new();
+    for _ in 0..6 {
+        captured.extend(rx_capt.recv().unwrap());
+    }
+    drop(rx_capt);
+    let silent_positions = handle.join().unwrap().unwrap();
+    assert!(captured[..script.len()] == script[..]);
+    assert!(captured[script.len()..].iter().all(|value| *value == 0));
+    assert_eq!(silent_positions[0], 4800);
+}
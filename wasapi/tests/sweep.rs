@@ -0,0 +1,189 @@
+use std::env;
+
+use wasapi::loopback::SimulatedLoopback;
+use wasapi::sweep::{Sweep, SweepMeasurement};
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::load_wav;
+
+const SAMPLERATE: usize = 48000;
+const TAPS: [f64; 3] = [0.5, 0.3, 0.2];
+
+fn fir(data: &[f64]) -> Vec<f64> {
+    (0..data.len())
+        .map(|n| {
+            TAPS.iter()
+                .enumerate()
+                .filter(|(k, _)| *k <= n)
+                .map(|(k, tap)| tap * data[n - k])
+                .sum()
+        })
+        .collect()
+}
+
+fn fir_magnitude_db(freq: f64) -> f64 {
+    let w = 2.0 * std::f64::consts::PI * freq / SAMPLERATE as f64;
+    let re: f64 = TAPS
+        .iter()
+        .enumerate()
+        .map(|(k, t)| t * (w * k as f64).cos())
+        .sum();
+    let im: f64 = TAPS
+        .iter()
+        .enumerate()
+        .map(|(k, t)| t * (w * k as f64).sin())
+        .sum();
+    10.0 * (re * re + im * im).log10()
+}
+
+fn fir_phase_deg(freq: f64) -> f64 {
+    let w = 2.0 * std::f64::consts::PI * freq / SAMPLERATE as f64;
+    let re: f64 = TAPS
+        .iter()
+        .enumerate()
+        .map(|(k, t)| t * (w * k as f64).cos())
+        .sum();
+    let im: f64 = TAPS
+        .iter()
+        .enumerate()
+        .map(|(k, t)| -t * (w * k as f64).sin())
+        .sum();
+    im.atan2(re).to_degrees()
+}
+
+fn filtered_loopback(delay: usize) -> SimulatedLoopback {
+    let format = WaveFormat::new(32, 32, &SampleType::Float, SAMPLERATE, 2);
+    let mut loopback = SimulatedLoopback::new(&format, delay);
+    loopback.set_processor(Box::new(|data: &[Vec<f64>]| {
+        data.iter().map(|chan| fir(chan)).collect()
+    }));
+    loopback
+}
+
+#[test]
+fn sweep_deconvolves_to_impulse() {
+    let sweep = Sweep::new(10.0, 24000.0, 32768, SAMPLERATE, 0.5).unwrap();
+    let deconvolved = sweep.deconvolve(sweep.get_signal());
+    let zero = sweep.get_signal().len() - 1;
+    assert!(
+        (deconvolved[zero] - 1.0).abs() < 0.02,
+        "peak {}",
+        deconvolved[zero]
+    );
+    assert!(deconvolved[zero + 10].abs() < 0.02);
+    assert!(deconvolved[zero - 10].abs() < 0.02);
+}
+
+#[test]
+fn measure_known_filter() {
+    let mut loopback = filtered_loopback(321);
+    let mut measurement = SweepMeasurement::new(10.0, 24000.0, 65536);
+    measurement.amplitude_dbfs = 0.0;
+    measurement.max_latency = 4800;
+    let responses = measurement.measure(&mut loopback).unwrap();
+    assert_eq!(responses.len(), 2);
+    for response in responses.iter() {
+        assert_eq!(response.latency, 321);
+        let ir = &response.impulse_response;
+        assert_eq!(ir.len(), measurement.ir_length);
+        for (n, tap) in TAPS.iter().enumerate() {
+            let value = ir[response.pre_frames + n];
+            assert!((value - tap).abs() < 0.02, "tap {}: {}", n, value);
+        }
+        assert!(ir[response.pre_frames + 10].abs() < 0.02);
+        for freq in [100.0, 1000.0, 5000.0, 12000.0].iter() {
+            let magnitude = response.response.get_magnitude_at(*freq).unwrap();
+            let point = response.response.frequencies[response
+                .response
+                .frequencies
+                .iter()
+                .position(|f| (f - freq).abs() / freq < 0.03)
+                .unwrap()];
+            assert!(
+                (magnitude - fir_magnitude_db(point)).abs() < 0.2,
+                "{} Hz: {} dB, expected {} dB",
+                point,
+                magnitude,
+                fir_magnitude_db(point)
+            );
+        }
+        // The reference time for the phase is the first tap
+        let phase = response.response.get_phase_at(1000.0).unwrap();
+        let point = response.response.frequencies[response
+            .response
+            .frequencies
+            .iter()
+            .position(|f| (f - 1000.0).abs() < 30.0)
+            .unwrap()];
+        assert!(
+            (phase - fir_phase_deg(point)).abs() < 1.0,
+            "phase {}, expected {}",
+            phase,
+            fir_phase_deg(point)
+        );
+    }
+}
+
+#[test]
+fn separates_harmonics() {
+    let format = WaveFormat::new(32, 32, &SampleType::Float, SAMPLERATE, 1);
+    let mut loopback = SimulatedLoopback::new(&format, 100);
+    // x - 0.1 x^3 gives a third harmonic with an amplitude of 0.025 * A^3,
+    // and lowers the fundamental to (1 - 0.075 * A^2) * A.
+    // The sweep ends at 7 kHz to keep the harmonic below the Nyquist frequency.
+    loopback.set_processor(Box::new(|data: &[Vec<f64>]| {
+        data.iter()
+            .map(|chan| chan.iter().map(|x| x - 0.1 * x * x * x).collect())
+            .collect()
+    }));
+    let mut measurement = SweepMeasurement::new(20.0, 7000.0, 131072);
+    measurement.amplitude_dbfs = -6.0206;
+    measurement.max_latency = 1000;
+    let responses = measurement.measure(&mut loopback).unwrap();
+    let response = &responses[0];
+    assert_eq!(response.latency, 100);
+    let second = &response.harmonics[0];
+    let third = &response.harmonics[1];
+    assert_eq!(second.order, 2);
+    assert_eq!(third.order, 3);
+    let expected_third = 20.0 * (0.025f64 * 0.25 / (1.0 - 0.075 * 0.25)).log10();
+    let expected_linear = 20.0 * (1.0 - 0.075 * 0.25f64).log10();
+    for (n, freq) in response.response.frequencies.iter().enumerate() {
+        // Below a few hundred Hz the linear response is affected by the length of the impulse response
+        if *freq > 300.0 && *freq < 2000.0 {
+            assert!(
+                (third.distortion_db[n] - expected_third).abs() < 0.5,
+                "{} Hz: {} dB",
+                freq,
+                third.distortion_db[n]
+            );
+            assert!(
+                second.distortion_db[n] < -60.0,
+                "{} Hz: {} dB",
+                freq,
+                second.distortion_db[n]
+            );
+            assert!((response.response.magnitude_db[n] - expected_linear).abs() < 0.1);
+        }
+    }
+}
+
+#[test]
+fn export_impulse_response() {
+    let mut loopback = filtered_loopback(50);
+    let mut measurement = SweepMeasurement::new(10.0, 24000.0, 32768);
+    measurement.ir_length = 1024;
+    measurement.max_latency = 1000;
+    let responses = measurement.measure(&mut loopback).unwrap();
+    let path = env::temp_dir().join("wasapi_sweep_test_ir.wav");
+    responses[0]
+        .save_impulse_response(&path, SAMPLERATE)
+        .unwrap();
+    let (wave_fmt, channels) = load_wav(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(wave_fmt.get_samplespersec(), SAMPLERATE as u32);
+    assert_eq!(wave_fmt.get_nchannels(), 1);
+    assert_eq!(channels[0].len(), 1024);
+    for (saved, original) in channels[0].iter().zip(responses[0].impulse_response.iter()) {
+        assert_eq!(*saved, *original as f32 as f64);
+    }
+}
@@ -0,0 +1,169 @@
+use std::f64::consts::PI;
+
+use wasapi::conversion::channels_to_bytes;
+use wasapi::generator::{sine, NoiseGenerator};
+use wasapi::vad::{
+    spectral_flatness, AutoRecorder, RecorderEvent, VadEvent, VadEventKind, VadSettings,
+    VoiceActivityDetector,
+};
+use wasapi::wasapi::{SampleType, WaveFormat};
+
+const SAMPLERATE: usize = 48000;
+const BLOCK: u64 = 960;
+
+// A voiced sound with a 150 Hz fundamental and falling harmonics, with a syllable rate envelope
+fn speech_like(len: usize) -> Vec<f64> {
+    (0..len)
+        .map(|n| {
+            let t = n as f64 / SAMPLERATE as f64;
+            let envelope = 0.6 + 0.4 * (2.0 * PI * 4.0 * t).sin();
+            let voiced: f64 = (1..=10)
+                .map(|k| (2.0 * PI * 150.0 * k as f64 * t).sin() / k as f64)
+                .sum();
+            0.15 * envelope * voiced
+        })
+        .collect()
+}
+
+// Silence and bursts, as (start, length) in frames, over a quiet noise floor
+fn bursts(len: usize, bursts: &[(usize, usize)]) -> Vec<f64> {
+    let mut noise = NoiseGenerator::new(3);
+    let mut signal = noise.generate(len, 0.0003);
+    for (start, length) in bursts.iter() {
+        for (value, voiced) in signal[*start..].iter_mut().zip(speech_like(*length)) {
+            *value += voiced;
+        }
+    }
+    signal
+}
+
+fn run_detector(signal: &[f64], settings: VadSettings) -> Vec<VadEvent> {
+    let mut detector = VoiceActivityDetector::new(settings);
+    let mut events = Vec::new();
+    for chunk in signal.chunks(441) {
+        events.extend(detector.process(&[chunk.to_vec()]));
+    }
+    events
+}
+
+fn assert_between(value: u64, low: u64, high: u64) {
+    assert!(
+        value >= low && value <= high,
+        "{} is not in {}..{}",
+        value,
+        low,
+        high
+    );
+}
+
+#[test]
+fn flatness_of_tone_and_noise() {
+    let tone = sine(1000.0, 0.5, 960, SAMPLERATE);
+    assert!(spectral_flatness(&tone, SAMPLERATE, 100.0, 8000.0) < 0.01);
+    let noise = NoiseGenerator::new(1).generate(960, 0.5);
+    let flatness = spectral_flatness(&noise, SAMPLERATE, 100.0, 8000.0);
+    assert!(flatness > 0.4 && flatness < 0.8, "{}", flatness);
+}
+
+#[test]
+fn detects_start_and_stop() {
+    let signal = bursts(5 * SAMPLERATE, &[(48000, 72000)]);
+    let events = run_detector(&signal, VadSettings::new(SAMPLERATE));
+    assert_eq!(events.len(), 2, "{:?}", events);
+    assert_eq!(events[0].kind, VadEventKind::Start);
+    assert_between(events[0].position, 48000 - BLOCK, 48000 + BLOCK);
+    assert_eq!(events[1].kind, VadEventKind::Stop);
+    // One second of hang time after the end of the burst
+    assert_between(events[1].position, 168000, 168000 + 2 * BLOCK);
+    assert!((events[1].time - events[1].position as f64 / 48000.0).abs() < 1.0e-12);
+}
+
+#[test]
+fn ignores_loud_noise() {
+    let signal = NoiseGenerator::new(5).generate(3 * SAMPLERATE, 0.2);
+    let events = run_detector(&signal, VadSettings::new(SAMPLERATE));
+    assert!(events.is_empty(), "{:?}", events);
+}
+
+#[test]
+fn hang_time_bridges_pauses() {
+    let short_pause = bursts(6 * SAMPLERATE, &[(24000, 48000), (86400, 48000)]);
+    let events = run_detector(&short_pause, VadSettings::new(SAMPLERATE));
+    assert_eq!(events.len(), 2, "{:?}", events);
+
+    let long_pause = bursts(8 * SAMPLERATE, &[(24000, 48000), (168000, 48000)]);
+    let events = run_detector(&long_pause, VadSettings::new(SAMPLERATE));
+    let kinds: Vec<VadEventKind> = events.iter().map(|event| event.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            VadEventKind::Start,
+            VadEventKind::Stop,
+            VadEventKind::Start,
+            VadEventKind::Stop
+        ]
+    );
+    assert_between(events[2].position, 168000 - BLOCK, 168000 + BLOCK);
+}
+
+#[test]
+fn hysteresis_keeps_quiet_tail() {
+    // A tail 28 dB down, at about -47 dBFS, is too quiet to start but loud enough to keep going
+    let mut signal = bursts(6 * SAMPLERATE, &[(48000, 48000)]);
+    for (value, voiced) in signal[96000..].iter_mut().zip(speech_like(96000)) {
+        *value += 0.04 * voiced;
+    }
+    let mut settings = VadSettings::new(SAMPLERATE);
+    settings.hang_time = 0.5;
+    let events = run_detector(&signal, settings.clone());
+    assert_eq!(events.len(), 2, "{:?}", events);
+    assert_between(events[1].position, 216000, 216000 + 2 * BLOCK);
+
+    let mut quiet = bursts(3 * SAMPLERATE, &[]);
+    for (value, voiced) in quiet[48000..].iter_mut().zip(speech_like(96000)) {
+        *value += 0.04 * voiced;
+    }
+    assert!(run_detector(&quiet, settings).is_empty());
+}
+
+#[test]
+fn records_with_pre_roll() {
+    let format = WaveFormat::new(16, 16, &SampleType::Int, SAMPLERATE, 2);
+    let signal = bursts(5 * SAMPLERATE, &[(96000, 48000)]);
+    let data = channels_to_bytes(&[signal.clone(), signal], &format).unwrap();
+    let mut settings = VadSettings::new(SAMPLERATE);
+    settings.hang_time = 0.5;
+    let mut recorder = AutoRecorder::new(&format, settings, 0.25);
+    let mut started = None;
+    let mut stopped = None;
+    let mut recorded = Vec::new();
+    for chunk in data.chunks(4 * 480) {
+        for event in recorder.process(chunk).unwrap() {
+            match event {
+                RecorderEvent::Started { position, .. } => {
+                    assert!(started.is_none());
+                    started = Some(position);
+                }
+                RecorderEvent::Data(bytes) => {
+                    assert!(started.is_some() && stopped.is_none());
+                    recorded.extend(bytes);
+                }
+                RecorderEvent::Stopped {
+                    position,
+                    nbr_frames,
+                    ..
+                } => {
+                    assert_eq!(position - started.unwrap(), nbr_frames);
+                    stopped = Some(position);
+                }
+            }
+        }
+    }
+    assert!(!recorder.is_recording());
+    let start = started.unwrap();
+    let end = stopped.unwrap();
+    assert_between(start, 96000 - 12000 - BLOCK, 96000 - 12000 + BLOCK);
+    assert_between(end, 144000 + 24000, 144000 + 24000 + 2 * BLOCK);
+    assert_eq!(recorded.len() as u64, (end - start) * 4);
+    assert_eq!(recorded[..], data[start as usize * 4..end as usize * 4]);
+}
@@ -0,0 +1,307 @@
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use wasapi::generator::NoiseGenerator;
+use wasapi::wasapi::{SampleType, WaveFormat};
+use wasapi::wav::{load_wav, repair_wav, save_wav, WavChunk, WavReader, WavWriter};
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("wasapi_wav_test_{}.wav", name))
+}
+
+// Random samples, quantized to what the format can store
+fn test_signal(wave_fmt: &WaveFormat, nbr_frames: usize) -> Vec<Vec<f64>> {
+    let mut noise = NoiseGenerator::new(11);
+    let validbits = wave_fmt.get_validbitspersample() as i32;
+    (0..wave_fmt.get_nchannels())
+        .map(|_| {
+            noise
+                .generate(nbr_frames, 0.9)
+                .iter()
+                .map(|value| match wave_fmt.get_subformat().unwrap() {
+                    SampleType::Int => {
+                        let scale = 2.0f64.powi(validbits - 1);
+                        (value * scale).round() / scale
+                    }
+                    SampleType::Float if validbits == 32 => *value as f32 as f64,
+                    SampleType::Float => *value,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn all_formats() -> Vec<WaveFormat> {
+    let mut formats = Vec::new();
+    for (storebits, validbits, sample_type) in [
+        (8, 8, SampleType::Int),
+        (16, 16, SampleType::Int),
+        (24, 24, SampleType::Int),
+        (24, 20, SampleType::Int),
+        (32, 32, SampleType::Int),
+        (32, 24, SampleType::Int),
+        (32, 32, SampleType::Float),
+        (64, 64, SampleType::Float),
+    ]
+    .iter()
+    {
+        for channels in [1, 2, 6].iter() {
+            let extensible = WaveFormat::new(*storebits, *validbits, sample_type, 44100, *channels);
+            if let Ok(plain) = extensible.to_waveformatex() {
+                formats.push(plain);
+            }
+            formats.push(extensible);
+        }
+    }
+    formats
+}
+
+fn round_trip(wave_fmt: &WaveFormat, nbr_frames: usize) -> Vec<u8> {
+    let signal = test_signal(wave_fmt, nbr_frames);
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), wave_fmt).unwrap();
+    // Write in uneven pieces
+    let mut start = 0;
+    while start < nbr_frames {
+        let end = (start + 37).min(nbr_frames);
+        let chunk: Vec<Vec<f64>> = signal
+            .iter()
+            .map(|chan| chan[start..end].to_vec())
+            .collect();
+        writer.write_frames(&chunk).unwrap();
+        start = end;
+    }
+    writer.close().unwrap();
+    let file = writer.get_ref().get_ref().clone();
+
+    let mut reader = WavReader::new(Cursor::new(file.clone())).unwrap();
+    assert_eq!(reader.get_format(), *wave_fmt);
+    assert_eq!(reader.get_nbr_frames(), nbr_frames as u64);
+    let mut read = vec![Vec::new(); wave_fmt.get_nchannels() as usize];
+    loop {
+        let chunk = reader.read_frames(50).unwrap();
+        if chunk[0].is_empty() {
+            break;
+        }
+        for (chan, values) in read.iter_mut().zip(chunk) {
+            chan.extend(values);
+        }
+    }
+    assert_eq!(read, signal, "{:?}", wave_fmt);
+    file
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+#[test]
+fn round_trip_all_formats() {
+    for wave_fmt in all_formats().iter() {
+        let file = round_trip(wave_fmt, 1001);
+        assert_eq!(read_u32(&file, 4) as usize, file.len() - 8);
+        assert_eq!(file.len() % 2, 0);
+    }
+}
+
+#[test]
+fn fmt_chunk_sizes() {
+    let extensible = WaveFormat::new(16, 16, &SampleType::Int, 48000, 2);
+    assert_eq!(extensible.to_bytes().len(), 40);
+    let pcm = extensible.to_waveformatex().unwrap();
+    assert_eq!(pcm.get_formattag(), 1);
+    assert_eq!(pcm.to_bytes().len(), 16);
+    let float = WaveFormat::new(32, 32, &SampleType::Float, 48000, 2)
+        .to_waveformatex()
+        .unwrap();
+    assert_eq!(float.get_formattag(), 3);
+    assert_eq!(float.to_bytes().len(), 18);
+    assert!(WaveFormat::new(32, 24, &SampleType::Int, 48000, 2)
+        .to_waveformatex()
+        .is_err());
+    for wave_fmt in all_formats().iter() {
+        assert_eq!(
+            WaveFormat::from_bytes(&wave_fmt.to_bytes()).unwrap(),
+            *wave_fmt
+        );
+    }
+}
+
+#[test]
+fn extensible_fmt_chunk_bytes() {
+    // The layout and subformat GUID must match what Windows writes, on all platforms
+    let wave_fmt = WaveFormat::new(32, 32, &SampleType::Float, 48000, 2);
+    let expected = [
+        0xFE, 0xFF, 2, 0, 0x80, 0xBB, 0, 0, 0x00, 0xDC, 0x05, 0, 8, 0, 32, 0, 22, 0, 32, 0, 3, 0,
+        0, 0, 3, 0, 0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71,
+    ];
+    assert_eq!(wave_fmt.to_bytes(), expected);
+    assert_eq!(WaveFormat::from_bytes(&expected).unwrap(), wave_fmt);
+    assert!(matches!(
+        WaveFormat::from_bytes(&expected).unwrap().get_subformat(),
+        Ok(SampleType::Float)
+    ));
+}
+
+#[test]
+fn invalid_fmt_chunks() {
+    let valid = WaveFormat::new(16, 16, &SampleType::Int, 48000, 2)
+        .to_waveformatex()
+        .unwrap()
+        .to_bytes();
+    // More channels than the channel mask has bits, no channels, and a block alignment of zero
+    for (offset, value) in [(2, 40u16), (2, 0), (12, 0)] {
+        let mut fmt = valid.clone();
+        fmt[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        assert!(WaveFormat::from_bytes(&fmt).is_err());
+    }
+
+    // The same in files, which are rejected by both the reader and the repair
+    let path = temp_path("invalid_fmt");
+    save_wav(
+        &path,
+        &WaveFormat::new(16, 16, &SampleType::Int, 48000, 2),
+        &[vec![0.0; 10], vec![0.0; 10]],
+    )
+    .unwrap();
+    let file = fs::read(&path).unwrap();
+    let fmt_offset = file.windows(4).position(|id| id == b"fmt ").unwrap() + 8;
+    for (offset, value) in [(2, 40u16), (12, 0)] {
+        let mut broken = file.clone();
+        broken[fmt_offset + offset..fmt_offset + offset + 2].copy_from_slice(&value.to_le_bytes());
+        fs::write(&path, &broken).unwrap();
+        assert!(WavReader::open(&path).is_err());
+        assert!(repair_wav(&path).is_err());
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn keeps_channel_mask() {
+    let mut wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 48000, 4);
+    // Front left, front right, back left, back right
+    wave_fmt.set_dwchannelmask(0x33);
+    let file = round_trip(&wave_fmt, 100);
+    let reader = WavReader::new(Cursor::new(file)).unwrap();
+    assert_eq!(reader.get_format().get_dwchannelmask(), 0x33);
+}
+
+#[test]
+fn odd_sizes_and_unknown_chunks() {
+    // 8 bit mono with an odd number of frames needs a pad byte after the data
+    let wave_fmt = WaveFormat::new(8, 8, &SampleType::Int, 8000, 1)
+        .to_waveformatex()
+        .unwrap();
+    let signal = test_signal(&wave_fmt, 7);
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), &wave_fmt).unwrap();
+    writer.add_chunk(b"abcd", &[1, 2, 3]).unwrap();
+    writer.write_frames(&signal).unwrap();
+    writer.add_chunk(b"efgh", &[4, 5, 6, 7, 8]).unwrap();
+    assert!(writer.add_chunk(b"data", &[]).is_err());
+    writer.close().unwrap();
+    let file = writer.get_ref().get_ref().clone();
+    // RIFF header, JUNK, fmt, abcd with pad, data with pad, efgh with pad
+    assert_eq!(file.len(), 12 + 36 + 8 + 16 + 8 + 4 + 8 + 8 + 8 + 6);
+    assert_eq!(read_u32(&file, 4) as usize, file.len() - 8);
+
+    let mut reader = WavReader::new(Cursor::new(file)).unwrap();
+    assert_eq!(
+        reader.get_chunks(),
+        &[
+            WavChunk {
+                id: *b"abcd",
+                data: vec![1, 2, 3]
+            },
+            WavChunk {
+                id: *b"efgh",
+                data: vec![4, 5, 6, 7, 8]
+            },
+        ]
+    );
+    assert_eq!(reader.get_chunk(b"efgh"), Some(&[4u8, 5, 6, 7, 8][..]));
+    assert_eq!(reader.get_chunk(b"ijkl"), None);
+    assert_eq!(reader.read_frames(100).unwrap(), signal);
+}
+
+#[test]
+fn seek_and_read() {
+    let wave_fmt = WaveFormat::new(16, 16, &SampleType::Int, 44100, 2);
+    let signal = test_signal(&wave_fmt, 1000);
+    let mut writer = WavWriter::new(Cursor::new(Vec::new()), &wave_fmt).unwrap();
+    writer.write_frames(&signal).unwrap();
+    writer.close().unwrap();
+    let mut reader = WavReader::new(Cursor::new(writer.get_ref().get_ref().clone())).unwrap();
+    reader.seek(990).unwrap();
+    assert_eq!(reader.get_position(), 990);
+    let tail = reader.read_frames(100).unwrap();
+    assert_eq!(tail[1], signal[1][990..].to_vec());
+    assert_eq!(reader.get_position(), 1000);
+    assert!(reader.read_frames(100).unwrap()[0].is_empty());
+}
+
+#[test]
+fn finalizes_on_drop() {
+    let path = temp_path("drop");
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 48000, 2);
+    let signal = test_signal(&wave_fmt, 333);
+    {
+        let mut writer = WavWriter::create(&path, &wave_fmt).unwrap();
+        writer.write_frames(&signal).unwrap();
+    }
+    let file = fs::read(&path).unwrap();
+    assert_eq!(read_u32(&file, 4) as usize, file.len() - 8);
+    let (read_fmt, read) = load_wav(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(read_fmt, wave_fmt);
+    assert_eq!(read, signal);
+}
+
+#[test]
+fn recovers_unfinished_file() {
+    let path = temp_path("crash");
+    let wave_fmt = WaveFormat::new(24, 24, &SampleType::Int, 48000, 1);
+    let signal = test_signal(&wave_fmt, 501);
+    let mut writer = WavWriter::create(&path, &wave_fmt).unwrap();
+    writer.write_frames(&signal).unwrap();
+    writer.flush().unwrap();
+    // Simulate a crash, the writer never gets to update the header
+    std::mem::forget(writer);
+    // Add half a frame, as if the crash happened in the middle of a write
+    let mut file = fs::read(&path).unwrap();
+    file.extend_from_slice(&[1, 2]);
+    fs::write(&path, &file).unwrap();
+    assert_eq!(read_u32(&file, 4), 0xFFFF_FFFF);
+
+    // The reader uses the data up to the end of the file
+    let reader = WavReader::open(&path).unwrap();
+    assert_eq!(reader.get_nbr_frames(), 501);
+    drop(reader);
+
+    assert_eq!(repair_wav(&path).unwrap(), 501);
+    let file = fs::read(&path).unwrap();
+    let data_size = 501 * 3;
+    assert_eq!(file.len(), 12 + 36 + 8 + 40 + 8 + data_size + 1);
+    assert_eq!(read_u32(&file, 4) as usize, file.len() - 8);
+    assert_eq!(read_u32(&file, 100) as usize, data_size);
+    let (_, read) = load_wav(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(read, signal);
+}
+
+#[test]
+fn save_and_load() {
+    let path = temp_path("save");
+    let wave_fmt = WaveFormat::new(32, 32, &SampleType::Float, 96000, 2);
+    let signal = test_signal(&wave_fmt, 2048);
+    save_wav(&path, &wave_fmt, &signal).unwrap();
+    let (read_fmt, read) = load_wav(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(read_fmt, wave_fmt);
+    assert_eq!(read, signal);
+}